@@ -0,0 +1,70 @@
+//! Encrypting and decrypting secrets of 1KB, 1MB and 100MB the way arcanum does, armored, to
+//! catch regressions in the armor path that dominates for large secrets. Run with
+//! `cargo bench`.
+
+use arcanum::{armor, decrypt_bytes, encrypt_bytes};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const SIZES: [(&str, usize); 3] = [("1KB", 1 << 10), ("1MB", 1 << 20), ("100MB", 100 << 20)];
+
+fn encrypt(plaintext: &[u8], recipient: &age::x25519::Recipient) -> Vec<u8> {
+    encrypt_bytes(vec![Box::new(recipient.clone())], plaintext)
+}
+
+fn decrypt(encrypted: &[u8], identity: &age::x25519::Identity) -> Vec<u8> {
+    decrypt_bytes(&[identity], encrypted).unwrap()
+}
+
+fn crypto(c: &mut Criterion) {
+    let identity = age::x25519::Identity::generate();
+    let recipient = identity.to_public();
+    let mut encrypt_group = c.benchmark_group("encrypt");
+    for (name, size) in SIZES {
+        let plaintext = vec![0x5a; size];
+        encrypt_group.throughput(Throughput::Bytes(size as u64));
+        encrypt_group.bench_with_input(BenchmarkId::from_parameter(name), &plaintext, |b, p| {
+            b.iter(|| encrypt(p, &recipient))
+        });
+    }
+    encrypt_group.finish();
+
+    let mut decrypt_group = c.benchmark_group("decrypt");
+    for (name, size) in SIZES {
+        let encrypted = encrypt(&vec![0x5a; size], &recipient);
+        decrypt_group.throughput(Throughput::Bytes(size as u64));
+        decrypt_group.bench_with_input(BenchmarkId::from_parameter(name), &encrypted, |b, e| {
+            b.iter(|| decrypt(e, &identity))
+        });
+    }
+    decrypt_group.finish();
+}
+
+fn armoring(c: &mut Criterion) {
+    let mut armor_group = c.benchmark_group("armor");
+    for (name, size) in SIZES {
+        let binary = vec![0xa5; size];
+        armor_group.throughput(Throughput::Bytes(size as u64));
+        armor_group.bench_with_input(BenchmarkId::from_parameter(name), &binary, |b, binary| {
+            b.iter(|| armor::armor(binary))
+        });
+    }
+    armor_group.finish();
+
+    let mut dearmor_group = c.benchmark_group("dearmor");
+    for (name, size) in SIZES {
+        let armored = armor::armor(&vec![0xa5; size]);
+        dearmor_group.throughput(Throughput::Bytes(size as u64));
+        dearmor_group.bench_with_input(BenchmarkId::from_parameter(name), &armored, |b, a| {
+            b.iter(|| armor::dearmor(a).unwrap())
+        });
+    }
+    dearmor_group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    // The 100MB cases take seconds per iteration
+    config = Criterion::default().sample_size(10);
+    targets = crypto, armoring
+}
+criterion_main!(benches);
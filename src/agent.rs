@@ -0,0 +1,246 @@
+//! `arcanum agent` keeps identities unlocked in a small daemon listening on a unix socket, so
+//! passphrases and hardware tokens are only needed once per session. The identities never
+//! leave the agent: clients send it the stanzas of a file header and get the file key back.
+
+use crate::identity::{IdentitySource, IdentityStore};
+use age::secrecy::ExposeSecret;
+use age::{DecryptError, Identity};
+use age_core::format::{FileKey, Stanza};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize)]
+struct WireStanza {
+    tag: String,
+    args: Vec<String>,
+    /// Base64 of the stanza body
+    body: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    stanzas: Vec<WireStanza>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    /// Base64 of the file key, when one of the agent's identities could unwrap it
+    file_key: Option<String>,
+}
+
+/// Where the agent listens, `$ARCANUM_AGENT_SOCK` when set. Setting it to an empty string
+/// stops the CLI from using an agent.
+pub fn socket_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("ARCANUM_AGENT_SOCK") {
+        return Some(PathBuf::from(path)).filter(|p| !p.as_os_str().is_empty());
+    }
+    Some(
+        dirs::runtime_dir()
+            .or_else(dirs::cache_dir)?
+            .join("arcanum")
+            .join("agent.sock"),
+    )
+}
+
+/// Identity forwarding every unwrap to a running agent
+pub struct AgentIdentity {
+    socket: PathBuf,
+}
+
+impl AgentIdentity {
+    /// The running agent, if there is one
+    #[cfg(unix)]
+    pub fn connect() -> Option<Self> {
+        let socket = socket_path()?;
+        std::os::unix::net::UnixStream::connect(&socket).ok()?;
+        Some(AgentIdentity { socket })
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect() -> Option<Self> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn request(&self, request: &Request) -> std::io::Result<Response> {
+        use std::io::{BufRead, BufReader, Write};
+        let mut stream = std::os::unix::net::UnixStream::connect(&self.socket)?;
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        stream.write_all(&line)?;
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    #[cfg(not(unix))]
+    fn request(&self, _request: &Request) -> std::io::Result<Response> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Identity for AgentIdentity {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        self.unwrap_stanzas(std::slice::from_ref(stanza))
+    }
+
+    fn unwrap_stanzas(&self, stanzas: &[Stanza]) -> Option<Result<FileKey, DecryptError>> {
+        let request = Request {
+            stanzas: stanzas
+                .iter()
+                .map(|s| WireStanza {
+                    tag: s.tag.clone(),
+                    args: s.args.clone(),
+                    body: STANDARD.encode(&s.body),
+                })
+                .collect(),
+        };
+        let response = match self.request(&request) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Ignoring the agent at {:?}: {}", self.socket, e);
+                return None;
+            }
+        };
+        let key: [u8; 16] = STANDARD.decode(response.file_key?).ok()?.try_into().ok()?;
+        Some(Ok(FileKey::from(key)))
+    }
+}
+
+/// Unwrap the stanzas of one request with the agent's identities
+fn answer(identities: &[Box<dyn Identity>], request: Request) -> Response {
+    let stanzas: Option<Vec<Stanza>> = request
+        .stanzas
+        .into_iter()
+        .map(|s| {
+            Some(Stanza {
+                tag: s.tag,
+                args: s.args,
+                body: STANDARD.decode(s.body).ok()?,
+            })
+        })
+        .collect();
+    let file_key = stanzas.and_then(|stanzas| {
+        identities
+            .iter()
+            .find_map(|i| i.unwrap_stanzas(&stanzas).and_then(Result::ok))
+    });
+    Response {
+        file_key: file_key.map(|key| STANDARD.encode(key.expose_secret())),
+    }
+}
+
+/// Keep the process's memory out of swap, so unlocked keys never hit the disk
+#[cfg(target_os = "linux")]
+fn lock_memory() {
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        eprintln!(
+            "warning: unable to lock memory, unlocked identities may be swapped out: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock_memory() {
+    eprintln!("warning: memory isn't locked on this platform, identities may be swapped out");
+}
+
+/// Serve the identities of `sources` on the agent socket for `ttl` seconds, after which the
+/// agent exits and the unlocked identities are gone. Returns false when it couldn't start.
+#[cfg(unix)]
+pub fn run(sources: Vec<IdentitySource>, ttl: u64) -> bool {
+    use crate::fsutil::{create_dir_all_with_mode, set_mode};
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let Some(socket) = socket_path() else {
+        eprintln!("The agent is disabled, ARCANUM_AGENT_SOCK is empty");
+        return false;
+    };
+    if UnixStream::connect(&socket).is_ok() {
+        eprintln!("An agent is already listening on {:?}", socket);
+        return false;
+    }
+
+    lock_memory();
+    let store = IdentityStore::local(sources);
+    let identities = store.identities();
+    // Passphrase protected identities unlock on first use, do that now rather than on a
+    // client's request so the prompt shows up here
+    for identity in identities {
+        identity.unwrap_stanzas(&[]);
+    }
+
+    if let Some(parent) = socket.parent() {
+        if let Err(e) = create_dir_all_with_mode(parent, 0o700) {
+            eprintln!("Unable to create {:?}: {}", parent, e);
+            return false;
+        }
+    }
+    // Left behind by an agent that didn't shut down cleanly
+    let _ = std::fs::remove_file(&socket);
+    // Bound under a umask leaving the socket to its owner, so other users can't connect in
+    // the moment before its mode is set, whatever directory it's in
+    let umask = unsafe { libc::umask(0o177) };
+    let bound = UnixListener::bind(&socket);
+    unsafe { libc::umask(umask) };
+    let listener = match bound {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Unable to listen on {:?}: {}", socket, e);
+            return false;
+        }
+    };
+    if let Err(e) = set_mode(&socket, 0o600) {
+        eprintln!("Unable to restrict {:?} to its owner: {}", socket, e);
+        let _ = std::fs::remove_file(&socket);
+        return false;
+    }
+    eprintln!(
+        "Agent holding {} identities on {:?} for {} seconds",
+        identities.len(),
+        socket,
+        ttl
+    );
+    eprintln!("export ARCANUM_AGENT_SOCK={}", socket.display());
+
+    let expiring = socket.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_secs(ttl));
+        let _ = std::fs::remove_file(&expiring);
+        eprintln!("Agent expired");
+        std::process::exit(0);
+    });
+
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        // Clients are served one at a time, so one that never finishes its request mustn't
+        // keep the others waiting
+        let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(5)));
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        if reader.read_line(&mut line).is_err() {
+            continue;
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => answer(identities, request),
+            Err(_) => Response { file_key: None },
+        };
+        let mut data = serde_json::to_vec(&response).unwrap();
+        data.push(b'\n');
+        let _ = (&stream).write_all(&data);
+    }
+    true
+}
+
+#[cfg(not(unix))]
+pub fn run(_sources: Vec<IdentitySource>, _ttl: u64) -> bool {
+    eprintln!("The agent needs unix sockets, which this platform doesn't have");
+    false
+}
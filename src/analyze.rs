@@ -0,0 +1,185 @@
+use crate::identity::IdentityStore;
+use crate::{plaintext_from_ciphertext_source, transform, CacheFile};
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use std::path::Path;
+
+/// Shorter than this is weak whatever the characters
+const MIN_LENGTH: usize = 12;
+
+/// Rough guessing resistance below which a password should be rotated
+const MIN_BITS: f64 = 64.0;
+
+/// Key names that hold passwords and the like, rather than user names, hosts or ports
+const SECRET_WORDS: [&str; 7] = [
+    "pass",
+    "secret",
+    "token",
+    "key",
+    "pin",
+    "credential",
+    "auth",
+];
+
+fn is_secret_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SECRET_WORDS.iter().any(|word| name.contains(word))
+}
+
+/// Password-like values in a plaintext with a name for each: the secret looking string values
+/// of a JSON document, the secret looking variables of an env file, or the whole plaintext
+/// when it is a single line
+pub fn fields(plaintext: &str) -> Vec<(String, String)> {
+    let mut fields = vec![];
+    if let Ok(value) = serde_json::from_str::<Value>(plaintext) {
+        json_fields(&value, "", false, &mut fields);
+        return fields;
+    }
+
+    let lines: Vec<&str> = plaintext
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    let assignments: Vec<(&str, &str)> = lines
+        .iter()
+        .filter_map(|line| line.strip_prefix("export ").unwrap_or(line).split_once('='))
+        .collect();
+    if !assignments.is_empty() && assignments.len() == lines.len() {
+        for (name, value) in assignments {
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            if is_secret_name(name) && !value.is_empty() {
+                fields.push((name.trim().to_string(), value.to_string()));
+            }
+        }
+    } else if let [line] = lines[..] {
+        fields.push(("(whole file)".to_string(), line.to_string()));
+    }
+    fields
+}
+
+fn json_fields(value: &Value, path: &str, secret: bool, fields: &mut Vec<(String, String)>) {
+    match value {
+        Value::String(s) if secret => fields.push((path.to_string(), s.clone())),
+        Value::Object(map) => {
+            for (key, value) in map {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                json_fields(value, &path, secret || is_secret_name(key), fields);
+            }
+        }
+        Value::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                json_fields(value, &format!("{}[{}]", path, i), secret, fields);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Which kind of character `c` is, and how many characters there are of that kind
+fn kind_of(c: char) -> (usize, f64) {
+    if c.is_ascii_lowercase() {
+        (0, 26.0)
+    } else if c.is_ascii_uppercase() {
+        (1, 26.0)
+    } else if c.is_ascii_digit() {
+        (2, 10.0)
+    } else if c.is_ascii() {
+        (3, 33.0)
+    } else {
+        (4, 100.0)
+    }
+}
+
+/// Number of kinds of characters used and a rough estimate of the bits of entropy, assuming
+/// the characters were picked at random from the kinds used
+fn strength(password: &str) -> (usize, f64) {
+    let mut used = [0.0; 5];
+    for c in password.chars() {
+        let (kind, size) = kind_of(c);
+        used[kind] = size;
+    }
+    let kinds = used.iter().filter(|size| **size > 0.0).count();
+    let pool: f64 = used.iter().sum();
+    let mut distinct: Vec<char> = password.chars().collect();
+    distinct.sort_unstable();
+    distinct.dedup();
+    // Repeating a few characters doesn't make a password any harder to guess
+    let length = password.chars().count().min(distinct.len() * 2);
+    (kinds, length as f64 * pool.max(1.0).log2())
+}
+
+/// How often `password` appears in an offline copy of the Pwned Passwords range files, as
+/// written by `haveibeenpwned-downloader --single false`: one `<first 5 hex of SHA-1>.txt`
+/// per prefix with `<rest of the hash>:<count>` lines. Only the prefix file is read.
+fn breach_count(dataset: &Path, password: &str) -> Result<u64, String> {
+    let hash = format!("{:X}", Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = hash.split_at(5);
+    let path = dataset.join(format!("{}.txt", prefix));
+    let data = std::fs::read_to_string(&path).map_err(|e| format!("{:?}: {}", path, e))?;
+    Ok(data
+        .lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .find(|(s, _)| s.eq_ignore_ascii_case(suffix))
+        .and_then(|(_, count)| count.parse().ok())
+        .unwrap_or(0))
+}
+
+/// Decrypt `ciphertext` and report on the strength of the passwords in it. Returns true when
+/// any of them should be rotated.
+pub fn analyze(
+    cache: &CacheFile,
+    ciphertext: &Path,
+    breached: Option<&Path>,
+    identities: &IdentityStore,
+) -> bool {
+    let plaintext = plaintext_from_ciphertext_source(&cache.resolve_source(ciphertext), identities);
+    let plaintext = transform::apply_all(&cache.transforms_for_file(ciphertext), plaintext)
+        .unwrap_or_else(|e| {
+            eprintln!("Unable to transform plaintext of {:?}: {}", ciphertext, e);
+            std::process::exit(1);
+        });
+    let Ok(plaintext) = String::from_utf8(plaintext) else {
+        eprintln!("{:?} is not text, nothing to analyze", ciphertext);
+        return false;
+    };
+
+    let fields = fields(&plaintext);
+    if fields.is_empty() {
+        eprintln!("No password-like values found in {:?}", ciphertext);
+        return false;
+    }
+    let mut weak = false;
+    for (name, password) in fields {
+        let (kinds, bits) = strength(&password);
+        let mut problems = vec![];
+        let length = password.chars().count();
+        if length < MIN_LENGTH {
+            problems.push(format!("only {} characters", length));
+        }
+        if bits < MIN_BITS {
+            problems.push(format!(
+                "about {:.0} bits of entropy from {} kinds of characters",
+                bits, kinds
+            ));
+        }
+        if let Some(dataset) = breached {
+            match breach_count(dataset, &password) {
+                Ok(0) => {}
+                Ok(count) => problems.push(format!("seen {} times in breaches", count)),
+                Err(e) => eprintln!("Unable to check {} against breaches: {}", name, e),
+            }
+        }
+        if problems.is_empty() {
+            println!("ok        {}: about {:.0} bits", name, bits);
+        } else {
+            println!("weak      {}: {}", name, problems.join(", "));
+            weak = true;
+        }
+    }
+    weak
+}
@@ -0,0 +1,222 @@
+//! Versioned JSON view of a project's secrets for other tools. The internal cache format can
+//! change between releases; a version emitted here never does, new fields or meanings get a
+//! new version instead.
+
+use crate::{canonical_recipient, CacheFile};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Versions `cache emit` can produce
+pub const VERSIONS: [u32; 1] = [1];
+
+/// How `cache show` prints the config
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ShowFormat {
+    /// The newest API version's document, as `cache emit` prints it
+    Json,
+    /// A line per file of each section, for reading
+    Table,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FileV1 {
+    name: String,
+    source: PathBuf,
+    dest: PathBuf,
+    owner: String,
+    group: String,
+    permissions: String,
+    /// Canonical keys able to decrypt on their own, or as share holders when `threshold` is set
+    readers: Vec<String>,
+    threshold: Option<u8>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SectionV1 {
+    kind: &'static str,
+    name: Vec<String>,
+    admin_recipients: Vec<String>,
+    files: Vec<FileV1>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentV1 {
+    api_version: u32,
+    project: Option<PathBuf>,
+    sections: Vec<SectionV1>,
+    /// Sections missing because they failed to evaluate
+    failed: Vec<String>,
+}
+
+fn v1(cache: &CacheFile) -> DocumentV1 {
+    let sections = cache
+        .configs()
+        .into_iter()
+        .map(|(scope, config)| {
+            let mut names: Vec<&String> = config.files.keys().collect();
+            names.sort();
+            SectionV1 {
+                kind: scope.kind(),
+                name: scope.name_parts(),
+                admin_recipients: config
+                    .admin_recipients
+                    .iter()
+                    .map(|r| canonical_recipient(r))
+                    .collect(),
+                files: names
+                    .into_iter()
+                    .map(|name| {
+                        let file = &config.files[name];
+                        FileV1 {
+                            name: name.clone(),
+                            source: file.source.clone(),
+                            dest: file.dest.clone(),
+                            owner: file.owner.clone(),
+                            group: file.group.clone(),
+                            permissions: file.permissions.clone(),
+                            readers: file
+                                .readers(config)
+                                .into_iter()
+                                .map(|r| canonical_recipient(r))
+                                .collect(),
+                            threshold: file.threshold,
+                        }
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    DocumentV1 {
+        api_version: 1,
+        project: cache.project.clone(),
+        sections,
+        failed: cache.failed.clone(),
+    }
+}
+
+/// The cache as a document of API `version`, None for versions that don't exist
+pub fn emit(cache: &CacheFile, version: u32) -> Option<String> {
+    match version {
+        1 => Some(serde_json::to_string_pretty(&v1(cache)).unwrap()),
+        _ => None,
+    }
+}
+
+/// Print the resolved config: every file of every section with its destination and readers
+pub fn show(cache: &CacheFile, format: ShowFormat) {
+    let document = v1(cache);
+    if let ShowFormat::Json = format {
+        println!("{}", serde_json::to_string_pretty(&document).unwrap());
+        return;
+    }
+    // Tags aren't part of API version 1, they come from the config itself
+    let configs = cache.configs();
+    for (section, (_, config)) in document.sections.iter().zip(&configs) {
+        let name = match section.name.as_slice() {
+            [] => section.kind.to_string(),
+            parts => format!("{} {}", section.kind, parts.join(".")),
+        };
+        println!("{} ({} files)", name, section.files.len());
+        for recipient in &section.admin_recipients {
+            println!("  admin: {}", recipient);
+        }
+        for file in &section.files {
+            let readers = match file.threshold {
+                Some(threshold) => format!("{} of {} holders", threshold, file.readers.len()),
+                None => format!("{} readers", file.readers.len()),
+            };
+            let tags = &config.files[&file.name].tags;
+            let tags = if tags.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", tags.join(", "))
+            };
+            println!(
+                "  {:<24} {} -> {} {}:{} {} ({}){}",
+                file.name,
+                file.source.display(),
+                file.dest.display(),
+                file.owner,
+                file.group,
+                file.permissions,
+                readers,
+                tags
+            );
+        }
+    }
+    for section in &document.failed {
+        println!("{} (failed to evaluate)", section);
+    }
+}
+
+const SCHEMA_V1: &str = r##"{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "$id": "https://github.com/bitnixdev/arcanum/schema/cache-v1.json",
+  "title": "arcanum cache emit --version 1",
+  "type": "object",
+  "required": ["apiVersion", "project", "sections", "failed"],
+  "properties": {
+    "apiVersion": { "const": 1 },
+    "project": {
+      "description": "Root of the project, null when the cache didn't record it",
+      "type": ["string", "null"]
+    },
+    "sections": {
+      "type": "array",
+      "items": {
+        "type": "object",
+        "required": ["kind", "name", "adminRecipients", "files"],
+        "properties": {
+          "kind": { "enum": ["flake", "nixos", "homeManager", "devShell"] },
+          "name": {
+            "description": "Empty for flake, [host] for nixos, [outer, inner] otherwise",
+            "type": "array",
+            "items": { "type": "string" }
+          },
+          "adminRecipients": { "type": "array", "items": { "type": "string" } },
+          "files": {
+            "type": "array",
+            "items": {
+              "type": "object",
+              "required": [
+                "name", "source", "dest", "owner", "group", "permissions", "readers", "threshold"
+              ],
+              "properties": {
+                "name": { "type": "string" },
+                "source": { "description": "Ciphertext, relative to the project", "type": "string" },
+                "dest": { "type": "string" },
+                "owner": { "type": "string" },
+                "group": { "type": "string" },
+                "permissions": { "description": "Octal mode", "type": "string" },
+                "readers": {
+                  "description": "Canonical keys able to decrypt, share holders when threshold is set",
+                  "type": "array",
+                  "items": { "type": "string" }
+                },
+                "threshold": { "type": ["integer", "null"], "minimum": 1 }
+              }
+            }
+          }
+        }
+      }
+    },
+    "failed": {
+      "description": "Sections missing because they failed to evaluate",
+      "type": "array",
+      "items": { "type": "string" }
+    }
+  }
+}
+"##;
+
+/// JSON Schema of API `version`
+pub fn schema(version: u32) -> Option<&'static str> {
+    match version {
+        1 => Some(SCHEMA_V1),
+        _ => None,
+    }
+}
@@ -0,0 +1,151 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io::Write;
+
+const BEGIN_MARKER: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+const END_MARKER: &[u8] = b"-----END AGE ENCRYPTED FILE-----";
+
+/// Bytes per armored line, encoding to exactly 64 base64 columns
+const LINE_BYTES: usize = 48;
+const LINE_COLUMNS: usize = 64;
+
+/// Payloads at least this large are armored here across threads rather than by age's
+/// single-threaded armor, which dominates runtime for secrets in the hundreds of megabytes
+pub const PARALLEL_THRESHOLD: usize = 1 << 20;
+
+fn threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Binary bytes each thread armors per round when streaming, so only that much armored output
+/// is held at a time
+const STREAM_BYTES: usize = LINE_BYTES * 16 * 1024;
+
+/// Armor `binary` age output, producing the same layout as age's `ArmoredWriter`
+pub fn armor(binary: &[u8]) -> Vec<u8> {
+    let lines = binary.len().div_ceil(LINE_BYTES);
+    let mut armored =
+        Vec::with_capacity(BEGIN_MARKER.len() + END_MARKER.len() + lines * (LINE_COLUMNS + 1) + 2);
+    armor_to(binary, &mut armored).unwrap();
+    armored
+}
+
+/// Armor `binary` age output straight into `out`, a round of lines at a time
+pub fn armor_to(binary: &[u8], out: &mut impl Write) -> std::io::Result<()> {
+    let threads = threads();
+    out.write_all(BEGIN_MARKER)?;
+    out.write_all(b"\n")?;
+    for round in binary.chunks(STREAM_BYTES * threads) {
+        armor_round(round, threads, out)?;
+    }
+    out.write_all(END_MARKER)?;
+    out.write_all(b"\n")
+}
+
+/// Armor one round of `binary` into `out` across `threads`. Every round but the last has to
+/// be whole lines.
+fn armor_round(round: &[u8], threads: usize, out: &mut impl Write) -> std::io::Result<()> {
+    let lines = round.len().div_ceil(LINE_BYTES);
+    // Each thread encodes whole lines so the chunks can simply be concatenated
+    let chunk_bytes = lines.div_ceil(threads).max(1) * LINE_BYTES;
+    let encoded: Vec<Vec<u8>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = round
+            .chunks(chunk_bytes)
+            .map(|chunk| scope.spawn(move || encode_lines(chunk)))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    for chunk in encoded {
+        out.write_all(&chunk)?;
+    }
+    Ok(())
+}
+
+/// Writer armoring the binary age output written to it into `out` as it comes, like
+/// `armor_to`, so an encryptor can stream into a file without the ciphertext ever being held
+/// in memory. `finish` writes the rest and the end marker.
+pub struct ArmorWriter<W: Write> {
+    out: W,
+    pending: Vec<u8>,
+    threads: usize,
+}
+
+impl<W: Write> ArmorWriter<W> {
+    pub fn new(mut out: W) -> std::io::Result<Self> {
+        out.write_all(BEGIN_MARKER)?;
+        out.write_all(b"\n")?;
+        let threads = threads();
+        Ok(ArmorWriter {
+            out,
+            pending: Vec::with_capacity(STREAM_BYTES * threads),
+            threads,
+        })
+    }
+
+    /// Armor what's left and write the end marker, returning `out`
+    pub fn finish(mut self) -> std::io::Result<W> {
+        armor_round(&self.pending, self.threads, &mut self.out)?;
+        self.out.write_all(END_MARKER)?;
+        self.out.write_all(b"\n")?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> Write for ArmorWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let round = STREAM_BYTES * self.threads;
+        let taken = buf.len().min(round - self.pending.len());
+        self.pending.extend_from_slice(&buf[..taken]);
+        if self.pending.len() == round {
+            armor_round(&self.pending, self.threads, &mut self.out)?;
+            self.pending.clear();
+        }
+        Ok(taken)
+    }
+
+    /// Only flushes `out`, a partial line can't be armored until the rest of it is written
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn encode_lines(chunk: &[u8]) -> Vec<u8> {
+    let encoded = STANDARD.encode(chunk);
+    let mut out = Vec::with_capacity(encoded.len() + encoded.len() / LINE_COLUMNS + 1);
+    for line in encoded.as_bytes().chunks(LINE_COLUMNS) {
+        out.extend_from_slice(line);
+        out.push(b'\n');
+    }
+    out
+}
+
+/// Decode armored age output back to binary, or None when `armored` isn't armored (or is
+/// malformed, in which case age's own reader reports the error)
+pub fn dearmor(armored: &[u8]) -> Option<Vec<u8>> {
+    let mut lines = armored
+        .split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .skip_while(|line| line.iter().all(u8::is_ascii_whitespace));
+    if lines.next()? != BEGIN_MARKER {
+        return None;
+    }
+    let body: Vec<&[u8]> = lines.take_while(|line| *line != END_MARKER).collect();
+    if body.iter().any(|line| line.len() > LINE_COLUMNS) {
+        return None;
+    }
+
+    let chunk_lines = body.len().div_ceil(threads()).max(1);
+    let decoded: Vec<Option<Vec<u8>>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = body
+            .chunks(chunk_lines)
+            .map(|chunk| scope.spawn(move || STANDARD.decode(chunk.concat()).ok()))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let mut binary = Vec::with_capacity(body.len() * LINE_BYTES);
+    for chunk in decoded {
+        binary.extend_from_slice(&chunk?);
+    }
+    Some(binary)
+}
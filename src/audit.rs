@@ -0,0 +1,181 @@
+use crate::fsutil::{create_dir_all_with_mode, write_atomic};
+use crate::identity::IdentityStore;
+use crate::provenance::{environment, Environment};
+use crate::{
+    canonical_recipient, ciphertext_from_plaintext_buffer, multi_edit,
+    plaintext_from_ciphertext_buffer, plaintext_from_ciphertext_source, trace, CacheFile,
+};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+/// Who can read a file, as recorded in an audit bundle
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Access {
+    /// Share holders needed to decrypt, for threshold files
+    threshold: Option<u8>,
+    readers: BTreeMap<String, Option<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BundleFile {
+    access: Access,
+    /// Base64 of the stored plaintext
+    plaintext: String,
+}
+
+/// Secrets re-encrypted for an auditor. The expiry is enforced by `audit-import`, anyone
+/// decrypting the bundle with age directly can of course ignore it.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bundle {
+    created: DateTime<Utc>,
+    expires: DateTime<Utc>,
+    /// Software that exported the bundle, missing from bundles of older releases
+    #[serde(default)]
+    environment: Option<Environment>,
+    files: BTreeMap<PathBuf, BundleFile>,
+}
+
+fn access_for(cache: &CacheFile, source: &Path) -> Access {
+    let mut owners: BTreeMap<String, String> = BTreeMap::new();
+    for (_, config) in cache.configs() {
+        for (recipient, metadata) in &config.recipient_metadata {
+            if let Some(owner) = &metadata.owner {
+                owners
+                    .entry(canonical_recipient(recipient))
+                    .or_insert_with(|| owner.clone());
+            }
+        }
+    }
+    Access {
+        threshold: cache.threshold_for_file(source),
+        readers: cache
+            .recipient_strings_for_file(source)
+            .into_iter()
+            .map(|r| {
+                let owner = owners.get(&r).cloned();
+                (r, owner)
+            })
+            .collect(),
+    }
+}
+
+/// Write a bundle of the managed files matching `filter` (all of them by default), encrypted
+/// only to `auditor` and valid for `valid_days`
+pub fn export(
+    cache: &CacheFile,
+    auditor: &str,
+    filter: Option<&str>,
+    valid_days: i64,
+    output: &Path,
+    identities: &IdentityStore,
+) {
+    let sources = multi_edit::matching_sources(cache, filter.unwrap_or("**"));
+    if sources.is_empty() {
+        eprintln!("No managed files to export");
+        std::process::exit(1);
+    }
+
+    let created = Utc::now();
+    let mut bundle = Bundle {
+        created,
+        expires: created + Duration::days(valid_days),
+        environment: Some(environment().clone()),
+        files: BTreeMap::new(),
+    };
+    for source in sources {
+        let plaintext = plaintext_from_ciphertext_source(&source, identities);
+        let access = access_for(cache, &source);
+        bundle.files.insert(
+            source,
+            BundleFile {
+                access,
+                plaintext: STANDARD.encode(plaintext),
+            },
+        );
+    }
+
+    let data = serde_json::to_vec(&bundle).unwrap();
+    let recipient = crate::parse_recipient(&canonical_recipient(auditor));
+    let ciphertext = ciphertext_from_plaintext_buffer(&data, vec![recipient]);
+    write_atomic(output, &ciphertext, 0o644, None).unwrap();
+    trace::ciphertext_written(output, &ciphertext);
+    eprintln!(
+        "Exported {} files to {:?}, valid until {}",
+        bundle.files.len(),
+        output,
+        bundle.expires.format("%Y-%m-%d %H:%M UTC")
+    );
+}
+
+/// Decrypt an audit bundle, refusing once it has expired, and write its files below
+/// `directory` along with the access report
+pub fn import(bundle: &Path, directory: &Path, identities: &IdentityStore) {
+    let ciphertext = std::fs::read(bundle).unwrap_or_else(|e| {
+        eprintln!("Unable to read {:?}: {}", bundle, e);
+        std::process::exit(1);
+    });
+    let data = plaintext_from_ciphertext_buffer(&ciphertext, identities);
+    let bundle: Bundle = serde_json::from_slice(&data).unwrap_or_else(|e| {
+        eprintln!("{:?} is not an audit bundle: {}", bundle, e);
+        std::process::exit(1);
+    });
+    if Utc::now() > bundle.expires {
+        eprintln!(
+            "This audit bundle expired at {}",
+            bundle.expires.format("%Y-%m-%d %H:%M UTC")
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(environment) = &bundle.environment {
+        eprintln!("Exported by {}", environment);
+    }
+
+    create_dir_all_with_mode(directory, 0o700).unwrap();
+    for (source, file) in &bundle.files {
+        // Only keep the normal components so a bundle can't write outside `directory`
+        let relative: PathBuf = source
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect();
+        let path = directory.join(relative);
+        let plaintext = STANDARD.decode(&file.plaintext).unwrap_or_else(|e| {
+            eprintln!("Corrupt plaintext for {:?} in the bundle: {}", source, e);
+            std::process::exit(1);
+        });
+        create_dir_all_with_mode(path.parent().unwrap(), 0o700).unwrap();
+        write_atomic(&path, &plaintext, 0o600, None).unwrap();
+
+        match file.access.threshold {
+            Some(threshold) => {
+                println!("{} ({} share holders needed)", source.display(), threshold)
+            }
+            None => println!("{}", source.display()),
+        }
+        for (reader, owner) in &file.access.readers {
+            match owner {
+                Some(owner) => println!("  - {} ({})", reader, owner),
+                None => println!("  - {}", reader),
+            }
+        }
+    }
+
+    let report: BTreeMap<&PathBuf, &Access> =
+        bundle.files.iter().map(|(s, f)| (s, &f.access)).collect();
+    let report = serde_json::to_vec_pretty(&report).unwrap();
+    write_atomic(&directory.join("access-report.json"), &report, 0o600, None).unwrap();
+    eprintln!(
+        "Wrote {} files to {:?}, created {}, valid until {}",
+        bundle.files.len(),
+        directory,
+        bundle.created.format("%Y-%m-%d %H:%M UTC"),
+        bundle.expires.format("%Y-%m-%d %H:%M UTC")
+    );
+}
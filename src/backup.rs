@@ -0,0 +1,66 @@
+//! `--backup` and `--backup-dir` keep the previous version of a ciphertext before a command
+//! replaces it, so a bad edit or rekey can be undone even when it was never committed.
+
+use crate::fsutil::{create_dir_all_with_mode, normalize};
+use chrono::Utc;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+struct Settings {
+    /// Keep a `.bak` next to the file
+    sibling: bool,
+    /// Keep timestamped copies below this directory
+    dir: Option<PathBuf>,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Set where backups go. `dir` is taken relative to the current directory.
+pub fn init(sibling: bool, dir: Option<&Path>) {
+    let dir = dir.map(|dir| std::path::absolute(dir).unwrap_or(dir.to_path_buf()));
+    let _ = SETTINGS.set(Settings { sibling, dir });
+}
+
+/// `path` below the backup directory, mirroring its relative path with a timestamp appended
+fn in_dir(dir: &Path, path: &Path) -> PathBuf {
+    let relative: PathBuf = normalize(path)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    let name = relative.file_name().unwrap_or_default().to_string_lossy();
+    let stamped = format!("{}.{}", name, Utc::now().format("%Y%m%dT%H%M%S%.3fZ"));
+    dir.join(relative.with_file_name(stamped))
+}
+
+/// Copy the current contents of `path` aside before it is replaced, when backups are enabled
+/// and there is something to keep. Exits when the copy fails rather than replacing the only
+/// one.
+pub fn save(path: &Path) {
+    let Some(settings) = SETTINGS.get() else {
+        return;
+    };
+    if !path.is_file() {
+        return;
+    }
+    let mut targets = vec![];
+    if settings.sibling {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        targets.push(path.with_file_name(format!("{}.bak", name)));
+    }
+    if let Some(dir) = &settings.dir {
+        targets.push(in_dir(dir, path));
+    }
+    for target in targets {
+        let result = match target.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => {
+                create_dir_all_with_mode(parent, 0o700)
+            }
+            _ => Ok(()),
+        }
+        .and_then(|()| std::fs::copy(path, &target));
+        if let Err(e) = result {
+            eprintln!("Unable to back up {:?} to {:?}: {}", path, target, e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,648 @@
+use crate::api::ShowFormat;
+use crate::fsutil::{create_dir_all_with_mode, mode_of, write_atomic};
+use crate::identity::IdentityStore;
+use crate::provenance::BuildPin;
+use crate::{
+    check, ciphertext_from_plaintext_buffer, config_source, doctor, parse_recipient, try_decrypt,
+    ArcanumConfig, CacheFile, Scope,
+};
+use clap::Subcommand;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+/// Version of the flat cache format written by this release
+const VERSION: u32 = 2;
+
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// Regenerate the cache file for the current project (the default)
+    Generate,
+
+    /// Convert every cache file on this machine to the current format
+    Migrate,
+
+    /// Print the project's cache in a stable, versioned format for other tools, see `schema`
+    // `--version` is the API version here, not arcanum's
+    #[command(disable_version_flag = true)]
+    Emit {
+        #[clap(long, default_value = "1")]
+        version: u32,
+    },
+
+    /// Print the project's resolved config: each section's files with their destinations and
+    /// readers. JSON is the newest `emit` version.
+    Show {
+        #[clap(long, value_enum, default_value = "table")]
+        format: ShowFormat,
+    },
+
+    /// List every cache file on this machine with the project it belongs to
+    List {
+        /// Print as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Remove cache files of projects that no longer exist, or that haven't been regenerated
+    /// in a while
+    Gc {
+        /// Also remove caches not regenerated for this many days
+        #[clap(long)]
+        older_than_days: Option<u64>,
+
+        /// Only print what would be removed
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+impl Scope {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Scope::Flake => "flake",
+            Scope::Nixos(_) => "nixos",
+            Scope::HomeManager(_, _) => "homeManager",
+            Scope::DevShell(_, _) => "devShell",
+        }
+    }
+
+    pub fn name_parts(&self) -> Vec<String> {
+        match self {
+            Scope::Flake => vec![],
+            Scope::Nixos(host) => vec![host.clone()],
+            Scope::HomeManager(outer, inner) | Scope::DevShell(outer, inner) => {
+                vec![outer.clone(), inner.clone()]
+            }
+        }
+    }
+
+    fn from_parts(kind: &str, name: &[String]) -> Option<Scope> {
+        match (kind, name) {
+            ("flake", []) => Some(Scope::Flake),
+            ("nixos", [host]) => Some(Scope::Nixos(host.clone())),
+            ("homeManager", [outer, inner]) => {
+                Some(Scope::HomeManager(outer.clone(), inner.clone()))
+            }
+            ("devShell", [outer, inner]) => Some(Scope::DevShell(outer.clone(), inner.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// One configuration section of the flat format
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FlatEntry<C> {
+    kind: String,
+    name: Vec<String>,
+    config: C,
+}
+
+/// Cache format with every section in a single list, regardless of where it came from
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FlatCache<C> {
+    version: u32,
+    entries: Vec<FlatEntry<C>>,
+    #[serde(default)]
+    build: Option<BuildPin>,
+    /// Sections that failed to evaluate when the cache was generated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    project: Option<PathBuf>,
+}
+
+/// Versions of the nix module's output this release understands. Output without a
+/// `schemaVersion` predates it being versioned and is version 1.
+const MODULE_SCHEMA_VERSIONS: std::ops::RangeInclusive<u32> = 1..=1;
+
+/// Refuse module output of a schema version this release doesn't understand, saying which side
+/// to update
+fn check_module_schema(version: u64) -> Result<(), String> {
+    let (oldest, newest) = (
+        *MODULE_SCHEMA_VERSIONS.start(),
+        *MODULE_SCHEMA_VERSIONS.end(),
+    );
+    if version > u64::from(newest) {
+        return Err(format!(
+            "the nix module emits schema version {}, newer than this arcanum understands ({}), \
+             update arcanum to the release matching the module",
+            version, newest
+        ));
+    }
+    if version < u64::from(oldest) {
+        return Err(format!(
+            "the nix module emits schema version {}, older than this arcanum understands ({}), \
+             update the arcanum input of the flake",
+            version, oldest
+        ));
+    }
+    Ok(())
+}
+
+/// Cache format with a field per kind of section, as older releases (and nix modules) wrote it.
+/// Every kind of section may be missing.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NestedCache {
+    nixos: Option<HashMap<String, ArcanumConfig>>,
+    dev_shells: Option<HashMap<String, HashMap<String, ArcanumConfig>>>,
+    home_manager: Option<HashMap<String, HashMap<String, ArcanumConfig>>>,
+    flake: Option<ArcanumConfig>,
+    #[serde(default)]
+    build: Option<BuildPin>,
+}
+
+impl From<NestedCache> for CacheFile {
+    fn from(nested: NestedCache) -> Self {
+        let mut sections = BTreeMap::new();
+        if let Some(flake) = nested.flake {
+            sections.insert(Scope::Flake, flake);
+        }
+        for (host, config) in nested.nixos.unwrap_or_default() {
+            sections.insert(Scope::Nixos(host), config);
+        }
+        for (outer, configs) in nested.home_manager.unwrap_or_default() {
+            for (inner, config) in configs {
+                sections.insert(Scope::HomeManager(outer.clone(), inner), config);
+            }
+        }
+        for (outer, configs) in nested.dev_shells.unwrap_or_default() {
+            for (inner, config) in configs {
+                sections.insert(Scope::DevShell(outer.clone(), inner), config);
+            }
+        }
+        CacheFile {
+            sections,
+            build: nested.build,
+            revoked: Default::default(),
+            failed: vec![],
+            project: None,
+        }
+    }
+}
+
+impl TryFrom<FlatCache<ArcanumConfig>> for CacheFile {
+    type Error = String;
+
+    fn try_from(flat: FlatCache<ArcanumConfig>) -> Result<Self, String> {
+        let mut sections = BTreeMap::new();
+        for entry in flat.entries {
+            let scope = Scope::from_parts(&entry.kind, &entry.name)
+                .ok_or_else(|| format!("unknown section {} {:?}", entry.kind, entry.name))?;
+            sections.insert(scope, entry.config);
+        }
+        Ok(CacheFile {
+            sections,
+            build: flat.build,
+            revoked: Default::default(),
+            failed: flat.failed,
+            project: flat.project,
+        })
+    }
+}
+
+/// Parse a cache in either format, also saying whether it was in the old nested one. The
+/// version is checked before anything else, so a cache or module output from a newer release
+/// says so rather than failing on whatever field changed.
+fn parse_format(data: &str) -> Result<(CacheFile, bool), String> {
+    let value: serde_json::Value = serde_json::from_str(data).map_err(|e| e.to_string())?;
+    if value.get("entries").is_some() {
+        let version = value.get("version").and_then(serde_json::Value::as_u64);
+        if version.is_some_and(|version| version > u64::from(VERSION)) {
+            return Err(format!(
+                "cache format version {} is newer than this arcanum supports ({})",
+                version.unwrap(),
+                VERSION
+            ));
+        }
+        let flat: FlatCache<ArcanumConfig> =
+            serde_json::from_value(value).map_err(|e| e.to_string())?;
+        return Ok((flat.try_into()?, false));
+    }
+    let version = match value.get("schemaVersion") {
+        None => 1,
+        Some(version) => version.as_u64().ok_or("schemaVersion is not a number")?,
+    };
+    check_module_schema(version)?;
+    let nested: NestedCache = serde_json::from_value(value)
+        .map_err(|e| format!("unexpected output of the nix module: {}", e))?;
+    Ok((nested.into(), true))
+}
+
+/// Parse a cache file, or the output of the nix module, in either format
+pub fn parse(data: &str) -> Result<CacheFile, String> {
+    parse_format(data).map(|(cache, _)| cache)
+}
+
+static ENCRYPT: AtomicBool = AtomicBool::new(false);
+
+/// Encrypt cache files written from now on to your own identities
+pub fn init_encryption(encrypt: bool) {
+    ENCRYPT.store(encrypt, Ordering::Relaxed);
+}
+
+/// Whether `data` is an age file rather than JSON, as caches written with `--encrypt-cache` are
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(b"age-encryption.org/") || data.starts_with(b"-----BEGIN AGE ENCRYPTED FILE")
+}
+
+/// Parse the contents of a cache file, decrypting them with `identities` first when they are
+/// encrypted
+pub fn decode(data: &[u8], identities: &IdentityStore) -> Result<CacheFile, String> {
+    if !is_encrypted(data) {
+        return parse(&String::from_utf8_lossy(data));
+    }
+    let plaintext =
+        try_decrypt(data, identities).map_err(|e| format!("unable to decrypt it: {}", e))?;
+    parse(&String::from_utf8_lossy(&plaintext))
+}
+
+/// What to write to the cache file at `path` for `cache`: its JSON, encrypted to the public
+/// keys of `identities` with `--encrypt-cache` or when the cache it replaces was encrypted, so
+/// regenerating it without the flag doesn't write the topology out in plaintext
+pub fn encode(
+    cache: &CacheFile,
+    path: &Path,
+    identities: &IdentityStore,
+) -> Result<Vec<u8>, String> {
+    let json = to_json(cache);
+    let was_encrypted = std::fs::read(path).is_ok_and(|data| is_encrypted(&data));
+    if !ENCRYPT.load(Ordering::Relaxed) && !was_encrypted {
+        return Ok(json);
+    }
+    let keys: BTreeSet<String> = identities
+        .public_keys()
+        .into_iter()
+        .filter(|key| check::check_recipient(key).is_ok())
+        .collect();
+    if keys.is_empty() {
+        return Err(
+            "none of your identities has a public key to encrypt the cache to, create one \
+             with `arcanum init-user`"
+                .to_string(),
+        );
+    }
+    let recipients = keys.iter().map(|key| parse_recipient(key)).collect();
+    Ok(ciphertext_from_plaintext_buffer(&json, recipients))
+}
+
+/// Serialize `cache` in the current format
+pub fn to_json(cache: &CacheFile) -> Vec<u8> {
+    let flat = FlatCache {
+        version: VERSION,
+        entries: cache
+            .sections
+            .iter()
+            .map(|(scope, config)| FlatEntry {
+                kind: scope.kind().to_string(),
+                name: scope.name_parts(),
+                config,
+            })
+            .collect(),
+        build: cache.build.clone(),
+        failed: cache.failed.clone(),
+        project: cache.project.clone(),
+    };
+    serde_json::to_vec(&flat).unwrap()
+}
+
+const DEFAULT_FLAKE: &str = ".";
+const DEFAULT_ATTRIBUTE: &str = "lib.arcanum";
+
+/// The flake and attribute path the config is evaluated from
+pub struct EvalTarget {
+    pub flake: String,
+    pub attribute: String,
+}
+
+impl EvalTarget {
+    /// Whether this is the project's own flake at `.#lib.arcanum`
+    pub fn is_default(&self) -> bool {
+        self.flake == DEFAULT_FLAKE && self.attribute == DEFAULT_ATTRIBUTE
+    }
+}
+
+static EVAL_TARGET: OnceLock<EvalTarget> = OnceLock::new();
+
+/// Set where the config is evaluated from, defaulting to `.#lib.arcanum`
+pub fn init_eval_target(flake: Option<String>, attribute: Option<String>) {
+    let target = EvalTarget {
+        flake: flake.unwrap_or_else(|| DEFAULT_FLAKE.to_string()),
+        attribute: attribute
+            .map(|a| a.trim_matches('.').to_string())
+            .unwrap_or_else(|| DEFAULT_ATTRIBUTE.to_string()),
+    };
+    let _ = EVAL_TARGET.set(target);
+}
+
+pub fn eval_target() -> &'static EvalTarget {
+    EVAL_TARGET.get_or_init(|| EvalTarget {
+        flake: DEFAULT_FLAKE.to_string(),
+        attribute: DEFAULT_ATTRIBUTE.to_string(),
+    })
+}
+
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keep cache files in `dir` instead of the per-user cache directory
+pub fn init_dir(dir: Option<PathBuf>) {
+    if let Some(dir) = dir {
+        let _ = DIR.set(dir);
+    }
+}
+
+/// Whether files can be created in `dir`, creating it if needed. Sandboxes often have a
+/// `$HOME` that exists but is read-only.
+fn writable(dir: &Path) -> bool {
+    if create_dir_all_with_mode(dir, 0o700).is_err() {
+        return false;
+    }
+    let probe = dir.join(format!(".arcanum-probe-{}", std::process::id()));
+    let created = std::fs::File::create(&probe).is_ok();
+    let _ = std::fs::remove_file(&probe);
+    created
+}
+
+/// The directory cache files are kept in: the one given with `--cache-dir` or
+/// `ARCANUM_CACHE_DIR`, else the per-user cache directory if it can be written to. None when
+/// neither, projects then keep their cache in `.arcanum/cache.json`.
+pub fn dir() -> Option<PathBuf> {
+    if let Some(dir) = DIR.get() {
+        return Some(dir.clone());
+    }
+    dirs::cache_dir().filter(|dir| writable(dir))
+}
+
+static EVAL_FORBIDDEN: AtomicBool = AtomicBool::new(false);
+
+/// Never run nix from now on, for machines that only have a cache file to go by
+pub fn forbid_eval() {
+    EVAL_FORBIDDEN.store(true, Ordering::Relaxed);
+}
+
+pub fn eval_forbidden() -> bool {
+    EVAL_FORBIDDEN.load(Ordering::Relaxed)
+}
+
+/// Evaluate `attr` below the project's config attribute as JSON
+pub fn eval(project_root: &Path, attr: &str) -> Result<String, String> {
+    config_source::source().eval(project_root, attr)
+}
+
+/// `.name` as a quoted attribute path component
+fn attr(name: &str) -> String {
+    format!(".{:?}", name)
+}
+
+/// Names of the attributes of the set at `path`
+fn attr_names(project_root: &Path, path: &str) -> Result<Vec<String>, String> {
+    config_source::source().attr_names(project_root, path)
+}
+
+fn eval_config(project_root: &Path, path: &str) -> Result<ArcanumConfig, String> {
+    let data = eval(project_root, path)?;
+    serde_json::from_str(&data).map_err(|e| e.to_string())
+}
+
+/// Build the cache one section at a time, keeping every section that evaluates and recording
+/// the ones that don't, for when evaluating everything at once fails
+pub fn eval_sections(project_root: &Path) -> CacheFile {
+    let mut cache = CacheFile {
+        sections: BTreeMap::new(),
+        build: None,
+        revoked: Default::default(),
+        failed: vec![],
+        project: None,
+    };
+    let mut failed = |label: String, error: String| {
+        eprintln!("Unable to evaluate {}: {}", label, error);
+        cache.failed.push(label);
+    };
+
+    let kinds = match attr_names(project_root, "") {
+        Ok(kinds) => kinds,
+        Err(e) => {
+            failed(eval_target().attribute.clone(), e);
+            return cache;
+        }
+    };
+    let present = |kind: &str| kinds.iter().any(|k| k == kind);
+    if present("schemaVersion") {
+        let version = eval(project_root, ".schemaVersion")
+            .and_then(|data| serde_json::from_str::<u64>(&data).map_err(|e| e.to_string()))
+            .and_then(check_module_schema);
+        if let Err(e) = version {
+            failed("schemaVersion".to_string(), e);
+            return cache;
+        }
+    }
+
+    let mut scopes: Vec<(String, Scope)> = vec![];
+    if present("flake") {
+        scopes.push((".flake".to_string(), Scope::Flake));
+    }
+    if present("nixos") {
+        match attr_names(project_root, ".nixos") {
+            Ok(hosts) => scopes.extend(
+                hosts
+                    .into_iter()
+                    .map(|host| (format!(".nixos{}", attr(&host)), Scope::Nixos(host))),
+            ),
+            Err(e) => failed("nixos".to_string(), e),
+        }
+    }
+    for (kind, make) in [
+        (
+            "homeManager",
+            Scope::HomeManager as fn(String, String) -> Scope,
+        ),
+        ("devShells", Scope::DevShell),
+    ] {
+        if !present(kind) {
+            continue;
+        }
+        let outers = match attr_names(project_root, &attr(kind)) {
+            Ok(outers) => outers,
+            Err(e) => {
+                failed(kind.to_string(), e);
+                continue;
+            }
+        };
+        for outer in outers {
+            let path = format!("{}{}", attr(kind), attr(&outer));
+            match attr_names(project_root, &path) {
+                Ok(inners) => scopes.extend(inners.into_iter().map(|inner| {
+                    (
+                        format!("{}{}", path, attr(&inner)),
+                        make(outer.clone(), inner),
+                    )
+                })),
+                Err(e) => failed(format!("{}.{}", kind, outer), e),
+            }
+        }
+    }
+
+    let mut sections = BTreeMap::new();
+    for (path, scope) in scopes {
+        match eval_config(project_root, &path) {
+            Ok(config) => {
+                sections.insert(scope, config);
+            }
+            Err(e) => failed(scope.to_string(), e),
+        }
+    }
+    cache.sections = sections;
+    if present("build") {
+        cache.build = eval(project_root, ".build")
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok());
+    }
+    cache
+}
+
+/// Rewrite every cache file in `cache_dir` still in the nested format. Returns false when any
+/// couldn't be converted.
+pub fn migrate(cache_dir: &Path) -> bool {
+    let mut ok = true;
+    for path in doctor::state_files(cache_dir) {
+        let data = match std::fs::read(&path) {
+            Ok(data) if is_encrypted(&data) => {
+                println!("encrypted {}", path.display());
+                continue;
+            }
+            Ok(data) => Ok(String::from_utf8_lossy(&data).into_owned()),
+            Err(e) => Err(e.to_string()),
+        };
+        match data.and_then(|data| parse_format(&data)) {
+            Ok((_, false)) => println!("current   {}", path.display()),
+            Ok((cache, true)) => {
+                let mode = mode_of(&path).unwrap_or(0o600);
+                match write_atomic(&path, &to_json(&cache), mode, None) {
+                    Ok(()) => println!("migrated  {}", path.display()),
+                    Err(e) => {
+                        println!("error     {}: {}", path.display(), e);
+                        ok = false;
+                    }
+                }
+            }
+            Err(e) => {
+                println!("error     {}: {}", path.display(), e);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// A cache file with what is known about it
+struct CacheInfo {
+    path: PathBuf,
+    /// Days since it was last written
+    age_days: Option<u64>,
+    /// None for caches of older releases, which didn't record their project, and encrypted
+    /// ones
+    project: Result<Option<PathBuf>, String>,
+}
+
+fn cache_infos(cache_dir: &Path) -> Vec<CacheInfo> {
+    doctor::state_files(cache_dir)
+        .into_iter()
+        .map(|path| {
+            let age_days = std::fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .map(|age| age.as_secs() / 86400);
+            let project = std::fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| match is_encrypted(&data) {
+                    true => Ok(None),
+                    false => parse(&String::from_utf8_lossy(&data)).map(|cache| cache.project),
+                });
+            CacheInfo {
+                path,
+                age_days,
+                project,
+            }
+        })
+        .collect()
+}
+
+/// Print every cache file in `cache_dir`. Returns false when any couldn't be read.
+pub fn list(cache_dir: &Path, json: bool) -> bool {
+    let infos = cache_infos(cache_dir);
+    let ok = infos.iter().all(|info| info.project.is_ok());
+    if json {
+        let entries: Vec<_> = infos
+            .iter()
+            .map(|info| {
+                let project = info.project.as_ref().ok().cloned().flatten();
+                json!({
+                    "path": info.path,
+                    "ageDays": info.age_days,
+                    "project": project,
+                    "projectExists": project.as_ref().map(|p| p.exists()),
+                    "error": info.project.as_ref().err(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+        return ok;
+    }
+    for info in &infos {
+        let age = info
+            .age_days
+            .map_or("?".to_string(), |days| format!("{}d", days));
+        let project = match &info.project {
+            Ok(Some(project)) if project.exists() => project.display().to_string(),
+            Ok(Some(project)) => format!("{} (missing)", project.display()),
+            Ok(None) => "unknown project".to_string(),
+            Err(e) => format!("unreadable: {}", e),
+        };
+        println!("{:>5}  {}  {}", age, info.path.display(), project);
+    }
+    ok
+}
+
+/// Lock file in `cache_dir` held while the cache at `cache` is regenerated
+pub fn lock_path(cache_dir: &Path, cache: &Path) -> PathBuf {
+    let name = cache.file_name().unwrap_or_default().to_string_lossy();
+    cache_dir.join(format!("{}.lock", name))
+}
+
+/// Remove cache files whose project is gone, or older than `older_than_days`. Returns false
+/// when any couldn't be removed.
+pub fn gc(cache_dir: &Path, older_than_days: Option<u64>, dry_run: bool) -> bool {
+    let mut ok = true;
+    for info in cache_infos(cache_dir) {
+        let reason = match (&info.project, info.age_days, older_than_days) {
+            (Ok(Some(project)), _, _) if !project.exists() => {
+                format!("project {} no longer exists", project.display())
+            }
+            (_, Some(age), Some(limit)) if age > limit => format!("{} days old", age),
+            _ => continue,
+        };
+        if dry_run {
+            println!("would remove  {}: {}", info.path.display(), reason);
+            continue;
+        }
+        match std::fs::remove_file(&info.path) {
+            Ok(()) => {
+                let _ = std::fs::remove_file(lock_path(cache_dir, &info.path));
+                println!("removed   {}: {}", info.path.display(), reason)
+            }
+            Err(e) => {
+                println!("error     {}: {}", info.path.display(), e);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
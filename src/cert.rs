@@ -0,0 +1,180 @@
+//! X.509 certificates in managed secrets, such as TLS certificates kept with their keys.
+//! Certificates are found by their PEM armor in the plaintext and read with `openssl x509`.
+
+use crate::identity::IdentityStore;
+use crate::{concurrent, decrypt, transform, CacheFile};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const END: &str = "-----END CERTIFICATE-----";
+
+pub struct Certificate {
+    pub subject: String,
+    pub issuer: String,
+    /// Subject alternative names as openssl prints them, such as `DNS:example.com`
+    pub sans: Vec<String>,
+    pub not_after: DateTime<Utc>,
+}
+
+/// The PEM certificates in `plaintext` in the order they appear, such as a certificate
+/// followed by its chain
+pub fn pem_blocks(plaintext: &[u8]) -> Vec<String> {
+    let text = String::from_utf8_lossy(plaintext);
+    let mut blocks = vec![];
+    let mut rest = &*text;
+    while let Some(start) = rest.find(BEGIN) {
+        let Some(length) = rest[start..].find(END) else {
+            break;
+        };
+        let end = start + length + END.len();
+        blocks.push(format!("{}\n", &rest[start..end]));
+        rest = &rest[end..];
+    }
+    blocks
+}
+
+/// Read the certificate in `pem` with openssl
+pub fn inspect(pem: &str) -> Result<Certificate, String> {
+    let mut child = Command::new("openssl")
+        .args(["x509", "-noout", "-nameopt", "RFC2253"])
+        .args(["-subject", "-issuer", "-enddate", "-ext", "subjectAltName"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("unable to run openssl: {}", e))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let _ = stdin.write_all(pem.as_bytes());
+    drop(stdin);
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!(
+            "openssl can't read it: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The fields of a certificate from what `inspect` has openssl print
+fn parse(printed: &str) -> Result<Certificate, String> {
+    let (mut subject, mut issuer, mut not_after, mut sans) = (None, None, None, vec![]);
+    let mut lines = printed.lines();
+    while let Some(line) = lines.next() {
+        if let Some(value) = line.strip_prefix("subject=") {
+            subject = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("issuer=") {
+            issuer = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("notAfter=") {
+            not_after = Some(parse_time(value)?);
+        } else if line.contains("Subject Alternative Name") {
+            // The names follow on the next line, separated by commas
+            sans = lines
+                .next()
+                .unwrap_or_default()
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect();
+        }
+    }
+    let missing = |field: &str| format!("openssl printed no {}", field);
+    Ok(Certificate {
+        subject: subject.ok_or_else(|| missing("subject"))?,
+        issuer: issuer.ok_or_else(|| missing("issuer"))?,
+        sans,
+        not_after: not_after.ok_or_else(|| missing("notAfter"))?,
+    })
+}
+
+/// A time as openssl prints them, such as `Nov  1 12:00:00 2026 GMT`
+fn parse_time(time: &str) -> Result<DateTime<Utc>, String> {
+    let trimmed = time.trim().trim_end_matches("GMT").trim();
+    NaiveDateTime::parse_from_str(trimmed, "%b %e %H:%M:%S %Y")
+        .map(|time| Utc.from_utc_datetime(&time))
+        .map_err(|e| format!("unable to parse the time {:?}: {}", time, e))
+}
+
+/// The certificates in the plaintexts of every managed file `identities` can decrypt, by
+/// source, and how many files couldn't be decrypted
+pub fn managed(
+    cache: &CacheFile,
+    project_root: &Path,
+    identities: &IdentityStore,
+) -> (BTreeMap<PathBuf, Vec<Result<Certificate, String>>>, usize) {
+    let (sources, encrypted): (Vec<PathBuf>, Vec<Vec<u8>>) = cache
+        .sources()
+        .into_iter()
+        .filter_map(|source| {
+            let path = cache.resolve_source(&project_root.join(&source));
+            Some((source, std::fs::read(path).ok()?))
+        })
+        .unzip();
+    let mut certificates = BTreeMap::new();
+    let mut unreadable = 0;
+    for (source, decrypted) in sources
+        .into_iter()
+        .zip(concurrent::decrypt_all(&encrypted, identities))
+    {
+        let Ok(plaintext) = decrypted else {
+            unreadable += 1;
+            continue;
+        };
+        let transforms = cache.transforms_for_file(&project_root.join(&source));
+        let plaintext = transform::apply_all(&transforms, plaintext.clone()).unwrap_or(plaintext);
+        let found: Vec<_> = pem_blocks(&plaintext)
+            .iter()
+            .map(|pem| inspect(pem))
+            .collect();
+        if !found.is_empty() {
+            certificates.insert(source, found);
+        }
+    }
+    (certificates, unreadable)
+}
+
+/// Show the certificates in the plaintext of `ciphertext`. Exits when it holds none.
+pub fn cert_info(cache: &CacheFile, ciphertext: &Path, identities: &IdentityStore) {
+    let plaintext = decrypt(cache, ciphertext, false, identities);
+    let blocks = pem_blocks(&plaintext);
+    if blocks.is_empty() {
+        eprintln!("No PEM certificates found in {:?}", ciphertext);
+        std::process::exit(1);
+    }
+    let now = Utc::now();
+    for (i, pem) in blocks.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("certificate: {} of {}", i + 1, blocks.len());
+        let certificate = match inspect(pem) {
+            Ok(certificate) => certificate,
+            Err(e) => {
+                println!("unreadable:  {}", e);
+                continue;
+            }
+        };
+        println!("subject:     {}", certificate.subject);
+        println!("issuer:      {}", certificate.issuer);
+        if certificate.sans.is_empty() {
+            println!("SANs:        none");
+        } else {
+            println!("SANs:        {}", certificate.sans.join(", "));
+        }
+        let days = (certificate.not_after - now).num_days();
+        let when = if certificate.not_after <= now {
+            "expired".to_string()
+        } else {
+            format!("in {} days", days)
+        };
+        println!(
+            "not after:   {} ({})",
+            certificate.not_after.with_timezone(&Local).to_rfc3339(),
+            when
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use crate::fsutil::parse_mode;
+use crate::{cache, canonical_recipient, envelope, expiry, providers, quorum, CacheFile};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Something wrong with the config, found without looking at anything but the config itself
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Problem {
+    /// Short identifier of the check that failed, for tooling
+    pub check: &'static str,
+    pub section: String,
+    /// Name of the file entry, when the problem is with one
+    pub file: Option<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{} {}: {}", self.section, file, self.message),
+            None => write!(f, "{}: {}", self.section, self.message),
+        }
+    }
+}
+
+pub fn check_recipient(recipient: &str) -> Result<(), String> {
+    let canonical = canonical_recipient(recipient);
+    if let Some(parsed) = envelope::parse(&canonical) {
+        return parsed.map(|_| ());
+    }
+    let valid = if canonical.starts_with("age1") {
+        age::x25519::Recipient::from_str(&canonical).is_ok()
+    } else {
+        age::ssh::Recipient::from_str(&canonical).is_ok()
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "{:?} is not a valid age or ssh public key",
+            recipient
+        ))
+    }
+}
+
+/// `check_recipient`, except that recipients standing for keys kept elsewhere are only checked
+/// for their syntax, as the evaluated config holds them unresolved
+fn check_configured_recipient(recipient: &str) -> Result<(), String> {
+    providers::check(recipient).unwrap_or_else(|| check_recipient(recipient))
+}
+
+/// Validate everything in the config that doesn't need the file system: recipients parse,
+/// permission strings are octal, thresholds are satisfiable and no section installs two files
+/// to the same destination
+pub fn static_checks(cache: &CacheFile) -> Vec<Problem> {
+    let mut problems = vec![];
+    for (scope, config) in cache.configs() {
+        let section = scope.to_string();
+        let mut problem = |check: &'static str, file: Option<&str>, message: String| {
+            problems.push(Problem {
+                check,
+                section: section.clone(),
+                file: file.map(str::to_string),
+                message,
+            })
+        };
+
+        for recipient in config
+            .admin_recipients
+            .iter()
+            .chain(config.recipient_metadata.keys())
+        {
+            if let Err(e) = check_configured_recipient(recipient) {
+                problem("invalid-recipient", None, e);
+            }
+        }
+
+        let mut names: Vec<&String> = config.files.keys().collect();
+        names.sort();
+        let mut dests: BTreeMap<&Path, &str> = BTreeMap::new();
+        for name in names {
+            let file = &config.files[name];
+            for recipient in file.recipients.iter().chain(&file.share_holders) {
+                if let Err(e) = check_configured_recipient(recipient) {
+                    problem("invalid-recipient", Some(name), e);
+                }
+            }
+            if let Err(e) = parse_mode(&file.permissions) {
+                problem(
+                    "invalid-permissions",
+                    Some(name),
+                    format!("permissions: {}", e),
+                );
+            }
+            if let Err(e) = parse_mode(&file.directory_permissions) {
+                problem(
+                    "invalid-permissions",
+                    Some(name),
+                    format!("directoryPermissions: {}", e),
+                );
+            }
+            if let Some(threshold) = file.threshold {
+                let holders: BTreeSet<String> = file
+                    .share_holders
+                    .iter()
+                    .map(|h| canonical_recipient(h))
+                    .collect();
+                if let Err(e) = quorum::validate(threshold, &holders) {
+                    problem("invalid-threshold", Some(name), e);
+                }
+            }
+            if let Some(Err(e)) = file.max_age.as_deref().map(expiry::parse_period) {
+                problem("invalid-max-age", Some(name), format!("maxAge: {}", e));
+            }
+            if let Some(other) = dests.insert(&file.dest, name) {
+                problem(
+                    "duplicate-dest",
+                    Some(name),
+                    format!("installs to {:?}, already used by {}", file.dest, other),
+                );
+            }
+        }
+    }
+    problems
+}
+
+/// Every recipient configured to read `source` that isn't a valid key, with the section and
+/// entry it comes from. Revoked recipients are skipped as they aren't encrypted to anyway.
+pub fn invalid_recipients_for(cache: &CacheFile, source: &Path) -> Vec<Problem> {
+    let mut problems = vec![];
+    let mut invalid = |section: &str, file: Option<&str>, recipient: &String| {
+        if cache.revoked.contains(&canonical_recipient(recipient)) {
+            return;
+        }
+        if let Err(message) = check_recipient(recipient) {
+            problems.push(Problem {
+                check: "invalid-recipient",
+                section: section.to_string(),
+                file: file.map(str::to_string),
+                message,
+            });
+        }
+    };
+    let source = cache.project_relative(source);
+    for (scope, config) in cache.configs() {
+        let section = scope.to_string();
+        let mut names: Vec<&String> = config.files.keys().collect();
+        names.sort();
+        let mut admins_used = false;
+        for name in names {
+            let file = &config.files[name];
+            if !file.matches(&source) {
+                continue;
+            }
+            for recipient in file.recipients.iter().chain(&file.share_holders) {
+                invalid(&section, Some(name), recipient);
+            }
+            admins_used |= file.threshold.is_none();
+        }
+        if admins_used {
+            for recipient in &config.admin_recipients {
+                invalid(&section, None, recipient);
+            }
+        }
+    }
+    problems
+}
+
+/// Run the static checks on config JSON read from stdin, as the nix module does at build time.
+/// Returns true when there are no problems.
+pub fn check_json() -> bool {
+    let mut data = String::new();
+    if let Err(e) = std::io::stdin().read_to_string(&mut data) {
+        eprintln!("Unable to read config from stdin: {}", e);
+        return false;
+    }
+    let cache = match cache::parse(&data) {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("Unable to parse config: {}", e);
+            return false;
+        }
+    };
+    let problems = static_checks(&cache);
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    problems.is_empty()
+}
+
+/// Section, entry name and readers of an entry using a source
+type SourceUse<'a> = (String, &'a str, BTreeSet<String>);
+
+/// Checks that need the project checked out: every source exists, and entries sharing a
+/// source agree on who can read it
+fn project_checks(cache: &CacheFile) -> Vec<Problem> {
+    let mut problems = vec![];
+    let mut readers_by_source: BTreeMap<&Path, Vec<SourceUse>> = BTreeMap::new();
+    for (scope, config) in cache.configs() {
+        let mut names: Vec<&String> = config.files.keys().collect();
+        names.sort();
+        for name in names {
+            let file = &config.files[name];
+            if !file.source.exists() && file.existing_legacy_source().is_none() {
+                problems.push(Problem {
+                    check: "missing-source",
+                    section: scope.to_string(),
+                    file: Some(name.clone()),
+                    message: format!("source {:?} does not exist", file.source),
+                });
+            }
+            let readers = file
+                .readers(config)
+                .into_iter()
+                .map(|r| canonical_recipient(r))
+                .collect();
+            readers_by_source.entry(&file.source).or_default().push((
+                scope.to_string(),
+                name,
+                readers,
+            ));
+        }
+    }
+
+    for (source, entries) in readers_by_source {
+        let Some((first_section, first_name, first_readers)) = entries.first() else {
+            continue;
+        };
+        for (section, name, readers) in &entries[1..] {
+            if readers != first_readers {
+                problems.push(Problem {
+                    check: "conflicting-recipients",
+                    section: section.clone(),
+                    file: Some(name.to_string()),
+                    message: format!(
+                        "{:?} is also used by {} {} with different recipients",
+                        source, first_section, first_name
+                    ),
+                });
+            }
+        }
+    }
+    problems
+}
+
+/// Validate the project's config, printing problems as text or JSON. Returns true when there
+/// are none.
+pub fn lint(cache: &CacheFile, json: bool) -> bool {
+    let mut problems = static_checks(cache);
+    problems.extend(project_checks(cache));
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&problems).unwrap());
+    } else {
+        for problem in &problems {
+            println!("{}", problem);
+        }
+    }
+    problems.is_empty()
+}
@@ -0,0 +1,28 @@
+//! `arcanum copy`: put a secret on the clipboard and take it off again after a while, so it
+//! never passes through a shell pipeline or its history.
+
+use arboard::Clipboard;
+use std::time::Duration;
+
+/// Copy `value` to the clipboard and wait `clear_after` seconds before clearing it, unless
+/// something else was copied in the meantime. Stays in the foreground until then: on X11 and
+/// Wayland the clipboard is owned by the process that set it and empties when it exits.
+pub fn copy(value: &str, clear_after: u64) {
+    let mut clipboard = Clipboard::new().unwrap_or_else(|e| {
+        eprintln!("Unable to access the clipboard: {}", e);
+        std::process::exit(1);
+    });
+    if let Err(e) = clipboard.set_text(value) {
+        eprintln!("Unable to copy to the clipboard: {}", e);
+        std::process::exit(1);
+    }
+    eprintln!(
+        "Copied to the clipboard, clearing it in {} seconds",
+        clear_after
+    );
+    std::thread::sleep(Duration::from_secs(clear_after));
+    if clipboard.get_text().ok().as_deref() == Some(value) {
+        let _ = clipboard.clear();
+        eprintln!("Cleared the clipboard");
+    }
+}
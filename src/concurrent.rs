@@ -0,0 +1,118 @@
+//! Decrypting several files at once, for merges, bulk rekeys and grep. Identities can't be
+//! shared between threads, and unlocking one may prompt for a passphrase, a PIN or a touch, so
+//! file keys are unwrapped one file at a time on the calling thread, the only place anything
+//! is ever asked. The payloads, where the time goes for large files, are decrypted by worker
+//! threads meanwhile.
+
+use crate::identity::IdentityStore;
+use crate::{decrypt_with, try_decrypt, with_unwrap_chain};
+use age::armor::ArmoredReader;
+use age::secrecy::ExposeSecret;
+use age::{DecryptError, Identity};
+use age_core::format::{FileKey, Stanza};
+use std::cell::RefCell;
+use std::sync::{mpsc, Mutex};
+
+fn threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Identity passing on what another unwraps, keeping a copy of the file key
+struct Recording<'a> {
+    inner: &'a dyn Identity,
+    file_key: &'a RefCell<Option<FileKey>>,
+}
+
+impl Identity for Recording<'_> {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        self.inner.unwrap_stanza(stanza)
+    }
+
+    fn unwrap_stanzas(&self, stanzas: &[Stanza]) -> Option<Result<FileKey, DecryptError>> {
+        let unwrapped = self.inner.unwrap_stanzas(stanzas);
+        if let Some(Ok(file_key)) = &unwrapped {
+            *self.file_key.borrow_mut() = Some(FileKey::from(*file_key.expose_secret()));
+        }
+        unwrapped
+    }
+}
+
+/// Identity for a file key already unwrapped
+struct Unwrapped(FileKey);
+
+impl Identity for Unwrapped {
+    fn unwrap_stanza(&self, _: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        Some(Ok(FileKey::from(*self.0.expose_secret())))
+    }
+}
+
+/// Unwrap the file key of `encrypted` with `identities`, only reading its header
+fn unwrap_file_key(encrypted: &[u8], identities: &IdentityStore) -> Result<FileKey, DecryptError> {
+    let decryptor = match age::Decryptor::new(ArmoredReader::new(encrypted))? {
+        age::Decryptor::Recipients(d) => d,
+        // Passphrase-encrypted files aren't decrypted with identities, as in `try_decrypt`
+        _ => return Err(DecryptError::NoMatchingKeys),
+    };
+    let file_key = RefCell::new(None);
+    with_unwrap_chain(identities.identities(), |chain| {
+        let recording: Vec<Recording> = chain
+            .into_iter()
+            .map(|inner| Recording {
+                inner,
+                file_key: &file_key,
+            })
+            .collect();
+        decryptor
+            .decrypt(recording.iter().map(|r| r as &dyn Identity))
+            .map(|_| ())
+    })?;
+    // Decrypting only succeeds once one of the identities gave the file key
+    Ok(file_key.into_inner().unwrap())
+}
+
+/// Decrypt each of `encrypted` with `identities`, as `try_decrypt` would one by one
+pub fn decrypt_all<T: AsRef<[u8]> + Sync>(
+    encrypted: &[T],
+    identities: &IdentityStore,
+) -> Vec<Result<Vec<u8>, DecryptError>> {
+    if encrypted.len() < 2 {
+        return encrypted
+            .iter()
+            .map(|e| try_decrypt(e.as_ref(), identities))
+            .collect();
+    }
+    let mut decrypted: Vec<Option<Result<Vec<u8>, DecryptError>>> =
+        encrypted.iter().map(|_| None).collect();
+    let (send, receive) = mpsc::channel::<(usize, FileKey)>();
+    let receive = &Mutex::new(receive);
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads().min(encrypted.len()))
+            .map(|_| {
+                scope.spawn(move || {
+                    let mut done = vec![];
+                    loop {
+                        let next = receive.lock().unwrap().recv();
+                        let Ok((i, file_key)) = next else {
+                            return done;
+                        };
+                        let unwrapped = Unwrapped(file_key);
+                        done.push((i, decrypt_with(encrypted[i].as_ref(), vec![&unwrapped])));
+                    }
+                })
+            })
+            .collect();
+        for (i, data) in encrypted.iter().enumerate() {
+            match unwrap_file_key(data.as_ref(), identities) {
+                Ok(file_key) => send.send((i, file_key)).unwrap(),
+                Err(e) => decrypted[i] = Some(Err(e)),
+            }
+        }
+        drop(send);
+        for worker in workers {
+            for (i, result) in worker.join().unwrap() {
+                decrypted[i] = Some(result);
+            }
+        }
+    });
+    decrypted.into_iter().map(Option::unwrap).collect()
+}
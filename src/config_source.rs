@@ -0,0 +1,269 @@
+//! Where the project's config is evaluated from: `nix eval` of the flake, or a document
+//! standing for what the flake evaluates to, such as the output of
+//! `nix eval --json .#lib.arcanum`. Documents are JSON or TOML files, or served over HTTPS for
+//! organizations keeping their recipient policy in one place rather than in every clone.
+//! Projects choose one in `.arcanum/config.toml`:
+//!
+//! ```toml
+//! [eval]
+//! url = "https://policy.example.com/arcanum/web.json"
+//! ```
+//!
+//! `--config-json` overrides it with a file, so tests and CI jobs without nix can exercise
+//! everything that reads the config.
+
+use crate::cache::{eval_forbidden, eval_target};
+use crate::project_config::EvalSettings;
+use serde_json::Value;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+/// Environment variable with a bearer token sent with requests for `url` sources
+pub const TOKEN_ENV: &str = "ARCANUM_CONFIG_TOKEN";
+
+pub trait ConfigSource: Send + Sync {
+    /// `attr` below the config attribute, such as `.nixos."web"`, as JSON
+    fn eval(&self, project_root: &Path, attr: &str) -> Result<String, String>;
+
+    /// Names of the attributes of the set at `attr`
+    fn attr_names(&self, project_root: &Path, attr: &str) -> Result<Vec<String>, String>;
+
+    /// What is evaluated, such as another flake or a document's path, so caches of different
+    /// targets are kept apart. None for the project's own flake at `.#lib.arcanum`.
+    fn target(&self) -> Option<String>;
+
+    /// Local files the config is read from, so `arcanum.lock` can tell whether it's current
+    fn inputs(&self, project_root: &Path) -> Vec<PathBuf>;
+}
+
+/// Evaluates the flake with `nix eval`
+pub struct NixEval;
+
+impl NixEval {
+    fn run(&self, project_root: &Path, attr: &str, apply: Option<&str>) -> Result<String, String> {
+        if eval_forbidden() {
+            return Err("evaluating with nix is disabled by --no-eval".to_string());
+        }
+        let target = eval_target();
+        let mut command = Command::new("nix");
+        command
+            .arg("eval")
+            .arg("--json")
+            .arg(format!("{}#{}{}", target.flake, target.attribute, attr))
+            .current_dir(project_root);
+        if let Some(apply) = apply {
+            command.arg("--apply").arg(apply);
+        }
+        let result = command.output().map_err(|e| e.to_string())?;
+        if !result.status.success() {
+            return Err(String::from_utf8_lossy(&result.stderr).trim().to_string());
+        }
+        String::from_utf8(result.stdout).map_err(|e| e.to_string())
+    }
+}
+
+impl ConfigSource for NixEval {
+    fn eval(&self, project_root: &Path, attr: &str) -> Result<String, String> {
+        self.run(project_root, attr, None)
+    }
+
+    fn attr_names(&self, project_root: &Path, attr: &str) -> Result<Vec<String>, String> {
+        let data = self.run(project_root, attr, Some("builtins.attrNames"))?;
+        serde_json::from_str(&data).map_err(|e| e.to_string())
+    }
+
+    fn target(&self) -> Option<String> {
+        let target = eval_target();
+        (!target.is_default()).then(|| format!("flake:{}#{}", target.flake, target.attribute))
+    }
+
+    fn inputs(&self, project_root: &Path) -> Vec<PathBuf> {
+        vec![
+            project_root.join("flake.nix"),
+            project_root.join("flake.lock"),
+        ]
+    }
+}
+
+/// Where a document is read from
+pub enum Origin {
+    File(PathBuf),
+    Url(String),
+}
+
+impl Origin {
+    fn is_toml(&self) -> bool {
+        match self {
+            Origin::File(path) => path.extension().is_some_and(|e| e == "toml"),
+            Origin::Url(url) => url.split(['?', '#']).next().unwrap().ends_with(".toml"),
+        }
+    }
+
+    fn read(&self) -> Result<Vec<u8>, String> {
+        match self {
+            Origin::File(path) => {
+                std::fs::read(path).map_err(|e| format!("unable to read {:?}: {}", path, e))
+            }
+            Origin::Url(url) => fetch(url),
+        }
+    }
+}
+
+/// `url` fetched with curl. The token, when there is one, goes through curl's stdin so it
+/// never shows up in the process list.
+fn fetch(url: &str) -> Result<Vec<u8>, String> {
+    let token = std::env::var(TOKEN_ENV).ok().filter(|t| !t.is_empty());
+    let mut command = Command::new("curl");
+    command
+        .args(["-fsSL", "--proto", "=https", "--max-time", "30"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if token.is_some() {
+        command.args(["-H", "@-"]);
+    }
+    let mut child = command
+        .arg(url)
+        .spawn()
+        .map_err(|e| format!("unable to run curl: {}", e))?;
+    let mut stdin = child.stdin.take().unwrap();
+    if let Some(token) = token {
+        let _ = writeln!(stdin, "Authorization: Bearer {}", token);
+    }
+    drop(stdin);
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!(
+            "fetching {} failed: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Reads the evaluated config from a JSON or TOML document, once per run
+pub struct Document {
+    origin: Origin,
+    value: OnceLock<Result<Value, String>>,
+}
+
+impl Document {
+    pub fn new(origin: Origin) -> Self {
+        Document {
+            origin,
+            value: OnceLock::new(),
+        }
+    }
+
+    fn load(&self) -> Result<Value, String> {
+        let data = self.origin.read()?;
+        if self.origin.is_toml() {
+            let text = String::from_utf8(data).map_err(|e| e.to_string())?;
+            toml::from_str(&text).map_err(|e| e.to_string())
+        } else {
+            serde_json::from_slice(&data).map_err(|e| e.to_string())
+        }
+    }
+
+    fn value(&self, attr: &str) -> Result<&Value, String> {
+        let mut value = self.value.get_or_init(|| self.load()).as_ref()?;
+        for name in attr_path(attr)? {
+            value = value
+                .get(&name)
+                .ok_or_else(|| format!("attribute {:?} missing in the config", name))?;
+        }
+        Ok(value)
+    }
+}
+
+impl ConfigSource for Document {
+    fn eval(&self, _project_root: &Path, attr: &str) -> Result<String, String> {
+        Ok(self.value(attr)?.to_string())
+    }
+
+    fn attr_names(&self, _project_root: &Path, attr: &str) -> Result<Vec<String>, String> {
+        match self.value(attr)? {
+            // Sorted, as nix returns them
+            Value::Object(set) => Ok(set.keys().cloned().collect()),
+            _ => Err(format!("{} isn't a set", attr)),
+        }
+    }
+
+    fn target(&self) -> Option<String> {
+        Some(match &self.origin {
+            Origin::File(path) => format!("file:{}", path.display()),
+            Origin::Url(url) => format!("url:{}", url),
+        })
+    }
+
+    fn inputs(&self, _project_root: &Path) -> Vec<PathBuf> {
+        match &self.origin {
+            Origin::File(path) => vec![path.clone()],
+            Origin::Url(_) => vec![],
+        }
+    }
+}
+
+/// The names in an attribute path such as `.nixos."web"`, each either bare or quoted
+fn attr_path(attr: &str) -> Result<Vec<String>, String> {
+    let mut names = vec![];
+    let mut rest = attr;
+    while !rest.is_empty() {
+        let after = rest
+            .strip_prefix('.')
+            .ok_or_else(|| format!("invalid attribute path {:?}", attr))?;
+        let end = if after.starts_with('"') {
+            let mut escaped = false;
+            let close = after.char_indices().skip(1).find(|&(_, c)| {
+                let close = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                close
+            });
+            let (close, _) = close.ok_or_else(|| format!("invalid attribute path {:?}", attr))?;
+            let name = serde_json::from_str(&after[..=close]).map_err(|e| e.to_string())?;
+            names.push(name);
+            close + 1
+        } else {
+            let end = after.find('.').unwrap_or(after.len());
+            names.push(after[..end].to_string());
+            end
+        };
+        rest = &after[end..];
+    }
+    Ok(names)
+}
+
+static SOURCE: OnceLock<Box<dyn ConfigSource>> = OnceLock::new();
+
+/// Choose where the config comes from: the JSON file `json` when given, else what `settings`
+/// say, else the flake. Files in `settings` are relative to `project_root`.
+pub fn init(json: Option<PathBuf>, settings: Option<&EvalSettings>, project_root: &Path) {
+    let default = EvalSettings::default();
+    let settings = settings.unwrap_or(&default);
+    let flake = settings.flake.is_some() || settings.attribute.is_some();
+    let chosen = [flake, settings.file.is_some(), settings.url.is_some()];
+    if chosen.into_iter().filter(|&c| c).count() > 1 {
+        eprintln!(
+            "Only one of flake/attribute, file and url can be set in [eval] of {:?}",
+            crate::project_config::path(project_root)
+        );
+        std::process::exit(1);
+    }
+    let origin = if let Some(json) = json {
+        Origin::File(json)
+    } else if let Some(file) = &settings.file {
+        Origin::File(project_root.join(file))
+    } else if let Some(url) = &settings.url {
+        Origin::Url(url.clone())
+    } else {
+        return;
+    };
+    let _ = SOURCE.set(Box::new(Document::new(origin)));
+}
+
+pub fn source() -> &'static dyn ConfigSource {
+    SOURCE.get_or_init(|| Box::new(NixEval)).as_ref()
+}
@@ -0,0 +1,131 @@
+//! Confirmation prompts for destructive actions. Every prompt goes through `confirm` so they
+//! look the same, and automation opts out with `--yes`/`-y`, `--no-input` or per-action
+//! defaults in the `[confirm]` table of `~/.config/arcanum/config.toml`:
+//!
+//! ```toml
+//! [confirm]
+//! overwrite = "yes"
+//! removeRecipient = "ask"
+//! rekey = "yes"
+//! ```
+
+use crate::profile;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{BufRead, IsTerminal, Write};
+use std::sync::OnceLock;
+
+/// Something that needs confirming before it is done
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Action {
+    /// Replacing an existing file
+    Overwrite,
+    /// Taking away someone's access to secrets
+    RemoveRecipient,
+    /// Removing a secret or part of one
+    Delete,
+    /// Adding a plaintext path to `.gitignore`
+    Ignore,
+    /// Re-encrypting many files at once
+    Rekey,
+    /// Writing a ciphertext none of your identities can decrypt
+    Unreadable,
+}
+
+/// What to do when an action comes up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Policy {
+    /// Prompt when there is someone to ask, refuse otherwise
+    #[default]
+    Ask,
+    /// Go ahead without asking
+    Yes,
+    /// Always refuse
+    No,
+}
+
+struct Settings {
+    yes: bool,
+    no_input: bool,
+    defaults: HashMap<Action, Policy>,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Set up confirmations from the global flags: `yes` accepts every prompt, `no_input` refuses
+/// anything that would need one
+pub fn init(yes: bool, no_input: bool) {
+    let _ = SETTINGS.set(Settings {
+        yes,
+        no_input,
+        defaults: profile::confirm_defaults(),
+    });
+}
+
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(|| Settings {
+        yes: false,
+        no_input: false,
+        defaults: HashMap::new(),
+    })
+}
+
+/// Ask whether to go ahead with `action`, described by `question`. Returns false when it was
+/// declined, or couldn't be asked, after saying how to allow it next time.
+pub fn confirm(action: Action, question: &str) -> bool {
+    ask(action, question, false)
+}
+
+/// `confirm`, except that without anyone to ask, with `--no-input` or stdin not a terminal, it
+/// goes ahead unless the defaults refuse. For prompts added to what scripts already ran
+/// without one, such as `decrypt` overwriting its output.
+pub fn confirm_attended(action: Action, question: &str) -> bool {
+    ask(action, question, true)
+}
+
+/// Ask about `action`, answering `unattended` when there's no one to ask
+fn ask(action: Action, question: &str, unattended: bool) -> bool {
+    let settings = settings();
+    if settings.yes {
+        return true;
+    }
+    match settings.defaults.get(&action).copied().unwrap_or_default() {
+        Policy::Yes => return true,
+        Policy::No => {
+            eprintln!("{} Refused by the confirmation defaults.", question);
+            return false;
+        }
+        Policy::Ask => {}
+    }
+    if unattended && (settings.no_input || !std::io::stdin().is_terminal()) {
+        return true;
+    }
+    if settings.no_input {
+        eprintln!("{} Pass --yes to confirm without a prompt.", question);
+        return false;
+    }
+    if !std::io::stdin().is_terminal() {
+        eprintln!(
+            "{} Not asking as stdin isn't a terminal, pass --yes to confirm without a prompt.",
+            question
+        );
+        return false;
+    }
+
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush().unwrap();
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Exit unless `action` is confirmed
+pub fn require(action: Action, question: &str) {
+    if !confirm(action, question) {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::identity::IdentityStore;
+use crate::{git, plaintext_from_ciphertext_buffer, plaintext_from_ciphertext_source, CacheFile};
+use similar::TextDiff;
+use std::path::Path;
+
+/// Unified diff between two plaintexts, or a note when they aren't text. Empty when they are
+/// equal.
+pub fn unified(old: &[u8], new: &[u8], old_label: &str, new_label: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    match (std::str::from_utf8(old), std::str::from_utf8(new)) {
+        (Ok(old), Ok(new)) => TextDiff::from_lines(old, new)
+            .unified_diff()
+            .context_radius(3)
+            .header(old_label, new_label)
+            .to_string(),
+        _ => format!("Binary plaintexts {} and {} differ\n", old_label, new_label),
+    }
+}
+
+/// Exit unless `rev` is a commit available locally, explaining when a shallow clone is why
+pub fn require_rev(rev: &str) {
+    if git::rev_exists(rev) {
+        return;
+    }
+    let cwd = std::env::current_dir().unwrap_or_default();
+    if git::is_shallow(&cwd) {
+        eprintln!(
+            "{} is not available in this shallow clone, fetch more history with `git fetch --deepen` or `git fetch --unshallow`",
+            rev
+        );
+    } else {
+        eprintln!("{} is not a commit in this repository", rev);
+    }
+    std::process::exit(1);
+}
+
+/// Print the difference between the plaintext of `ciphertext` and either `other` or the
+/// version of `ciphertext` at `rev`. Returns true when they differ.
+pub fn diff(
+    cache: &CacheFile,
+    ciphertext: &Path,
+    other: Option<&Path>,
+    rev: &str,
+    identities: &IdentityStore,
+) -> bool {
+    let (old, old_label) = match other {
+        Some(other) => (
+            plaintext_from_ciphertext_source(&cache.resolve_source(other), identities),
+            other.display().to_string(),
+        ),
+        None => {
+            require_rev(rev);
+            let Some(encrypted) = git::show(rev, ciphertext) else {
+                eprintln!("{:?} does not exist at {} in git", ciphertext, rev);
+                std::process::exit(1);
+            };
+            (
+                plaintext_from_ciphertext_buffer(&encrypted, identities),
+                format!("{} ({})", ciphertext.display(), rev),
+            )
+        }
+    };
+    let new = plaintext_from_ciphertext_source(&cache.resolve_source(ciphertext), identities);
+
+    let diff = unified(&old, &new, &old_label, &ciphertext.display().to_string());
+    print!("{}", diff);
+    !diff.is_empty()
+}
@@ -0,0 +1,86 @@
+//! `arcanum docker-secret`: hand a secret to a container build through BuildKit's `--secret`,
+//! so it is mounted for the `RUN` steps that ask for it and never ends up in a layer.
+//!
+//! The plaintext is written to a private directory in the runtime directory, a tmpfs on most
+//! systems, and the `--secret id=...,src=...` argument for it printed. Given a build command
+//! after `--`, the argument is appended to it and the plaintext removed once it finishes:
+//!
+//! ```sh
+//! arcanum docker-secret secrets/npmrc.age -- docker build .
+//! ```
+
+use crate::identity::IdentityStore;
+use crate::{decrypt, fsutil, shell_hook, CacheFile};
+use std::path::Path;
+use std::process::Command;
+
+/// Whether BuildKit accepts `id` as a secret id
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// The id a build refers to `ciphertext` by unless given one: its file name without `.age`
+fn default_id(ciphertext: &Path) -> String {
+    let name = ciphertext.file_name().unwrap_or_default().to_string_lossy();
+    name.strip_suffix(".age").unwrap_or(&name).to_string()
+}
+
+/// Decrypt `ciphertext` for a build as the secret `id`, then print the `--secret` argument for
+/// it or run `command` with it. Returns the exit code.
+pub fn docker_secret(
+    cache: &CacheFile,
+    project_root: &Path,
+    ciphertext: &Path,
+    id: Option<&str>,
+    command: &[String],
+    identities: &IdentityStore,
+) -> i32 {
+    let id = id.map_or_else(|| default_id(ciphertext), str::to_string);
+    if !valid_id(&id) {
+        eprintln!(
+            "{:?} isn't usable as a secret id, pass one with --id using letters, digits, -, _ \
+             and .",
+            id
+        );
+        return 1;
+    }
+    let plaintext = decrypt(cache, ciphertext, false, identities);
+    // Next to the devShell's secrets rather than among them, where they'd count as stale
+    let dir = shell_hook::secrets_dir(project_root).with_extension("docker");
+    let path = dir.join(&id);
+    let written = fsutil::create_dir_all_with_mode(&dir, 0o700)
+        .and_then(|_| fsutil::set_mode(&dir, 0o700))
+        .and_then(|_| fsutil::write_atomic(&path, &plaintext, 0o600, None));
+    if let Err(e) = written {
+        eprintln!("Unable to write the secret to {:?}: {}", path, e);
+        return 1;
+    }
+    let argument = format!("id={},src={}", id, path.display());
+
+    let Some((program, args)) = command.split_first() else {
+        println!("--secret {}", argument);
+        eprintln!(
+            "The plaintext stays in {:?}, remove it after the build",
+            path
+        );
+        return 0;
+    };
+    let status = Command::new(program)
+        .args(args)
+        .arg("--secret")
+        .arg(&argument)
+        .status();
+    if let Err(e) = std::fs::remove_file(&path) {
+        eprintln!("Unable to remove the plaintext at {:?}: {}", path, e);
+    }
+    match status {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(e) => {
+            eprintln!("Unable to run {:?}: {}", program, e);
+            1
+        }
+    }
+}
@@ -0,0 +1,146 @@
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Mode cache and state files should have, they describe the project's secrets topology
+#[cfg(unix)]
+const STATE_FILE_MODE: u32 = 0o600;
+
+/// The user cache and state files should belong to. Under sudo that's the invoking user, so
+/// running `sudo arcanum doctor --fix` hands root-owned files back to them.
+#[cfg(unix)]
+fn expected_owner() -> (u32, u32) {
+    let euid = unsafe { libc::geteuid() };
+    let egid = unsafe { libc::getegid() };
+    if euid == 0 {
+        let sudo_uid = std::env::var("SUDO_UID").ok().and_then(|u| u.parse().ok());
+        let sudo_gid = std::env::var("SUDO_GID").ok().and_then(|g| g.parse().ok());
+        if let (Some(uid), Some(gid)) = (sudo_uid, sudo_gid) {
+            return (uid, gid);
+        }
+    }
+    (euid, egid)
+}
+
+/// Every cache file arcanum has written to `cache_dir`, for any project
+pub fn state_files(cache_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(cache_dir) else {
+        return vec![];
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with("arcanum-") && name.ends_with(".json")
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// Check ownership and permissions of cache/state files, repairing them when `fix` is set.
+/// Returns true when everything is (now) healthy.
+#[cfg(unix)]
+pub fn doctor(cache_dir: &Path, fix: bool) -> bool {
+    let (uid, gid) = expected_owner();
+    let euid = unsafe { libc::geteuid() };
+    let mut healthy = true;
+
+    for path in state_files(cache_dir) {
+        let metadata = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                println!("error    {}: {}", path.display(), e);
+                healthy = false;
+                continue;
+            }
+        };
+
+        if metadata.uid() != uid {
+            println!(
+                "problem  {}: owned by uid {}, expected {}",
+                path.display(),
+                metadata.uid(),
+                uid
+            );
+            if !fix {
+                healthy = false;
+                continue;
+            }
+            if euid == 0 {
+                match std::os::unix::fs::chown(&path, Some(uid), Some(gid)) {
+                    Ok(()) => println!("fixed    {}: changed owner to {}", path.display(), uid),
+                    Err(e) => {
+                        println!("error    {}: {}", path.display(), e);
+                        healthy = false;
+                        continue;
+                    }
+                }
+            } else {
+                // We can't chown it back, but it is only a cache, so it can be regenerated
+                match std::fs::remove_file(&path) {
+                    Ok(()) => {
+                        println!(
+                            "fixed    {}: removed, it will be regenerated",
+                            path.display()
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        println!("error    {}: {}", path.display(), e);
+                        println!("         try `sudo arcanum doctor --fix`");
+                        healthy = false;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        let mode = metadata.mode() & 0o7777;
+        if mode != STATE_FILE_MODE {
+            println!(
+                "problem  {}: mode {:o}, expected {:o}",
+                path.display(),
+                mode,
+                STATE_FILE_MODE
+            );
+            if !fix {
+                healthy = false;
+                continue;
+            }
+            let permissions = std::fs::Permissions::from_mode(STATE_FILE_MODE);
+            match std::fs::set_permissions(&path, permissions) {
+                Ok(()) => println!(
+                    "fixed    {}: changed mode to {:o}",
+                    path.display(),
+                    STATE_FILE_MODE
+                ),
+                Err(e) => {
+                    println!("error    {}: {}", path.display(), e);
+                    healthy = false;
+                }
+            }
+        }
+    }
+
+    if healthy {
+        eprintln!("All cache files look healthy.");
+    } else if !fix {
+        eprintln!("Run `arcanum doctor --fix` to repair these problems.");
+    }
+    healthy
+}
+
+/// Files on Windows inherit the ACL of the user's profile directory, there's no mode or owner
+/// to check
+#[cfg(not(unix))]
+pub fn doctor(cache_dir: &Path, _fix: bool) -> bool {
+    for path in state_files(cache_dir) {
+        println!(
+            "skipped  {}: permissions aren't checked on this platform",
+            path.display()
+        );
+    }
+    true
+}
@@ -0,0 +1,167 @@
+use crate::identity::IdentityStore;
+use crate::{plaintext_from_ciphertext_source, transform, CacheFile, Scope};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Where the value of a variable comes from: the whole plaintext of a managed file, or one key
+/// of a managed file holding a JSON object (such as a `kv` store)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EnvSource {
+    File(PathBuf),
+    Key { source: PathBuf, key: String },
+}
+
+impl EnvSource {
+    fn source(&self) -> &PathBuf {
+        match self {
+            EnvSource::File(source) | EnvSource::Key { source, .. } => source,
+        }
+    }
+}
+
+/// Variable names and the secrets they are set to
+pub type EnvTemplate = BTreeMap<String, EnvSource>;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum EnvFormat {
+    /// `NAME="value"` lines, for tools reading .env files
+    Dotenv,
+    /// `export NAME='value'` lines, for `eval` in a shell
+    Shell,
+    /// A JSON object of names to values
+    Json,
+}
+
+fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The template called `name`, refusing one defined differently by several sections
+fn find_template<'a>(cache: &'a CacheFile, name: &str) -> Result<&'a EnvTemplate, String> {
+    let mut found: Option<(Scope, &EnvTemplate)> = None;
+    for (scope, config) in cache.configs() {
+        let Some(template) = config.env_templates.get(name) else {
+            continue;
+        };
+        match &found {
+            Some((first, other)) if *other != template => {
+                return Err(format!(
+                    "env template {:?} is defined differently in {} and {}",
+                    name, first, scope
+                ));
+            }
+            Some(_) => {}
+            None => found = Some((scope, template)),
+        }
+    }
+    found.map(|(_, template)| template).ok_or_else(|| {
+        let mut names: Vec<&String> = cache
+            .configs()
+            .into_iter()
+            .flat_map(|(_, config)| config.env_templates.keys())
+            .collect();
+        names.sort();
+        names.dedup();
+        if names.is_empty() {
+            format!("no env template named {:?}, none are configured", name)
+        } else {
+            format!(
+                "no env template named {:?}, configured ones are: {}",
+                name,
+                names
+                    .iter()
+                    .map(|n| n.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+    })
+}
+
+fn value_of(
+    cache: &CacheFile,
+    source: &EnvSource,
+    identities: &IdentityStore,
+) -> Result<String, String> {
+    let path = source.source();
+    let plaintext = plaintext_from_ciphertext_source(&cache.resolve_source(path), identities);
+    if plaintext.is_empty() {
+        return Err(format!("{:?} is empty", path));
+    }
+    let plaintext = transform::apply_all(&cache.transforms_for_file(path), plaintext)?;
+    let text = String::from_utf8(plaintext).map_err(|_| format!("{:?} is not text", path))?;
+    match source {
+        EnvSource::File(_) => Ok(text.strip_suffix('\n').unwrap_or(&text).to_string()),
+        EnvSource::Key { key, .. } => {
+            let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&text)
+                .map_err(|e| format!("{:?} is not a JSON object: {}", path, e))?;
+            match object.get(key) {
+                Some(serde_json::Value::String(value)) => Ok(value.clone()),
+                Some(value) => Ok(value.to_string()),
+                None => Err(format!("{:?} has no key {:?}", path, key)),
+            }
+        }
+    }
+}
+
+fn dotenv_quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | '$' | '`' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// `value` in single quotes, safe to paste into a POSIX shell
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Decrypt the secrets of the env template `name` and render them in `format`
+pub fn render(
+    cache: &CacheFile,
+    name: &str,
+    format: EnvFormat,
+    identities: &IdentityStore,
+) -> Result<String, String> {
+    let template = find_template(cache, name)?;
+    let mut values = BTreeMap::new();
+    for (variable, source) in template {
+        if !valid_name(variable) {
+            return Err(format!("{:?} is not a valid variable name", variable));
+        }
+        let value = value_of(cache, source, identities)
+            .map_err(|e| format!("Unable to set {}: {}", variable, e))?;
+        values.insert(variable.as_str(), value);
+    }
+
+    let rendered = match format {
+        EnvFormat::Dotenv => values
+            .iter()
+            .map(|(name, value)| format!("{}={}\n", name, dotenv_quote(value)))
+            .collect(),
+        EnvFormat::Shell => values
+            .iter()
+            .map(|(name, value)| format!("export {}={}\n", name, shell_quote(value)))
+            .collect(),
+        EnvFormat::Json => {
+            let mut json = serde_json::to_string_pretty(&values).unwrap();
+            json.push('\n');
+            json
+        }
+    };
+    Ok(rendered)
+}
@@ -0,0 +1,50 @@
+//! Which editor `edit` opens plaintexts in. The command may carry arguments, such as
+//! `code --wait`, and is split like a shell would split it.
+
+use std::path::Path;
+use std::process::Command;
+use std::sync::OnceLock;
+
+static EDITOR: OnceLock<String> = OnceLock::new();
+
+/// Use `editor`, from `--editor` or `ARCANUM_EDITOR`, over $VISUAL and $EDITOR
+pub fn init(editor: Option<&str>) {
+    if let Some(editor) = editor.filter(|e| !e.trim().is_empty()) {
+        let _ = EDITOR.set(editor.to_string());
+    }
+}
+
+/// The editor command, with any arguments it was configured with
+pub fn command() -> Command {
+    let configured = EDITOR.get().cloned().or_else(|| {
+        ["VISUAL", "EDITOR"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.trim().is_empty())
+    });
+    let Some(configured) = configured else {
+        return Command::new(edit::get_editor().unwrap());
+    };
+    let parts = shlex::split(&configured).filter(|parts| !parts.is_empty());
+    let Some(parts) = parts else {
+        eprintln!("Unable to parse the editor command {:?}", configured);
+        std::process::exit(1);
+    };
+    let mut command = Command::new(&parts[0]);
+    command.args(&parts[1..]);
+    command
+}
+
+/// Open `paths` in the editor and wait for it to exit successfully
+pub fn open(paths: &[&Path]) -> std::io::Result<()> {
+    let mut editor = command();
+    eprintln!("Opening plaintext in editor: {:?}", editor.get_program());
+    let status = editor.args(paths).status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "editor exited with {}",
+            status
+        )));
+    }
+    Ok(())
+}
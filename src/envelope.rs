@@ -0,0 +1,297 @@
+//! Envelope recipients: the file key additionally wrapped by a key management service, giving
+//! a break-glass way to decrypt that is controlled by cloud IAM rather than personal keys, or
+//! by OpenPGP for people whose keys live on GnuPG smartcards.
+//!
+//! - `kms:<key id>` (e.g. `kms:alias/prod-secrets`) wraps with AWS KMS through the `aws` CLI,
+//!   in an `arcanum-kms <key id>` stanza. Needs the `kms` feature.
+//! - `vault:[<mount>/]<key>` wraps with HashiCorp Vault's transit engine through the `vault`
+//!   CLI, in an `arcanum-vault <mount>/<key>` stanza. The mount defaults to `transit`. Needs
+//!   the `vault` feature.
+//! - `pgp:<fingerprint>` encrypts to an OpenPGP key through `gpg`, in an `arcanum-pgp
+//!   <fingerprint>` stanza. Decrypting goes through gpg-agent, which asks for the smartcard
+//!   PIN. Needs the `pgp` feature.
+//!
+//! The CLIs take their credentials from the environment as usual. File keys only ever pass
+//! through their stdin, never their arguments.
+
+use crate::header;
+use age::secrecy::ExposeSecret;
+use age::{DecryptError, EncryptError, Identity, Recipient};
+use age_core::format::{FileKey, Stanza};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Kms,
+    Vault,
+    Pgp,
+}
+
+impl Backend {
+    const ALL: [Backend; 3] = [Backend::Kms, Backend::Vault, Backend::Pgp];
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Backend::Kms => "kms:",
+            Backend::Vault => "vault:",
+            Backend::Pgp => "pgp:",
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Backend::Kms => "arcanum-kms",
+            Backend::Vault => "arcanum-vault",
+            Backend::Pgp => "arcanum-pgp",
+        }
+    }
+
+    fn feature(self) -> &'static str {
+        match self {
+            Backend::Kms => "kms",
+            Backend::Vault => "vault",
+            Backend::Pgp => "pgp",
+        }
+    }
+
+    fn enabled(self) -> bool {
+        match self {
+            Backend::Kms => cfg!(feature = "kms"),
+            Backend::Vault => cfg!(feature = "vault"),
+            Backend::Pgp => cfg!(feature = "pgp"),
+        }
+    }
+
+    /// The key as stanzas name it
+    fn key_name(self, key: &str) -> String {
+        match self {
+            Backend::Vault if !key.contains('/') => format!("transit/{}", key),
+            Backend::Pgp => key.replace(' ', "").to_uppercase(),
+            _ => key.to_string(),
+        }
+    }
+
+    fn wrap(self, key: &str, file_key: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Backend::Kms => {
+                let output = run(
+                    "aws",
+                    &[
+                        "kms",
+                        "encrypt",
+                        "--key-id",
+                        key,
+                        "--plaintext",
+                        "fileb:///dev/stdin",
+                        "--output",
+                        "text",
+                        "--query",
+                        "CiphertextBlob",
+                    ],
+                    file_key,
+                )?;
+                STANDARD
+                    .decode(String::from_utf8_lossy(&output).trim())
+                    .map_err(|e| e.to_string())
+            }
+            Backend::Vault => {
+                let (mount, name) = key.rsplit_once('/').unwrap();
+                let request = serde_json::json!({ "plaintext": STANDARD.encode(file_key) });
+                let output = run(
+                    "vault",
+                    &[
+                        "write",
+                        "-field=ciphertext",
+                        &format!("{}/encrypt/{}", mount, name),
+                        "-",
+                    ],
+                    request.to_string().as_bytes(),
+                )?;
+                Ok(String::from_utf8_lossy(&output).trim().as_bytes().to_vec())
+            }
+            Backend::Pgp => run(
+                "gpg",
+                &[
+                    "--batch",
+                    "--quiet",
+                    "--trust-model",
+                    "always",
+                    "--encrypt",
+                    "--recipient",
+                    &format!("{}!", key),
+                    "--output",
+                    "-",
+                ],
+                file_key,
+            ),
+        }
+    }
+
+    fn unwrap(self, key: &str, body: &[u8]) -> Result<Vec<u8>, String> {
+        let encoded = match self {
+            // gpg finds the secret key from the message itself
+            Backend::Pgp => {
+                return run(
+                    "gpg",
+                    &["--batch", "--quiet", "--decrypt", "--output", "-"],
+                    body,
+                )
+            }
+            Backend::Kms => run(
+                "aws",
+                &[
+                    "kms",
+                    "decrypt",
+                    "--key-id",
+                    key,
+                    "--ciphertext-blob",
+                    "fileb:///dev/stdin",
+                    "--output",
+                    "text",
+                    "--query",
+                    "Plaintext",
+                ],
+                body,
+            )?,
+            Backend::Vault => {
+                // Read from the file being decrypted, which may be corrupt
+                let (mount, name) = key
+                    .rsplit_once('/')
+                    .ok_or_else(|| format!("{:?} isn't a <mount>/<key> Vault key", key))?;
+                let request = serde_json::json!({ "ciphertext": String::from_utf8_lossy(body) });
+                run(
+                    "vault",
+                    &[
+                        "write",
+                        "-field=plaintext",
+                        &format!("{}/decrypt/{}", mount, name),
+                        "-",
+                    ],
+                    request.to_string().as_bytes(),
+                )?
+            }
+        };
+        STANDARD
+            .decode(String::from_utf8_lossy(&encoded).trim())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Run `program` with `input` on its stdin, returning its stdout
+fn run(program: &str, args: &[&str], input: &[u8]) -> Result<Vec<u8>, String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("unable to run {}: {}", program, e))?;
+    // Writing fails when the program gives up before reading its input, such as gpg not
+    // finding the key or its smartcard, so its exit status and stderr say what went wrong
+    let _ = child.stdin.take().unwrap().write_all(input);
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// The backend and stanza key name of an envelope recipient, None for other recipients
+fn split(recipient: &str) -> Option<(Backend, String)> {
+    Backend::ALL.into_iter().find_map(|backend| {
+        let key = recipient.strip_prefix(backend.prefix())?;
+        Some((backend, backend.key_name(key)))
+    })
+}
+
+/// Recipient wrapping the file key with a key management service
+pub struct EnvelopeRecipient {
+    backend: Backend,
+    key: String,
+}
+
+/// Parse an envelope recipient. None when `recipient` isn't one, an error when it is but can't
+/// be used.
+pub fn parse(recipient: &str) -> Option<Result<EnvelopeRecipient, String>> {
+    let (backend, key) = split(recipient)?;
+    let parsed = if !backend.enabled() {
+        Err(format!(
+            "{:?} needs arcanum built with the {} feature",
+            recipient,
+            backend.feature()
+        ))
+    } else if key.is_empty() || key.ends_with('/') || key.contains(char::is_whitespace) {
+        Err(format!("{:?} doesn't name a key", recipient))
+    } else if backend == Backend::Pgp
+        && !(matches!(key.len(), 40 | 64) && key.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        Err(format!("{:?} isn't a full OpenPGP fingerprint", recipient))
+    } else {
+        Ok(EnvelopeRecipient { backend, key })
+    };
+    Some(parsed)
+}
+
+impl Recipient for EnvelopeRecipient {
+    fn wrap_file_key(&self, file_key: &FileKey) -> Result<Vec<Stanza>, EncryptError> {
+        let body = self
+            .backend
+            .wrap(&self.key, file_key.expose_secret())
+            .map_err(|e| {
+                let message = format!("{}{}: {}", self.backend.prefix(), self.key, e);
+                EncryptError::Io(std::io::Error::other(message))
+            })?;
+        Ok(vec![Stanza {
+            tag: self.backend.tag().to_string(),
+            args: vec![self.key.clone()],
+            body,
+        }])
+    }
+}
+
+/// Whether `stanzas` hold a stanza for the envelope recipient `recipient`. None when it isn't
+/// one.
+pub fn in_stanzas(stanzas: &[&header::Stanza], recipient: &str) -> Option<bool> {
+    let (backend, key) = split(recipient)?;
+    Some(
+        stanzas
+            .iter()
+            .any(|s| s.tag == backend.tag() && s.args.first() == Some(&key)),
+    )
+}
+
+/// Identity asking the key management services to unwrap envelope stanzas. It is tried after
+/// every personal identity, so the services are only called when nothing else can decrypt.
+pub struct EnvelopeIdentity;
+
+impl Identity for EnvelopeIdentity {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        let backend = Backend::ALL
+            .into_iter()
+            .find(|backend| backend.enabled() && stanza.tag == backend.tag())?;
+        let key = stanza.args.first()?;
+        let file_key = backend
+            .unwrap(key, &stanza.body)
+            .map_err(|e| {
+                eprintln!(
+                    "Unable to unwrap the {} stanza for {}: {}",
+                    backend.feature(),
+                    key,
+                    e
+                )
+            })
+            .ok()?;
+        let file_key: [u8; 16] = match file_key.try_into() {
+            Ok(file_key) => file_key,
+            Err(_) => return Some(Err(DecryptError::DecryptionFailed)),
+        };
+        Some(Ok(FileKey::from(file_key)))
+    }
+}
@@ -0,0 +1,229 @@
+//! Secrets that have to be replaced by a date. Files can set `expires`, the date by which the
+//! secret must have been replaced, and `maxAge`, how long it may be used after it was last
+//! rotated:
+//!
+//! ```nix
+//! arcanum.files.api-token = {
+//!   source = "secrets/api-token.age";
+//!   maxAge = "90d";
+//! };
+//! ```
+//!
+//! When a secret was last rotated is read from its metadata, which rekeys leave alone, and
+//! for ciphertexts written before it was recorded there, from the last commit changing them.
+//! With `--certificates`, the secrets you can decrypt are searched for certificates too, due
+//! when they expire. That decrypts every secret, which may ask for passphrases or touches, so
+//! it isn't done by default.
+
+use crate::identity::IdentityStore;
+use crate::{cert, git, metadata, CacheFile};
+use chrono::{Duration, Local, NaiveDate};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Days in a period such as `30d`, `12w` or `1y`. A bare number is days.
+pub fn parse_period(period: &str) -> Result<i64, String> {
+    let invalid = || format!("{:?} isn't a period such as 30d, 12w or 1y", period);
+    let (number, unit) = match period.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => period.split_at(i),
+        None => (period, "d"),
+    };
+    let days = match unit {
+        "d" => 1,
+        "w" => 7,
+        "y" => 365,
+        _ => return Err(invalid()),
+    };
+    number
+        .parse::<i64>()
+        .ok()
+        .and_then(|number| number.checked_mul(days))
+        // Keeps due dates well within what chrono can represent
+        .filter(|&days| days <= 100_000 * 365)
+        .ok_or_else(invalid)
+}
+
+/// A secret that has to be replaced by `date`
+pub struct Due {
+    pub date: NaiveDate,
+    /// Why, such as `maxAge 90d from 2026-01-02`
+    pub reason: String,
+}
+
+/// When the secret in `ciphertext` was last rotated, None when neither its metadata nor git
+/// know. Git doesn't tell rekeys apart from rotations, so it may be later than the real one.
+fn last_rotated(ciphertext: &Path) -> Option<NaiveDate> {
+    let recorded = metadata::read(ciphertext)
+        .and_then(Result::ok)
+        .and_then(|metadata| metadata.rotated_at);
+    if let Some(rotated_at) = recorded {
+        return Some(rotated_at.with_timezone(&Local).date_naive());
+    }
+    let commits = git::log(ciphertext)?;
+    NaiveDate::parse_from_str(&commits.first()?.date, "%Y-%m-%d").ok()
+}
+
+/// Every managed secret with a date it has to be replaced by, keyed by source. Files with a
+/// `maxAge` but no known rotation are listed in `unknown`, invalid ones and certificates
+/// openssl can't read in `invalid`, with every reason for each.
+pub struct Report {
+    pub due: BTreeMap<PathBuf, Vec<Due>>,
+    pub unknown: BTreeMap<PathBuf, Vec<String>>,
+    pub invalid: BTreeMap<PathBuf, Vec<String>>,
+    /// Files that couldn't be decrypted to look for certificates
+    pub undecryptable: usize,
+}
+
+/// When every managed secret in the project at `project_root` has to be replaced by, looking
+/// for certificates in the secrets `certificates` can decrypt when given
+pub fn report(
+    cache: &CacheFile,
+    project_root: &Path,
+    certificates: Option<&IdentityStore>,
+) -> Report {
+    let (certificates, undecryptable) = match certificates {
+        Some(identities) => cert::managed(cache, project_root, identities),
+        None => (BTreeMap::new(), 0),
+    };
+    let mut report = Report {
+        due: BTreeMap::new(),
+        unknown: BTreeMap::new(),
+        invalid: BTreeMap::new(),
+        undecryptable,
+    };
+    for (source, certificates) in certificates {
+        for (i, certificate) in certificates.into_iter().enumerate() {
+            match certificate {
+                Ok(certificate) => report.add(
+                    &source,
+                    certificate.not_after.date_naive(),
+                    format!("certificate {}", certificate.subject),
+                ),
+                Err(e) => report.flag_invalid(&source, format!("certificate {}: {}", i + 1, e)),
+            }
+        }
+    }
+    for (_, _, file) in cache.entries() {
+        let ciphertext = project_root.join(&file.source);
+        if let Some(expires) = file.expires {
+            report.add(&file.source, expires, "expires".to_string());
+        }
+        let Some(max_age) = &file.max_age else {
+            continue;
+        };
+        let days = match parse_period(max_age) {
+            Ok(days) => days,
+            Err(e) => {
+                report.flag_invalid(&file.source, format!("maxAge: {}", e));
+                continue;
+            }
+        };
+        // Nothing to rotate until it has been written
+        if !ciphertext.exists() {
+            continue;
+        }
+        match last_rotated(&ciphertext) {
+            Some(rotated) => report.add(
+                &file.source,
+                rotated + Duration::days(days),
+                format!("maxAge {} from {}", max_age, rotated),
+            ),
+            None => {
+                let reason = format!(
+                    "maxAge {}, but neither its metadata nor git know when it was rotated",
+                    max_age
+                );
+                let unknown = report.unknown.entry(file.source.clone()).or_default();
+                if !unknown.contains(&reason) {
+                    unknown.push(reason);
+                }
+            }
+        }
+    }
+    report
+}
+
+impl Report {
+    /// Record that `source` is due at `date`, the same reason only once however many sections
+    /// declare it
+    pub fn add(&mut self, source: &Path, date: NaiveDate, reason: String) {
+        let due = self.due.entry(source.to_path_buf()).or_default();
+        if !due.iter().any(|d| d.date == date && d.reason == reason) {
+            due.push(Due { date, reason });
+        }
+    }
+
+    /// Record that `source` is invalid for `reason`, once however many sections declare it
+    fn flag_invalid(&mut self, source: &Path, reason: String) {
+        let invalid = self.invalid.entry(source.to_path_buf()).or_default();
+        if !invalid.contains(&reason) {
+            invalid.push(reason);
+        }
+    }
+}
+
+/// Print every secret that expired or expires within `within_days`, soonest first, with the
+/// certificates in them when `certificates` is given to decrypt them. Returns whether any were
+/// found, or had an expiry that couldn't be worked out.
+pub fn expiring(
+    cache: &CacheFile,
+    project_root: &Path,
+    within_days: i64,
+    certificates: Option<&IdentityStore>,
+) -> bool {
+    let today = Local::now().date_naive();
+    let horizon = today + Duration::days(within_days);
+    let report = report(cache, project_root, certificates);
+
+    let mut flagged: Vec<(&PathBuf, &Due)> = report
+        .due
+        .iter()
+        .flat_map(|(source, due)| due.iter().map(move |due| (source, due)))
+        .filter(|(_, due)| due.date <= horizon)
+        .collect();
+    flagged.sort_by_key(|(source, due)| (due.date, *source));
+
+    for (source, due) in &flagged {
+        if due.date <= today {
+            println!(
+                "expired   {} ({}, due {})",
+                source.display(),
+                due.reason,
+                due.date
+            );
+        } else {
+            let days = (due.date - today).num_days();
+            println!(
+                "expiring  {} ({}, due {}, in {} days)",
+                source.display(),
+                due.reason,
+                due.date,
+                days
+            );
+        }
+    }
+    for (source, reasons) in &report.unknown {
+        for reason in reasons {
+            println!("unknown   {} ({})", source.display(), reason);
+        }
+    }
+    for (source, reasons) in &report.invalid {
+        for reason in reasons {
+            println!("invalid   {} ({})", source.display(), reason);
+        }
+    }
+
+    if report.undecryptable > 0 {
+        eprintln!(
+            "{} managed file(s) weren't checked for certificates, none of your identities can \
+             decrypt them",
+            report.undecryptable
+        );
+    }
+
+    let found = !flagged.is_empty() || !report.unknown.is_empty() || !report.invalid.is_empty();
+    if !found {
+        eprintln!("No secrets expire within {} days.", within_days);
+    }
+    found
+}
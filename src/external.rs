@@ -0,0 +1,44 @@
+//! `arcanum external`: Terraform's external data source protocol, so a `data "external"` block
+//! can read a secret from the repository rather than it being duplicated in variables:
+//!
+//! ```hcl
+//! data "external" "db" {
+//!   program = ["arcanum", "--project", "${path.root}/..", "external"]
+//!   query   = { file = "secrets/db.json.age", key = "password" }
+//! }
+//! ```
+//!
+//! The query names a managed file and optionally a key in it, as `--key` takes them. The
+//! result is `{ value = "..." }`. Terraform keeps results in its state, so treat that as
+//! holding the secret too.
+
+use crate::identity::IdentityStore;
+use crate::{field, CacheFile};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Query {
+    file: PathBuf,
+    /// A dotted path into a JSON plaintext, or an env file variable
+    #[serde(default)]
+    key: Option<String>,
+}
+
+/// Answer the query Terraform writes to stdin with the value it asks for on stdout
+pub fn external(cache: &CacheFile, identities: &IdentityStore) {
+    let query: Query = serde_json::from_reader(std::io::stdin()).unwrap_or_else(|e| {
+        eprintln!(
+            "Unable to read the query, expected {{\"file\": ..., \"key\": ...}}: {}",
+            e
+        );
+        std::process::exit(1);
+    });
+    // Terraform only sends strings, so an unset key arrives empty
+    let key = query.key.as_deref().filter(|key| !key.is_empty());
+    let value = field::value(cache, &query.file, key, identities);
+    let result = BTreeMap::from([("value", value)]);
+    println!("{}", serde_json::to_string(&result).unwrap());
+}
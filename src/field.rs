@@ -0,0 +1,65 @@
+//! Picking a single value out of a decrypted secret with `--key`, for commands that hand one
+//! value to something else rather than printing the whole plaintext.
+
+use crate::identity::IdentityStore;
+use crate::{plaintext_from_ciphertext_source, transform, CacheFile};
+use serde_json::Value;
+use std::path::Path;
+
+/// The value at `key` in `text`: a dotted path into a JSON document (`db.password`,
+/// `servers.0.host`), or a variable of an env file
+pub fn extract(text: &str, key: &str) -> Result<String, String> {
+    if let Ok(document) = serde_json::from_str::<Value>(text) {
+        let mut value = &document;
+        for part in key.split('.') {
+            value = match value {
+                Value::Object(map) => map.get(part),
+                Value::Array(values) => part.parse::<usize>().ok().and_then(|i| values.get(i)),
+                _ => None,
+            }
+            .ok_or_else(|| format!("no key {:?}", key))?;
+        }
+        return Ok(match value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        });
+    }
+    text.lines()
+        .map(str::trim)
+        .filter_map(|line| line.strip_prefix("export ").unwrap_or(line).split_once('='))
+        .find(|(name, _)| name.trim() == key)
+        .map(|(_, value)| {
+            value
+                .trim()
+                .trim_matches(|c| c == '"' || c == '\'')
+                .to_string()
+        })
+        .ok_or_else(|| format!("no key {:?}", key))
+}
+
+/// The plaintext of `ciphertext` with its transforms applied, or the value at `key` in it.
+/// A trailing newline of a whole plaintext is dropped. Exits when it can't be had.
+pub fn value(
+    cache: &CacheFile,
+    ciphertext: &Path,
+    key: Option<&str>,
+    identities: &IdentityStore,
+) -> String {
+    let plaintext = plaintext_from_ciphertext_source(&cache.resolve_source(ciphertext), identities);
+    if plaintext.is_empty() {
+        std::process::exit(1);
+    }
+    let text = transform::apply_all(&cache.transforms_for_file(ciphertext), plaintext).and_then(
+        |plaintext| {
+            String::from_utf8(plaintext).map_err(|_| "the plaintext is not text".to_string())
+        },
+    );
+    let value = text.and_then(|text| match key {
+        Some(key) => extract(&text, key),
+        None => Ok(text.strip_suffix('\n').unwrap_or(&text).to_string()),
+    });
+    value.unwrap_or_else(|e| {
+        eprintln!("Unable to read {:?}: {}", ciphertext, e);
+        std::process::exit(1);
+    })
+}
@@ -0,0 +1,234 @@
+//! File system helpers. Modes and ownership only exist on unix, elsewhere they are accepted
+//! and ignored so callers don't need to care which platform they run on.
+
+use fs2::FileExt;
+#[cfg(unix)]
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+/// Parse an octal permission string such as `0400` or `750`
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|m| *m <= 0o7777)
+        .ok_or_else(|| format!("{:?} is not an octal permission string", mode))
+}
+
+/// A sibling of `path` used to stage writes before they are renamed into place
+fn staging_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.arcanum-{}", name, std::process::id()))
+}
+
+/// Write `data` to `path` by staging it next to the destination with `mode`, optionally
+/// changing its ownership, and renaming it over the destination so readers never see a
+/// partially written file.
+pub fn write_atomic(
+    path: &Path,
+    data: &[u8],
+    mode: u32,
+    owner: Option<(u32, u32)>,
+) -> std::io::Result<()> {
+    write_atomic_with(path, mode, owner, |out| out.write_all(data))
+}
+
+/// `write_atomic` with the contents written by `write` through a buffer, for output too large
+/// to build in memory first
+pub fn write_atomic_with(
+    path: &Path,
+    mode: u32,
+    owner: Option<(u32, u32)>,
+    write: impl FnOnce(&mut BufWriter<&File>) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    write_atomic_checked(path, mode, owner, write, |_| Ok(()))
+}
+
+/// `write_atomic_with`, where `check` gets to look at the staged file before it replaces the
+/// destination and stops it from doing so by failing
+pub fn write_atomic_checked(
+    path: &Path,
+    mode: u32,
+    owner: Option<(u32, u32)>,
+    write: impl FnOnce(&mut BufWriter<&File>) -> std::io::Result<()>,
+    check: impl FnOnce(&Path) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let staging = staging_path(path);
+    let result = (|| {
+        let file = create_with_mode(&staging, mode)?;
+        #[cfg(unix)]
+        if let Some((uid, gid)) = owner {
+            std::os::unix::fs::chown(&staging, Some(uid), Some(gid))?;
+        }
+        #[cfg(not(unix))]
+        let _ = owner;
+        let mut out = BufWriter::new(&file);
+        write(&mut out)?;
+        out.flush()?;
+        drop(out);
+        file.sync_all()?;
+        check(&staging)?;
+        std::fs::rename(&staging, path)?;
+        // Directories can't be opened (or synced) like this on Windows
+        #[cfg(unix)]
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&staging);
+    }
+    result
+}
+
+/// Take an exclusive advisory lock on `path`, creating it with `mode` if needed and waiting
+/// while another process holds the lock. It is released when the returned file is dropped.
+pub fn lock_exclusive(path: &Path, mode: u32) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    #[cfg(unix)]
+    options.mode(mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    let file = options.open(path)?;
+    file.lock_exclusive()?;
+    Ok(file)
+}
+
+/// Create a new file, failing if it exists, with exactly `mode` regardless of the umask
+pub fn create_with_mode(path: &Path, mode: u32) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(mode);
+    let file = options.open(path)?;
+    // The mode passed to open is filtered through the umask, so set it explicitly
+    #[cfg(unix)]
+    file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(file)
+}
+
+/// Create a single new directory with `mode`, failing if it exists
+pub fn create_dir_with_mode(dir: &Path, mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    std::fs::DirBuilder::new().mode(mode).create(dir)?;
+    #[cfg(not(unix))]
+    std::fs::DirBuilder::new().create(dir)?;
+    set_mode(dir, mode)
+}
+
+/// Create `dir` and any missing parents, giving the directories created here `mode`
+pub fn create_dir_all_with_mode(dir: &Path, mode: u32) -> std::io::Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent().filter(|p| !p.as_os_str().is_empty()) {
+        create_dir_all_with_mode(parent, mode)?;
+    }
+    std::fs::create_dir(dir)?;
+    set_mode(dir, mode)
+}
+
+/// `path` with `.` components dropped and `..` resolved against the components before it,
+/// without touching the file system
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) {
+                    normalized.pop();
+                } else if !normalized.has_root() {
+                    normalized.push("..");
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Set the permission bits of `path`
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Permission bits of `path`, None when it doesn't exist or the platform has none
+#[cfg(unix)]
+pub fn mode_of(path: &Path) -> Option<u32> {
+    std::fs::metadata(path)
+        .ok()
+        .map(|m| m.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn mode_of(_path: &Path) -> Option<u32> {
+    None
+}
+
+/// Look up a user by name (or numeric id)
+#[cfg(unix)]
+pub fn uid_for(owner: &str) -> std::io::Result<u32> {
+    if let Ok(uid) = owner.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(owner)?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no such user {:?}", owner),
+        ));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+/// Look up a group by name (or numeric id)
+#[cfg(unix)]
+pub fn gid_for(group: &str) -> std::io::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no such group {:?}", group),
+        ));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+/// The name of the machine we're running on
+#[cfg(unix)]
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if result != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+#[cfg(not(unix))]
+pub fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
@@ -0,0 +1,341 @@
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+/// `path` as git expects it in a `<rev>:<path>` spec, relative to the current directory and
+/// with forward slashes on every platform
+fn spec_path(path: &Path) -> String {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let relative: PathBuf = path.strip_prefix(&cwd).unwrap_or(path).to_path_buf();
+    let parts: Vec<String> = relative
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+            Component::ParentDir => Some("..".to_string()),
+            _ => None,
+        })
+        .collect();
+    format!("./{}", parts.join("/"))
+}
+
+/// Contents of `path` at `rev`, None when git doesn't know the file at that revision
+pub fn show(rev: &str, path: &Path) -> Option<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("show")
+        .arg(format!("{}:{}", rev, spec_path(path)))
+        .output()
+        .ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+/// Files tracked by git in the repository at `root` matching `pathspec`, relative to `root`.
+/// None outside a git repository.
+pub fn tracked(root: &Path, pathspec: &str) -> Option<Vec<PathBuf>> {
+    let output = Command::new("git")
+        .current_dir(root)
+        .args(["ls-files", "-z", "--", pathspec])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        output
+            .stdout
+            .split(|b| *b == 0)
+            .filter(|path| !path.is_empty())
+            .map(|path| PathBuf::from(String::from_utf8_lossy(path).into_owned()))
+            .collect(),
+    )
+}
+
+/// Trimmed stdout of a successful git command run in `dir`
+fn output(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Top of the work tree containing `dir`. Works for linked worktrees and submodules too, where
+/// `.git` is a file pointing at the real git directory rather than a directory.
+pub fn toplevel(dir: &Path) -> Option<PathBuf> {
+    output(dir, &["rev-parse", "--show-toplevel"]).map(PathBuf::from)
+}
+
+/// Whether `rev` names a commit available locally, which older commits of a shallow clone and
+/// HEAD of a repository without commits aren't
+pub fn rev_exists(rev: &str) -> bool {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    output(
+        &cwd,
+        &[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{}^{{commit}}", rev),
+        ],
+    )
+    .is_some()
+}
+
+/// Whether the repository at `dir` is a shallow clone, with history cut off at some commits
+pub fn is_shallow(dir: &Path) -> bool {
+    output(dir, &["rev-parse", "--is-shallow-repository"]).as_deref() == Some("true")
+}
+
+/// Commits a shallow clone's history is cut off at. Their parents are missing, so they look
+/// like they added every file.
+fn shallow_boundaries(root: &Path) -> BTreeSet<String> {
+    // --git-path finds the file in the common directory when run from a linked worktree
+    let Some(path) = output(root, &["rev-parse", "--git-path", "shallow"]) else {
+        return BTreeSet::new();
+    };
+    std::fs::read_to_string(root.join(path))
+        .map(|data| data.lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Abbreviated hash, date and subject of the latest commit adding or removing `needle` in
+/// files matching `pathspec`. None when there isn't one, or it is only the boundary of a
+/// shallow clone.
+pub fn last_commit_changing(root: &Path, needle: &str, pathspec: &str) -> Option<String> {
+    let line = output(
+        root,
+        &[
+            "log",
+            "-1",
+            "--date=short",
+            "--format=%H %h %ad %s",
+            &format!("-S{}", needle),
+            "--",
+            pathspec,
+        ],
+    )?;
+    let (hash, summary) = line.split_once(' ')?;
+    if shallow_boundaries(root).contains(hash) {
+        return None;
+    }
+    Some(summary.to_string())
+}
+
+/// The committer identity git would use in the current directory, as `Name <email>`
+pub fn user() -> Option<String> {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let name = output(&cwd, &["config", "user.name"]);
+    let email = output(&cwd, &["config", "user.email"]);
+    match (name, email) {
+        (Some(name), Some(email)) => Some(format!("{} <{}>", name, email)),
+        (name, email) => name.or(email),
+    }
+}
+
+/// A commit changing a file
+pub struct Commit {
+    pub hash: String,
+    pub short: String,
+    pub date: String,
+    pub author: String,
+    pub subject: String,
+    /// Path of the file in this commit, relative to the top of the work tree, which differs
+    /// from the current one when it was renamed since
+    pub path: Option<PathBuf>,
+}
+
+/// Commits changing `path`, newest first and following renames. None when git fails, such as
+/// outside a repository.
+pub fn log(path: &Path) -> Option<Vec<Commit>> {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let output = Command::new("git")
+        .current_dir(&cwd)
+        .args([
+            "log",
+            "--follow",
+            "--name-only",
+            "--date=short",
+            "--format=%x1e%H%x1f%h%x1f%ad%x1f%an%x1f%s",
+            "--",
+        ])
+        .arg(spec_path(path))
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let commits = stdout
+        .split('\x1e')
+        .filter(|record| !record.trim().is_empty())
+        .filter_map(|record| {
+            let mut lines = record.lines();
+            let fields: Vec<&str> = lines.next()?.split('\x1f').collect();
+            let [hash, short, date, author, subject] = fields[..] else {
+                return None;
+            };
+            Some(Commit {
+                hash: hash.to_string(),
+                short: short.to_string(),
+                date: date.to_string(),
+                author: author.to_string(),
+                subject: subject.to_string(),
+                path: lines.find(|l| !l.trim().is_empty()).map(PathBuf::from),
+            })
+        })
+        .collect();
+    Some(commits)
+}
+
+/// Contents of `path`, relative to the top of the work tree, at `rev`
+pub fn show_from_toplevel(rev: &str, path: &Path) -> Option<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("show")
+        .arg(format!(
+            "{}:{}",
+            rev,
+            path.to_string_lossy().replace('\\', "/")
+        ))
+        .output()
+        .ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+/// Tracked and untracked, not ignored, files of the work tree at `root`, relative to it. None
+/// outside a git repository.
+pub fn work_tree_files(root: &Path) -> Option<Vec<PathBuf>> {
+    let output = Command::new("git")
+        .current_dir(root)
+        .args([
+            "ls-files",
+            "-z",
+            "--cached",
+            "--others",
+            "--exclude-standard",
+        ])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        output
+            .stdout
+            .split(|b| *b == 0)
+            .filter(|path| !path.is_empty())
+            .map(|path| PathBuf::from(String::from_utf8_lossy(path).into_owned()))
+            .collect(),
+    )
+}
+
+/// The line counts of the old and new side of a hunk header like `@@ -12,3 +12,4 @@`, where a
+/// count left out is one
+fn hunk_lengths(header: &str) -> Option<(u64, u64)> {
+    let ranges = header.strip_prefix("@@ ")?.split(" @@").next()?;
+    let (old, new) = ranges.split_once(' ')?;
+    let length = |range: &str| match range.split_once(',') {
+        Some((_, count)) => count.parse().ok(),
+        None => Some(1),
+    };
+    Some((
+        length(old.strip_prefix('-')?)?,
+        length(new.strip_prefix('+')?)?,
+    ))
+}
+
+/// Call `f` with the abbreviated commit, path and text of every line added in any commit
+/// reachable from a ref of the repository at `root`. Returns false when git fails.
+pub fn for_each_added_line(root: &Path, mut f: impl FnMut(&str, &str, &str)) -> bool {
+    use std::io::BufRead;
+
+    let child = Command::new("git")
+        .current_dir(root)
+        .args([
+            "log",
+            "--all",
+            "--patch",
+            "--no-color",
+            "--no-ext-diff",
+            "--unified=0",
+            "--format=%x1e%h",
+        ])
+        .stdout(std::process::Stdio::piped())
+        .spawn();
+    let Ok(mut child) = child else {
+        return false;
+    };
+    let stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+    let mut commit = String::new();
+    let mut path = String::new();
+    // Lines of the current hunk still to come, removed and added. Content can look like a
+    // header, such as an added line starting with `++ `, so headers are only read outside.
+    let (mut removed, mut added) = (0u64, 0u64);
+    for line in stdout.split(b'\n') {
+        let Ok(line) = line else {
+            break;
+        };
+        let line = String::from_utf8_lossy(&line);
+        if removed > 0 || added > 0 {
+            if let Some(text) = line.strip_prefix('+') {
+                added = added.saturating_sub(1);
+                f(&commit, &path, text);
+            } else if line.starts_with('-') {
+                removed = removed.saturating_sub(1);
+            } else if line.starts_with(' ') {
+                removed = removed.saturating_sub(1);
+                added = added.saturating_sub(1);
+            }
+        } else if let Some(hash) = line.strip_prefix('\x1e') {
+            commit = hash.to_string();
+        } else if let Some(new_path) = line.strip_prefix("+++ ") {
+            path = new_path.strip_prefix("b/").unwrap_or(new_path).to_string();
+        } else if let Some((old, new)) = hunk_lengths(&line) {
+            (removed, added) = (old, new);
+        }
+    }
+    child.wait().map(|status| status.success()).unwrap_or(false)
+}
+
+/// Where git keeps `name` in the git directory of the current directory's repository
+pub fn git_path(name: &str) -> Option<PathBuf> {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    output(&cwd, &["rev-parse", "--git-path", name]).map(|path| cwd.join(path))
+}
+
+/// The boolean git config `key`, false when unset
+pub fn config_bool(key: &str) -> bool {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    output(&cwd, &["config", "--bool", key]).as_deref() == Some("true")
+}
+
+/// Stage `path` in the index. Returns false when git fails.
+pub fn add(path: &Path) -> bool {
+    Command::new("git")
+        .arg("add")
+        .arg("--")
+        .arg(path)
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Whether git ignores `path`, which doesn't have to exist yet. Tracked files never are. None
+/// outside a work tree.
+pub fn is_ignored(path: &Path) -> Option<bool> {
+    let path = std::path::absolute(path).ok()?;
+    let dir = path.ancestors().skip(1).find(|dir| dir.is_dir())?;
+    let status = Command::new("git")
+        .current_dir(dir)
+        .args(["check-ignore", "-q", "--"])
+        .arg(&path)
+        .stderr(std::process::Stdio::null())
+        .status()
+        .ok()?;
+    match status.code() {
+        Some(0) => Some(true),
+        Some(1) => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,130 @@
+//! `arcanum grep`: which managed files contain a pattern. Plaintexts are only ever held in
+//! memory. The files are decrypted concurrently and the plaintexts then searched in parallel.
+
+use crate::identity::IdentityStore;
+use crate::{concurrent, CacheFile};
+use clap::Args;
+use regex::bytes::{Regex, RegexBuilder};
+use std::path::PathBuf;
+
+#[derive(Args)]
+pub struct GrepArgs {
+    pattern: String,
+
+    /// Match regardless of case
+    #[clap(long, short = 'i')]
+    ignore_case: bool,
+
+    /// Treat the pattern as a literal string rather than a regular expression
+    #[clap(long, short = 'F')]
+    fixed_strings: bool,
+
+    /// Only list the files that match
+    #[clap(long, short = 'l')]
+    files_with_matches: bool,
+
+    /// Print the matching lines too, rather than just where they are
+    #[clap(long)]
+    show: bool,
+}
+
+/// A matching line of a plaintext
+struct Match {
+    line: usize,
+    text: String,
+}
+
+fn threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+fn search(regex: &Regex, plaintext: &[u8]) -> Vec<Match> {
+    plaintext
+        .split(|b| *b == b'\n')
+        .enumerate()
+        .filter(|(_, line)| regex.is_match(line))
+        .map(|(i, line)| Match {
+            line: i + 1,
+            text: String::from_utf8_lossy(line).into_owned(),
+        })
+        .collect()
+}
+
+/// Print where the plaintexts of the managed files you can decrypt match. Returns true when
+/// anything matched.
+pub fn grep(cache: &CacheFile, args: &GrepArgs, identities: &IdentityStore) -> bool {
+    let pattern = if args.fixed_strings {
+        regex::escape(&args.pattern)
+    } else {
+        args.pattern.clone()
+    };
+    let regex = RegexBuilder::new(&pattern)
+        .case_insensitive(args.ignore_case)
+        .build()
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid pattern {:?}: {}", args.pattern, e);
+            std::process::exit(1);
+        });
+
+    let (paths, encrypted): (Vec<(PathBuf, PathBuf)>, Vec<Vec<u8>>) = cache
+        .sources()
+        .into_iter()
+        .filter_map(|source| {
+            let path = cache.resolve_source(&source);
+            let encrypted = std::fs::read(&path).ok()?;
+            Some(((source, path), encrypted))
+        })
+        .unzip();
+    let mut plaintexts: Vec<(PathBuf, Vec<u8>)> = vec![];
+    let mut unreadable = 0;
+    let decrypted = concurrent::decrypt_all(&encrypted, identities);
+    for ((source, path), decrypted) in paths.into_iter().zip(decrypted) {
+        match decrypted {
+            Ok(plaintext) => plaintexts.push((source, plaintext)),
+            Err(age::DecryptError::NoMatchingKeys) => unreadable += 1,
+            Err(e) => eprintln!("warning: unable to decrypt {:?}: {}", path, e),
+        }
+    }
+
+    let chunk = plaintexts.len().div_ceil(threads()).max(1);
+    let matches: Vec<(PathBuf, Vec<Match>)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = plaintexts
+            .chunks(chunk)
+            .map(|chunk| {
+                let regex = &regex;
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|(source, plaintext)| (source.clone(), search(regex, plaintext)))
+                        .filter(|(_, matches)| !matches.is_empty())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    });
+
+    for (source, found) in &matches {
+        if args.files_with_matches {
+            println!("{}", source.display());
+            continue;
+        }
+        for m in found {
+            if args.show {
+                println!("{}:{}:{}", source.display(), m.line, m.text);
+            } else {
+                println!("{}:{}", source.display(), m.line);
+            }
+        }
+    }
+    if unreadable > 0 {
+        eprintln!(
+            "{} managed file(s) were skipped, none of your identities can decrypt them",
+            unreadable
+        );
+    }
+    !matches.is_empty()
+}
@@ -0,0 +1,103 @@
+use crate::envelope;
+use age::armor::ArmoredReader;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// A recipient stanza line (`-> <tag> <args...>`) from an age header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stanza {
+    pub tag: String,
+    pub args: Vec<String>,
+}
+
+/// Parse the recipient stanzas from the header of an armored or binary age file
+pub fn read_stanzas(path: &Path) -> std::io::Result<Vec<Stanza>> {
+    let encrypted = std::fs::read(path)?;
+    stanzas_from_bytes(&encrypted)
+}
+
+pub fn stanzas_from_bytes(encrypted: &[u8]) -> std::io::Result<Vec<Stanza>> {
+    let reader = BufReader::new(ArmoredReader::new(encrypted));
+    let mut lines = reader.split(b'\n');
+
+    match lines.next() {
+        Some(Ok(line)) if line.starts_with(b"age-encryption.org/") => {}
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "not an age encrypted file",
+            ))
+        }
+    }
+
+    let mut stanzas = vec![];
+    for line in lines {
+        let line = line?;
+        if line.starts_with(b"---") {
+            break;
+        }
+        if let Some(stanza) = line.strip_prefix(b"-> ") {
+            let stanza = String::from_utf8_lossy(stanza);
+            let mut parts = stanza.split(' ').map(|s| s.to_string());
+            let tag = parts.next().unwrap_or_default();
+            stanzas.push(Stanza {
+                tag,
+                args: parts.collect(),
+            });
+        }
+    }
+    Ok(stanzas)
+}
+
+/// The short tag age puts in ssh stanzas to identify the recipient key, if `recipient` is an
+/// ssh public key
+pub fn ssh_tag(recipient: &str) -> Option<String> {
+    let mut parts = recipient.split_whitespace();
+    let key_type = parts.next()?;
+    if !key_type.starts_with("ssh-") {
+        return None;
+    }
+    let key = base64::engine::general_purpose::STANDARD
+        .decode(parts.next()?)
+        .ok()?;
+    let digest = Sha256::digest(&key);
+    Some(STANDARD_NO_PAD.encode(&digest[..4]))
+}
+
+/// Whether `stanzas` look like they were produced for exactly `recipients`
+///
+/// X25519 stanzas don't identify their recipient, so those are only compared by count.
+pub fn matches_recipients(stanzas: &[Stanza], recipients: &BTreeSet<String>) -> bool {
+    let stanzas: Vec<&Stanza> = stanzas
+        .iter()
+        .filter(|s| !s.tag.ends_with("-grease"))
+        .collect();
+    if stanzas.len() != recipients.len() {
+        return false;
+    }
+    let ssh_tags: BTreeSet<&str> = stanzas
+        .iter()
+        .filter(|s| s.tag.starts_with("ssh-"))
+        .filter_map(|s| s.args.first().map(|a| a.as_str()))
+        .collect();
+    let x25519_stanzas = stanzas.iter().filter(|s| s.tag == "X25519").count();
+    let mut x25519_recipients = 0;
+    for recipient in recipients {
+        if let Some(found) = envelope::in_stanzas(&stanzas, recipient) {
+            if !found {
+                return false;
+            }
+        } else if recipient.starts_with("age1") {
+            x25519_recipients += 1;
+        } else if let Some(tag) = ssh_tag(recipient) {
+            if !ssh_tags.contains(tag.as_str()) {
+                return false;
+            }
+        }
+    }
+    x25519_stanzas == x25519_recipients
+}
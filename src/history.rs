@@ -0,0 +1,141 @@
+//! `arcanum history`: the plaintext changelog of a ciphertext, from every version git has of it
+//! that your identities can still decrypt, and `arcanum restore` to bring one of them back.
+
+use crate::identity::IdentityStore;
+use crate::{
+    ciphertext_from_plaintext_buffer, ciphertext_mode, confirm, diff, exit_unwritable, git,
+    plaintext_from_ciphertext_buffer, read_input, try_decrypt, verify, write_output, CacheFile,
+    RecipientArgs,
+};
+use std::path::Path;
+
+/// Plaintext of one version, or why it couldn't be read
+fn decrypt(encrypted: Option<Vec<u8>>, identities: &IdentityStore) -> Result<Vec<u8>, String> {
+    let Some(encrypted) = encrypted else {
+        return Err("removed in this commit".to_string());
+    };
+    try_decrypt(&encrypted, identities).map_err(|e| match e {
+        age::DecryptError::NoMatchingKeys => "not readable with your identities".to_string(),
+        e => e.to_string(),
+    })
+}
+
+/// Print the commits changing `ciphertext`, newest first, each with the diff of its plaintext
+/// against the previous readable version. Uncommitted changes are shown first.
+pub fn history(
+    cache: &CacheFile,
+    ciphertext: &Path,
+    max_count: Option<usize>,
+    identities: &IdentityStore,
+) {
+    let source = cache.resolve_source(ciphertext);
+    let Some(mut commits) = git::log(&source) else {
+        eprintln!("Unable to read the git history of {:?}", source);
+        std::process::exit(1);
+    };
+    if commits.is_empty() {
+        eprintln!("{:?} has no history in git", source);
+        std::process::exit(1);
+    }
+    if let Some(max_count) = max_count {
+        commits.truncate(max_count);
+    }
+
+    // Oldest first, so each version is compared with the one before it
+    let mut entries = vec![];
+    let mut previous: Option<(&str, Vec<u8>)> = None;
+    for commit in commits.iter().rev() {
+        let encrypted = commit
+            .path
+            .as_deref()
+            .and_then(|path| git::show_from_toplevel(&commit.hash, path));
+        let body = match decrypt(encrypted, identities) {
+            Ok(plaintext) => {
+                let (old_label, old) = previous
+                    .as_ref()
+                    .map_or(("(none)", &[][..]), |(label, old)| (*label, old.as_slice()));
+                let body = diff::unified(old, &plaintext, old_label, &commit.short);
+                previous = Some((&commit.short, plaintext));
+                if body.is_empty() {
+                    "  plaintext unchanged, re-encrypted only\n".to_string()
+                } else {
+                    body
+                }
+            }
+            Err(e) => format!("  {}\n", e),
+        };
+        let label = format!(
+            "{} {} {}: {}",
+            commit.short, commit.date, commit.author, commit.subject
+        );
+        entries.push((label, body));
+    }
+
+    if source.exists() {
+        let current = read_input(&source)
+            .map_err(|e| e.to_string())
+            .and_then(|encrypted| decrypt(Some(encrypted), identities));
+        if let (Ok(current), Some((old_label, old))) = (&current, &previous) {
+            let body = diff::unified(old, current, old_label, "working tree");
+            if !body.is_empty() {
+                entries.push(("uncommitted changes".to_string(), body));
+            }
+        }
+    }
+
+    for (label, body) in entries.into_iter().rev() {
+        println!("{}", label);
+        print!("{}", body);
+        println!();
+    }
+}
+
+/// Replace `ciphertext` with its plaintext at `rev`, encrypted to its current recipients
+pub fn restore(
+    cache: &CacheFile,
+    ciphertext: &Path,
+    rev: &str,
+    identities: &IdentityStore,
+    args: &RecipientArgs,
+) {
+    diff::require_rev(rev);
+    let source = cache.resolve_source(ciphertext);
+    let Some(encrypted) = git::show(rev, &source) else {
+        eprintln!("{:?} does not exist at {} in git", source, rev);
+        std::process::exit(1);
+    };
+    let plaintext = plaintext_from_ciphertext_buffer(&encrypted, identities);
+    // A current version that can't be read, say clobbered or encrypted to the wrong keys, is
+    // what restoring is for, so it counts as different
+    let current = std::fs::read(&source)
+        .ok()
+        .and_then(|encrypted| try_decrypt(&encrypted, identities).ok());
+    if current.as_ref() == Some(&plaintext) {
+        eprintln!(
+            "The plaintext of {:?} is the same as at {}, not writing it",
+            ciphertext, rev
+        );
+        return;
+    }
+    let recipients = cache.recipients_for_target(ciphertext, args);
+    if recipients.is_empty() {
+        cache.report_no_recipients(ciphertext);
+        std::process::exit(1);
+    }
+    if ciphertext.exists() {
+        confirm::require(
+            confirm::Action::Overwrite,
+            &format!("Replace {:?} with its plaintext at {}?", ciphertext, rev),
+        );
+    }
+    let ciphertext_data = ciphertext_from_plaintext_buffer(&plaintext, recipients);
+    verify::round_trip(ciphertext, &ciphertext_data, &plaintext, identities);
+    write_output(
+        cache,
+        ciphertext,
+        &ciphertext_data,
+        ciphertext_mode(ciphertext),
+    )
+    .unwrap_or_else(|e| exit_unwritable(ciphertext, e));
+    eprintln!("Restored {:?} to its plaintext at {}", ciphertext, rev);
+}
@@ -0,0 +1,387 @@
+use crate::agent::AgentIdentity;
+use crate::header;
+use age::cli_common::read_identities;
+use age::Identity;
+use serde::Deserialize;
+use std::cell::OnceCell;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// Environment variable holding age identities (`AGE-SECRET-KEY-1...` lines) themselves, for
+/// CI jobs and containers where writing the key to a file is awkward
+pub const IDENTITY_ENV: &str = "ARCANUM_IDENTITY";
+
+/// Where an identity comes from
+#[derive(Clone)]
+enum Kind {
+    /// An identity file: age, ssh, or a plugin's, such as the `AGE-PLUGIN-YUBIKEY-1...` ones
+    /// `age-plugin-yubikey` writes for keys on a PIV token
+    File(String),
+    /// Age identities given as text. Never shown anywhere.
+    Literal(String),
+}
+
+/// One entry of the ordered identity chain, with the label it is shown as in diagnostics
+#[derive(Clone)]
+pub struct IdentitySource {
+    pub label: String,
+    kind: Kind,
+}
+
+impl IdentitySource {
+    /// An identity file, labelled with its path
+    pub fn file(path: String) -> Self {
+        IdentitySource {
+            label: path.clone(),
+            kind: Kind::File(path),
+        }
+    }
+
+    /// An identity file shown as `label`
+    pub fn labelled(label: String, path: String) -> Self {
+        IdentitySource {
+            label,
+            kind: Kind::File(path),
+        }
+    }
+
+    /// Read the identities, prompting for a passphrase or a PIN when the file needs one
+    pub fn read(&self) -> Result<Vec<Box<dyn Identity>>, String> {
+        match &self.kind {
+            Kind::File(path) => {
+                read_identities(vec![path.clone()], Some(30)).map_err(|e| e.to_string())
+            }
+            Kind::Literal(text) => parse_literal(text),
+        }
+    }
+
+    /// Public keys of the identities, without unlocking anything: from the `.pub` file next to
+    /// an ssh key, the `# public key:` comment `age-keygen` writes or the `Recipient:` one of
+    /// plugin identity files
+    pub fn public_keys(&self) -> Vec<String> {
+        match &self.kind {
+            Kind::File(path) => {
+                if let Ok(public) = std::fs::read_to_string(format!("{}.pub", path)) {
+                    return public.lines().take(1).map(str::to_string).collect();
+                }
+                let text = std::fs::read_to_string(path).unwrap_or_default();
+                text.lines()
+                    .filter_map(|line| line.strip_prefix('#'))
+                    .filter_map(|comment| {
+                        let comment = comment.trim();
+                        comment
+                            .strip_prefix("public key:")
+                            .or_else(|| comment.strip_prefix("Recipient:"))
+                    })
+                    .map(|key| key.trim().to_string())
+                    .collect()
+            }
+            Kind::Literal(text) => text
+                .lines()
+                .filter_map(|line| line.trim().parse::<age::x25519::Identity>().ok())
+                .map(|identity| identity.to_public().to_string())
+                .collect(),
+        }
+    }
+
+    /// The ssh key tag of an ssh identity file, when its `.pub` file is next to it
+    pub fn ssh_tag(&self) -> Option<String> {
+        match &self.kind {
+            Kind::File(path) => ssh_tag_of(path),
+            Kind::Literal(_) => None,
+        }
+    }
+}
+
+/// Parse age identities given as text, one per line with `#` comments. Errors only give the
+/// line number, as the lines are key material.
+fn parse_literal(text: &str) -> Result<Vec<Box<dyn Identity>>, String> {
+    let mut identities: Vec<Box<dyn Identity>> = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with("AGE-PLUGIN-") {
+            return Err(format!(
+                "line {}: plugin identities have to be given as a file",
+                i + 1
+            ));
+        }
+        let identity: age::x25519::Identity = line
+            .parse()
+            .map_err(|_| format!("line {}: not an age identity", i + 1))?;
+        identities.push(Box::new(identity));
+    }
+    if identities.is_empty() {
+        return Err("no identities".to_string());
+    }
+    Ok(identities)
+}
+
+/// The identity given in `ARCANUM_IDENTITY`, if any. The variable is removed from the
+/// environment so the editor, git and the other programs arcanum runs don't inherit it.
+pub fn from_env() -> Option<IdentitySource> {
+    let text = std::env::var(IDENTITY_ENV).ok()?;
+    std::env::remove_var(IDENTITY_ENV);
+    if text.trim().is_empty() {
+        return None;
+    }
+    Some(IdentitySource {
+        label: format!("${}", IDENTITY_ENV),
+        kind: Kind::Literal(text),
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChainEntry {
+    label: Option<String>,
+    file: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChainFile {
+    #[serde(default)]
+    identity: Vec<ChainEntry>,
+}
+
+/// Where `arcanum init-user` keeps the identity it generates
+pub fn user_identity_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("arcanum").join("identity.txt"))
+}
+
+/// Where the configured identity chain is kept
+pub fn chain_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("arcanum").join("identities.toml"))
+}
+
+/// The identities configured in `~/.config/arcanum/identities.toml`, in the order to try them:
+///
+/// ```toml
+/// [[identity]]
+/// label = "yubikey"
+/// file = "~/.config/age/yubikey-identity.txt"
+///
+/// [[identity]]
+/// label = "laptop"
+/// file = "~/.ssh/id_ed25519"
+/// ```
+///
+/// None when there is no such file, in which case the default ssh keys are used.
+pub fn configured_chain() -> Option<Vec<IdentitySource>> {
+    let path = chain_path()?;
+    let text = std::fs::read_to_string(&path).ok()?;
+    let chain: ChainFile = toml::from_str(&text).unwrap_or_else(|e| {
+        eprintln!("Unable to parse {:?}: {}", path, e);
+        std::process::exit(1);
+    });
+    let mut sources = vec![];
+    for entry in chain.identity {
+        let file = expand_home(&entry.file);
+        if !std::path::Path::new(&file).exists() {
+            eprintln!("warning: identity file {} does not exist", file);
+            continue;
+        }
+        sources.push(IdentitySource::labelled(
+            entry.label.unwrap_or_else(|| file.clone()),
+            file,
+        ));
+    }
+    Some(sources)
+}
+
+/// `path` with a leading `~/` replaced by the home directory, as config files write them
+pub fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).display().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+/// What reading the identity files produced
+struct Loaded {
+    identities: Vec<Box<dyn Identity>>,
+    /// Whether an agent was connected to
+    agent: bool,
+    /// Labels of the identities that were read, with where theirs are in `identities`
+    read: Vec<(String, Range<usize>)>,
+    /// Labels of the identities that couldn't be read, with why
+    failed: Vec<(String, String)>,
+}
+
+/// Identities for this invocation, read and unlocked the first time something is decrypted
+/// and reused for every file after that, so bulk operations don't re-read every file or prompt
+/// again for passphrase protected ones. A running `arcanum agent` is asked first, so nothing is
+/// unlocked locally when it has the key.
+pub struct IdentityStore {
+    sources: Vec<IdentitySource>,
+    /// Whether to use a running agent
+    agent: bool,
+    loaded: OnceCell<Loaded>,
+}
+
+impl IdentityStore {
+    pub fn new(sources: Vec<IdentitySource>) -> Self {
+        IdentityStore {
+            sources,
+            agent: true,
+            loaded: OnceCell::new(),
+        }
+    }
+
+    /// A store only using the identities themselves, never an agent
+    pub fn local(sources: Vec<IdentitySource>) -> Self {
+        IdentityStore {
+            agent: false,
+            ..IdentityStore::new(sources)
+        }
+    }
+
+    /// The identities this store reads, in the order they are tried
+    pub fn sources(&self) -> &[IdentitySource] {
+        &self.sources
+    }
+
+    /// A store with `extra` identity files in addition to these ones
+    pub fn with_files(&self, extra: impl IntoIterator<Item = String>) -> Self {
+        let mut sources = self.sources.clone();
+        sources.extend(extra.into_iter().map(IdentitySource::file));
+        IdentityStore {
+            agent: self.agent,
+            ..IdentityStore::new(sources)
+        }
+    }
+
+    fn loaded(&self) -> &Loaded {
+        self.loaded.get_or_init(|| {
+            let mut loaded = Loaded {
+                identities: vec![],
+                agent: false,
+                read: vec![],
+                failed: vec![],
+            };
+            if let Some(agent) = AgentIdentity::connect().filter(|_| self.agent) {
+                loaded.identities.push(Box::new(agent));
+                loaded.agent = true;
+            }
+            // Read one by one so a broken file doesn't keep the others from being used
+            for source in &self.sources {
+                match source.read() {
+                    Ok(identities) => {
+                        let start = loaded.identities.len();
+                        loaded.identities.extend(identities);
+                        let read = start..loaded.identities.len();
+                        loaded.read.push((source.label.clone(), read));
+                    }
+                    Err(e) => {
+                        eprintln!("warning: unable to read identity {}: {}", source.label, e);
+                        loaded.failed.push((source.label.clone(), e));
+                    }
+                }
+            }
+            loaded
+        })
+    }
+
+    /// Public keys of the identities this store reads, as far as they can be told without
+    /// unlocking them
+    pub fn public_keys(&self) -> Vec<String> {
+        self.sources
+            .iter()
+            .flat_map(IdentitySource::public_keys)
+            .collect()
+    }
+
+    /// The parsed identities, read from disk on first use
+    pub fn identities(&self) -> &[Box<dyn Identity>] {
+        &self.loaded().identities
+    }
+
+    /// The identity of the running agent, when one is used, tried before the others
+    pub fn agent(&self) -> Option<&[Box<dyn Identity>]> {
+        let loaded = self.loaded();
+        loaded.agent.then(|| &loaded.identities[..1])
+    }
+
+    /// The identities read from each of `sources`, in the same order, or why they couldn't be
+    pub fn by_source(&self) -> Vec<Result<&[Box<dyn Identity>], &str>> {
+        let loaded = self.loaded();
+        self.sources
+            .iter()
+            .map(|source| {
+                let read = loaded.read.iter().find(|(label, _)| *label == source.label);
+                let failed = loaded
+                    .failed
+                    .iter()
+                    .find(|(label, _)| *label == source.label);
+                match (read, failed) {
+                    (Some((_, range)), _) => Ok(&loaded.identities[range.clone()]),
+                    (None, Some((_, e))) => Err(e.as_str()),
+                    (None, None) => Err("not read"),
+                }
+            })
+            .collect()
+    }
+
+    /// Explain why none of the identities could decrypt `encrypted`: which identity files were
+    /// found and read, in the order tried, and how the ssh keys among them compare to the ones
+    /// the file is for
+    pub fn explain_no_match(&self, encrypted: &[u8]) {
+        let loaded = self.loaded();
+        if self.sources.is_empty() {
+            eprintln!(
+                "No identities were found: looked for ~/.config/arcanum/identity.txt, \
+                 ~/.ssh/id_ed25519, ~/.ssh/id_rsa, ${}, the chain in \
+                 ~/.config/arcanum/identities.toml and the files given with --identity. \
+                 Create one with `arcanum init-user`.",
+                IDENTITY_ENV
+            );
+        } else {
+            eprintln!("Identities:");
+        }
+        for source in &self.sources {
+            let label = &source.label;
+            match loaded.failed.iter().find(|(failed, _)| failed == label) {
+                Some((_, e)) => eprintln!("  {}: unable to read it ({})", label, e),
+                None => match source.ssh_tag() {
+                    Some(tag) => eprintln!("  {}: tried, ssh key tag {}", label, tag),
+                    None => eprintln!("  {}: tried", label),
+                },
+            }
+        }
+        if loaded.agent {
+            eprintln!("  arcanum agent: tried");
+        } else if self.agent {
+            eprintln!("  arcanum agent: not running");
+        }
+
+        let Ok(stanzas) = header::stanzas_from_bytes(encrypted) else {
+            return;
+        };
+        let ssh_tags: Vec<&str> = stanzas
+            .iter()
+            .filter(|s| s.tag.starts_with("ssh-"))
+            .filter_map(|s| s.args.first().map(String::as_str))
+            .collect();
+        let x25519 = stanzas.iter().filter(|s| s.tag == "X25519").count();
+        eprintln!(
+            "The file is encrypted to {} ssh key(s){} and {} age key(s).",
+            ssh_tags.len(),
+            if ssh_tags.is_empty() {
+                String::new()
+            } else {
+                format!(" with tags {}", ssh_tags.join(", "))
+            },
+            x25519
+        );
+    }
+}
+
+/// The tag ssh stanzas carry for the key in the ssh identity file `file`, looked up from its
+/// `.pub` file next to it
+fn ssh_tag_of(file: &str) -> Option<String> {
+    let public = std::fs::read_to_string(format!("{}.pub", file)).ok()?;
+    header::ssh_tag(public.lines().next()?)
+}
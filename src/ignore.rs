@@ -0,0 +1,67 @@
+//! Keeping decrypted plaintext out of git: `decrypt` only writes where git ignores the file,
+//! unless forced.
+
+use crate::confirm::{self, Action};
+use crate::git;
+use std::io::Write;
+use std::path::Path;
+
+/// Append `/<relative>` to the `.gitignore` at the top of the work tree
+fn add_to_gitignore(toplevel: &Path, relative: &Path) -> std::io::Result<()> {
+    let gitignore = toplevel.join(".gitignore");
+    let existing = std::fs::read_to_string(&gitignore).unwrap_or_default();
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&gitignore)?;
+    if !existing.is_empty() && !existing.ends_with('\n') {
+        writeln!(file)?;
+    }
+    let pattern: Vec<String> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    writeln!(file, "/{}", pattern.join("/"))
+}
+
+/// Exit before plaintext is written to `plaintext` where git could pick it up, offering to
+/// add it to `.gitignore` instead. Nothing is checked with `force` or outside a work tree.
+pub fn guard(plaintext: &Path, force: bool) {
+    if force || git::is_ignored(plaintext) != Some(false) {
+        return;
+    }
+    let Ok(absolute) = std::path::absolute(plaintext) else {
+        return;
+    };
+    let parent = absolute.ancestors().skip(1).find(|dir| dir.is_dir());
+    let Some(toplevel) = parent.and_then(git::toplevel) else {
+        return;
+    };
+    let Ok(relative) = absolute.strip_prefix(&toplevel) else {
+        return;
+    };
+    let tracked =
+        git::tracked(&toplevel, &relative.to_string_lossy()).is_some_and(|files| !files.is_empty());
+    if tracked {
+        eprintln!(
+            "{:?} is tracked by git, writing plaintext there would commit it",
+            plaintext
+        );
+        eprintln!("Pass --force to write it anyway.");
+        std::process::exit(1);
+    }
+    eprintln!(
+        "{:?} is not ignored by git, its plaintext could be committed",
+        plaintext
+    );
+    if confirm::confirm(Action::Ignore, "Add it to .gitignore?") {
+        if let Err(e) = add_to_gitignore(&toplevel, relative) {
+            eprintln!("Unable to update .gitignore: {}", e);
+            std::process::exit(1);
+        }
+        eprintln!("Added /{} to .gitignore", relative.display());
+        return;
+    }
+    eprintln!("Pass --force to write it anyway.");
+    std::process::exit(1);
+}
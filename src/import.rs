@@ -0,0 +1,61 @@
+//! `arcanum import`: pull a value out of the team's password manager and encrypt it into a
+//! managed file, through the password manager's own CLI, so bootstrap credentials never pass
+//! through the clipboard, a terminal or shell history.
+//!
+//! - `op://<vault>/<item>/<field>` reads with 1Password's `op read`.
+//! - `bw://<item>/<field>` reads with Bitwarden's `bw`. The field is `password`, `username`,
+//!   `notes`, `totp` or the name of a custom field. Unlock first and export `BW_SESSION`.
+
+use std::process::{Command, Stdio};
+
+/// Run a password manager CLI, returning its stdout. Its stderr, where it asks for unlocking,
+/// stays on the terminal.
+fn run(program: &str, args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("unable to run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!("{} failed", program));
+    }
+    Ok(output.stdout)
+}
+
+/// A custom field of the Bitwarden item JSON `item`
+fn bitwarden_field(item: &[u8], field: &str) -> Result<Vec<u8>, String> {
+    let item: serde_json::Value = serde_json::from_slice(item).map_err(|e| e.to_string())?;
+    item["fields"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|f| f["name"] == field)
+        .and_then(|f| f["value"].as_str())
+        .map(|value| value.as_bytes().to_vec())
+        .ok_or_else(|| format!("the item has no field {:?}", field))
+}
+
+/// The value `reference` points at in a password manager
+pub fn read(reference: &str) -> Result<Vec<u8>, String> {
+    if reference.starts_with("op://") {
+        return run("op", &["read", "--no-newline", reference]);
+    }
+    if let Some(path) = reference.strip_prefix("bw://") {
+        let (item, field) = path
+            .rsplit_once('/')
+            .filter(|(item, field)| !item.is_empty() && !field.is_empty())
+            .ok_or("expected bw://<item>/<field>")?;
+        return match field {
+            "password" | "username" | "notes" | "totp" => {
+                let mut value = run("bw", &["get", field, item])?;
+                if value.ends_with(b"\n") {
+                    value.pop();
+                }
+                Ok(value)
+            }
+            _ => bitwarden_field(&run("bw", &["get", "item", item])?, field),
+        };
+    }
+    Err("expected an op:// or bw:// reference".to_string())
+}
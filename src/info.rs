@@ -0,0 +1,129 @@
+//! `arcanum info`: what a ciphertext is, who last wrote it, whom its header is encrypted to and
+//! which of your identities can decrypt it.
+
+use crate::identity::IdentityStore;
+use crate::{header, metadata, try_decrypt_with, CacheFile};
+use age::{DecryptError, Identity};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Whether `identities`, read from `label`, decrypt `encrypted`, and if not why
+fn check(
+    label: &str,
+    identities: Result<&[Box<dyn Identity>], &str>,
+    ssh_tag: Option<String>,
+    stanza_tags: &BTreeSet<&str>,
+    encrypted: &[u8],
+) -> bool {
+    let identities = match identities {
+        Ok(identities) => identities,
+        Err(e) => {
+            println!("  {:<30} CANNOT decrypt: unable to read it ({})", label, e);
+            return false;
+        }
+    };
+    match try_decrypt_with(encrypted, identities) {
+        Ok(_) => {
+            println!("  {:<30} CAN decrypt", label);
+            true
+        }
+        Err(DecryptError::NoMatchingKeys) => {
+            let reason = match ssh_tag {
+                Some(tag) if !stanza_tags.contains(tag.as_str()) => {
+                    format!("the header has no stanza for this ssh key (tag {})", tag)
+                }
+                _ => "none of its keys is a recipient".to_string(),
+            };
+            println!("  {:<30} CANNOT decrypt: {}", label, reason);
+            false
+        }
+        Err(e) => {
+            println!("  {:<30} CANNOT decrypt: {}", label, e);
+            false
+        }
+    }
+}
+
+pub fn info(cache: &CacheFile, ciphertext: &Path, identities: &IdentityStore) {
+    let source = cache.resolve_source(ciphertext);
+    let stanzas = match header::read_stanzas(&source) {
+        Ok(stanzas) => stanzas,
+        Err(e) => {
+            eprintln!("Unable to read the header of {:?}: {}", source, e);
+            std::process::exit(1);
+        }
+    };
+
+    println!("file:        {}", source.display());
+    match metadata::read(&source) {
+        Some(Ok(metadata)) => {
+            if let Some(description) = &metadata.description {
+                println!("description: {}", description);
+            }
+            println!("created:     {}", metadata.created_at.to_rfc3339());
+            println!(
+                "updated:     {} by {}",
+                metadata.updated_at.to_rfc3339(),
+                metadata.updated_by.as_deref().unwrap_or("unknown")
+            );
+            if let Some(rotated_at) = metadata.rotated_at {
+                println!("rotated:     {}", rotated_at.to_rfc3339());
+            }
+            println!("written by:  arcanum {}", metadata.arcanum);
+        }
+        Some(Err(e)) => println!("metadata:    unreadable ({})", e),
+        None => println!("metadata:    none"),
+    }
+
+    let configured = cache.recipient_strings_for_file(ciphertext);
+    println!("stanzas:");
+    for stanza in stanzas.iter().filter(|s| !s.tag.ends_with("-grease")) {
+        if stanza.tag.starts_with("ssh-") {
+            let tag = stanza.args.first().map(String::as_str).unwrap_or_default();
+            let recipient = configured
+                .iter()
+                .find(|r| header::ssh_tag(r).as_deref() == Some(tag));
+            match recipient {
+                Some(recipient) => println!("  {:<12} {}  {}", stanza.tag, tag, recipient),
+                None => println!("  {:<12} {}  (not a configured recipient)", stanza.tag, tag),
+            }
+        } else if stanza.tag == "X25519" {
+            println!(
+                "  {:<12} (recipient isn't recorded in the header)",
+                stanza.tag
+            );
+        } else {
+            println!("  {:<12} {}", stanza.tag, stanza.args.join(" "));
+        }
+    }
+
+    let encrypted = std::fs::read(&source).unwrap();
+    let stanza_tags: BTreeSet<&str> = stanzas
+        .iter()
+        .filter(|s| s.tag.starts_with("ssh-"))
+        .filter_map(|s| s.args.first().map(String::as_str))
+        .collect();
+    println!("identities:");
+    let mut readable = false;
+    // Read once, so passphrase protected identities are only asked for once
+    if let Some(agent) = identities.agent() {
+        readable |= check("agent", Ok(agent), None, &stanza_tags, &encrypted);
+    }
+    for (source, read) in identities.sources().iter().zip(identities.by_source()) {
+        readable |= check(
+            &source.label,
+            read,
+            source.ssh_tag(),
+            &stanza_tags,
+            &encrypted,
+        );
+    }
+    if identities.sources().is_empty() && !readable {
+        println!("  no identities found, pass one with --identity");
+    }
+    if readable {
+        println!("You CAN decrypt this file.");
+    } else {
+        println!("You CANNOT decrypt this file.");
+    }
+}
@@ -0,0 +1,375 @@
+use crate::fsutil::{create_dir_all_with_mode, parse_mode, set_mode, write_atomic};
+#[cfg(unix)]
+use crate::fsutil::{gid_for, uid_for};
+use crate::identity::IdentityStore;
+use crate::{confirm, transform, try_decrypt, ArcanumConfig, ArcanumFile, CacheFile, Scope};
+use digest::Digest;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha3::Sha3_256;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Identities a host uses to decrypt its own secrets, in addition to the user's
+const HOST_IDENTITIES: [&str; 2] = ["/etc/ssh/ssh_host_ed25519_key", "/etc/ssh/ssh_host_rsa_key"];
+
+/// What was installed last time, used to tell which secrets changed between runs. The state
+/// file is shared by every project and host installed on the machine, so each keeps its own.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InstallState {
+    /// Content hashes keyed by destination path, keyed by `scope_key`
+    #[serde(default)]
+    scopes: BTreeMap<String, BTreeMap<PathBuf, String>>,
+}
+
+/// The part of the install state belonging to `host` of the project at `project_root`
+fn scope_key(project_root: &Path, host: &str) -> String {
+    format!("{}#{}", project_root.display(), host)
+}
+
+impl InstallState {
+    fn load(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                eprintln!("Ignoring unreadable install state at {:?}: {}", path, e);
+                InstallState::default()
+            }),
+            Err(_) => InstallState::default(),
+        }
+    }
+
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            create_dir_all_with_mode(parent, 0o700)?;
+        }
+        let data = serde_json::to_vec_pretty(self).unwrap();
+        write_atomic(path, &data, 0o600, None)
+    }
+}
+
+/// Where install state is kept when `--state-file` isn't given
+pub fn default_state_file() -> PathBuf {
+    if crate::running_as_root() {
+        return PathBuf::from("/var/lib/arcanum/install-state.json");
+    }
+    dirs::state_dir()
+        .or_else(dirs::cache_dir)
+        .unwrap()
+        .join("arcanum")
+        .join("install-state.json")
+}
+
+const HASH_KEY_BYTES: usize = 32;
+
+/// The key hashes in the state file at `state_file` are made with, kept beside it and created
+/// on first use, so the state file alone can't be used to test guesses of a secret
+fn hash_key(state_file: &Path) -> std::io::Result<Vec<u8>> {
+    let path = state_file.with_extension("key");
+    match std::fs::read(&path) {
+        Ok(key) if key.len() == HASH_KEY_BYTES => return Ok(key),
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all_with_mode(parent, 0o700)?;
+    }
+    let mut key = vec![0; HASH_KEY_BYTES];
+    rand::thread_rng().fill_bytes(&mut key);
+    write_atomic(&path, &key, 0o600, None)?;
+    Ok(key)
+}
+
+/// Keyed hash identifying installed content, mixed with the destination so equal secrets
+/// installed to different places don't share a hash
+fn content_hash(key: &[u8], dest: &Path, plaintext: &[u8]) -> String {
+    let mut hasher = Sha3_256::new();
+    hasher.update(key);
+    hasher.update(dest.to_string_lossy().as_bytes());
+    hasher.update([0]);
+    hasher.update(plaintext);
+    format!("{:x}", hasher.finalize())
+}
+
+pub struct InstallOptions {
+    pub state_file: PathBuf,
+    /// Run `systemctl try-restart` for units of changed secrets
+    pub restart: bool,
+    /// Remove destinations installed before that are no longer declared
+    pub prune: bool,
+    /// Destinations never pruned, whatever the config says
+    pub keep: Vec<glob::Pattern>,
+}
+
+#[derive(Default)]
+pub struct InstallReport {
+    pub ok: bool,
+    pub changed: usize,
+    pub units: BTreeSet<String>,
+}
+
+/// Decrypt every file configured for `host` and write the ones that changed since the last run
+/// to their destinations.
+pub fn install(
+    cache: &CacheFile,
+    project_root: &Path,
+    host: &str,
+    identities: &IdentityStore,
+    options: &InstallOptions,
+) -> InstallReport {
+    let mut report = InstallReport::default();
+    #[cfg(not(unix))]
+    eprintln!("warning: permissions, owners and groups aren't applied on this platform");
+    let Some(config) = cache.sections.get(&Scope::Nixos(host.to_string())) else {
+        eprintln!("No nixos configuration named {:?} in the cache", host);
+        let hosts: Vec<&Scope> = cache
+            .sections
+            .keys()
+            .filter(|scope| matches!(scope, Scope::Nixos(_)))
+            .collect();
+        if !hosts.is_empty() {
+            eprintln!("Available hosts:");
+            for host in hosts {
+                eprintln!(" - {}", host.name_parts().join("."));
+            }
+        }
+        return report;
+    };
+
+    let identities = identities.with_files(
+        HOST_IDENTITIES
+            .iter()
+            .filter(|identity| Path::new(identity).exists())
+            .map(|identity| identity.to_string()),
+    );
+
+    let key = match hash_key(&options.state_file) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!(
+                "Unable to read or create the install state key beside {:?}: {}",
+                options.state_file, e
+            );
+            return report;
+        }
+    };
+    let mut state = InstallState::load(&options.state_file);
+    let scope = scope_key(project_root, host);
+    let mut installed = state.scopes.remove(&scope).unwrap_or_default();
+    let mut names: Vec<&String> = config.files.keys().collect();
+    names.sort();
+    report.ok = true;
+    for name in names {
+        let file = &config.files[name];
+        let previous = installed.get(&file.dest).map(|h| h.as_str());
+        match install_file(cache, file, &identities, &key, previous) {
+            Ok(None) => eprintln!("Unchanged {} at {:?}", name, file.dest),
+            Ok(Some(hash)) => {
+                eprintln!("Installed {} to {:?}", name, file.dest);
+                installed.insert(file.dest.clone(), hash);
+                report.changed += 1;
+                report.units.extend(file.restart_units.iter().cloned());
+            }
+            Err(e) => {
+                eprintln!("Failed to install {} to {:?}: {}", name, file.dest, e);
+                report.ok = false;
+            }
+        }
+    }
+
+    prune(config, &state, &mut installed, &key, options, &mut report);
+    state.scopes.insert(scope, installed);
+
+    if let Err(e) = state.save(&options.state_file) {
+        eprintln!(
+            "Unable to save install state to {:?}: {}",
+            options.state_file, e
+        );
+        report.ok = false;
+    }
+
+    for unit in &report.units {
+        println!("{}", unit);
+    }
+    if options.restart && !report.units.is_empty() {
+        let status = Command::new("systemctl")
+            .arg("try-restart")
+            .args(&report.units)
+            .status();
+        if !matches!(status, Ok(status) if status.success()) {
+            eprintln!("Failed to restart units: {:?}", report.units);
+            report.ok = false;
+        }
+    }
+    report
+}
+
+/// Handle destinations `installed` for this project and host that `config` no longer declares.
+/// With `--prune` they're removed, unless kept, changed since they were installed or also
+/// installed for another project or host in `state`, otherwise only listed.
+fn prune(
+    config: &ArcanumConfig,
+    state: &InstallState,
+    installed: &mut BTreeMap<PathBuf, String>,
+    key: &[u8],
+    options: &InstallOptions,
+    report: &mut InstallReport,
+) {
+    let declared: BTreeSet<&PathBuf> = config.files.values().map(|file| &file.dest).collect();
+    let stale: Vec<(PathBuf, String)> = installed
+        .iter()
+        .filter(|(dest, _)| !declared.contains(dest))
+        .map(|(dest, hash)| (dest.clone(), hash.clone()))
+        .collect();
+    let mut removable = vec![];
+    for (dest, hash) in stale {
+        if options
+            .keep
+            .iter()
+            .any(|pattern| pattern.matches_path(&dest))
+        {
+            eprintln!(
+                "Keeping {:?}, no longer declared but on the keep-list",
+                dest
+            );
+            continue;
+        }
+        if !options.prune {
+            eprintln!("No longer declared: {:?}, remove it with --prune", dest);
+            continue;
+        }
+        if state.scopes.values().any(|other| other.contains_key(&dest)) {
+            eprintln!(
+                "Not pruning {:?}, another project or host installed it too",
+                dest
+            );
+            installed.remove(&dest);
+            continue;
+        }
+        let current = match std::fs::read(&dest) {
+            Ok(current) => current,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                installed.remove(&dest);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Unable to read {:?} to prune it: {}", dest, e);
+                report.ok = false;
+                continue;
+            }
+        };
+        // Only what arcanum wrote is removed, never something put there since
+        if content_hash(key, &dest, &current) != hash {
+            eprintln!(
+                "Not pruning {:?}, it changed since it was installed. Remove it by hand.",
+                dest
+            );
+            continue;
+        }
+        removable.push(dest);
+    }
+    if removable.is_empty() {
+        return;
+    }
+
+    eprintln!("No longer declared:");
+    for dest in &removable {
+        eprintln!(" - {}", dest.display());
+    }
+    let question = format!("Remove these {} secrets?", removable.len());
+    if !confirm::confirm(confirm::Action::Delete, &question) {
+        report.ok = false;
+        return;
+    }
+    for dest in removable {
+        match std::fs::remove_file(&dest) {
+            Ok(()) => {
+                eprintln!("Pruned {:?}", dest);
+                installed.remove(&dest);
+                report.changed += 1;
+            }
+            Err(e) => {
+                eprintln!("Unable to prune {:?}: {}", dest, e);
+                report.ok = false;
+            }
+        }
+    }
+}
+
+/// The uid and gid `file` is installed with, its configured owner and group or the effective
+/// ones for those left empty
+#[cfg(unix)]
+pub fn file_owner(file: &ArcanumFile) -> Result<Option<(u32, u32)>, String> {
+    let uid = if file.owner.is_empty() {
+        unsafe { libc::geteuid() }
+    } else {
+        uid_for(&file.owner).map_err(|e| e.to_string())?
+    };
+    let gid = if file.group.is_empty() {
+        unsafe { libc::getegid() }
+    } else {
+        gid_for(&file.group).map_err(|e| e.to_string())?
+    };
+    Ok(Some((uid, gid)))
+}
+
+/// Windows has no uid/gid to map owners onto, so installed files keep the default ACL
+#[cfg(not(unix))]
+pub fn file_owner(_file: &ArcanumFile) -> Result<Option<(u32, u32)>, String> {
+    Ok(None)
+}
+
+fn install_file(
+    cache: &CacheFile,
+    file: &ArcanumFile,
+    identities: &IdentityStore,
+    key: &[u8],
+    previous_hash: Option<&str>,
+) -> Result<Option<String>, String> {
+    let mode = parse_mode(&file.permissions)?;
+    let owner = if file.owner.is_empty() && file.group.is_empty() {
+        None
+    } else {
+        file_owner(file)?
+    };
+
+    let source = cache.resolve_source(&file.source);
+    let encrypted =
+        std::fs::read(&source).map_err(|e| format!("unable to read {:?}: {}", source, e))?;
+    // Reported with the file rather than exiting, so the host's other secrets still install
+    let plaintext = try_decrypt(&encrypted, identities).map_err(|e| match e {
+        age::DecryptError::NoMatchingKeys => {
+            "none of the host's identities can decrypt it".to_string()
+        }
+        e => format!("unable to decrypt it: {}", e),
+    })?;
+    if plaintext.is_empty() {
+        return Err("plaintext is empty".to_string());
+    }
+    let plaintext = transform::apply_all(&file.transform, plaintext)?;
+    let hash = content_hash(key, &file.dest, &plaintext);
+    if file.dest.exists() && previous_hash == Some(hash.as_str()) {
+        // The content is as installed, but its mode or owner may have been changed since
+        set_mode(&file.dest, mode).map_err(|e| format!("unable to set its mode: {}", e))?;
+        #[cfg(unix)]
+        if let Some((uid, gid)) = owner {
+            std::os::unix::fs::chown(&file.dest, Some(uid), Some(gid))
+                .map_err(|e| format!("unable to set its owner: {}", e))?;
+        }
+        return Ok(None);
+    }
+
+    if let Some(parent) = file.dest.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if !parent.exists() {
+            if !file.make_directory {
+                return Err(format!("directory {:?} does not exist", parent));
+            }
+            let directory_mode = parse_mode(&file.directory_permissions)?;
+            create_dir_all_with_mode(parent, directory_mode).map_err(|e| e.to_string())?;
+        }
+    }
+
+    write_atomic(&file.dest, &plaintext, mode, owner).map_err(|e| e.to_string())?;
+    Ok(Some(hash))
+}
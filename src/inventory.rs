@@ -0,0 +1,129 @@
+use crate::{canonical_recipient, CacheFile};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HostEntry {
+    section: String,
+    kind: &'static str,
+    name: Vec<String>,
+    admin_recipients: Vec<String>,
+    secrets: usize,
+}
+
+/// List every configuration section in the cache with its admins and number of secrets
+pub fn hosts(cache: &CacheFile, json: bool) {
+    let entries: Vec<HostEntry> = cache
+        .configs()
+        .into_iter()
+        .map(|(scope, config)| {
+            let mut admin_recipients = config.admin_recipients.clone();
+            admin_recipients.sort();
+            HostEntry {
+                section: scope.to_string(),
+                kind: scope.kind(),
+                name: scope.name_parts(),
+                admin_recipients,
+                secrets: config.files.len(),
+            }
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+        return;
+    }
+
+    for entry in entries {
+        println!("{} ({} secrets)", entry.section, entry.secrets);
+        for recipient in entry.admin_recipients {
+            println!("  admin: {}", recipient);
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RecipientEntry {
+    recipient: String,
+    owner: Option<String>,
+    files: BTreeSet<PathBuf>,
+    /// Sections where the recipient is an admin
+    admin_of: BTreeSet<String>,
+}
+
+/// The entry of `recipient`, shared by every way the config writes the same key
+fn entry<'a>(
+    entries: &'a mut BTreeMap<String, RecipientEntry>,
+    recipient: &str,
+) -> &'a mut RecipientEntry {
+    let recipient = canonical_recipient(recipient);
+    entries
+        .entry(recipient.clone())
+        .or_insert_with(|| RecipientEntry {
+            recipient,
+            owner: None,
+            files: BTreeSet::new(),
+            admin_of: BTreeSet::new(),
+        })
+}
+
+/// List every recipient in the config with the files it can decrypt, optionally limited to
+/// recipients whose key or owner contains `filter`
+pub fn recipients(cache: &CacheFile, filter: Option<&str>, json: bool) {
+    let mut entries: BTreeMap<String, RecipientEntry> = BTreeMap::new();
+    for (scope, config) in cache.configs() {
+        for recipient in &config.admin_recipients {
+            entry(&mut entries, recipient)
+                .admin_of
+                .insert(scope.to_string());
+        }
+        for (recipient, metadata) in &config.recipient_metadata {
+            let entry = entry(&mut entries, recipient);
+            if entry.owner.is_none() {
+                entry.owner = metadata.owner.clone();
+            }
+        }
+        for file in config.files.values() {
+            for recipient in file.readers(config) {
+                entry(&mut entries, recipient)
+                    .files
+                    .insert(file.source.clone());
+            }
+        }
+    }
+
+    let entries: Vec<RecipientEntry> = entries
+        .into_values()
+        .filter(|entry| match filter {
+            Some(filter) => {
+                entry.recipient.contains(filter)
+                    || entry.owner.as_deref().is_some_and(|o| o.contains(filter))
+            }
+            None => true,
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+        return;
+    }
+
+    for entry in entries {
+        match &entry.owner {
+            Some(owner) => println!("{} ({})", entry.recipient, owner),
+            None => println!("{}", entry.recipient),
+        }
+        for section in &entry.admin_of {
+            println!("  admin of {}", section);
+        }
+        if entry.files.is_empty() {
+            println!("  warning: not a recipient of any file");
+        }
+        for file in &entry.files {
+            println!("  - {}", file.display());
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! `arcanum export-k8s`: a Kubernetes `Secret` manifest holding decrypted secrets, for teams
+//! that keep the same credentials for NixOS hosts and clusters. Each file becomes one key of
+//! the Secret, named by its `k8sKey` in the config or else by its file name without `.age`.
+
+use crate::identity::IdentityStore;
+use crate::{decrypt, CacheFile};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+#[derive(Serialize)]
+struct Metadata<'a> {
+    name: &'a str,
+    namespace: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Secret<'a> {
+    api_version: &'static str,
+    kind: &'static str,
+    metadata: Metadata<'a>,
+    #[serde(rename = "type")]
+    secret_type: &'static str,
+    /// Keys to base64 encoded values
+    data: BTreeMap<String, String>,
+}
+
+/// Whether Kubernetes accepts `key` as a key of a Secret's data
+fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 253
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// The key of `ciphertext` in the Secret
+fn key_for(cache: &CacheFile, ciphertext: &Path) -> String {
+    let relative = cache.project_relative(ciphertext);
+    let configured = cache
+        .entries()
+        .into_iter()
+        .find(|(_, _, file)| file.matches(&relative))
+        .and_then(|(_, _, file)| file.k8s_key.clone());
+    configured.unwrap_or_else(|| {
+        let name = relative.file_name().unwrap_or_default().to_string_lossy();
+        name.strip_suffix(".age").unwrap_or(&name).to_string()
+    })
+}
+
+/// Export `ciphertexts` as the Secret `name` in `namespace`, printing the manifest or with
+/// `apply` passing it to `kubectl apply`. Returns false when kubectl failed.
+pub fn export(
+    cache: &CacheFile,
+    ciphertexts: &[PathBuf],
+    name: &str,
+    namespace: &str,
+    apply: bool,
+    identities: &IdentityStore,
+) -> bool {
+    let mut data = BTreeMap::new();
+    let mut keys: BTreeMap<String, &PathBuf> = BTreeMap::new();
+    for ciphertext in ciphertexts {
+        let key = key_for(cache, ciphertext);
+        if !valid_key(&key) {
+            eprintln!(
+                "{:?} isn't a valid Secret key for {:?}, set k8sKey for it in the config",
+                key, ciphertext
+            );
+            std::process::exit(1);
+        }
+        if let Some(other) = keys.insert(key.clone(), ciphertext) {
+            eprintln!(
+                "{:?} and {:?} would both be the key {:?}, set k8sKey for one of them",
+                other, ciphertext, key
+            );
+            std::process::exit(1);
+        }
+        let plaintext = decrypt(cache, ciphertext, false, identities);
+        data.insert(key, STANDARD.encode(plaintext));
+    }
+    let secret = Secret {
+        api_version: "v1",
+        kind: "Secret",
+        metadata: Metadata { name, namespace },
+        secret_type: "Opaque",
+        data,
+    };
+    let manifest = serde_yaml::to_string(&secret).unwrap();
+    if !apply {
+        print!("{}", manifest);
+        return true;
+    }
+    // The manifest only goes through kubectl's stdin, never its arguments or a file
+    let child = Command::new("kubectl")
+        .args(["apply", "--namespace", namespace, "-f", "-"])
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut child = child.unwrap_or_else(|e| {
+        eprintln!("Unable to run kubectl: {}", e);
+        std::process::exit(1);
+    });
+    let written = child.stdin.take().unwrap().write_all(manifest.as_bytes());
+    let status = child.wait().unwrap();
+    if written.is_err() || !status.success() {
+        eprintln!("kubectl apply failed for Secret {} in {}", name, namespace);
+        return false;
+    }
+    true
+}
@@ -0,0 +1,168 @@
+use crate::fsutil::lock_exclusive;
+use crate::identity::IdentityStore;
+use crate::{
+    ciphertext_mode, confirm, exit_unwritable, plaintext_from_ciphertext_source, write_encrypted,
+    CacheFile,
+};
+use clap::Subcommand;
+use digest::Digest;
+use serde_json::{Map, Value};
+use sha3::Sha3_256;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Subcommand)]
+pub enum KvCommand {
+    /// Print the value of a key
+    Get { key: String },
+
+    /// Set a key, reading the value from stdin when not given
+    Set {
+        key: String,
+        value: Option<String>,
+
+        /// Parse the value as JSON instead of storing it as a string
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Remove a key
+    Del { key: String },
+
+    /// List all keys
+    List,
+}
+
+/// A decrypted store along with a fingerprint of the ciphertext it was read from
+struct Store {
+    entries: Map<String, Value>,
+    fingerprint: Option<String>,
+}
+
+fn fingerprint(path: &Path) -> Option<String> {
+    let data = std::fs::read(path).ok()?;
+    Some(format!("{:x}", Sha3_256::digest(&data)))
+}
+
+fn load(store: &Path, identities: &IdentityStore) -> Store {
+    let fingerprint = fingerprint(store);
+    if fingerprint.is_none() {
+        return Store {
+            entries: Map::new(),
+            fingerprint,
+        };
+    }
+    let plaintext = plaintext_from_ciphertext_source(store, identities);
+    let entries = if plaintext.is_empty() {
+        Map::new()
+    } else {
+        match serde_json::from_slice(&plaintext) {
+            Ok(Value::Object(entries)) => entries,
+            _ => {
+                eprintln!(
+                    "{:?} does not contain a JSON object, refusing to use it as a store",
+                    store
+                );
+                std::process::exit(1);
+            }
+        }
+    };
+    Store {
+        entries,
+        fingerprint,
+    }
+}
+
+/// Lock `store` against other kv updates from before it is read until it is written back,
+/// so concurrent ones don't lose each other's changes
+fn lock(store: &Path) -> File {
+    let name = store.file_name().unwrap_or_default().to_string_lossy();
+    let path = store.with_file_name(format!("{}.lock", name));
+    lock_exclusive(&path, 0o600).unwrap_or_else(|e| {
+        eprintln!("Unable to lock {:?}: {}", path, e);
+        std::process::exit(1);
+    })
+}
+
+/// Encrypt and write `store` back, unless the ciphertext changed since it was loaded, such as
+/// by an edit, which doesn't take the lock
+fn save(cache: &CacheFile, path: &Path, store: &Store, identities: &IdentityStore) {
+    let recipients = cache.recipients_for_file(path);
+    if recipients.is_empty() {
+        cache.report_no_recipients(path);
+        std::process::exit(1);
+    }
+    if fingerprint(path) != store.fingerprint {
+        eprintln!(
+            "{:?} was modified by someone else while updating it, retry the command",
+            path
+        );
+        std::process::exit(1);
+    }
+    let plaintext = serde_json::to_vec_pretty(&store.entries).unwrap();
+    write_encrypted(
+        cache,
+        path,
+        &plaintext,
+        recipients,
+        ciphertext_mode(path),
+        identities,
+    )
+    .unwrap_or_else(|e| exit_unwritable(path, e));
+}
+
+pub fn kv(cache: &CacheFile, path: &Path, command: &KvCommand, identities: &IdentityStore) {
+    let _lock =
+        matches!(command, KvCommand::Set { .. } | KvCommand::Del { .. }).then(|| lock(path));
+    let mut store = load(path, identities);
+    match command {
+        KvCommand::Get { key } => match store.entries.get(key) {
+            Some(Value::String(value)) => println!("{}", value),
+            Some(value) => println!("{}", value),
+            None => {
+                eprintln!("No key {:?} in {:?}", key, path);
+                std::process::exit(1);
+            }
+        },
+        KvCommand::Set { key, value, json } => {
+            let value = match value {
+                Some(value) => value.clone(),
+                None => {
+                    let mut buffer = String::new();
+                    std::io::stdin().read_to_string(&mut buffer).unwrap();
+                    buffer
+                }
+            };
+            let value = if *json {
+                serde_json::from_str(&value).unwrap_or_else(|e| {
+                    eprintln!("Value is not valid JSON: {}", e);
+                    std::process::exit(1);
+                })
+            } else {
+                Value::String(value)
+            };
+            store.entries.insert(key.clone(), value);
+            save(cache, path, &store, identities);
+            eprintln!("Set {:?} in {:?}", key, path);
+        }
+        KvCommand::Del { key } => {
+            if !store.entries.contains_key(key) {
+                eprintln!("No key {:?} in {:?}", key, path);
+                std::process::exit(1);
+            }
+            confirm::require(
+                confirm::Action::Delete,
+                &format!("Remove {:?} from {:?}?", key, path),
+            );
+            store.entries.remove(key);
+            save(cache, path, &store, identities);
+            eprintln!("Removed {:?} from {:?}", key, path);
+        }
+        KvCommand::List => {
+            for key in store.entries.keys() {
+                println!("{}", key);
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! `arcanum scan-leaks`: look for the values of managed secrets in plaintext, in the work tree,
+//! the git history and any other paths given. The values are only kept as digests salted
+//! with a key made up for the run, so the scan itself holds no plaintext beyond decrypting.
+
+use crate::identity::IdentityStore;
+use crate::{analyze, git, transform, try_decrypt, CacheFile};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Values shorter than this are too likely to appear by chance to report
+const MIN_LENGTH: usize = 8;
+
+/// Where a secret value comes from
+struct Origin {
+    source: PathBuf,
+    field: String,
+}
+
+struct Digests {
+    salt: [u8; 32],
+    values: HashMap<[u8; 32], Vec<Origin>>,
+}
+
+impl Digests {
+    fn digest(&self, value: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt);
+        hasher.update(value.as_bytes());
+        hasher.finalize().into()
+    }
+
+    fn insert(&mut self, value: &str, origin: Origin) {
+        let digest = self.digest(value);
+        self.values.entry(digest).or_default().push(origin);
+    }
+
+    /// Origins of the secret values appearing in `line`, as a whole, as the value of an
+    /// assignment or as a word
+    fn find(&self, line: &str) -> BTreeSet<(PathBuf, String)> {
+        let trimmed = line.trim();
+        let mut candidates = vec![trimmed];
+        if let Some((_, value)) = trimmed.split_once(['=', ':']) {
+            candidates.push(value.trim().trim_matches(|c| c == '"' || c == '\''));
+        }
+        candidates.extend(
+            trimmed
+                .split(|c: char| c.is_whitespace() || "\"'`=:,;()[]{}<>".contains(c))
+                .filter(|word| word.len() >= MIN_LENGTH),
+        );
+        candidates
+            .into_iter()
+            .filter(|candidate| candidate.len() >= MIN_LENGTH)
+            .filter_map(|candidate| self.values.get(&self.digest(candidate)))
+            .flatten()
+            .map(|origin| (origin.source.clone(), origin.field.clone()))
+            .collect()
+    }
+}
+
+/// The values worth looking for in a plaintext: the password-like fields `analyze` finds, or
+/// every long enough line when it finds none, such as for a private key
+fn values(plaintext: &str) -> Vec<(String, String)> {
+    let fields = analyze::fields(plaintext);
+    if !fields.is_empty() {
+        return fields;
+    }
+    plaintext
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (format!("line {}", i + 1), line.trim().to_string()))
+        .filter(|(_, line)| !line.starts_with("-----"))
+        .collect()
+}
+
+fn digests(cache: &CacheFile, identities: &IdentityStore) -> Digests {
+    let mut salt = [0; 32];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut digests = Digests {
+        salt,
+        values: HashMap::new(),
+    };
+    for source in cache.sources() {
+        let Ok(encrypted) = std::fs::read(cache.resolve_source(&source)) else {
+            continue;
+        };
+        let Ok(plaintext) = try_decrypt(&encrypted, identities) else {
+            continue;
+        };
+        let plaintext = transform::apply_all(&cache.transforms_for_file(&source), plaintext);
+        let Ok(Ok(plaintext)) = plaintext.map(String::from_utf8) else {
+            continue;
+        };
+        for (field, value) in values(&plaintext) {
+            if value.len() >= MIN_LENGTH {
+                let origin = Origin {
+                    source: source.clone(),
+                    field,
+                };
+                digests.insert(&value, origin);
+            }
+        }
+    }
+    digests
+}
+
+fn report(found: BTreeSet<(PathBuf, String)>, location: &str) -> bool {
+    for (source, field) in &found {
+        println!("{}: value of {} ({})", location, source.display(), field);
+    }
+    !found.is_empty()
+}
+
+/// Scan the lines of the text files below `path`, skipping ciphertexts and `.git`
+fn scan_path(digests: &Digests, path: &Path, shown_as: &Path) -> bool {
+    if path.is_dir() {
+        let Ok(entries) = std::fs::read_dir(path) else {
+            return false;
+        };
+        let mut leaked = false;
+        for entry in entries.flatten() {
+            if entry.file_name() != ".git" {
+                leaked |= scan_path(digests, &entry.path(), &shown_as.join(entry.file_name()));
+            }
+        }
+        return leaked;
+    }
+    if path.extension().is_some_and(|ext| ext == "age") {
+        return false;
+    }
+    let Ok(contents) = std::fs::read(path) else {
+        return false;
+    };
+    let contents = String::from_utf8_lossy(&contents);
+    let mut leaked = false;
+    for (i, line) in contents.lines().enumerate() {
+        let location = format!("{}:{}", shown_as.display(), i + 1);
+        leaked |= report(digests.find(line), &location);
+    }
+    leaked
+}
+
+/// Report where the values of the managed secrets you can decrypt appear in plaintext.
+/// Returns true when any were found.
+pub fn scan(
+    cache: &CacheFile,
+    project_root: &Path,
+    history: bool,
+    paths: &[PathBuf],
+    identities: &IdentityStore,
+) -> bool {
+    let digests = digests(cache, identities);
+    if digests.values.is_empty() {
+        eprintln!("None of the managed files you can decrypt hold values to look for");
+        return false;
+    }
+
+    let mut leaked = false;
+    match git::work_tree_files(project_root) {
+        Some(files) => {
+            for file in files {
+                leaked |= scan_path(&digests, &project_root.join(&file), &file);
+            }
+        }
+        None => leaked |= scan_path(&digests, project_root, Path::new("")),
+    }
+    if history {
+        let scanned = git::for_each_added_line(project_root, |commit, path, line| {
+            if !path.ends_with(".age") {
+                let location = format!("commit {} {}", commit, path);
+                leaked |= report(digests.find(line), &location);
+            }
+        });
+        if !scanned {
+            eprintln!("warning: unable to scan the git history");
+        }
+    }
+    for path in paths {
+        leaked |= scan_path(&digests, path, path);
+    }
+    leaked
+}
@@ -0,0 +1,99 @@
+//! The encryption at the core of arcanum, for tools reading or writing the secrets it manages.
+//! Ciphertexts are written armored, the way arcanum commits them, and read armored or binary.
+//!
+//! ```no_run
+//! use std::str::FromStr;
+//!
+//! let identity = age::x25519::Identity::generate();
+//! let recipient = age::ssh::Recipient::from_str("ssh-ed25519 AAAA...").unwrap();
+//! let ciphertext = arcanum::encrypt_bytes(
+//!     vec![Box::new(identity.to_public()), Box::new(recipient)],
+//!     b"hunter2",
+//! );
+//! let plaintext = arcanum::decrypt_bytes(&[&identity], &ciphertext).unwrap();
+//! ```
+
+pub mod armor;
+pub mod shamir;
+pub mod totp;
+
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::{DecryptError, EncryptError, Identity, Recipient};
+use std::io::{Read, Write};
+
+/// Encrypt `plaintext` to `recipients`, armored. Payloads of `armor::PARALLEL_THRESHOLD` bytes
+/// and more are armored across threads.
+///
+/// Panics when `recipients` is empty or one of them fails to wrap the file key, see
+/// `try_encrypt_bytes`.
+pub fn encrypt_bytes(recipients: Vec<Box<dyn Recipient + Send>>, plaintext: &[u8]) -> Vec<u8> {
+    try_encrypt_bytes(recipients, plaintext).unwrap()
+}
+
+/// Encrypt `plaintext` to `recipients` without armoring it
+///
+/// Panics when `recipients` is empty or one of them fails to wrap the file key, see
+/// `try_encrypt_binary`.
+pub fn encrypt_binary(recipients: Vec<Box<dyn Recipient + Send>>, plaintext: &[u8]) -> Vec<u8> {
+    try_encrypt_binary(recipients, plaintext).unwrap()
+}
+
+/// `encrypt_bytes`, failing rather than panicking when `recipients` is empty or one of them
+/// can't wrap the file key, such as a key management service that can't be reached
+pub fn try_encrypt_bytes(
+    recipients: Vec<Box<dyn Recipient + Send>>,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EncryptError> {
+    if plaintext.len() >= armor::PARALLEL_THRESHOLD {
+        return Ok(armor::armor(&try_encrypt_binary(recipients, plaintext)?));
+    }
+    let encryptor =
+        age::Encryptor::with_recipients(recipients).ok_or(EncryptError::InvalidRecipients)?;
+    let mut encrypted = vec![];
+    let mut armored_writer = ArmoredWriter::wrap_output(&mut encrypted, Format::AsciiArmor)?;
+    let mut writer = encryptor.wrap_output(&mut armored_writer)?;
+    writer.write_all(plaintext)?;
+    writer.finish()?;
+    armored_writer.finish()?;
+    Ok(encrypted)
+}
+
+/// `encrypt_binary`, failing rather than panicking like `try_encrypt_bytes`
+pub fn try_encrypt_binary(
+    recipients: Vec<Box<dyn Recipient + Send>>,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EncryptError> {
+    let encryptor =
+        age::Encryptor::with_recipients(recipients).ok_or(EncryptError::InvalidRecipients)?;
+    let mut encrypted = Vec::with_capacity(plaintext.len() + 1024);
+    let mut writer = encryptor.wrap_output(&mut encrypted)?;
+    writer.write_all(plaintext)?;
+    writer.finish()?;
+    Ok(encrypted)
+}
+
+/// Decrypt `ciphertext`, armored or not, with the first of `identities` that unwraps its file
+/// key
+pub fn decrypt_bytes(
+    identities: &[&dyn Identity],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, DecryptError> {
+    let dearmored = if ciphertext.len() >= armor::PARALLEL_THRESHOLD {
+        armor::dearmor(ciphertext)
+    } else {
+        None
+    };
+    // ArmoredReader passes binary input through untouched
+    let armor_reader = ArmoredReader::new(dearmored.as_deref().unwrap_or(ciphertext));
+    let decryptor = match age::Decryptor::new(armor_reader)? {
+        age::Decryptor::Recipients(d) => d,
+        // arcanum never encrypts with a passphrase
+        age::Decryptor::Passphrase(_) => return Err(DecryptError::NoMatchingKeys),
+    };
+
+    let mut decrypted = vec![];
+    let mut reader = decryptor.decrypt(identities.iter().copied())?;
+    reader.read_to_end(&mut decrypted)?;
+
+    Ok(decrypted)
+}
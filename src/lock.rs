@@ -0,0 +1,132 @@
+//! `arcanum.lock`, the evaluated config committed to the repository. Recipient changes show
+//! up in review as a diff of it, and commands read it instead of evaluating while it matches
+//! the files it was generated from.
+
+use crate::{cache, config_source, standalone, CacheFile};
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+use sha3::Sha3_256;
+use std::path::{Path, PathBuf};
+
+/// Version of the lock file format written by this release
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LockFile {
+    version: u32,
+    /// Hash of the files the config was evaluated from, see `source_hash`
+    source_hash: String,
+    /// The config, in the format of the cache file
+    cache: serde_json::Value,
+}
+
+pub fn path(project_root: &Path) -> PathBuf {
+    project_root.join("arcanum.lock")
+}
+
+/// Files below the directory `dir`, relative to the project root
+fn collect_dir(project_root: &Path, dir: &Path, sources: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(project_root.join(dir)) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let relative = dir.join(entry.file_name());
+        if file_type.is_dir() {
+            collect_dir(project_root, &relative, sources);
+        } else if file_type.is_file() {
+            sources.push(relative);
+        }
+    }
+}
+
+/// Hash of the paths and contents of the config inputs: `.arcanum/`, a standalone config and
+/// what the config source reads, such as `flake.nix` and `flake.lock`. It runs on every
+/// command, so it doesn't walk the project, and it only needs the files themselves, so
+/// machines without nix or git can tell whether a lock is current too.
+pub fn source_hash(project_root: &Path) -> String {
+    let mut sources = vec![];
+    collect_dir(project_root, Path::new(".arcanum"), &mut sources);
+    sources.extend(standalone::FILE_NAMES.iter().map(PathBuf::from));
+    for input in config_source::source().inputs(project_root) {
+        // Relative where possible, so the hash is the same in every clone
+        let relative = input.strip_prefix(project_root).map(Path::to_path_buf);
+        sources.push(relative.unwrap_or(input));
+    }
+    sources.sort();
+    sources.dedup();
+    let mut hasher = Sha3_256::new();
+    for source in sources {
+        let Ok(data) = std::fs::read(project_root.join(&source)) else {
+            continue;
+        };
+        hasher.update(source.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update((data.len() as u64).to_le_bytes());
+        hasher.update(&data);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Lock file contents for `cache`. Keys are sorted and nothing machine specific is kept, so
+/// the same config always gives the same file.
+fn render(cache: &CacheFile, source_hash: String) -> String {
+    let mut config: serde_json::Value = serde_json::from_slice(&cache::to_json(cache)).unwrap();
+    if let Some(config) = config.as_object_mut() {
+        config.remove("project");
+    }
+    let lock = LockFile {
+        version: VERSION,
+        source_hash,
+        cache: config,
+    };
+    let mut rendered = serde_json::to_string_pretty(&lock).unwrap();
+    rendered.push('\n');
+    rendered
+}
+
+/// Write the lock file for `cache`, just evaluated from the project at `project_root`.
+/// Returns whether it changed.
+pub fn write(cache: &CacheFile, project_root: &Path) -> std::io::Result<bool> {
+    let path = path(project_root);
+    let rendered = render(cache, source_hash(project_root));
+    if std::fs::read_to_string(&path).is_ok_and(|current| current == rendered) {
+        return Ok(false);
+    }
+    crate::fsutil::write_atomic(&path, rendered.as_bytes(), 0o644, None)?;
+    Ok(true)
+}
+
+/// A lock file that was read, and whether it matches the files it was generated from
+pub struct Locked {
+    pub cache: CacheFile,
+    pub current: bool,
+}
+
+/// The project's lock file, None when it has none
+pub fn load(project_root: &Path) -> Option<Result<Locked, String>> {
+    let data = match std::fs::read_to_string(path(project_root)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => return Some(Err(e.to_string())),
+    };
+    Some(parse(&data, project_root))
+}
+
+fn parse(data: &str, project_root: &Path) -> Result<Locked, String> {
+    let lock: LockFile = serde_json::from_str(data).map_err(|e| e.to_string())?;
+    if lock.version > VERSION {
+        return Err(format!(
+            "lock file version {} is newer than this arcanum supports ({})",
+            lock.version, VERSION
+        ));
+    }
+    let cache = cache::parse(&lock.cache.to_string())?;
+    Ok(Locked {
+        cache,
+        current: lock.source_hash == source_hash(project_root),
+    })
+}
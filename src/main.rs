@@ -1,18 +1,80 @@
-use age::armor::{ArmoredReader, Format};
-use age::cli_common::read_identities;
 use age::{Identity, Recipient};
-use clap::{Parser, Subcommand};
+use arcanum::armor;
+use arcanum::totp;
+use chrono::NaiveDate;
+use clap::{Args, Parser, Subcommand};
 use digest::Digest;
-use dirs::cache_dir;
-use edit::{edit_file, get_editor};
+use identity::{IdentitySource, IdentityStore};
 use serde::{Deserialize, Serialize};
 use sha3::Sha3_256;
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::str::FromStr;
 use toor::project::find_project_root;
+use transform::Transform;
+
+mod agent;
+mod analyze;
+mod api;
+mod audit;
+mod backup;
+mod cache;
+mod cert;
+mod check;
+#[cfg(feature = "clipboard")]
+mod clipboard;
+mod concurrent;
+mod config_source;
+mod confirm;
+mod diff;
+mod docker;
+mod doctor;
+mod dotenv;
+mod editor;
+mod envelope;
+mod expiry;
+mod external;
+mod field;
+mod fsutil;
+mod git;
+mod grep;
+mod header;
+mod history;
+mod identity;
+mod ignore;
+mod import;
+mod info;
+mod install;
+mod inventory;
+mod k8s;
+mod kv;
+mod leaks;
+mod lock;
+mod merge;
+mod metadata;
+mod multi_edit;
+mod onboard;
+mod output;
+mod profile;
+mod project_config;
+mod provenance;
+mod providers;
+mod quorum;
+mod revoke;
+mod rotation;
+mod serve;
+mod shell_hook;
+mod standalone;
+mod suggest;
+mod tombstone;
+mod trace;
+mod transform;
+#[cfg(feature = "tui")]
+mod tui;
+mod verify;
+mod workspace;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -21,339 +83,2994 @@ struct Cli {
     #[command(subcommand)]
     command: Commands,
 
-    #[clap(long)]
-    identity: Vec<PathBuf>,
+    /// Identity file to try first: age, ssh, or a plugin's such as age-plugin-yubikey's
+    ///
+    /// Age identities can also be given in ARCANUM_IDENTITY, and the identities tried after
+    /// these configured with labels in ~/.config/arcanum/identities.toml.
+    #[clap(long)]
+    identity: Vec<PathBuf>,
+
+    /// Identity profile from ~/.config/arcanum/config.toml to use [default: the one mapped to
+    /// the project root, if any]
+    #[clap(long, env = "ARCANUM_PROFILE", global = true)]
+    profile: Option<String>,
+
+    /// Project root to operate on, instead of discovering it from the current directory
+    ///
+    /// Relative file arguments are resolved against the project root when this is set.
+    #[clap(long, env = "ARCANUM_PROJECT", global = true)]
+    project: Option<PathBuf>,
+
+    /// Allow interactive commands to run as root
+    #[clap(long, env = "ARCANUM_ALLOW_ROOT", global = true)]
+    allow_root: bool,
+
+    /// Only print simple lines of text, for screen readers and dumb terminals
+    #[clap(long, env = "ARCANUM_PLAIN", global = true)]
+    plain: bool,
+
+    /// Answer yes to every confirmation prompt
+    #[clap(long, short = 'y', env = "ARCANUM_YES", global = true)]
+    yes: bool,
+
+    /// Append a JSON line with the software environment for every ciphertext written to this file
+    #[clap(long, env = "ARCANUM_TRACE_FILE", global = true)]
+    trace_file: Option<PathBuf>,
+
+    /// Never prompt, refusing anything that would need confirming
+    #[clap(long, env = "ARCANUM_NO_INPUT", global = true, conflicts_with = "yes")]
+    no_input: bool,
+
+    /// Keep the previous version of a ciphertext as `<file>.bak` before replacing it
+    #[clap(long, env = "ARCANUM_BACKUP", global = true)]
+    backup: bool,
+
+    /// Keep timestamped copies of replaced ciphertexts below this directory
+    #[clap(long, env = "ARCANUM_BACKUP_DIR", global = true)]
+    backup_dir: Option<PathBuf>,
+
+    /// Editor command for `edit`, arguments included (e.g. "code --wait") [default: $VISUAL or
+    /// $EDITOR]
+    #[clap(long, env = "ARCANUM_EDITOR", global = true)]
+    editor: Option<String>,
+
+    /// Check that every ciphertext written decrypts back to its plaintext first
+    #[clap(
+        long,
+        env = "ARCANUM_VERIFY",
+        global = true,
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    verify: bool,
+
+    /// Never run nix, only use an existing cache file (or a standalone config)
+    #[clap(long, alias = "offline", env = "ARCANUM_NO_EVAL", global = true)]
+    no_eval: bool,
+
+    /// Cache file to use instead of the per-user one, e.g. one committed to the repository
+    #[clap(long, env = "ARCANUM_CACHE_FILE", global = true)]
+    cache_file: Option<PathBuf>,
+
+    /// Directory for the per-project cache files [default: the per-user cache directory, or
+    /// the project's .arcanum/cache.json when it isn't writable]
+    #[clap(long, env = "ARCANUM_CACHE_DIR", global = true)]
+    cache_dir: Option<PathBuf>,
+
+    /// Encrypt the cache file to your own identities, as it maps out every secret, host and
+    /// recipient of the project. Encrypted caches are read either way, and stay encrypted when
+    /// regenerated.
+    #[clap(long, env = "ARCANUM_ENCRYPT_CACHE", global = true)]
+    encrypt_cache: bool,
+
+    /// Flake reference the config is evaluated from [default: .]
+    #[clap(long, env = "ARCANUM_FLAKE", global = true)]
+    flake: Option<String>,
+
+    /// Attribute path of the config in the flake [default: lib.arcanum]
+    #[clap(long, env = "ARCANUM_ATTRIBUTE", global = true)]
+    attribute: Option<String>,
+
+    /// Read the config from this JSON file instead of evaluating the flake or the source set in
+    /// .arcanum/config.toml, e.g. the output of `nix eval --json .#lib.arcanum`
+    #[clap(
+        long,
+        env = "ARCANUM_CONFIG_JSON",
+        global = true,
+        conflicts_with_all = ["flake", "attribute"]
+    )]
+    config_json: Option<PathBuf>,
+}
+
+/// Configuration sections and tags to restrict a bulk operation to. Files of any of the
+/// sections, or every section when none is given, that have any of the tags.
+#[derive(Args, Default)]
+struct SectionArgs {
+    /// Only files of this nixos configuration
+    #[clap(long)]
+    host: Option<String>,
+
+    /// Only files of this home-manager user, as `<user>` or `<outer>.<user>`
+    #[clap(long)]
+    user: Option<String>,
+
+    /// Only files of this devShell, as `<name>` or `<system>.<name>`
+    #[clap(long)]
+    dev_shell: Option<String>,
+
+    /// Only files with this tag in the config, may be given more than once
+    #[clap(long)]
+    tag: Vec<String>,
+}
+
+impl SectionArgs {
+    fn is_empty(&self) -> bool {
+        !self.has_sections() && self.tag.is_empty()
+    }
+
+    fn has_sections(&self) -> bool {
+        self.host.is_some() || self.user.is_some() || self.dev_shell.is_some()
+    }
+
+    fn matches_tags(&self, file: &ArcanumFile) -> bool {
+        self.tag.is_empty() || self.tag.iter().any(|tag| file.tags.contains(tag))
+    }
+
+    fn matches(&self, scope: &Scope) -> bool {
+        if !self.has_sections() {
+            return true;
+        }
+        let nested = |wanted: &Option<String>, outer: &str, inner: &str| {
+            wanted
+                .as_deref()
+                .is_some_and(|w| w == inner || w == format!("{}.{}", outer, inner))
+        };
+        match scope {
+            Scope::Flake => false,
+            Scope::Nixos(host) => self.host.as_ref() == Some(host),
+            Scope::HomeManager(outer, inner) => nested(&self.user, outer, inner),
+            Scope::DevShell(outer, inner) => nested(&self.dev_shell, outer, inner),
+        }
+    }
+}
+
+/// Recipients given on the command line, in addition to the ones configured for the file
+#[derive(Args, Default)]
+struct RecipientArgs {
+    /// Also encrypt to this recipient
+    #[clap(long)]
+    recipient: Vec<String>,
+
+    /// Also encrypt to the recipients configured for this managed file
+    #[clap(long)]
+    recipients_of: Option<PathBuf>,
+
+    /// Also encrypt to the recipients listed in this file, one per line
+    #[clap(long)]
+    recipients_file: Vec<PathBuf>,
+
+    /// Encrypt only to the recipients given on the command line, ignoring the configured ones
+    #[clap(long)]
+    replace_recipients: bool,
+
+    /// Fail instead of warning when a file would only be encrypted to admin recipients
+    #[clap(long)]
+    strict: bool,
+}
+
+impl RecipientArgs {
+    /// Recipients given directly or through recipients files
+    fn explicit_recipients(&self) -> BTreeSet<String> {
+        let mut recipients: BTreeSet<String> = self
+            .recipient
+            .iter()
+            .map(|r| canonical_recipient(r))
+            .collect();
+        for path in &self.recipients_file {
+            let data = std::fs::read_to_string(path).unwrap_or_else(|e| {
+                eprintln!("Unable to read recipients file {:?}: {}", path, e);
+                std::process::exit(1);
+            });
+            recipients.extend(
+                data.lines()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(canonical_recipient),
+            );
+        }
+        recipients
+    }
+}
+
+// Any path argument may be `-` to read from stdin or write to stdout. Output written to stdout
+// has no configured recipients, so they must be given with `--recipient` or `--recipients-of`.
+#[derive(Subcommand)]
+enum Commands {
+    /// Encrypt a file
+    ///
+    /// The ciphertext may be a glob over managed files, the plaintext is then a directory
+    /// holding their plaintexts, named like `decrypt` writes them.
+    Encrypt {
+        plaintext: PathBuf,
+        ciphertext: PathBuf,
+
+        /// Encrypt the plaintext as-is, without reversing the file's configured transforms
+        #[clap(long)]
+        raw: bool,
+
+        /// Octal mode of the ciphertext, by default 0644 or that of the file being replaced
+        #[clap(long, value_parser = fsutil::parse_mode)]
+        mode: Option<u32>,
+
+        #[command(flatten)]
+        recipients: RecipientArgs,
+    },
+
+    /// Decrypt a file
+    ///
+    /// The ciphertext may be a glob over managed files, each is then decrypted into the
+    /// plaintext directory under its source path without the `.age` suffix. Existing plaintext
+    /// is only overwritten once confirmed, or right away when there's no terminal to ask on.
+    Decrypt {
+        ciphertext: PathBuf,
+        #[clap(required_unless_present = "to_cmd")]
+        plaintext: Option<PathBuf>,
+
+        /// Pipe the plaintext into this shell command instead of writing it anywhere
+        #[clap(long, conflicts_with = "plaintext")]
+        to_cmd: Option<String>,
+
+        /// Output the stored plaintext as-is, without applying the file's configured transforms
+        #[clap(long)]
+        raw: bool,
+
+        /// Octal mode of the plaintext [default: the configured permissions when writing to
+        /// the file's destination, else 0600]
+        #[clap(long, value_parser = fsutil::parse_mode)]
+        mode: Option<u32>,
+
+        /// Write the plaintext even where git doesn't ignore it
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Edit the plaintext of a file
+    ///
+    /// Several files or globs over managed files can be given at once, they are opened
+    /// together in one editor session and the ones that changed are re-encrypted.
+    Edit {
+        #[clap(required_unless_present = "all_matching")]
+        ciphertext: Vec<PathBuf>,
+
+        /// Also edit every managed file whose source matches this glob
+        #[clap(long)]
+        all_matching: Option<String>,
+
+        /// Read the new plaintext from stdin instead of opening an editor, for scripted edits
+        #[clap(long)]
+        stdin: bool,
+
+        #[command(flatten)]
+        recipients: RecipientArgs,
+    },
+
+    /// Show how the plaintext of a file changed since a git revision, or how it differs from
+    /// another file
+    ///
+    /// Exits non-zero when the plaintexts differ.
+    Diff {
+        ciphertext: PathBuf,
+        other: Option<PathBuf>,
+
+        /// Revision to compare against when no other file is given
+        #[clap(long, default_value = "HEAD", conflicts_with = "other")]
+        rev: String,
+    },
+
+    /// Copy a secret, or one value in it, to the clipboard and clear it again after a while
+    #[cfg(feature = "clipboard")]
+    Copy {
+        ciphertext: PathBuf,
+
+        /// Copy only this value: a dotted path into a JSON plaintext, or an env file variable
+        #[clap(long)]
+        key: Option<String>,
+
+        /// Seconds to keep the secret on the clipboard
+        #[clap(long, env = "ARCANUM_CLIPBOARD_TIMEOUT", default_value_t = 45,
+               value_parser = clap::value_parser!(u64).range(1..))]
+        clear_after: u64,
+    },
+
+    /// Search the plaintexts of every managed file you can decrypt, printing the files and line
+    /// numbers that match
+    ///
+    /// Nothing is written to disk. Exits non-zero when nothing matches.
+    Grep {
+        #[command(flatten)]
+        args: grep::GrepArgs,
+    },
+
+    /// Serve secrets over HTTP on localhost to the development tools configured in
+    /// .arcanum/config.toml, each with its own token and allowlist
+    ///
+    /// Clients request `GET /secret/<path>` with `Authorization: Bearer <token>`. Runs until
+    /// killed.
+    Serve {
+        /// Port to listen on, on 127.0.0.1 only
+        #[clap(long, env = "ARCANUM_SERVE_PORT", default_value_t = 7373)]
+        port: u16,
+    },
+
+    /// Print the current one-time code of a secret holding an `otpauth://totp/...` URI
+    Totp {
+        ciphertext: PathBuf,
+
+        /// Read the URI from this value: a dotted path into a JSON plaintext, or an env file
+        /// variable
+        #[clap(long)]
+        key: Option<String>,
+    },
+
+    /// Merge driver for git: three-way merge the plaintexts of a ciphertext
+    ///
+    /// Configure with `git config merge.arcanum.driver 'arcanum merge %O %A %B --path %P'`
+    /// and `*.age merge=arcanum` in .gitattributes. Exits non-zero when conflicts are left.
+    Merge {
+        base: PathBuf,
+        ours: PathBuf,
+        theirs: PathBuf,
+
+        /// Path of the ciphertext in the repository, to find its recipients
+        #[clap(long)]
+        path: PathBuf,
+
+        /// Print diffs and a preview of the merged plaintext instead of a redacted summary
+        #[clap(long)]
+        show_plaintext: bool,
+    },
+
+    /// Resolve the conflicts `merge` left in a ciphertext in your editor
+    ///
+    /// Does nothing to a file without conflicts, and leaves the file as it was when the editor
+    /// fails or conflict markers remain.
+    Resolve {
+        ciphertext: PathBuf,
+
+        /// `git add` the file once it is resolved
+        #[clap(long)]
+        stage: bool,
+    },
+
+    /// Show every version of a ciphertext in git history that you can still decrypt, with the
+    /// changes to its plaintext between them
+    History {
+        ciphertext: PathBuf,
+
+        /// Only look at this many of the latest commits
+        #[clap(long, short = 'n')]
+        max_count: Option<usize>,
+    },
+
+    /// Restore a ciphertext to its plaintext at an earlier commit, encrypted to its current
+    /// recipients
+    Restore {
+        ciphertext: PathBuf,
+
+        /// Commit to take the plaintext from
+        #[clap(long)]
+        rev: String,
+
+        #[command(flatten)]
+        recipients: RecipientArgs,
+    },
+
+    /// Show a ciphertext's metadata, the recipients in its header and which of your identities
+    /// can decrypt it
+    Info { ciphertext: PathBuf },
+
+    /// Show the subject, SANs, issuer and expiry of the PEM certificates in a secret
+    CertInfo { ciphertext: PathBuf },
+
+    /// Re-encrypt files to all their configured recipients
+    ///
+    /// Globs such as `'secrets/web*/*.age'` are expanded against the managed files.
+    Rekey {
+        #[clap(required_unless_present_any = ["all", "host", "user", "dev_shell", "tag"])]
+        ciphertext: Vec<PathBuf>,
+
+        /// Rekey every managed file, or with a section filter every file of those sections
+        #[clap(long, conflicts_with = "ciphertext")]
+        all: bool,
+
+        #[command(flatten)]
+        sections: SectionArgs,
+
+        /// Skip files already encrypted to exactly the recipients they would be rekeyed to. Age
+        /// keys can't be told apart in a header, so for those the recipients recorded in the
+        /// file's metadata are compared, and files without a record are rekeyed.
+        #[clap(long)]
+        only_missing: bool,
+
+        #[command(flatten)]
+        recipients: RecipientArgs,
+    },
+
+    /// Decrypt the secrets of a host and install them to their destinations
+    ///
+    /// Files are written atomically with the configured owner, group and permissions. Meant to
+    /// be run as root, e.g. from a NixOS activation script.
+    ///
+    /// Names of systemd units whose secrets changed since the last install are printed to
+    /// stdout.
+    Install {
+        /// nixos configuration to install secrets for, defaults to this machine's hostname
+        #[clap(long)]
+        host: Option<String>,
+
+        /// Exit with this code when any secret changed, instead of 0
+        #[clap(long)]
+        changed_exit_code: Option<i32>,
+
+        /// Restart the systemd units of changed secrets
+        #[clap(long)]
+        restart: bool,
+
+        /// Where to keep hashes of installed secrets for change detection
+        #[clap(long)]
+        state_file: Option<PathBuf>,
+
+        /// Remove secrets installed for this project and host by earlier runs that are no
+        /// longer declared, when they haven't changed since
+        #[clap(long)]
+        prune: bool,
+
+        /// Never prune destinations matching this glob, can be given multiple times
+        #[clap(long, value_name = "GLOB")]
+        keep: Vec<String>,
+    },
+
+    /// Decrypt a file and report passwords in it weak enough to need rotating
+    Analyze {
+        ciphertext: PathBuf,
+
+        /// Also check against an offline copy of the Pwned Passwords range files
+        #[clap(long)]
+        breached: Option<PathBuf>,
+    },
+
+    /// Look for the values of managed secrets committed in plaintext
+    ///
+    /// Searches the work tree, every commit reachable from a ref and any --path given, for the
+    /// values in the managed files you can decrypt. Exits non-zero when any are found.
+    ScanLeaks {
+        /// Only scan the work tree, not the git history
+        #[clap(long)]
+        no_history: bool,
+
+        /// Other files or directories to scan as well
+        #[clap(long = "path")]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Keep identities unlocked in an agent, so other commands don't prompt for passphrases
+    ///
+    /// Other invocations find it through ARCANUM_AGENT_SOCK, or the default socket path.
+    Agent {
+        /// Seconds to hold the identities before exiting
+        #[clap(long, default_value = "3600")]
+        ttl: u64,
+    },
+
+    /// Check cache files for wrong ownership or permissions, e.g. after running under sudo
+    Doctor {
+        /// Repair the problems found
+        #[clap(long)]
+        fix: bool,
+    },
+
+    /// Set up a new NixOS host: get its host key and print the config making it a recipient
+    /// of the host's secrets
+    ///
+    /// Once the config is updated and `arcanum cache` was run, --rekey re-encrypts the host's
+    /// secrets to the key.
+    InitHost {
+        name: String,
+
+        #[command(flatten)]
+        key: onboard::HostKeyArgs,
+
+        /// Rekey the host's secrets not yet encrypted to the key, instead of printing the config
+        #[clap(long, conflicts_with = "generate")]
+        rekey: bool,
+    },
+
+    /// Set up a new user: generate an age identity in ~/.config/arcanum/identity.txt and print
+    /// the config making its public key an admin recipient
+    ///
+    /// An existing identity there is kept, its config is printed again.
+    InitUser {
+        /// Name recorded as the owner of the key [default: git's user.name and user.email]
+        #[clap(long)]
+        owner: Option<String>,
+    },
+
+    /// Print the ssh keys a GitHub or GitLab user has published that age can encrypt to, one
+    /// per line
+    ///
+    /// The same `github:<user>` and `gitlab:<user>` work as recipients in the config, where
+    /// they are resolved when the cache is generated and pinned in arcanum.lock.
+    FetchKeys {
+        #[clap(required = true)]
+        account: Vec<String>,
+    },
+
+    /// List every host, home-manager and devShell configuration arcanum knows about
+    Hosts {
+        /// Print as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// List every recipient in the config and the files each of them can decrypt
+    Recipients {
+        /// Only show recipients whose key or owner contains this
+        filter: Option<String>,
+
+        /// Print as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Use a single encrypted JSON file as a small key-value store
+    Kv {
+        /// Encrypted file holding the store
+        #[clap(long)]
+        store: PathBuf,
+
+        #[command(subcommand)]
+        command: kv::KvCommand,
+    },
+
+    /// Checks on the arcanum binary itself
+    #[command(name = "self")]
+    SelfCheck {
+        #[command(subcommand)]
+        command: SelfCommand,
+    },
+
+    /// Archive and delete ciphertexts whose secrets were removed from the config
+    Gc,
+
+    /// Write the evaluated config to `arcanum.lock`, for committing. While it is current,
+    /// commands use it instead of the cache.
+    Lock {
+        /// Only check that the lock file matches the config, exiting 1 when it doesn't
+        #[clap(long)]
+        check: bool,
+    },
+
+    /// Print a Kubernetes Secret manifest holding the plaintexts of managed files, or apply it
+    /// with kubectl
+    ///
+    /// Each file is a key of the Secret: its `k8sKey` in the config, or else its file name
+    /// without `.age`.
+    ExportK8s {
+        /// Managed files, or globs of them, to put in the Secret
+        #[clap(required_unless_present_any = ["host", "user", "dev_shell", "tag"])]
+        ciphertext: Vec<PathBuf>,
+
+        /// Every file of these sections
+        #[command(flatten)]
+        sections: SectionArgs,
+
+        /// Name of the Secret
+        #[clap(long)]
+        name: String,
+
+        /// Namespace of the Secret
+        #[clap(long)]
+        namespace: String,
+
+        /// Pass the manifest to `kubectl apply` rather than printing it
+        #[clap(long)]
+        apply: bool,
+    },
+
+    /// Decrypt a secret for a container build's `--secret`, printing the argument for it or
+    /// running the build command given after `--` with it
+    ///
+    /// The plaintext is written to a private directory in the runtime directory, and removed
+    /// after the build when a command is given.
+    DockerSecret {
+        ciphertext: PathBuf,
+
+        /// Id the Dockerfile mounts the secret by [default: the file name without .age]
+        #[clap(long)]
+        id: Option<String>,
+
+        /// Build command to run with the `--secret` argument appended, e.g. `docker build .`
+        #[clap(last = true)]
+        command: Vec<String>,
+    },
+
+    /// Answer a Terraform external data source query on stdin with a secret, or a value in
+    /// one, on stdout
+    ///
+    /// The query is `{"file": "<managed file>", "key": "<optional key>"}` and the result
+    /// `{"value": "..."}`.
+    External,
+
+    /// Encrypt what a command prints into a managed file, without the plaintext touching disk
+    ///
+    /// For example `arcanum encrypt-exec secrets/wg.key.age -- wg genkey`. Nothing is written
+    /// when the command fails or prints nothing.
+    EncryptExec {
+        ciphertext: PathBuf,
+
+        #[command(flatten)]
+        recipients: RecipientArgs,
+
+        /// The command and its arguments
+        #[clap(last = true, required = true)]
+        command: Vec<String>,
+    },
+
+    /// Encrypt a value from 1Password (`op://<vault>/<item>/<field>`) or Bitwarden
+    /// (`bw://<item>/<field>`) into a managed file, read through their CLIs
+    Import {
+        reference: String,
+        ciphertext: PathBuf,
+
+        #[command(flatten)]
+        recipients: RecipientArgs,
+    },
+
+    /// Decrypt the secrets of an env template from the config into environment variables
+    RenderEnv {
+        /// Name of the template in `envTemplates`
+        template: String,
+
+        #[clap(long, value_enum, default_value = "dotenv")]
+        format: dotenv::EnvFormat,
+
+        /// Write to this file instead of stdout
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+
+    /// Print the JSON Schema of a version of the format written by `cache emit`
+    // `--version` is the API version here, not arcanum's
+    #[command(disable_version_flag = true)]
+    Schema {
+        #[clap(long, default_value = "1")]
+        version: u32,
+    },
+
+    /// Decrypt the devShell's secrets into a private runtime directory and print exports for
+    /// them, for running on shell entry: `eval "$(arcanum shell-hook)"`
+    ShellHook {
+        /// Name of the devShell whose secrets to provide
+        #[clap(long, default_value = "default")]
+        shell: String,
+    },
+
+    /// Keep the devShell's secrets from `shell-hook` up to date as their ciphertexts and the
+    /// cache change, until interrupted
+    Watch {
+        /// Name of the devShell whose secrets to provide
+        #[clap(long, default_value = "default")]
+        shell: String,
+    },
+
+    /// Show the state and tags of every managed file in the project, and of ciphertexts left
+    /// behind after their secret was removed from the config
+    Status,
+
+    /// Browse managed files interactively, editing or rekeying them
+    #[cfg(feature = "tui")]
+    Tui,
+
+    /// Replace a secret with a new value minted by its `rotationCommand`
+    ///
+    /// The command's stdout becomes the new plaintext, after which the file's
+    /// `postRotationCommand`, if any, is run, e.g. to reload the services using it.
+    Rotate {
+        ciphertext: PathBuf,
+
+        #[command(flatten)]
+        recipients: RecipientArgs,
+    },
+
+    /// Check for files readable by recipients whose keys have expired or expire soon
+    ///
+    /// Exits non-zero when any are found, so it can be used as a CI check.
+    RotateCheck {
+        /// Also flag keys expiring within this many days
+        #[clap(long, default_value_t = 30)]
+        within_days: i64,
+    },
+
+    /// List secrets past or near their `expires` date, or their `maxAge` since last rotated,
+    /// and with --certificates, certificates in secrets you can decrypt that expire
+    ///
+    /// Exits non-zero when any are found, so it can be used as a CI check.
+    Expiring {
+        /// Also flag secrets expiring within this period, in days or such as 12w or 1y
+        #[clap(long, default_value = "30d", value_parser = expiry::parse_period)]
+        within: i64,
+
+        /// Decrypt every secret you can to check the certificates in them, which may ask for
+        /// passphrases, PINs or touches
+        #[clap(long)]
+        certificates: bool,
+    },
+
+    /// Show every file a recipient can decrypt, optionally revoking its access
+    ///
+    /// Takes a public key or an owner from the recipient metadata. With --rekey the keys are
+    /// recorded in .arcanum/revoked-recipients and every affected file is rekeyed without them.
+    Revoke {
+        recipient: String,
+
+        /// Revoke the recipient and rekey every file it can decrypt
+        #[clap(long)]
+        rekey: bool,
+    },
+
+    /// Export managed files for an auditor, re-encrypted only to their key
+    ///
+    /// The bundle includes who can read each file and expires after --valid-days, which
+    /// audit-import enforces.
+    AuditExport {
+        output: PathBuf,
+
+        /// Auditor's public key
+        #[clap(long)]
+        recipient: String,
+
+        /// Only export managed files whose source matches this glob
+        #[clap(long)]
+        filter: Option<String>,
+
+        #[clap(long, default_value_t = 30)]
+        valid_days: i64,
+    },
+
+    /// Unpack an audit bundle into a directory, unless it has expired
+    AuditImport { bundle: PathBuf, directory: PathBuf },
+
+    /// Check the project's config for mistakes, such as malformed recipients, duplicate
+    /// destinations or missing sources
+    ///
+    /// Exits non-zero when any are found.
+    Lint {
+        /// Print as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Validate evaluated config JSON read from stdin, without touching the file system
+    ///
+    /// Meant for the nix module to run as a build check.
+    CheckJson,
+
+    /// Regenerate a cache file for the current project
+    ///
+    /// Needed when adding new files to the project or changing the recipients.
+    Cache {
+        #[command(subcommand)]
+        command: Option<cache::CacheCommand>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArcanumFile {
+    dest: PathBuf,
+    source: PathBuf,
+    directory_permissions: String,
+    make_directory: bool,
+    group: String,
+    owner: String,
+    permissions: String,
+    recipients: Vec<String>,
+    /// Previous locations of `source`, still read from while a move is in progress
+    #[serde(default)]
+    legacy_sources: Vec<PathBuf>,
+    /// Conversions applied to the plaintext on decrypt and reversed on encrypt
+    #[serde(default)]
+    transform: Vec<Transform>,
+    /// systemd units to restart when the installed secret changes
+    #[serde(default)]
+    restart_units: Vec<String>,
+    /// Number of `share_holders` needed to decrypt, instead of any single recipient or admin
+    #[serde(default)]
+    threshold: Option<u8>,
+    #[serde(default)]
+    share_holders: Vec<String>,
+    /// What the secret is, recorded in the ciphertext's metadata
+    #[serde(default)]
+    description: Option<String>,
+    /// Key of the secret in Kubernetes Secrets made by `export-k8s`
+    #[serde(default)]
+    k8s_key: Option<String>,
+    /// Labels grouping files across sections, for `--tag`
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Shell command printing a new value for the secret, run by `arcanum rotate`
+    #[serde(default)]
+    rotation_command: Option<String>,
+    /// Shell command run once `arcanum rotate` wrote the new value, e.g. to reload a service
+    #[serde(default)]
+    post_rotation_command: Option<String>,
+    /// Date by which the secret must have been replaced, see `arcanum expiring`
+    #[serde(default)]
+    expires: Option<NaiveDate>,
+    /// How long the secret may be used after it was last rotated, such as `90d` or `1y`
+    #[serde(default)]
+    max_age: Option<String>,
+}
+
+impl ArcanumFile {
+    /// Whether `path`, relative to the project root as `CacheFile::project_relative` makes it,
+    /// is this file's source or one of its legacy locations
+    fn matches(&self, path: &Path) -> bool {
+        std::iter::once(&self.source)
+            .chain(&self.legacy_sources)
+            .any(|source| fsutil::normalize(source) == path)
+    }
+
+    /// Keys that can decrypt the file, on their own or as share holders of a threshold file
+    fn readers<'a>(&'a self, config: &'a ArcanumConfig) -> Vec<&'a String> {
+        if self.threshold.is_some() {
+            self.share_holders.iter().collect()
+        } else {
+            self.recipients
+                .iter()
+                .chain(&config.admin_recipients)
+                .collect()
+        }
+    }
+
+    /// The first legacy location that still exists on disk, if any
+    fn existing_legacy_source(&self) -> Option<&PathBuf> {
+        self.legacy_sources.iter().find(|legacy| legacy.exists())
+    }
+}
+
+#[derive(Subcommand)]
+enum SelfCommand {
+    /// Check that this binary is the build pinned by the project
+    Verify,
+}
+
+/// How a command behaves when run as root
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RootPolicy {
+    /// Meant to be run as root, e.g. when deploying
+    Allow,
+    /// Works, but leaves root-owned cache files behind
+    Warn,
+    /// Creates plaintext temp files in a user session, refused without --allow-root
+    Refuse,
+}
+
+impl Commands {
+    fn root_policy(&self) -> RootPolicy {
+        match self {
+            Commands::Install { .. } | Commands::CheckJson => RootPolicy::Allow,
+            Commands::Edit { .. } => RootPolicy::Refuse,
+            #[cfg(feature = "tui")]
+            Commands::Tui => RootPolicy::Refuse,
+            _ => RootPolicy::Warn,
+        }
+    }
+}
+
+/// Optional bookkeeping attached to a recipient key in the config
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecipientMetadata {
+    added: Option<NaiveDate>,
+    expires: Option<NaiveDate>,
+    owner: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArcanumConfig {
+    #[serde(default)]
+    files: HashMap<String, ArcanumFile>,
+    #[serde(default)]
+    admin_recipients: Vec<String>,
+    #[serde(default)]
+    recipient_metadata: HashMap<String, RecipientMetadata>,
+    /// Named sets of environment variables set from managed secrets, see `render-env`
+    #[serde(default)]
+    env_templates: HashMap<String, dotenv::EnvTemplate>,
+}
+
+/// The evaluated config of a project, see `cache` for how it's stored
+#[derive(Debug)]
+struct CacheFile {
+    sections: BTreeMap<Scope, ArcanumConfig>,
+    /// Build of arcanum the project expects, checked by `arcanum self verify`
+    build: Option<provenance::BuildPin>,
+    /// Recipients revoked locally with `arcanum revoke --rekey`, left out of every file
+    revoked: BTreeSet<String>,
+    /// Sections whose evaluation failed, so they are missing from `sections`
+    failed: Vec<String>,
+    /// Root of the project the cache was generated for, so `cache gc` can tell when it's gone
+    project: Option<PathBuf>,
+}
+
+/// Where in the flake a configuration section was found
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Scope {
+    Flake,
+    Nixos(String),
+    HomeManager(String, String),
+    DevShell(String, String),
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Flake => write!(f, "flake"),
+            Scope::Nixos(host) => write!(f, "nixos.{}", host),
+            Scope::HomeManager(outer, inner) => write!(f, "homeManager.{}.{}", outer, inner),
+            Scope::DevShell(outer, inner) => write!(f, "devShells.{}.{}", outer, inner),
+        }
+    }
+}
+
+/// State of a managed file's ciphertext on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FileStatus {
+    /// Encrypted to exactly the configured recipients
+    Ok,
+    /// Header doesn't match the configured recipients, needs a rekey
+    Stale,
+    /// Only present at a legacy location
+    Legacy(PathBuf),
+    Missing,
+    /// Exists but isn't a readable age file
+    Unreadable,
+}
+
+impl FileStatus {
+    fn label(&self) -> &'static str {
+        match self {
+            FileStatus::Ok => "ok",
+            FileStatus::Stale => "stale",
+            FileStatus::Legacy(_) => "legacy",
+            FileStatus::Missing => "missing",
+            FileStatus::Unreadable => "unreadable",
+        }
+    }
+}
+
+impl CacheFile {
+    /// All configuration sections present in the cache, in a stable order
+    fn configs(&self) -> Vec<(Scope, &ArcanumConfig)> {
+        self.sections
+            .iter()
+            .map(|(scope, config)| (scope.clone(), config))
+            .collect()
+    }
+
+    /// Every file entry in the cache along with the section that declares it
+    fn entries(&self) -> Vec<(Scope, &ArcanumConfig, &ArcanumFile)> {
+        let mut entries = vec![];
+        for (scope, config) in self.configs() {
+            let mut files: Vec<&ArcanumFile> = config.files.values().collect();
+            files.sort_by(|a, b| a.source.cmp(&b.source));
+            for file in files {
+                entries.push((scope.clone(), config, file));
+            }
+        }
+        entries
+    }
+
+    /// `path` as the config writes sources: relative to the project root, without `.` or `..`.
+    /// Paths given relative to the current directory or absolute ones match the config too.
+    fn project_relative(&self, path: &Path) -> PathBuf {
+        if is_stdio(path) {
+            return path.to_path_buf();
+        }
+        let Some(root) = &self.project else {
+            return fsutil::normalize(path);
+        };
+        let absolute = fsutil::normalize(&std::path::absolute(path).unwrap_or(path.to_path_buf()));
+        match absolute.strip_prefix(root) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => absolute,
+        }
+    }
+
+    /// Every managed source, once each
+    fn sources(&self) -> Vec<PathBuf> {
+        let sources: BTreeSet<PathBuf> = self
+            .entries()
+            .into_iter()
+            .map(|(_, _, file)| file.source.clone())
+            .collect();
+        sources.into_iter().collect()
+    }
+
+    /// Sources of the files in the sections `filter` selects, relative to the project root.
+    /// Exits when it selects no section at all, listing the ones there are.
+    fn sources_in(&self, filter: &SectionArgs) -> BTreeSet<PathBuf> {
+        let selected: Vec<(Scope, &ArcanumConfig)> = self
+            .configs()
+            .into_iter()
+            .filter(|(scope, _)| filter.matches(scope))
+            .collect();
+        if selected.is_empty() {
+            eprintln!("No configuration section matches the filter, the sections are:");
+            for (scope, _) in self.configs() {
+                eprintln!("  {}", scope);
+            }
+            std::process::exit(1);
+        }
+        selected
+            .into_iter()
+            .flat_map(|(_, config)| config.files.values())
+            .filter(|file| filter.matches_tags(file))
+            .map(|file| fsutil::normalize(&file.source))
+            .collect()
+    }
+
+    /// Configured sources close to `path`, for when it matches none of them
+    fn suggestions(&self, path: &Path) -> Vec<PathBuf> {
+        let path = self.project_relative(path);
+        let sources: BTreeSet<PathBuf> = self
+            .entries()
+            .into_iter()
+            .map(|(_, _, file)| fsutil::normalize(&file.source))
+            .collect();
+        if sources.contains(&path) {
+            return vec![];
+        }
+        suggest::closest(&path, sources.iter().map(PathBuf::as_path))
+    }
+
+    /// Report that nothing is configured to decrypt `path`, suggesting sources it may have
+    /// been meant as
+    fn report_no_recipients(&self, path: &Path) {
+        eprintln!("No recipients found for {:?}", path);
+        let suggestions = self.suggestions(path);
+        if !suggestions.is_empty() {
+            let suggestions: Vec<String> = suggestions
+                .iter()
+                .map(|s| s.display().to_string())
+                .collect();
+            eprintln!("Did you mean {}?", suggestions.join(" or "));
+        }
+    }
+
+    /// Path to read the ciphertext for `path` from, falling back to a legacy location
+    /// when the file has been moved in the config but not yet on disk.
+    fn resolve_source(&self, path: &Path) -> PathBuf {
+        if path.exists() {
+            return path.to_path_buf();
+        }
+        let relative = self.project_relative(path);
+        for (_, _, file) in self.entries() {
+            if fsutil::normalize(&file.source) != relative {
+                continue;
+            }
+            if let Some(legacy) = file.existing_legacy_source() {
+                eprintln!(
+                    "{:?} does not exist, reading from legacy location {:?}",
+                    path, legacy
+                );
+                return legacy.clone();
+            }
+        }
+        path.to_path_buf()
+    }
+
+    /// Number of share holders needed to decrypt `path`, when it's a threshold file
+    fn threshold_for_file(&self, path: &Path) -> Option<u8> {
+        let path = self.project_relative(path);
+        self.entries()
+            .into_iter()
+            .find(|(_, _, file)| file.matches(&path))
+            .and_then(|(_, _, file)| file.threshold)
+    }
+
+    /// The managed file `path` is the ciphertext of, when it's configured to be installed to
+    /// `dest`
+    fn file_installed_to(&self, path: &Path, dest: &Path) -> Option<&ArcanumFile> {
+        let path = self.project_relative(path);
+        let dest = fsutil::normalize(&std::path::absolute(dest).ok()?);
+        self.entries()
+            .into_iter()
+            .map(|(_, _, file)| file)
+            .find(|file| file.matches(&path) && fsutil::normalize(&file.dest) == dest)
+    }
+
+    fn description_for_file(&self, path: &Path) -> Option<&str> {
+        let path = self.project_relative(path);
+        self.entries()
+            .into_iter()
+            .find(|(_, _, file)| file.matches(&path))
+            .and_then(|(_, _, file)| file.description.as_deref())
+    }
+
+    /// Transforms configured for `path`, taken from the first entry that declares it
+    fn transforms_for_file(&self, path: &Path) -> Vec<Transform> {
+        let path = self.project_relative(path);
+        self.entries()
+            .into_iter()
+            .find(|(_, _, file)| file.matches(&path))
+            .map(|(_, _, file)| file.transform.clone())
+            .unwrap_or_default()
+    }
+
+    /// Where `file` stands on disk relative to its configured recipients
+    fn status_of(&self, file: &ArcanumFile) -> FileStatus {
+        if !file.source.exists() {
+            return match file.existing_legacy_source() {
+                Some(legacy) => FileStatus::Legacy(legacy.clone()),
+                None => FileStatus::Missing,
+            };
+        }
+        match header::read_stanzas(&file.source) {
+            Ok(stanzas) => {
+                let recipients = self.recipient_strings_for_file(&file.source);
+                let matches = match file.threshold {
+                    Some(threshold) => quorum::matches_holders(&stanzas, threshold, &recipients),
+                    None => encrypted_to(&file.source, &stanzas, &recipients),
+                };
+                if matches {
+                    FileStatus::Ok
+                } else {
+                    FileStatus::Stale
+                }
+            }
+            Err(_) => FileStatus::Unreadable,
+        }
+    }
+
+    /// Whether `ciphertext` is already encrypted to exactly what `args` would rekey it to
+    fn is_current(&self, ciphertext: &Path, args: &RecipientArgs) -> bool {
+        let relative = self.project_relative(ciphertext);
+        let Some((_, _, file)) = self
+            .entries()
+            .into_iter()
+            .find(|(_, _, file)| file.matches(&relative))
+        else {
+            return false;
+        };
+        if file.threshold.is_some() && !args.replace_recipients {
+            return self.status_of(file) == FileStatus::Ok;
+        }
+        if !ciphertext.exists() {
+            return false;
+        }
+        let recipients = self.recipient_strings_for_target(ciphertext, args);
+        header::read_stanzas(ciphertext)
+            .is_ok_and(|stanzas| encrypted_to(ciphertext, &stanzas, &recipients))
+    }
+
+    /// Every recipient configured for `source` across all sections, including admins, or the
+    /// share holders of a threshold file
+    fn recipient_strings_for_file(&self, source: &Path) -> BTreeSet<String> {
+        let source = self.project_relative(source);
+        let mut recipients: BTreeSet<String> = BTreeSet::new();
+        for (_, config, file) in self.entries() {
+            if file.matches(&source) {
+                recipients.extend(
+                    file.readers(config)
+                        .into_iter()
+                        .map(|r| canonical_recipient(r)),
+                );
+            }
+        }
+        recipients.retain(|r| !self.revoked.contains(r));
+        recipients
+    }
+
+    /// The admin recipients of the sections configuring `source`
+    fn admin_strings_for_file(&self, source: &Path) -> BTreeSet<String> {
+        let source = self.project_relative(source);
+        self.entries()
+            .into_iter()
+            .filter(|(_, _, file)| file.matches(&source))
+            .flat_map(|(_, config, _)| &config.admin_recipients)
+            .map(|r| canonical_recipient(r))
+            .collect()
+    }
+
+    fn recipients_for_file(&self, source: &Path) -> Vec<Box<dyn Recipient + Send>> {
+        self.recipients_for_target(source, &RecipientArgs::default())
+    }
+
+    /// Recipients to encrypt `target` to: the ones configured for it, plus (or, with
+    /// `--replace-recipients`, only) any given on the command line
+    fn recipients_for_target(
+        &self,
+        target: &Path,
+        args: &RecipientArgs,
+    ) -> Vec<Box<dyn Recipient + Send>> {
+        let configured = !is_stdio(target) && !args.replace_recipients;
+        if let Some(threshold) = self.threshold_for_file(target).filter(|_| configured) {
+            return self.quorum_for_target(target, threshold, args);
+        }
+        let explicit = args.explicit_recipients();
+        let mut problems = if configured {
+            check::invalid_recipients_for(self, target)
+        } else {
+            vec![]
+        };
+        if let Some(other) = &args.recipients_of {
+            problems.extend(check::invalid_recipients_for(self, other));
+        }
+        for recipient in &explicit {
+            if let Err(message) = check::check_recipient(recipient) {
+                problems.push(check::Problem {
+                    check: "invalid-recipient",
+                    section: "command line".to_string(),
+                    file: None,
+                    message,
+                });
+            }
+        }
+        exit_on_invalid_recipients(target, &problems);
+
+        let recipients = self.recipient_strings_for_target(target, args);
+        // Admins can always read a file, but one no host or user can read deploys unusable
+        let admins = self.admin_strings_for_file(target);
+        if configured && !recipients.is_empty() && recipients.is_subset(&admins) {
+            eprintln!(
+                "{}: {:?} would only be encrypted to admin recipients, so no host or user it is \
+                 deployed to could decrypt it. Add them to its recipients.",
+                if args.strict { "error" } else { "warning" },
+                target
+            );
+            if args.strict {
+                std::process::exit(1);
+            }
+        }
+        if !is_stdio(target) {
+            metadata::note_recipients(target, &recipients);
+        }
+        parse_recipients(target, &recipients)
+    }
+
+    /// The recipients `target` is encrypted to given `args`: the configured ones unless they
+    /// are replaced, and the ones given on the command line
+    fn recipient_strings_for_target(
+        &self,
+        target: &Path,
+        args: &RecipientArgs,
+    ) -> BTreeSet<String> {
+        let mut recipients = if is_stdio(target) || args.replace_recipients {
+            BTreeSet::new()
+        } else {
+            self.recipient_strings_for_file(target)
+        };
+        recipients.extend(args.explicit_recipients());
+        if let Some(other) = &args.recipients_of {
+            let configured = self.recipient_strings_for_file(other);
+            if configured.is_empty() {
+                eprintln!("No recipients configured for {:?}", other);
+                std::process::exit(1);
+            }
+            recipients.extend(configured);
+        }
+        recipients
+    }
+
+    /// The recipient splitting the file key between the share holders of a threshold file
+    fn quorum_for_target(
+        &self,
+        target: &Path,
+        threshold: u8,
+        args: &RecipientArgs,
+    ) -> Vec<Box<dyn Recipient + Send>> {
+        if !args.explicit_recipients().is_empty() || args.recipients_of.is_some() {
+            eprintln!(
+                "{:?} needs {} share holders to decrypt, extra recipients would bypass that",
+                target, threshold
+            );
+            std::process::exit(1);
+        }
+        exit_on_invalid_recipients(target, &check::invalid_recipients_for(self, target));
+        let holders = self.recipient_strings_for_file(target);
+        if let Err(e) = quorum::validate(threshold, &holders) {
+            eprintln!("Invalid threshold for {:?}: {}", target, e);
+            std::process::exit(1);
+        }
+        eprintln!(
+            "Share holders for {} ({} needed to decrypt):",
+            target.display(),
+            threshold
+        );
+        for holder in &holders {
+            eprintln!(" - {}", holder);
+        }
+        vec![Box::new(quorum::QuorumRecipient::new(threshold, &holders))]
+    }
+}
+
+/// Normalize how a recipient is written, so the same key always sorts (and so is wrapped in
+/// the header) in the same position regardless of comments, spacing or case
+fn canonical_recipient(recipient: &str) -> String {
+    let recipient = recipient.trim();
+    if recipient.starts_with("ssh-") {
+        // Drop the comment, it isn't part of the key
+        recipient
+            .split_whitespace()
+            .take(2)
+            .collect::<Vec<&str>>()
+            .join(" ")
+    } else if recipient.to_lowercase().starts_with("age1") {
+        recipient.to_lowercase()
+    } else {
+        recipient.to_string()
+    }
+}
+
+/// Abort before encrypting `target` when any of its recipients can't be parsed, listing all of
+/// them so a typo'd key can be found without fixing them one run at a time
+fn exit_on_invalid_recipients(target: &Path, problems: &[check::Problem]) {
+    if problems.is_empty() {
+        return;
+    }
+    eprintln!(
+        "Unable to encrypt {}, {} recipient(s) are not valid age or ssh public keys:",
+        target.display(),
+        problems.len()
+    );
+    for problem in problems {
+        eprintln!(" - {}", problem);
+    }
+    eprintln!("Run `arcanum lint` to check the rest of the config.");
+    std::process::exit(1);
+}
+
+/// Whether the ciphertext at `source` with the header `stanzas` was encrypted to exactly
+/// `recipients`. Headers don't say which age keys they are for, so with any among `recipients`
+/// the ones recorded in its metadata have to match too.
+fn encrypted_to(source: &Path, stanzas: &[header::Stanza], recipients: &BTreeSet<String>) -> bool {
+    header::matches_recipients(stanzas, recipients)
+        && (!recipients.iter().any(|r| r.starts_with("age1"))
+            || metadata::encrypted_to(source, recipients))
+}
+
+/// Parse `recipients` in their sorted order, so stanzas are emitted in a canonical order
+fn parse_recipients(
+    target: &Path,
+    recipients: &BTreeSet<String>,
+) -> Vec<Box<dyn Recipient + Send>> {
+    if !recipients.is_empty() {
+        eprintln!("Recipients for {}:", target.display());
+        for recipient in recipients {
+            eprintln!(" - {}", recipient);
+        }
+    }
+
+    recipients.iter().map(|r| parse_recipient(r)).collect()
+}
+
+fn parse_recipient(recipient: &str) -> Box<dyn Recipient + Send> {
+    if let Some(parsed) = envelope::parse(recipient) {
+        return Box::new(parsed.unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }));
+    }
+    let parsed: Option<Box<dyn Recipient + Send>> = if recipient.starts_with("age1") {
+        age::x25519::Recipient::from_str(recipient)
+            .ok()
+            .map(|r| Box::new(r) as _)
+    } else {
+        age::ssh::Recipient::from_str(recipient)
+            .ok()
+            .map(|r| Box::new(r) as _)
+    };
+    parsed.unwrap_or_else(|| {
+        eprintln!("{:?} is not a valid age or ssh public key", recipient);
+        eprintln!("Run `arcanum lint` to check the config for more problems like this.");
+        std::process::exit(1);
+    })
+}
+
+fn main() {
+    let cli = Cli::parse();
+    output::init(cli.plain);
+    confirm::init(cli.yes, cli.no_input);
+    trace::init(cli.trace_file.as_deref());
+    backup::init(cli.backup, cli.backup_dir.as_deref());
+    verify::init(cli.verify);
+    editor::init(cli.editor.as_deref());
+    // Relative to where arcanum was run, before `--project` changes directory
+    cache::init_dir(absolute_arg(cli.cache_dir.as_deref()));
+    check_root(&cli);
+
+    // Runs in the nix build sandbox, outside any project
+    if let Commands::CheckJson = &cli.command {
+        if !check::check_json() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Nor are anyone's published keys
+    if let Commands::FetchKeys { account } = &cli.command {
+        let mut ok = true;
+        for account in account {
+            match providers::fetch_keys(account) {
+                Some(Ok(keys)) => keys.iter().for_each(|key| println!("{}", key)),
+                Some(Err(e)) => {
+                    eprintln!("Unable to fetch the keys of {}: {}", account, e);
+                    ok = false;
+                }
+                None => {
+                    eprintln!("{:?} isn't github:<user> or gitlab:<user>", account);
+                    ok = false;
+                }
+            }
+        }
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    // A user's identity isn't tied to a project either
+    if let Commands::InitUser { owner } = &cli.command {
+        if !onboard::init_user(owner.as_deref()) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // The agent serves every project, so it doesn't need one
+    if let Commands::Agent { ttl } = &cli.command {
+        if !agent::run(identity_sources(&cli, None), *ttl) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // The schema, doctor and listing or collecting caches are about every project, not the current
+    // one, so they work outside of a project too
+    match &cli.command {
+        // Doctor repairs the cache itself, so it has to run before anything tries to load it
+        Commands::Doctor { fix } => {
+            if !doctor::doctor(&cache_directory(), *fix) {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Commands::Schema { version } => {
+            let Some(schema) = api::schema(*version) else {
+                eprintln!(
+                    "Unknown API version {}, known: {:?}",
+                    version,
+                    api::VERSIONS
+                );
+                std::process::exit(1);
+            };
+            print!("{}", schema);
+            return;
+        }
+        Commands::Cache {
+            command: Some(cache::CacheCommand::List { json }),
+        } => {
+            if !cache::list(&cache_directory(), *json) {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Commands::Cache {
+            command:
+                Some(cache::CacheCommand::Gc {
+                    older_than_days,
+                    dry_run,
+                }),
+        } => {
+            if !cache::gc(&cache_directory(), *older_than_days, *dry_run) {
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    // Relative to where arcanum was run, before `--project` changes directory
+    let explicit_cache = absolute_arg(cli.cache_file.as_deref());
+    let config_json = absolute_arg(cli.config_json.as_deref());
+    let project_root = project_root(&cli);
+    let project_config = project_config::load(&project_root);
+    // Flags choosing the flake override a file or url the project config chooses
+    let flake_flags = cli.flake.is_some() || cli.attribute.is_some();
+    config_source::init(
+        config_json,
+        Some(&project_config.eval).filter(|_| !flake_flags),
+        &project_root,
+    );
+    cache::init_eval_target(
+        cli.flake.clone().or(project_config.eval.flake),
+        cli.attribute.clone().or(project_config.eval.attribute),
+    );
+    if cli.no_eval {
+        cache::forbid_eval();
+    }
+    cache::init_encryption(cli.encrypt_cache);
+    // Read lazily, so commands that don't decrypt anything don't need identities
+    let identities = IdentityStore::new(identity_sources(&cli, Some(&project_root)));
+
+    // Migrating has to happen before a cache is loaded too
+    if let Commands::Cache {
+        command: Some(cache::CacheCommand::Migrate),
+    } = &cli.command
+    {
+        if !cache::migrate(&cache_directory()) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let cache_file_path = explicit_cache
+        .clone()
+        .unwrap_or_else(|| cache_file_path(&project_root));
+    if let Commands::Cache {
+        command: None | Some(cache::CacheCommand::Generate),
+    } = &cli.command
+    {
+        eprintln!("Using cache file at {:?}", cache_file_path);
+        generate_cache_file(&project_root, &cache_file_path, &identities);
+        return;
+    }
+    if let Commands::Lock { check } = &cli.command {
+        let lock_path = lock::path(&project_root);
+        if *check {
+            match lock::load(&project_root) {
+                Some(Ok(locked)) if locked.current => eprintln!("{:?} is current", lock_path),
+                Some(Ok(_)) => {
+                    eprintln!("{:?} is stale, run `arcanum lock`", lock_path);
+                    std::process::exit(1);
+                }
+                Some(Err(e)) => {
+                    eprintln!("Unable to read {:?}: {}", lock_path, e);
+                    std::process::exit(1);
+                }
+                None => {
+                    eprintln!("{:?} does not exist, run `arcanum lock`", lock_path);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        let cache = generate_cache_file(&project_root, &cache_file_path, &identities);
+        match lock::write(&cache, &project_root) {
+            Ok(true) => eprintln!("Wrote {:?}", lock_path),
+            Ok(false) => eprintln!("{:?} is unchanged", lock_path),
+            Err(e) => {
+                eprintln!("Unable to write {:?}: {}", lock_path, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    // An explicit cache file wins over the lock file, that's what it was given for
+    let locked = explicit_cache
+        .is_none()
+        .then(|| locked_cache(&project_root))
+        .flatten();
+    let mut cache: CacheFile = locked.unwrap_or_else(|| {
+        eprintln!("Using cache file at {:?}", cache_file_path);
+        load_cache_file(
+            &project_root,
+            &cache_file_path,
+            explicit_cache.is_some(),
+            &identities,
+        )
+    });
+    // Lock and explicit cache files don't record it, paths are matched relative to it
+    cache.project = Some(project_root.clone());
+    cache.revoked = revoke::load_revoked(&project_root);
+    if !cache.failed.is_empty() {
+        eprintln!("warning: the cache is incomplete, these sections failed to evaluate:");
+        for section in &cache.failed {
+            eprintln!("warning:   {}", section);
+        }
+        eprintln!("warning: their secrets are unknown, run `arcanum cache` once they are fixed");
+    }
+
+    // You can check for the existence of subcommands, and if found use their
+    // matches just as you would the top level cmd
+    match &cli.command {
+        Commands::Encrypt {
+            plaintext,
+            ciphertext,
+            raw,
+            mode,
+            recipients,
+        } => {
+            if !multi_edit::is_glob(ciphertext) {
+                encrypt(
+                    &cache,
+                    plaintext,
+                    ciphertext,
+                    *raw,
+                    *mode,
+                    recipients,
+                    &identities,
+                );
+                return;
+            }
+            if !plaintext.is_dir() {
+                eprintln!(
+                    "{:?} matches several files, the plaintext must be a directory holding them",
+                    ciphertext
+                );
+                std::process::exit(1);
+            }
+            for source in multi_edit::expand_sources(&cache, std::slice::from_ref(ciphertext)) {
+                let file = plaintext.join(multi_edit::plaintext_name(&source));
+                encrypt(&cache, &file, &source, *raw, *mode, recipients, &identities);
+            }
+        }
+        Commands::Decrypt {
+            ciphertext,
+            plaintext,
+            to_cmd,
+            raw,
+            mode,
+            force,
+        } => {
+            if multi_edit::is_glob(ciphertext) {
+                let Some(dir) = plaintext.as_deref().filter(|p| !is_stdio(p)) else {
+                    eprintln!(
+                        "{:?} matches several files, give a directory to decrypt them into",
+                        ciphertext
+                    );
+                    std::process::exit(1);
+                };
+                let sources = multi_edit::expand_sources(&cache, std::slice::from_ref(ciphertext));
+                for source in sources {
+                    let file = dir.join(multi_edit::plaintext_name(&source));
+                    ignore::guard(&file, *force);
+                    if file.exists()
+                        && !confirm::confirm_attended(
+                            confirm::Action::Overwrite,
+                            &format!("{:?} already exists, overwrite it?", file),
+                        )
+                    {
+                        continue;
+                    }
+                    let plaintext_data = decrypt(&cache, &source, *raw, &identities);
+                    let (mode, owner) = plaintext_permissions(&cache, &source, &file, *mode);
+                    write_plaintext(&file, &plaintext_data, mode, owner);
+                }
+                return;
+            }
+            if let Some(plaintext) = plaintext.as_deref().filter(|p| !is_stdio(p)) {
+                ignore::guard(plaintext, *force);
+                if plaintext.exists()
+                    && !confirm::confirm_attended(
+                        confirm::Action::Overwrite,
+                        &format!("{:?} already exists, overwrite it?", plaintext),
+                    )
+                {
+                    std::process::exit(1);
+                }
+            }
+            let plaintext_data = decrypt(&cache, ciphertext, *raw, &identities);
+            let Some(plaintext) = plaintext else {
+                let command = to_cmd.as_deref().unwrap();
+                std::process::exit(pipe_to_command(command, &plaintext_data));
+            };
+            if is_stdio(plaintext) {
+                std::io::stdout().write_all(&plaintext_data).unwrap();
+            } else {
+                let (mode, owner) = plaintext_permissions(&cache, ciphertext, plaintext, *mode);
+                write_plaintext(plaintext, &plaintext_data, mode, owner);
+            }
+        }
+        #[cfg(feature = "clipboard")]
+        Commands::Copy {
+            ciphertext,
+            key,
+            clear_after,
+        } => {
+            let value = field::value(&cache, ciphertext, key.as_deref(), &identities);
+            clipboard::copy(&value, *clear_after);
+        }
+        Commands::Grep { args } => {
+            if !grep::grep(&cache, args, &identities) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Serve { port } => {
+            let token_dir =
+                cache_directory().join(format!("arcanum-{}-serve", project_hash(&project_root)));
+            serve::serve(
+                &cache,
+                &project_config.serve.clients,
+                *port,
+                &token_dir,
+                &identities,
+            );
+        }
+        Commands::Totp { ciphertext, key } => {
+            let uri = field::value(&cache, ciphertext, key.as_deref(), &identities);
+            let totp = totp::Totp::parse(&uri).unwrap_or_else(|e| {
+                eprintln!(
+                    "Unable to read a one-time code secret from {:?}: {}",
+                    ciphertext, e
+                );
+                std::process::exit(1);
+            });
+            let (code, remaining) = totp.now();
+            println!("{}", code);
+            eprintln!("valid for {} more seconds", remaining);
+        }
+        Commands::Merge {
+            base,
+            ours,
+            theirs,
+            path,
+            show_plaintext,
+        } => {
+            if !merge::merge(
+                &cache,
+                base,
+                ours,
+                theirs,
+                path,
+                *show_plaintext,
+                &identities,
+            ) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Resolve { ciphertext, stage } => {
+            if !merge::resolve(&cache, ciphertext, *stage, &identities) {
+                std::process::exit(1);
+            }
+        }
+        Commands::History {
+            ciphertext,
+            max_count,
+        } => history::history(&cache, ciphertext, *max_count, &identities),
+        Commands::Restore {
+            ciphertext,
+            rev,
+            recipients,
+        } => history::restore(&cache, ciphertext, rev, &identities, recipients),
+        Commands::Info { ciphertext } => info::info(&cache, ciphertext, &identities),
+        Commands::CertInfo { ciphertext } => cert::cert_info(&cache, ciphertext, &identities),
+        Commands::Diff {
+            ciphertext,
+            other,
+            rev,
+        } => {
+            if diff::diff(&cache, ciphertext, other.as_deref(), rev, &identities) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Rekey {
+            ciphertext,
+            all: _,
+            sections,
+            only_missing,
+            recipients,
+        } => {
+            let bulk = ciphertext.is_empty();
+            let mut ciphertexts = if bulk {
+                // Files not encrypted yet have nothing to rekey
+                cache
+                    .sources()
+                    .into_iter()
+                    .filter(|source| cache.resolve_source(source).exists())
+                    .collect()
+            } else {
+                multi_edit::expand_sources(&cache, ciphertext)
+            };
+            if !sections.is_empty() {
+                let in_sections = cache.sources_in(sections);
+                ciphertexts.retain(|c| in_sections.contains(&cache.project_relative(c)));
+                if ciphertexts.is_empty() {
+                    eprintln!("No files to rekey match the section and tag filters");
+                    std::process::exit(1);
+                }
+            }
+            if *only_missing {
+                ciphertexts.retain(|ciphertext| {
+                    let current = cache.is_current(ciphertext, recipients);
+                    if current {
+                        eprintln!("{:?} is already encrypted to its recipients", ciphertext);
+                    }
+                    !current
+                });
+            }
+            let sources: Vec<PathBuf> = ciphertexts
+                .iter()
+                .map(|ciphertext| cache.resolve_source(ciphertext))
+                .collect();
+            if bulk && ciphertexts.len() > 1 {
+                confirm::require(
+                    confirm::Action::Rekey,
+                    &format!("Re-encrypt {} files?", ciphertexts.len()),
+                );
+            }
+            let plaintexts = plaintexts_from_ciphertext_sources(&sources, &identities);
+            for (ciphertext, plaintext) in ciphertexts.iter().zip(plaintexts) {
+                rekey_plaintext(&cache, ciphertext, &plaintext, &identities, recipients);
+            }
+        }
+        Commands::Edit {
+            ciphertext,
+            all_matching,
+            stdin,
+            recipients,
+        } => {
+            let mut ciphertexts = multi_edit::expand_sources(&cache, ciphertext);
+            if let Some(pattern) = all_matching {
+                let matching = multi_edit::matching_sources(&cache, pattern);
+                if matching.is_empty() {
+                    eprintln!("No managed files match {:?}", pattern);
+                    std::process::exit(1);
+                }
+                ciphertexts.extend(matching);
+            }
+            match ciphertexts.as_slice() {
+                [ciphertext] => edit(&cache, ciphertext, &identities, recipients, *stdin),
+                _ if *stdin => {
+                    eprintln!("--stdin edits a single file");
+                    std::process::exit(1);
+                }
+                _ => multi_edit::edit_many(&cache, &ciphertexts, &identities, recipients),
+            }
+        }
+        Commands::Install {
+            host,
+            changed_exit_code,
+            restart,
+            state_file,
+            prune,
+            keep,
+        } => {
+            let host = host.clone().unwrap_or_else(|| {
+                let hostname = fsutil::hostname();
+                hostname.split('.').next().unwrap_or_default().to_string()
+            });
+            let options = install::InstallOptions {
+                state_file: state_file
+                    .clone()
+                    .unwrap_or_else(install::default_state_file),
+                restart: *restart,
+                prune: *prune,
+                keep: keep
+                    .iter()
+                    .map(|pattern| {
+                        glob::Pattern::new(pattern).unwrap_or_else(|e| {
+                            eprintln!("Invalid --keep pattern {:?}: {}", pattern, e);
+                            std::process::exit(1);
+                        })
+                    })
+                    .collect(),
+            };
+            let report = install::install(&cache, &project_root, &host, &identities, &options);
+            if !report.ok {
+                std::process::exit(1);
+            }
+            if let Some(code) = changed_exit_code {
+                if report.changed > 0 {
+                    std::process::exit(*code);
+                }
+            }
+        }
+        Commands::Agent { .. }
+        | Commands::InitUser { .. }
+        | Commands::FetchKeys { .. }
+        | Commands::Doctor { .. }
+        | Commands::CheckJson
+        | Commands::Schema { .. }
+        | Commands::Lock { .. } => unreachable!(),
+        Commands::InitHost { name, key, rekey } => {
+            if !onboard::init_host(&cache, name, key, *rekey, &identities) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Hosts { json } => {
+            inventory::hosts(&cache, *json);
+        }
+        Commands::Recipients { filter, json } => {
+            inventory::recipients(&cache, filter.as_deref(), *json);
+        }
+        Commands::Kv { store, command } => {
+            kv::kv(&cache, store, command, &identities);
+        }
+        Commands::SelfCheck {
+            command: SelfCommand::Verify,
+        } => {
+            if !provenance::verify(cache.build.as_ref()) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Status => {
+            let mut files: BTreeMap<&Path, &ArcanumFile> = BTreeMap::new();
+            // Tags of every section declaring a source
+            let mut tags: BTreeMap<&Path, BTreeSet<&str>> = BTreeMap::new();
+            for (_, _, file) in cache.entries() {
+                files.entry(&file.source).or_insert(file);
+                tags.entry(&file.source)
+                    .or_default()
+                    .extend(file.tags.iter().map(String::as_str));
+            }
+            for (source, file) in files {
+                let status = cache.status_of(file);
+                let tags = &tags[source];
+                let tags = if tags.is_empty() {
+                    String::new()
+                } else {
+                    format!(" [{}]", tags.iter().copied().collect::<Vec<_>>().join(", "))
+                };
+                match &status {
+                    FileStatus::Legacy(legacy) => println!(
+                        "{:<10} {}{} (still at {})",
+                        status.label(),
+                        source.display(),
+                        tags,
+                        legacy.display()
+                    ),
+                    _ => println!("{:<10} {}{}", status.label(), source.display(), tags),
+                }
+            }
+            for tombstone in tombstone::tombstones(&cache, &project_root) {
+                match &tombstone.removed_in {
+                    Some(commit) => println!(
+                        "{:<10} {} (removed in {})",
+                        "tombstone",
+                        tombstone.path.display(),
+                        commit
+                    ),
+                    None => println!("{:<10} {}", "tombstone", tombstone.path.display()),
+                }
+            }
+        }
+        Commands::ShellHook { shell } => {
+            if !shell_hook::shell_hook(&cache, &project_root, shell, &identities) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Watch { shell } => {
+            if !shell_hook::watch(&cache, &cache_file_path, &project_root, shell, &identities) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Gc => {
+            if !tombstone::gc(&cache, &project_root) {
+                std::process::exit(1);
+            }
+        }
+        #[cfg(feature = "tui")]
+        Commands::Tui => {
+            tui::run(&cache).unwrap();
+        }
+        Commands::Rotate {
+            ciphertext,
+            recipients,
+        } => {
+            if !rotation::rotate(&cache, &project_root, ciphertext, recipients, &identities) {
+                std::process::exit(1);
+            }
+        }
+        Commands::RotateCheck { within_days } => {
+            if rotation::rotate_check(&cache, *within_days) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Expiring {
+            within,
+            certificates,
+        } => {
+            let certificates = Some(&identities).filter(|_| *certificates);
+            if expiry::expiring(&cache, &project_root, *within, certificates) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Revoke { recipient, rekey } => {
+            revoke::revoke(&mut cache, &project_root, recipient, *rekey, &identities);
+        }
+        Commands::Lint { json } => {
+            if !check::lint(&cache, *json) {
+                std::process::exit(1);
+            }
+        }
+        Commands::AuditExport {
+            output,
+            recipient,
+            filter,
+            valid_days,
+        } => {
+            audit::export(
+                &cache,
+                recipient,
+                filter.as_deref(),
+                *valid_days,
+                output,
+                &identities,
+            );
+        }
+        Commands::ScanLeaks { no_history, paths } => {
+            if leaks::scan(&cache, &project_root, !no_history, paths, &identities) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Analyze {
+            ciphertext,
+            breached,
+        } => {
+            if analyze::analyze(&cache, ciphertext, breached.as_deref(), &identities) {
+                std::process::exit(1);
+            }
+        }
+        Commands::ExportK8s {
+            ciphertext,
+            sections,
+            name,
+            namespace,
+            apply,
+        } => {
+            let mut ciphertexts = multi_edit::expand_sources(&cache, ciphertext);
+            if !sections.is_empty() {
+                let listed: BTreeSet<PathBuf> = ciphertexts
+                    .iter()
+                    .map(|c| cache.project_relative(c))
+                    .collect();
+                let in_sections = cache.sources_in(sections);
+                ciphertexts.extend(in_sections.into_iter().filter(|s| !listed.contains(s)));
+            }
+            if !k8s::export(&cache, &ciphertexts, name, namespace, *apply, &identities) {
+                std::process::exit(1);
+            }
+        }
+        Commands::DockerSecret {
+            ciphertext,
+            id,
+            command,
+        } => std::process::exit(docker::docker_secret(
+            &cache,
+            &project_root,
+            ciphertext,
+            id.as_deref(),
+            command,
+            &identities,
+        )),
+        Commands::External => external::external(&cache, &identities),
+        Commands::EncryptExec {
+            ciphertext,
+            recipients,
+            command,
+        } => {
+            if ciphertext.exists() {
+                confirm::require(
+                    confirm::Action::Overwrite,
+                    &format!("{:?} already exists, overwrite it?", ciphertext),
+                );
+            }
+            let (program, args) = command.split_first().unwrap();
+            let output = command_output(Command::new(program).args(args), program);
+            encrypt_data(
+                &cache,
+                output,
+                ciphertext,
+                false,
+                None,
+                recipients,
+                &identities,
+            );
+        }
+        Commands::Import {
+            reference,
+            ciphertext,
+            recipients,
+        } => {
+            if ciphertext.exists() {
+                confirm::require(
+                    confirm::Action::Overwrite,
+                    &format!("{:?} already exists, overwrite it?", ciphertext),
+                );
+            }
+            let value = import::read(reference).unwrap_or_else(|e| {
+                eprintln!("Unable to read {}: {}", reference, e);
+                std::process::exit(1);
+            });
+            if value.is_empty() {
+                eprintln!("{} is empty, not importing it", reference);
+                std::process::exit(1);
+            }
+            encrypt_data(
+                &cache,
+                value,
+                ciphertext,
+                false,
+                None,
+                recipients,
+                &identities,
+            );
+        }
+        Commands::RenderEnv {
+            template,
+            format,
+            output,
+        } => {
+            if let Some(output) = output.as_deref().filter(|p| p.exists()) {
+                confirm::require(
+                    confirm::Action::Overwrite,
+                    &format!("{:?} already exists, overwrite it?", output),
+                );
+            }
+            let rendered =
+                dotenv::render(&cache, template, *format, &identities).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                });
+            match output {
+                Some(output) => {
+                    fsutil::write_atomic(output, rendered.as_bytes(), 0o600, None).unwrap();
+                    eprintln!("Wrote {} to {:?}", template, output);
+                }
+                None => print!("{}", rendered),
+            }
+        }
+        Commands::AuditImport { bundle, directory } => {
+            audit::import(bundle, directory, &identities);
+        }
+        Commands::Cache {
+            command: Some(cache::CacheCommand::Show { format }),
+        } => api::show(&cache, *format),
+        Commands::Cache {
+            command: Some(cache::CacheCommand::Emit { version }),
+        } => {
+            let Some(document) = api::emit(&cache, *version) else {
+                eprintln!(
+                    "Unknown API version {}, known: {:?}",
+                    version,
+                    api::VERSIONS
+                );
+                std::process::exit(1);
+            };
+            println!("{}", document);
+        }
+        Commands::Cache {
+            command:
+                None
+                | Some(
+                    cache::CacheCommand::Generate
+                    | cache::CacheCommand::Migrate
+                    | cache::CacheCommand::List { .. }
+                    | cache::CacheCommand::Gc { .. },
+                ),
+        } => unreachable!(),
+    }
+}
+
+#[cfg(unix)]
+fn running_as_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
 }
 
-#[derive(Subcommand)]
-enum Commands {
-    /// Encrypt a file
-    Encrypt {
-        plaintext: PathBuf,
-        ciphertext: PathBuf,
-    },
+#[cfg(not(unix))]
+fn running_as_root() -> bool {
+    false
+}
 
-    /// Decrypt a file
-    Decrypt {
-        ciphertext: PathBuf,
-        plaintext: PathBuf,
-    },
+fn check_root(cli: &Cli) {
+    if !running_as_root() || cli.allow_root {
+        return;
+    }
+    match cli.command.root_policy() {
+        RootPolicy::Allow => {}
+        RootPolicy::Warn => {
+            eprintln!("Warning: running as root, any cache files written will be owned by root.");
+        }
+        RootPolicy::Refuse => {
+            eprintln!("Refusing to run this command as root, as it would leave root-owned plaintext and cache files behind.");
+            eprintln!("Run it as your own user, or pass --allow-root if this is intended.");
+            std::process::exit(1);
+        }
+    }
+}
 
-    /// Edit the plaintext of a file
-    Edit { ciphertext: PathBuf },
+fn project_root(cli: &Cli) -> PathBuf {
+    if let Some(project) = &cli.project {
+        if !project.is_dir() {
+            eprintln!("Project path {:?} is not a directory", project);
+            std::process::exit(1);
+        }
+        // Canonicalize so the cache file hash matches the one derived from discovery
+        let root = project.canonicalize().unwrap();
+        std::env::set_current_dir(&root).unwrap();
+        return root;
+    }
+    let cwd = std::env::current_dir().unwrap();
+    // Linked worktrees have a `.git` file rather than a directory, so fall back to asking git
+    let root = find_project_root(cwd.clone())
+        .or_else(|| git::toplevel(&cwd))
+        .or_else(|| standalone::find_root(&cwd));
+    match root {
+        Some(root) => root,
+        None => {
+            eprintln!("Could not find project root, are you in a project? (or pass --project)");
+            std::process::exit(1);
+        }
+    }
+}
 
-    /// Re-encrypt a file to all configured recipients
-    Rekey { ciphertext: PathBuf },
+/// Encrypt the file `plaintext` to `ciphertext`, reversing the ciphertext's transforms unless
+/// `raw`
+fn encrypt(
+    cache: &CacheFile,
+    plaintext: &Path,
+    ciphertext: &Path,
+    raw: bool,
+    mode: Option<u32>,
+    args: &RecipientArgs,
+    identities: &IdentityStore,
+) {
+    let data = if is_stdio(plaintext) || plaintext.exists() {
+        read_input(plaintext).unwrap()
+    } else {
+        eprintln!("plaintext does not exist at {:?}, aborting", plaintext);
+        return;
+    };
+    encrypt_data(cache, data, ciphertext, raw, mode, args, identities);
+}
 
-    /// Regenerate a cache file for the current project
-    ///
-    /// Needed when adding new files to the project or changing the recipients.
-    Cache,
+/// Encrypt `data` to `ciphertext`, reversing the ciphertext's transforms unless `raw`
+fn encrypt_data(
+    cache: &CacheFile,
+    data: Vec<u8>,
+    ciphertext: &Path,
+    raw: bool,
+    mode: Option<u32>,
+    args: &RecipientArgs,
+    identities: &IdentityStore,
+) {
+    let data = if raw {
+        data
+    } else {
+        let transforms = cache.transforms_for_file(ciphertext);
+        transform::reverse_all(&transforms, data).unwrap_or_else(|e| {
+            eprintln!("Unable to prepare plaintext for {:?}: {}", ciphertext, e);
+            std::process::exit(1);
+        })
+    };
+    let recipients = cache.recipients_for_target(ciphertext, args);
+    if recipients.is_empty() {
+        cache.report_no_recipients(ciphertext);
+        return;
+    }
+    let mode = mode.unwrap_or_else(|| ciphertext_mode(ciphertext));
+    write_encrypted(cache, ciphertext, &data, recipients, mode, identities)
+        .unwrap_or_else(|e| exit_unwritable(ciphertext, e));
+    if !is_stdio(ciphertext) {
+        eprintln!("Wrote ciphertext to {:?}", ciphertext);
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct ArcanumFile {
-    dest: PathBuf,
-    source: PathBuf,
-    directory_permissions: String,
-    make_directory: bool,
-    group: String,
-    owner: String,
-    permissions: String,
-    recipients: Vec<String>,
+/// The plaintext of `ciphertext` with its transforms applied unless `raw`, empty when it
+/// couldn't be read
+fn decrypt(cache: &CacheFile, ciphertext: &Path, raw: bool, identities: &IdentityStore) -> Vec<u8> {
+    let source = cache.resolve_source(ciphertext);
+    let plaintext_data = plaintext_from_ciphertext_source(&source, identities);
+    if raw || plaintext_data.is_empty() {
+        return plaintext_data;
+    }
+    let transforms = cache.transforms_for_file(ciphertext);
+    transform::apply_all(&transforms, plaintext_data).unwrap_or_else(|e| {
+        eprintln!("Unable to transform plaintext of {:?}: {}", ciphertext, e);
+        std::process::exit(1);
+    })
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct ArcanumConfig {
-    files: HashMap<String, ArcanumFile>,
-    admin_recipients: Vec<String>,
+/// Mode plaintext is written with unless configured otherwise, whatever the umask
+const PLAINTEXT_MODE: u32 = 0o600;
+
+/// Mode and owner for the plaintext of `ciphertext` written to `plaintext`: the configured
+/// ones when that is the file's destination, else `PLAINTEXT_MODE` and whoever runs arcanum.
+/// A `mode` given on the command line wins.
+fn plaintext_permissions(
+    cache: &CacheFile,
+    ciphertext: &Path,
+    plaintext: &Path,
+    mode: Option<u32>,
+) -> (u32, Option<(u32, u32)>) {
+    let Some(file) = cache.file_installed_to(ciphertext, plaintext) else {
+        return (mode.unwrap_or(PLAINTEXT_MODE), None);
+    };
+    let configured = fsutil::parse_mode(&file.permissions).unwrap_or_else(|e| {
+        eprintln!("Invalid permissions for {:?}: {}", file.dest, e);
+        std::process::exit(1);
+    });
+    let owner = if file.owner.is_empty() && file.group.is_empty() {
+        None
+    } else if !running_as_root() {
+        eprintln!(
+            "warning: not running as root, {:?} won't be owned by {}:{}",
+            plaintext, file.owner, file.group
+        );
+        None
+    } else {
+        install::file_owner(file).unwrap_or_else(|e| {
+            eprintln!("Unable to look up the owner of {:?}: {}", file.dest, e);
+            std::process::exit(1);
+        })
+    };
+    (mode.unwrap_or(configured), owner)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct CacheFile {
-    nixos: Option<HashMap<String, ArcanumConfig>>,
-    dev_shells: Option<HashMap<String, HashMap<String, ArcanumConfig>>>,
-    home_manager: Option<HashMap<String, HashMap<String, ArcanumConfig>>>,
-    flake: Option<ArcanumConfig>,
+/// Write decrypted `data` to the file `plaintext`, creating private parent directories. The
+/// mode and owner are set before anything is written.
+fn write_plaintext(plaintext: &Path, data: &[u8], mode: u32, owner: Option<(u32, u32)>) {
+    if data.is_empty() {
+        eprintln!("plaintext is empty, not writing to {:?}", plaintext);
+        return;
+    }
+    if let Some(parent) = plaintext.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fsutil::create_dir_all_with_mode(parent, 0o700).unwrap();
+    }
+    fsutil::write_atomic(plaintext, data, mode, owner).unwrap();
+    eprintln!("Wrote plaintext to {:?}", plaintext);
 }
 
-impl CacheFile {
-    fn recipients_for_file(&self, source: &Path) -> Vec<Box<dyn Recipient + Send>> {
-        let mut recipients: BTreeSet<String> = BTreeSet::new();
-        let flake = self.flake.as_ref().unwrap();
-        for (_, file) in &flake.files {
-            if source == file.source {
-                recipients.extend(file.recipients.clone());
-                recipients.extend(flake.admin_recipients.clone());
-            }
-        }
+/// Re-encrypt `ciphertext` to the recipients currently configured for it
+fn rekey(cache: &CacheFile, ciphertext: &Path, identities: &IdentityStore, args: &RecipientArgs) {
+    let source = cache.resolve_source(ciphertext);
+    let plaintext_data = plaintext_from_ciphertext_source(&source, identities);
+    rekey_plaintext(cache, ciphertext, &plaintext_data, identities, args);
+}
 
-        for (_, config) in self.nixos.as_ref().unwrap() {
-            for (_, file) in &config.files {
-                if source == file.source {
-                    recipients.extend(file.recipients.clone());
-                    recipients.extend(config.admin_recipients.clone());
-                }
-            }
-        }
+/// Re-encrypt `plaintext_data`, decrypted from `ciphertext`, to its configured recipients
+fn rekey_plaintext(
+    cache: &CacheFile,
+    ciphertext: &Path,
+    plaintext_data: &[u8],
+    identities: &IdentityStore,
+    args: &RecipientArgs,
+) {
+    let recipients = cache.recipients_for_target(ciphertext, args);
+    if recipients.is_empty() {
+        cache.report_no_recipients(ciphertext);
+        std::process::exit(1);
+    }
+    // The secret stays the same, so does when it was last rotated
+    if !is_stdio(ciphertext) {
+        metadata::note_rekey(ciphertext);
+    }
+    write_encrypted(
+        cache,
+        ciphertext,
+        plaintext_data,
+        recipients,
+        ciphertext_mode(ciphertext),
+        identities,
+    )
+    .unwrap_or_else(|e| exit_unwritable(ciphertext, e));
+    if !is_stdio(ciphertext) {
+        eprintln!("Rekeyed ciphertext at {:?}", ciphertext);
+    }
+}
 
-        for (_, config) in self.home_manager.as_ref().unwrap() {
-            for (_, system) in config {
-                for (_, file) in &system.files {
-                    if source == file.source {
-                        recipients.extend(file.recipients.clone());
-                        recipients.extend(system.admin_recipients.clone());
-                    }
-                }
-            }
-        }
+/// Decrypt `ciphertext` to a temporary file, open it in an editor and re-encrypt any changes.
+/// With `stdin` the new plaintext is read from stdin instead.
+fn edit(
+    cache: &CacheFile,
+    ciphertext: &Path,
+    identities: &IdentityStore,
+    args: &RecipientArgs,
+    stdin: bool,
+) {
+    if stdin && is_stdio(ciphertext) {
+        eprintln!("The ciphertext can't be read from stdin with --stdin");
+        std::process::exit(1);
+    }
+    let recipients = cache.recipients_for_target(ciphertext, args);
+    if recipients.is_empty() {
+        cache.report_no_recipients(ciphertext);
+        std::process::exit(1);
+    }
 
-        for (_, config) in self.dev_shells.as_ref().unwrap() {
-            for (_, system) in config {
-                for (_, file) in &system.files {
-                    if source == file.source {
-                        recipients.extend(file.recipients.clone());
-                        recipients.extend(system.admin_recipients.clone());
-                    }
-                }
-            }
+    // Ciphertext piped in is kept so it can be passed through unchanged
+    let (original_ciphertext, original_plaintext_data) = if is_stdio(ciphertext) {
+        let encrypted = read_input(ciphertext).unwrap();
+        let plaintext = plaintext_from_ciphertext_buffer(&encrypted, identities);
+        (Some(encrypted), plaintext)
+    } else {
+        let source = cache.resolve_source(ciphertext);
+        (None, plaintext_from_ciphertext_source(&source, identities))
+    };
+    let plaintext_data = if stdin {
+        read_input(Path::new("-")).unwrap()
+    } else {
+        edit_in_editor(ciphertext, &original_plaintext_data)
+    };
+    if plaintext_data.is_empty() || plaintext_data == original_plaintext_data {
+        if plaintext_data.is_empty() {
+            eprintln!("edited plaintext is empty, not writing to {:?}", ciphertext);
+        } else {
+            eprintln!("Plaintext is unchanged, not writing to {:?}", ciphertext);
+            eprintln!(
+                "If you want to re-encrypt the files to new recipents, use the 'rekey' command."
+            );
         }
-
-        if !recipients.is_empty() {
-            eprintln!("Recipients for {}:", source.display());
-            for recipient in &recipients {
-                eprintln!(" - {}", recipient);
-            }
+        if let Some(original_ciphertext) = original_ciphertext {
+            write_output(cache, ciphertext, &original_ciphertext, CIPHERTEXT_MODE)
+                .unwrap_or_else(|e| exit_unwritable(ciphertext, e));
         }
+        return;
+    }
+    write_encrypted(
+        cache,
+        ciphertext,
+        &plaintext_data,
+        recipients,
+        ciphertext_mode(ciphertext),
+        identities,
+    )
+    .unwrap_or_else(|e| exit_unwritable(ciphertext, e));
+    if !is_stdio(ciphertext) {
+        eprintln!("Wrote ciphertext to {:?}", ciphertext);
+    }
+}
 
-        let mut boxed_recipients: Vec<Box<dyn Recipient + Send>> = vec![];
-        for r in &recipients {
-            if r.starts_with("age1") {
-                boxed_recipients.push(Box::new(age::x25519::Recipient::from_str(r).unwrap()))
-            } else {
-                boxed_recipients.push(Box::new(age::ssh::Recipient::from_str(r).unwrap()))
-            }
+/// Open `plaintext` in the editor in a temporary file named after `ciphertext`, returning what
+/// it was edited into
+fn edit_in_editor(ciphertext: &Path, plaintext: &[u8]) -> Vec<u8> {
+    let name = if is_stdio(ciphertext) {
+        PathBuf::from("plaintext")
+    } else {
+        multi_edit::plaintext_name(ciphertext)
+    };
+    match workspace::edit(&[(name, plaintext)]) {
+        Ok(mut edited) => edited.remove(0),
+        Err(e) => {
+            eprintln!("Unable to edit the plaintext: {}", e);
+            std::process::exit(1);
         }
-        boxed_recipients
     }
 }
 
-fn main() {
-    let cwd = std::env::current_dir().unwrap();
-    let project_root = find_project_root(cwd);
-    if project_root.is_none() {
-        panic!("Could not find project root, are you in a project?");
-    }
-    let project_root = project_root.unwrap();
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
 
-    let cli = Cli::parse();
+/// Read all of `path`, or stdin when it is `-`
+fn read_input(path: &Path) -> std::io::Result<Vec<u8>> {
+    if is_stdio(path) {
+        let mut buffer = vec![];
+        std::io::stdin().read_to_end(&mut buffer)?;
+        Ok(buffer)
+    } else {
+        std::fs::read(path)
+    }
+}
 
-    let cache_file_path = cache_file_path(&project_root);
-    eprintln!("Using cache file at {:?}", cache_file_path);
-    let cache: CacheFile = load_cache_file(&project_root, &cache_file_path);
+/// What `command` prints to stdout, exiting when it fails or prints nothing. Its stdin and
+/// stderr stay on the terminal, for commands that prompt.
+fn command_output(command: &mut Command, name: &str) -> Vec<u8> {
+    let output = command
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap_or_else(|e| {
+            eprintln!("Unable to run {:?}: {}", name, e);
+            std::process::exit(1);
+        });
+    if !output.status.success() {
+        eprintln!(
+            "{:?} failed ({}), not encrypting its output",
+            name, output.status
+        );
+        std::process::exit(1);
+    }
+    if output.stdout.is_empty() {
+        eprintln!("{:?} printed nothing, not encrypting it", name);
+        std::process::exit(1);
+    }
+    output.stdout
+}
 
-    let identities = identity_files(&cli);
+/// `command` run through the platform's shell
+fn shell(command: &str) -> Command {
+    #[cfg(unix)]
+    let mut shell = Command::new("sh");
+    #[cfg(unix)]
+    shell.arg("-c");
+    #[cfg(not(unix))]
+    let mut shell = Command::new("cmd");
+    #[cfg(not(unix))]
+    shell.arg("/C");
+    shell.arg(command);
+    shell
+}
 
-    // You can check for the existence of subcommands, and if found use their
-    // matches just as you would the top level cmd
-    match &cli.command {
-        Commands::Encrypt {
-            plaintext,
-            ciphertext,
-        } => {
-            let data = if plaintext.display().to_string() == "-" {
-                let mut buffer = String::new();
-                std::io::stdin().read_to_string(&mut buffer).unwrap();
-                buffer.into_bytes()
-            } else if plaintext.exists() {
-                std::fs::read(plaintext).unwrap()
-            } else {
-                eprintln!("plaintext does not exist at {:?}, aborting", plaintext);
-                return;
-            };
-            let recipients = cache.recipients_for_file(ciphertext);
-            if recipients.is_empty() {
-                eprintln!("No recipients found for {:?}", ciphertext);
-                return;
-            }
-            let ciphertext_data = ciphertext_from_plaintext_buffer(&data, recipients);
-            std::fs::write(ciphertext, ciphertext_data).unwrap();
-            eprintln!("Wrote ciphertext to {:?}", ciphertext);
-        }
-        Commands::Decrypt {
-            ciphertext,
-            plaintext,
-        } => {
-            if plaintext.display().to_string() == "-" {
-                let plaintext_data = plaintext_from_ciphertext_source(ciphertext, identities);
-                std::io::stdout().write_all(&plaintext_data).unwrap();
-            } else {
-                let plaintext_data = plaintext_from_ciphertext_source(ciphertext, identities);
-                if plaintext_data.is_empty() {
-                    eprintln!("plaintext is empty, not writing to {:?}", plaintext);
-                    return;
-                }
-                std::fs::write(plaintext, plaintext_data).unwrap();
-                eprintln!("Wrote plaintext to {:?}", plaintext);
-            }
-        }
-        Commands::Rekey { ciphertext } => {
-            let plaintext_data = plaintext_from_ciphertext_source(ciphertext, identities);
-            let recipients = cache.recipients_for_file(ciphertext);
-            let ciphertext_data = ciphertext_from_plaintext_buffer(&plaintext_data, recipients);
-            std::fs::write(ciphertext, ciphertext_data).unwrap();
-            eprintln!("Rekeyed ciphertext at {:?}", ciphertext);
+/// Run `command` through the shell with `data` on its stdin, returning its exit code
+fn pipe_to_command(command: &str, data: &[u8]) -> i32 {
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| {
+            eprintln!("Unable to run {:?}: {}", command, e);
+            std::process::exit(1);
+        });
+    let mut stdin = child.stdin.take().unwrap();
+    match stdin.write_all(data) {
+        // The command is free to stop reading early
+        Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+            eprintln!("Unable to write plaintext to {:?}: {}", command, e);
         }
-        Commands::Edit { ciphertext } => {
-            let recipients = cache.recipients_for_file(ciphertext);
-            if recipients.is_empty() {
-                eprintln!("No recipients found, unable to edit.");
-                std::process::exit(1);
-            }
+        _ => {}
+    }
+    drop(stdin);
+    let status = child.wait().unwrap();
+    status.code().unwrap_or(1)
+}
 
-            let original_plaintext_data = plaintext_from_ciphertext_source(ciphertext, identities.clone());
-            let file_stem = PathBuf::from(ciphertext.file_stem().unwrap());
-            let extension = file_stem.extension().unwrap().to_str().unwrap();
-            let t = temp_file::TempFile::with_suffix(format!(".{}", extension)).unwrap();
-            std::fs::write(t.path(), &original_plaintext_data).unwrap();
-            eprintln!(
-                "Opening plaintext in editor: {}",
-                get_editor().unwrap().display()
-            );
-            edit_file(&t.path()).unwrap();
-            let plaintext_data = std::fs::read(t.path()).unwrap();
-            if plaintext_data.is_empty() {
-                eprintln!("edited plaintext is empty, not writing to {:?}", ciphertext);
-                return;
-            }
-            if plaintext_data == original_plaintext_data {
-                eprintln!("Plaintext is unchanged, not writing to {:?}", ciphertext);
-                eprintln!(
-                    "If you want to re-encrypt the files to new recipents, use the 'rekey' command."
-                );
-                return;
-            }
-            let ciphertext_data = ciphertext_from_plaintext_buffer(&plaintext_data, recipients);
-            let ciphertext_temp = temp_file::with_contents(&ciphertext_data);
+/// Mode new ciphertexts are written with, whatever the umask
+const CIPHERTEXT_MODE: u32 = 0o644;
 
-            // Verify we can decrypt the new ciphertext
-            plaintext_from_ciphertext_source(ciphertext_temp.path(), identities);
+/// Mode to rewrite `ciphertext` with, keeping the mode of an existing file
+fn ciphertext_mode(ciphertext: &Path) -> u32 {
+    fsutil::mode_of(ciphertext).unwrap_or(CIPHERTEXT_MODE)
+}
 
-            std::fs::write(ciphertext, ciphertext_data).unwrap();
-            eprintln!("Wrote ciphertext to {:?}", ciphertext);
-        }
-        Commands::Cache => {
-            generate_cache_file(&project_root, &cache_file_path);
-        }
+/// Write a ciphertext to `path`, or stdout for `-`, and note it in the trace and its metadata
+fn write_output(cache: &CacheFile, path: &Path, data: &[u8], mode: u32) -> std::io::Result<()> {
+    if !is_stdio(path) {
+        return write_ciphertext_file(cache, path, mode, |out| out.write_all(data), |_| Ok(()));
     }
+    let mut stdout = std::io::stdout();
+    stdout.write_all(data)?;
+    stdout.flush()?;
+    trace::ciphertext_written(path, data);
+    Ok(())
 }
 
-fn cache_file_path(project_root: &Path) -> PathBuf {
+/// Write the ciphertext file `path` with `write`, backing up what it replaces and noting it in
+/// the trace and its metadata. `check` sees the staged file first and can stop it replacing
+/// anything by failing.
+fn write_ciphertext_file(
+    cache: &CacheFile,
+    path: &Path,
+    mode: u32,
+    write: impl FnOnce(&mut std::io::BufWriter<&std::fs::File>) -> std::io::Result<()>,
+    check: impl FnOnce(&Path) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    backup::save(path);
+    fsutil::write_atomic_checked(path, mode, None, write, check)?;
+    metadata::write(path, cache.description_for_file(path)).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!(
+                "it was written, but not its metadata {:?}: {}",
+                metadata::path(path),
+                e
+            ),
+        )
+    })?;
+    trace::ciphertext_file_written(path);
+    Ok(())
+}
+
+/// Short hash identifying a project by its root, for naming per-project files
+/// `path` given on the command line or in the environment made absolute, None when it's empty
+/// as an environment variable set to nothing is
+fn absolute_arg(path: Option<&Path>) -> Option<PathBuf> {
+    let path = path.filter(|path| !path.as_os_str().is_empty())?;
+    Some(std::path::absolute(path).unwrap_or_else(|e| {
+        eprintln!("Unable to resolve {:?}: {}", path, e);
+        std::process::exit(1);
+    }))
+}
+
+fn project_hash(project_root: &Path) -> String {
     let mut hasher = Sha3_256::new();
     hasher.update(project_root.to_string_lossy().as_bytes());
     let hash = hasher.finalize();
-    let hash = format!("{:x}", hash)[..8].to_string();
-    let cache_file_name = format!("arcanum-{}.json", hash);
-    let dir = cache_dir().unwrap();
-    if !dir.exists() {
-        std::fs::create_dir_all(&dir).unwrap();
+    format!("{:x}", hash)[..8].to_string()
+}
+
+/// Hash naming the cache of the project at `project_root`, told apart by the target the config
+/// is evaluated from. The default target hashes as the project alone, as caches always have.
+fn cache_hash(project_root: &Path) -> String {
+    let Some(target) = config_source::source().target() else {
+        return project_hash(project_root);
+    };
+    let mut hasher = Sha3_256::new();
+    hasher.update(project_root.to_string_lossy().as_bytes());
+    hasher.update([0]);
+    hasher.update(target.as_bytes());
+    format!("{:x}", hasher.finalize())[..8].to_string()
+}
+
+fn cache_file_path(project_root: &Path) -> PathBuf {
+    let Some(dir) = cache::dir() else {
+        let path = local_cache_path(project_root);
+        eprintln!(
+            "warning: no writable cache directory, keeping the cache in the project, don't \
+             commit {:?}",
+            path
+        );
+        return path;
+    };
+    let cache_file_name = format!("arcanum-{}.json", cache_hash(project_root));
+    fsutil::create_dir_all_with_mode(&dir, 0o700).unwrap_or_else(|e| cache_access_error(&dir, e));
+    dir.join(cache_file_name)
+}
+
+/// Where the cache is kept when there's no cache directory to write to
+fn local_cache_path(project_root: &Path) -> PathBuf {
+    let dir = project_root.join(".arcanum");
+    fsutil::create_dir_all_with_mode(&dir, 0o700).unwrap_or_else(|e| cache_access_error(&dir, e));
+    match config_source::source().target() {
+        Some(_) => dir.join(format!("cache-{}.json", cache_hash(project_root))),
+        None => dir.join("cache.json"),
+    }
+}
+
+/// Lock file held while the cache at `cache` is regenerated. It is kept in the cache directory
+/// even for a cache given with `--cache-file`, so none ends up in the repository, unless there
+/// is no cache directory.
+fn cache_lock_path(cache: &Path) -> PathBuf {
+    match cache::dir() {
+        Some(dir) => {
+            fsutil::create_dir_all_with_mode(&dir, 0o700)
+                .unwrap_or_else(|e| cache_access_error(&dir, e));
+            cache::lock_path(&dir, cache)
+        }
+        None => cache::lock_path(cache.parent().unwrap_or(Path::new(".")), cache),
+    }
+}
+
+/// The cache directory, for commands about every project's cache
+fn cache_directory() -> PathBuf {
+    cache::dir().unwrap_or_else(|| {
+        eprintln!("No writable cache directory, give one with --cache-dir or ARCANUM_CACHE_DIR");
+        std::process::exit(1);
+    })
+}
+
+/// Exit with a pointer to `doctor` when a cache file can't be accessed
+fn cache_access_error(cache: &Path, error: std::io::Error) -> ! {
+    eprintln!("Unable to access cache file at {:?}: {}", cache, error);
+    if error.kind() == std::io::ErrorKind::PermissionDenied {
+        eprintln!("It may have been created by root, run `arcanum doctor --fix` to repair it.");
     }
-    let cache_path = dir.join(cache_file_name);
-    cache_path
+    std::process::exit(1);
 }
 
-fn identity_files(cli: &Cli) -> Vec<String> {
+/// The identities to try, in order: the `--identity` files, then `ARCANUM_IDENTITY`, then
+/// those of the profile given or mapped to `project_root`, or without one the chain configured
+/// in `~/.config/arcanum/identities.toml`, or when there is none the identity `init-user`
+/// generates and the default ssh keys
+fn identity_sources(cli: &Cli, project_root: Option<&Path>) -> Vec<IdentitySource> {
     let mut identities = vec![];
     for identity in &cli.identity {
         if identity.exists() {
-            identities.push(identity.clone().display().to_string());
+            identities.push(IdentitySource::file(identity.display().to_string()));
+        } else {
+            eprintln!("warning: identity file {:?} does not exist", identity);
         }
     }
-    let default_identities = vec![
-        dirs::home_dir().unwrap().join(".ssh/id_ed25519"),
-        dirs::home_dir().unwrap().join(".ssh/id_rsa"),
-    ];
+    identities.extend(identity::from_env());
+    if let Some(profile) = profile::identities(cli.profile.as_deref(), project_root) {
+        identities.extend(profile);
+        return identities;
+    }
+    if let Some(chain) = identity::configured_chain() {
+        identities.extend(chain);
+        return identities;
+    }
+    let ssh_dir = dirs::home_dir().map(|home| home.join(".ssh"));
+    let default_identities = identity::user_identity_path().into_iter().chain(
+        ["id_ed25519", "id_rsa"]
+            .iter()
+            .filter_map(|name| ssh_dir.as_ref().map(|dir| dir.join(name))),
+    );
     for identity in default_identities {
         if identity.exists() {
-            identities.push(identity.display().to_string());
+            identities.push(IdentitySource::file(identity.display().to_string()));
         }
     }
     identities
 }
 
-fn load_cache_file(project_root: &Path, cache: &Path) -> CacheFile {
-    if cache.exists() {
-        let data = std::fs::read_to_string(cache).unwrap();
-        let cache_file: CacheFile = serde_json::from_str(&data).unwrap();
-        cache_file
+/// The config from the project's lock file, unless it has none or it is stale and the
+/// project can be evaluated again
+fn locked_cache(project_root: &Path) -> Option<CacheFile> {
+    let lock_path = lock::path(project_root);
+    let locked = match lock::load(project_root)? {
+        Ok(locked) => locked,
+        Err(e) => {
+            eprintln!("Unable to read {:?}: {}", lock_path, e);
+            eprintln!("Run `arcanum lock` to regenerate it.");
+            std::process::exit(1);
+        }
+    };
+    if !locked.current {
+        eprintln!(
+            "warning: {:?} is stale, the config changed since it was written; run `arcanum lock`",
+            lock_path
+        );
+        if !cache::eval_forbidden() {
+            return None;
+        }
+    }
+    eprintln!("Using lock file at {:?}", lock_path);
+    Some(locked.cache)
+}
+
+/// Read the cache at `cache`, generating it when missing unless it was given explicitly
+fn load_cache_file(
+    project_root: &Path,
+    cache: &Path,
+    explicit: bool,
+    identities: &IdentityStore,
+) -> CacheFile {
+    // A standalone config is cheap to read, so pick up edits without `arcanum cache`
+    if cache.exists() && !standalone::newer_than(project_root, cache) {
+        let data = std::fs::read(cache).unwrap_or_else(|e| cache_access_error(cache, e));
+        cache::decode(&data, identities).unwrap_or_else(|e| {
+            eprintln!("Unable to parse cache file {:?}: {}", cache, e);
+            eprintln!("Run `arcanum cache` to regenerate it.");
+            std::process::exit(1);
+        })
+    } else if explicit {
+        eprintln!("Cache file {:?} does not exist", cache);
+        eprintln!(
+            "Generate it with `arcanum cache --cache-file {}` where nix is available.",
+            cache.display()
+        );
+        std::process::exit(1);
     } else {
-        generate_cache_file(project_root, cache)
+        generate_cache_file(project_root, cache, identities)
     }
 }
 
-fn generate_cache_file(project_root: &Path, cache: &Path) -> CacheFile {
-    let result = Command::new("nix")
-        .arg("eval")
-        .arg("--json")
-        .arg(".#lib.arcanum")
-        .current_dir(project_root)
-        .output()
-        .unwrap();
-    if !result.status.success() {
-        eprintln!("nix eval failed");
-        eprintln!("stdout: {}", String::from_utf8_lossy(&result.stdout));
-        eprintln!("stderr: {}", String::from_utf8_lossy(&result.stderr));
+fn generate_cache_file(project_root: &Path, cache: &Path, identities: &IdentityStore) -> CacheFile {
+    // Serialize regenerations, e.g. by `watch` and a manual `arcanum cache`, so the last one
+    // to finish is the last one evaluated
+    let lock_path = cache_lock_path(cache);
+    let _lock = fsutil::lock_exclusive(&lock_path, 0o600)
+        .unwrap_or_else(|e| cache_access_error(&lock_path, e));
+    let standalone = standalone::find(project_root).filter(|_| standalone::in_use(project_root));
+    let cache_file = if let Some(path) = standalone {
+        standalone::load(&path).unwrap_or_else(|e| {
+            eprintln!("Unable to read {:?}: {}", path, e);
+            std::process::exit(1);
+        })
+    } else if cache::eval_forbidden() {
+        eprintln!(
+            "Unable to generate the cache at {:?}: evaluating with nix is disabled by --no-eval",
+            cache
+        );
+        eprintln!(
+            "Pass a cache file generated elsewhere, e.g. one in the repository, with --cache-file."
+        );
         std::process::exit(1);
+    } else {
+        nix_cache_file(project_root)
+    };
+    let mut cache_file = cache_file;
+    providers::resolve(&mut cache_file, project_root);
+    // A cache kept elsewhere, such as in the repository, shouldn't name this machine's checkout
+    if cache::dir().is_some_and(|dir| cache.starts_with(dir))
+        || cache == project_root.join(".arcanum").join("cache.json")
+    {
+        cache_file.project = Some(project_root.to_path_buf());
     }
-    let data = String::from_utf8(result.stdout).unwrap();
-    let cache_file: CacheFile = serde_json::from_str(&data).unwrap();
-    std::fs::write(cache, data).unwrap();
+    let data = cache::encode(&cache_file, cache, identities).unwrap_or_else(|e| {
+        eprintln!("Unable to encrypt the cache: {}", e);
+        std::process::exit(1);
+    });
+    fsutil::write_atomic(cache, &data, 0o600, None)
+        .unwrap_or_else(|e| cache_access_error(cache, e));
 
     cache_file
 }
 
-fn plaintext_from_ciphertext_source(source: &Path, identities: Vec<String>) -> Vec<u8> {
-    let contents = if source.exists() {
-        let encrypted = std::fs::read(source).unwrap();
-        let armor_reader = ArmoredReader::new(&encrypted[..]);
-        let decryptor = match age::Decryptor::new(armor_reader).unwrap() {
-            age::Decryptor::Recipients(d) => d,
-            _ => unreachable!(),
-        };
-
-        let mut decrypted = vec![];
-        let identity = read_identities(identities, Some(30)).unwrap();
-        let identity_refs: Vec<&dyn Identity> = identity.iter().map(|i| i.as_ref()).collect();
-        let reader = decryptor.decrypt(identity_refs.into_iter());
-        if reader.is_err() {
-            eprintln!("You do not have an identity able to decrypt this file. Exiting.");
+/// Evaluate the project's flake, section by section if it fails as a whole
+fn nix_cache_file(project_root: &Path) -> CacheFile {
+    match cache::eval(project_root, "") {
+        Ok(data) => cache::parse(&data).unwrap_or_else(|e| {
+            eprintln!("Unable to parse the evaluated config: {}", e);
             std::process::exit(1);
+        }),
+        Err(e) => {
+            eprintln!("Evaluating the config failed: {}", e);
+            eprintln!("Evaluating each section on its own instead");
+            let cache_file = cache::eval_sections(project_root);
+            if cache_file.sections.is_empty() {
+                eprintln!("No section could be evaluated");
+                std::process::exit(1);
+            }
+            cache_file
         }
-        let mut reader = reader.unwrap();
-        reader.read_to_end(&mut decrypted).unwrap();
+    }
+}
 
-        decrypted
+fn plaintext_from_ciphertext_source(source: &Path, identities: &IdentityStore) -> Vec<u8> {
+    if is_stdio(source) || source.exists() {
+        let encrypted = read_input(source).unwrap();
+        plaintext_from_ciphertext_buffer(&encrypted, identities)
     } else {
         eprintln!("ciphertext does not exist: {:?}", source);
         vec![]
-    };
-    contents
+    }
+}
+
+/// `plaintext_from_ciphertext_source` for several sources, decrypting them concurrently
+fn plaintexts_from_ciphertext_sources(
+    sources: &[PathBuf],
+    identities: &IdentityStore,
+) -> Vec<Vec<u8>> {
+    if sources.iter().any(|source| is_stdio(source)) {
+        return sources
+            .iter()
+            .map(|source| plaintext_from_ciphertext_source(source, identities))
+            .collect();
+    }
+    let encrypted: Vec<Option<Vec<u8>>> = sources
+        .iter()
+        .map(|source| {
+            if !source.exists() {
+                eprintln!("ciphertext does not exist: {:?}", source);
+                return None;
+            }
+            Some(read_input(source).unwrap())
+        })
+        .collect();
+    let present: Vec<&[u8]> = encrypted.iter().flatten().map(Vec::as_slice).collect();
+    let mut decrypted = concurrent::decrypt_all(&present, identities)
+        .into_iter()
+        .zip(&present);
+    encrypted
+        .iter()
+        .map(|encrypted| match encrypted {
+            None => vec![],
+            Some(_) => {
+                let (result, encrypted) = decrypted.next().unwrap();
+                result.unwrap_or_else(|e| exit_undecryptable(e, encrypted, identities))
+            }
+        })
+        .collect()
+}
+
+fn plaintext_from_ciphertext_buffer(encrypted: &[u8], identities: &IdentityStore) -> Vec<u8> {
+    try_decrypt(encrypted, identities)
+        .unwrap_or_else(|e| exit_undecryptable(e, encrypted, identities))
+}
+
+/// Explain why `encrypted` couldn't be decrypted and exit
+fn exit_undecryptable(e: age::DecryptError, encrypted: &[u8], identities: &IdentityStore) -> ! {
+    if matches!(e, age::DecryptError::NoMatchingKeys) {
+        eprintln!("You do not have an identity able to decrypt this file.");
+        identities.explain_no_match(encrypted);
+    } else {
+        eprintln!("Unable to decrypt: {}", e);
+    }
+    std::process::exit(1);
+}
+
+/// Decrypt `encrypted`, armored or not, with `identities`
+fn try_decrypt(encrypted: &[u8], identities: &IdentityStore) -> Result<Vec<u8>, age::DecryptError> {
+    try_decrypt_with(encrypted, identities.identities())
+}
+
+/// `try_decrypt` with only `identities`, such as the ones read from one identity file
+fn try_decrypt_with(
+    encrypted: &[u8],
+    identities: &[Box<dyn Identity>],
+) -> Result<Vec<u8>, age::DecryptError> {
+    with_unwrap_chain(identities, |chain| decrypt_with(encrypted, chain))
+}
+
+/// Call `f` with everything that can unwrap a file key given `identities`, in the order tried:
+/// the identities themselves, then the shares they unlock combined for threshold files, then
+/// key management services for envelope recipients
+fn with_unwrap_chain<T>(
+    identity: &[Box<dyn Identity>],
+    f: impl FnOnce(Vec<&dyn Identity>) -> T,
+) -> T {
+    let quorum = quorum::QuorumIdentity::new(identity);
+    let chain = identity
+        .iter()
+        .map(|i| i.as_ref())
+        .chain(std::iter::once(&quorum as &dyn Identity))
+        .chain(std::iter::once(
+            &envelope::EnvelopeIdentity as &dyn Identity,
+        ))
+        .collect();
+    f(chain)
+}
+
+/// Decrypt `encrypted`, armored or not, with the first of `chain` that unwraps its file key
+fn decrypt_with(encrypted: &[u8], chain: Vec<&dyn Identity>) -> Result<Vec<u8>, age::DecryptError> {
+    arcanum::decrypt_bytes(&chain, encrypted)
 }
 
 fn ciphertext_from_plaintext_buffer(
     plaintext: &[u8],
     recipients: Vec<Box<dyn Recipient + Send>>,
 ) -> Vec<u8> {
-    let encryptor = age::Encryptor::with_recipients(recipients).unwrap();
-    let mut encrypted = vec![];
-    let mut armored_writer =
-        age::armor::ArmoredWriter::wrap_output(&mut encrypted, Format::AsciiArmor).unwrap();
-    let mut writer = encryptor.wrap_output(&mut armored_writer).unwrap();
-    writer.write_all(plaintext).unwrap();
-    writer.finish().unwrap();
-    armored_writer.finish().unwrap();
-    encrypted
+    arcanum::try_encrypt_bytes(recipients, plaintext).unwrap_or_else(|e| exit_unencryptable(e))
+}
+
+/// Report that the ciphertext `path` couldn't be written and exit
+fn exit_unwritable(path: &Path, e: std::io::Error) -> ! {
+    eprintln!("Unable to write {:?}: {}", path, e);
+    std::process::exit(1);
+}
+
+/// Explain why encrypting failed and exit. Recipients wrapping the file key through a key
+/// management service or gpg fail with an I/O error naming the recipient.
+fn exit_unencryptable(e: age::EncryptError) -> ! {
+    match e {
+        age::EncryptError::Io(e) => eprintln!("Unable to encrypt to {}", e),
+        e => eprintln!("Unable to encrypt: {}", e),
+    }
+    std::process::exit(1);
+}
+
+/// Encrypt `plaintext` to `recipients`, verify it and write it to `ciphertext`. Large secrets
+/// are encrypted and armored straight into the staged destination file rather than into
+/// memory, and verified from there before it replaces anything.
+fn write_encrypted(
+    cache: &CacheFile,
+    ciphertext: &Path,
+    plaintext: &[u8],
+    recipients: Vec<Box<dyn Recipient + Send>>,
+    mode: u32,
+    identities: &IdentityStore,
+) -> std::io::Result<()> {
+    if plaintext.len() < armor::PARALLEL_THRESHOLD || is_stdio(ciphertext) {
+        let ciphertext_data = ciphertext_from_plaintext_buffer(plaintext, recipients);
+        verify::round_trip(ciphertext, &ciphertext_data, plaintext, identities);
+        return write_output(cache, ciphertext, &ciphertext_data, mode);
+    }
+    let encryptor = age::Encryptor::with_recipients(recipients)
+        .unwrap_or_else(|| exit_unencryptable(age::EncryptError::InvalidRecipients));
+    // Kept to be reported once the staged file is removed, rather than as I/O errors
+    let mut unencryptable = None;
+    let mut unverified = None;
+    let written = write_ciphertext_file(
+        cache,
+        ciphertext,
+        mode,
+        |out| {
+            let mut writer = match encryptor.wrap_output(armor::ArmorWriter::new(out)?) {
+                Ok(writer) => writer,
+                Err(e) => {
+                    unencryptable = Some(e);
+                    return Err(std::io::Error::other("unable to encrypt"));
+                }
+            };
+            writer.write_all(plaintext)?;
+            writer.finish()?.finish()?;
+            Ok(())
+        },
+        |staged| {
+            verify::round_trip_file(ciphertext, staged, plaintext, identities).map_err(|e| {
+                unverified = Some(e);
+                std::io::Error::other("not verified")
+            })
+        },
+    );
+    if let Some(e) = unencryptable {
+        exit_unencryptable(e);
+    }
+    if let Some(e) = unverified {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    written
 }
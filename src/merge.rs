@@ -0,0 +1,517 @@
+//! `arcanum merge`: a git merge driver for ciphertexts. The three versions are decrypted into
+//! a private directory, merged with `git merge-file` and the result encrypted back over ours.
+//! JSON and YAML plaintexts are merged key by key first, so changes to different keys never
+//! conflict. Conflicts are encrypted with their markers, to be resolved with `arcanum edit`.
+//!
+//! Enable it with `git config merge.arcanum.driver 'arcanum merge %O %A %B --path %P'` and
+//! `*.age merge=arcanum` in `.gitattributes`.
+//!
+//! Nothing of the plaintexts is printed unless asked for with `--show-plaintext`, as merges
+//! often run in CI and their output ends up in logs.
+//!
+//! With git's `rerere.enabled` set, resolutions made with `arcanum resolve` are remembered,
+//! encrypted, in the git directory and reused when the same conflict comes up again, such as
+//! when rebasing.
+
+use crate::fsutil::{create_dir_all_with_mode, create_with_mode, mode_of, write_atomic};
+use crate::identity::IdentityStore;
+use crate::{
+    ciphertext_from_plaintext_buffer, ciphertext_mode, concurrent, diff, exit_unwritable, git,
+    multi_edit, plaintext_from_ciphertext_source, try_decrypt, verify, workspace, write_output,
+    CacheFile,
+};
+use rand::RngCore;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use similar::{ChangeTag, TextDiff};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Lines of the merged plaintext shown with `--show-plaintext`
+const PREVIEW_LINES: usize = 5;
+
+/// Decrypt the versions of a file git passes to merge, all at once
+fn decrypt_sides(paths: [&Path; 3], identities: &IdentityStore) -> [Vec<u8>; 3] {
+    let encrypted = paths.map(|path| {
+        std::fs::read(path).unwrap_or_else(|e| {
+            eprintln!("Unable to read {:?}: {}", path, e);
+            std::process::exit(1);
+        })
+    });
+    // Git passes an empty file for a side, or base, that doesn't have the file
+    let present: Vec<usize> = (0..3).filter(|i| !encrypted[*i].is_empty()).collect();
+    let to_decrypt: Vec<&[u8]> = present.iter().map(|i| encrypted[*i].as_slice()).collect();
+    let mut plaintexts: [Vec<u8>; 3] = Default::default();
+    for (i, decrypted) in present
+        .into_iter()
+        .zip(concurrent::decrypt_all(&to_decrypt, identities))
+    {
+        plaintexts[i] = decrypted.unwrap_or_else(|e| {
+            eprintln!("Unable to decrypt {:?} to merge it: {}", paths[i], e);
+            std::process::exit(1);
+        });
+    }
+    plaintexts
+}
+
+/// The values of a structured plaintext by dotted key: a JSON or YAML document, or an env
+/// file. None when it is none of those.
+fn flatten(text: &str) -> Option<BTreeMap<String, Value>> {
+    fn walk(value: &Value, prefix: &str, out: &mut BTreeMap<String, Value>) {
+        let join = |key: &str| {
+            if prefix.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", prefix, key)
+            }
+        };
+        match value {
+            Value::Object(map) if !map.is_empty() => {
+                for (key, value) in map {
+                    walk(value, &join(key), out);
+                }
+            }
+            _ => {
+                out.insert(prefix.to_string(), value.clone());
+            }
+        }
+    }
+
+    let document = serde_json::from_str::<Value>(text)
+        .ok()
+        .or_else(|| serde_yaml::from_str::<Value>(text).ok())
+        .filter(Value::is_object);
+    if let Some(document) = document {
+        let mut flat = BTreeMap::new();
+        walk(&document, "", &mut flat);
+        return Some(flat);
+    }
+    let lines: Vec<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    let assignments: BTreeMap<String, Value> = lines
+        .iter()
+        .filter_map(|line| line.strip_prefix("export ").unwrap_or(line).split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), Value::String(value.to_string())))
+        .collect();
+    (!lines.is_empty() && assignments.len() == lines.len()).then_some(assignments)
+}
+
+/// What changed from `old` to `new` without showing any of it: line counts, and the keys
+/// changed when both are structured
+fn summary(old: &[u8], new: &[u8]) -> String {
+    let old = String::from_utf8_lossy(old);
+    let new = String::from_utf8_lossy(new);
+    let (mut added, mut removed) = (0, 0);
+    for change in TextDiff::from_lines(&*old, &*new).iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Insert => added += 1,
+            ChangeTag::Delete => removed += 1,
+            ChangeTag::Equal => {}
+        }
+    }
+    let mut summary = format!("+{} -{} lines", added, removed);
+    if let (Some(old), Some(new)) = (flatten(&old), flatten(&new)) {
+        let changed: BTreeSet<&str> = old
+            .keys()
+            .chain(new.keys())
+            .filter(|key| old.get(*key) != new.get(*key))
+            .map(String::as_str)
+            .collect();
+        if !changed.is_empty() {
+            let changed: Vec<&str> = changed.into_iter().collect();
+            summary.push_str(&format!(", keys changed: {}", changed.join(", ")));
+        }
+    }
+    summary
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Yaml,
+}
+
+/// Parse a JSON or YAML document holding a mapping. Parsed as YAML either way, which JSON is a
+/// subset of, so key order is kept.
+fn parse_structured(plaintext: &[u8]) -> Option<(Format, serde_yaml::Value)> {
+    let text = std::str::from_utf8(plaintext).ok()?;
+    let format = if serde_json::from_str::<Value>(text).is_ok_and(|v| v.is_object()) {
+        Format::Json
+    } else {
+        Format::Yaml
+    };
+    let value: serde_yaml::Value = serde_yaml::from_str(text).ok()?;
+    value.is_mapping().then_some((format, value))
+}
+
+/// Three-way merge of parsed values, recording the dotted keys changed differently on both
+/// sides in `conflicts`. Missing values are None.
+fn merge_values(
+    base: Option<&serde_yaml::Value>,
+    ours: Option<&serde_yaml::Value>,
+    theirs: Option<&serde_yaml::Value>,
+    key: &str,
+    conflicts: &mut Vec<String>,
+) -> Option<serde_yaml::Value> {
+    if ours == theirs || base == theirs {
+        return ours.cloned();
+    }
+    if base == ours {
+        return theirs.cloned();
+    }
+    // Changed differently on both sides, which is only mergeable inside mappings
+    let (Some(ours_map), Some(theirs_map)) = (
+        ours.and_then(serde_yaml::Value::as_mapping),
+        theirs.and_then(serde_yaml::Value::as_mapping),
+    ) else {
+        conflicts.push(key.to_string());
+        return ours.cloned();
+    };
+    let empty = serde_yaml::Mapping::new();
+    let base = base
+        .and_then(serde_yaml::Value::as_mapping)
+        .unwrap_or(&empty);
+
+    // Keys removed on both sides stay removed, so only the ones either side has are merged
+    let keys = ours_map
+        .keys()
+        .chain(theirs_map.keys().filter(|k| !ours_map.contains_key(*k)));
+    let mut merged = serde_yaml::Mapping::new();
+    for k in keys {
+        let name = match k {
+            serde_yaml::Value::String(name) => name.clone(),
+            other => serde_yaml::to_string(other)
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+        };
+        let child_key = if key.is_empty() {
+            name
+        } else {
+            format!("{}.{}", key, name)
+        };
+        let value = merge_values(
+            base.get(k),
+            ours_map.get(k),
+            theirs_map.get(k),
+            &child_key,
+            conflicts,
+        );
+        if let Some(value) = value {
+            merged.insert(k.clone(), value);
+        }
+    }
+    Some(serde_yaml::Value::Mapping(merged))
+}
+
+/// Whether YAML `text` could have comments, which re-serializing it drops. Errs on the side of
+/// yes for a ` #` inside a string.
+fn has_comments(text: &[u8]) -> bool {
+    String::from_utf8_lossy(text)
+        .lines()
+        .any(|line| line.trim_start().starts_with('#') || line.contains(" #"))
+}
+
+/// Merge JSON or YAML plaintexts of `path` key by key. None when they aren't all documents of
+/// the same format, otherwise the merged plaintext or the keys changed differently on both
+/// sides. Keys only one side changed are never a conflict.
+fn merge_structured(
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    path: &Path,
+) -> Option<Result<Vec<u8>, Vec<String>>> {
+    let (format, ours_value) = parse_structured(ours)?;
+    let (theirs_format, theirs_value) = parse_structured(theirs)?;
+    let base_value = if base.is_empty() {
+        serde_yaml::Value::Mapping(serde_yaml::Mapping::new())
+    } else {
+        let (base_format, base_value) = parse_structured(base)?;
+        if base_format != format {
+            return None;
+        }
+        base_value
+    };
+    if theirs_format != format {
+        return None;
+    }
+
+    let mut conflicts = vec![];
+    let merged = merge_values(
+        Some(&base_value),
+        Some(&ours_value),
+        Some(&theirs_value),
+        "",
+        &mut conflicts,
+    )
+    .unwrap_or_default();
+    if !conflicts.is_empty() {
+        return Some(Err(conflicts));
+    }
+    // Keep a side's own formatting when the merge is just that side
+    if merged == ours_value {
+        return Some(Ok(ours.to_vec()));
+    }
+    if merged == theirs_value {
+        return Some(Ok(theirs.to_vec()));
+    }
+    // Re-serializing loses comments, so they're kept by merging the lines when that's clean
+    if format == Format::Yaml && [base, ours, theirs].into_iter().any(has_comments) {
+        if let Ok((merged, 0)) = merge_file(base, ours, theirs) {
+            return Some(Ok(merged));
+        }
+        eprintln!(
+            "warning: merged {:?} key by key, which drops the comments of the YAML",
+            path
+        );
+    }
+    let mut text = match format {
+        Format::Json => serde_json::to_vec_pretty(&merged).ok()?,
+        Format::Yaml => serde_yaml::to_string(&merged).ok()?.into_bytes(),
+    };
+    if ours.ends_with(b"\n") && !text.ends_with(b"\n") {
+        text.push(b'\n');
+    }
+    Some(Ok(text))
+}
+
+/// Whether `plaintext` still has the conflict markers of `git merge-file`
+fn has_conflicts(plaintext: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(plaintext);
+    let mut lines = text.lines();
+    lines.any(|line| line.starts_with("<<<<<<< "))
+        && lines.any(|line| line == "=======")
+        && lines.any(|line| line.starts_with(">>>>>>> "))
+}
+
+/// Where resolutions are remembered, when rerere is enabled
+fn memory_dir() -> Option<PathBuf> {
+    if !git::config_bool("rerere.enabled") {
+        return None;
+    }
+    git::git_path("arcanum-rerere")
+}
+
+/// Name a conflict is remembered under: a digest of its plaintext, salted with a key kept next
+/// to the resolutions so the names say nothing about the plaintext elsewhere
+fn conflict_digest(dir: &Path, conflicted: &[u8]) -> std::io::Result<String> {
+    let salt_path = dir.join("salt");
+    let salt = match std::fs::read(&salt_path) {
+        Ok(salt) => salt,
+        Err(_) => {
+            create_dir_all_with_mode(dir, 0o700)?;
+            let mut salt = vec![0; 32];
+            rand::thread_rng().fill_bytes(&mut salt);
+            create_with_mode(&salt_path, 0o600)?.write_all(&salt)?;
+            salt
+        }
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(&salt);
+    hasher.update(conflicted);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// A resolution recorded for `conflicted`
+fn recall(conflicted: &[u8], identities: &IdentityStore) -> Option<Vec<u8>> {
+    let dir = memory_dir()?;
+    let digest = conflict_digest(&dir, conflicted).ok()?;
+    let encrypted = std::fs::read(dir.join(format!("{}.age", digest))).ok()?;
+    try_decrypt(&encrypted, identities).ok()
+}
+
+/// Record `resolution`, a ciphertext, as how `conflicted` was resolved
+fn remember(conflicted: &[u8], resolution: &[u8]) {
+    let Some(dir) = memory_dir() else {
+        return;
+    };
+    let result = conflict_digest(&dir, conflicted).and_then(|digest| {
+        write_atomic(
+            &dir.join(format!("{}.age", digest)),
+            resolution,
+            0o600,
+            None,
+        )
+    });
+    if let Err(e) = result {
+        eprintln!("warning: unable to remember the resolution: {}", e);
+    }
+}
+
+/// Merge with `git merge-file`, returning the merged plaintext and the number of conflicts
+fn merge_file(base: &[u8], ours: &[u8], theirs: &[u8]) -> std::io::Result<(Vec<u8>, i32)> {
+    let files = [
+        (PathBuf::from("ours"), ours),
+        (PathBuf::from("base"), base),
+        (PathBuf::from("theirs"), theirs),
+    ];
+    workspace::with_files(&files, |_, paths| {
+        let output = Command::new("git")
+            .args([
+                "merge-file",
+                "-p",
+                "-L",
+                "ours",
+                "-L",
+                "base",
+                "-L",
+                "theirs",
+            ])
+            .args(paths)
+            .output()?;
+        match output.status.code() {
+            Some(conflicts) if (0..128).contains(&conflicts) => Ok((output.stdout, conflicts)),
+            _ => Err(std::io::Error::other(format!(
+                "git merge-file failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
+        }
+    })
+}
+
+/// Merge the ciphertexts `base` and `theirs` into `ours`, as git's merge driver for `path`.
+/// Returns false when conflicts were left to resolve.
+pub fn merge(
+    cache: &CacheFile,
+    base: &Path,
+    ours: &Path,
+    theirs: &Path,
+    path: &Path,
+    show_plaintext: bool,
+    identities: &IdentityStore,
+) -> bool {
+    let recipients = cache.recipients_for_file(path);
+    if recipients.is_empty() {
+        cache.report_no_recipients(path);
+        std::process::exit(1);
+    }
+    let [base_plaintext, ours_plaintext, theirs_plaintext] =
+        decrypt_sides([base, ours, theirs], identities);
+
+    let structured = merge_structured(&base_plaintext, &ours_plaintext, &theirs_plaintext, path);
+    let (merged, conflicts) = match structured {
+        Some(Ok(merged)) => (merged, 0),
+        structured => {
+            if let Some(Err(keys)) = structured {
+                eprintln!(
+                    "Both sides changed {} of {:?} differently, merging it line by line",
+                    keys.join(", "),
+                    path
+                );
+            }
+            merge_file(&base_plaintext, &ours_plaintext, &theirs_plaintext).unwrap_or_else(|e| {
+                eprintln!("Unable to merge {:?}: {}", path, e);
+                std::process::exit(1);
+            })
+        }
+    };
+    let (merged, conflicts) = match recall(&merged, identities).filter(|_| conflicts > 0) {
+        Some(resolution) => {
+            eprintln!("Reusing the recorded resolution of this conflict");
+            (resolution, 0)
+        }
+        None => (merged, conflicts),
+    };
+
+    eprintln!("Merging {:?}", path);
+    if show_plaintext {
+        eprint!(
+            "{}",
+            diff::unified(&base_plaintext, &ours_plaintext, "base", "ours")
+        );
+        eprint!(
+            "{}",
+            diff::unified(&base_plaintext, &theirs_plaintext, "base", "theirs")
+        );
+        eprintln!("Merged plaintext starts with:");
+        for line in String::from_utf8_lossy(&merged).lines().take(PREVIEW_LINES) {
+            eprintln!("  {}", line);
+        }
+    } else {
+        eprintln!("  ours:   {}", summary(&base_plaintext, &ours_plaintext));
+        eprintln!("  theirs: {}", summary(&base_plaintext, &theirs_plaintext));
+    }
+
+    let ciphertext = ciphertext_from_plaintext_buffer(&merged, recipients);
+    verify::round_trip(path, &ciphertext, &merged, identities);
+    let mode = mode_of(ours).unwrap_or(0o644);
+    write_atomic(ours, &ciphertext, mode, None).unwrap_or_else(|e| {
+        eprintln!("Unable to write the merged ciphertext: {}", e);
+        std::process::exit(1);
+    });
+    if conflicts > 0 {
+        eprintln!(
+            "{} conflict(s) in {:?}, resolve them with `arcanum resolve {}`",
+            conflicts,
+            path,
+            path.display()
+        );
+        return false;
+    }
+    true
+}
+
+/// Resolve the conflicts the merge driver left in `ciphertext` in the editor. Safe to run
+/// again: a file without conflicts is left alone, and one still holding conflict markers
+/// after editing isn't written. With `stage` the resolved file is added to the index.
+/// Returns false when it isn't resolved.
+pub fn resolve(
+    cache: &CacheFile,
+    ciphertext: &Path,
+    stage: bool,
+    identities: &IdentityStore,
+) -> bool {
+    let conflicted =
+        plaintext_from_ciphertext_source(&cache.resolve_source(ciphertext), identities);
+    if conflicted.is_empty() {
+        return false;
+    }
+    if !has_conflicts(&conflicted) {
+        eprintln!("{:?} has no conflicts left", ciphertext);
+    } else {
+        let recipients = cache.recipients_for_file(ciphertext);
+        if recipients.is_empty() {
+            cache.report_no_recipients(ciphertext);
+            return false;
+        }
+        let name = multi_edit::plaintext_name(ciphertext);
+        let resolved = match workspace::edit(&[(name, &conflicted)]) {
+            Ok(mut edited) => edited.remove(0),
+            Err(e) => {
+                eprintln!(
+                    "Unable to edit {:?}, leaving it unchanged: {}",
+                    ciphertext, e
+                );
+                return false;
+            }
+        };
+        if resolved.is_empty() || has_conflicts(&resolved) {
+            eprintln!(
+                "{:?} is empty or still has conflict markers, leaving it unchanged",
+                ciphertext
+            );
+            return false;
+        }
+        let ciphertext_data = ciphertext_from_plaintext_buffer(&resolved, recipients);
+        verify::round_trip(ciphertext, &ciphertext_data, &resolved, identities);
+        write_output(
+            cache,
+            ciphertext,
+            &ciphertext_data,
+            ciphertext_mode(ciphertext),
+        )
+        .unwrap_or_else(|e| exit_unwritable(ciphertext, e));
+        remember(&conflicted, &ciphertext_data);
+        eprintln!("Resolved {:?}", ciphertext);
+    }
+    if stage && !git::add(ciphertext) {
+        eprintln!("Unable to stage {:?}", ciphertext);
+        return false;
+    }
+    true
+}
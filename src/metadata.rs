@@ -0,0 +1,147 @@
+//! Metadata about a ciphertext, kept in a `<ciphertext>.meta.json` sidecar written alongside
+//! it. age allows nothing but whitespace around an armored file, so putting it in the file
+//! itself would break decrypting with anything but arcanum. Never holds anything secret.
+
+use crate::fsutil::{normalize, write_atomic};
+use crate::git;
+use chrono::{DateTime, Utc};
+use digest::Digest;
+use serde::{Deserialize, Serialize};
+use sha3::Sha3_256;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    pub version: u32,
+    /// Version of arcanum that last wrote the ciphertext
+    pub arcanum: String,
+    /// When the ciphertext was first written by arcanum
+    pub created_at: DateTime<Utc>,
+    /// When it was last encrypted with a new secret or to new recipients, by an edit, rekey or
+    /// encrypt
+    pub updated_at: DateTime<Utc>,
+    /// Git identity, or the user name, of whoever last encrypted it
+    pub updated_by: Option<String>,
+    /// The file's description from the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// When the secret itself last changed. Unlike `updated_at`, rekeys leave it alone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotated_at: Option<DateTime<Utc>>,
+    /// Hash of the recipients it was encrypted to, see `recipients_hash`. Tells age keys apart,
+    /// which its header can't.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipients: Option<String>,
+}
+
+/// Hashes of the recipients ciphertexts are about to be encrypted to, by absolute path, for
+/// `write` to record
+static PENDING: Mutex<BTreeMap<PathBuf, String>> = Mutex::new(BTreeMap::new());
+
+fn pending_key(ciphertext: &Path) -> PathBuf {
+    normalize(&std::path::absolute(ciphertext).unwrap_or(ciphertext.to_path_buf()))
+}
+
+/// Ciphertexts about to be rewritten with the same secret, by absolute path, for `write` to
+/// leave when they were rotated alone
+static REKEYS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Hash of a set of canonical recipients, as recorded in `Metadata::recipients`
+pub fn recipients_hash(recipients: &BTreeSet<String>) -> String {
+    let mut hasher = Sha3_256::new();
+    for recipient in recipients {
+        hasher.update(recipient.as_bytes());
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Note that `ciphertext` is about to be encrypted to `recipients`, recorded once it's written
+pub fn note_recipients(ciphertext: &Path, recipients: &BTreeSet<String>) {
+    let hash = recipients_hash(recipients);
+    PENDING
+        .lock()
+        .unwrap()
+        .insert(pending_key(ciphertext), hash);
+}
+
+/// Note that `ciphertext` is about to be rewritten with the secret it already holds, such as
+/// by a rekey
+pub fn note_rekey(ciphertext: &Path) {
+    REKEYS.lock().unwrap().insert(pending_key(ciphertext));
+}
+
+/// Whether the metadata of `ciphertext` records it was encrypted to exactly `recipients`
+pub fn encrypted_to(ciphertext: &Path, recipients: &BTreeSet<String>) -> bool {
+    match read(ciphertext) {
+        Some(Ok(metadata)) => metadata.recipients == Some(recipients_hash(recipients)),
+        _ => false,
+    }
+}
+
+/// Where the metadata of `ciphertext` is kept
+pub fn path(ciphertext: &Path) -> PathBuf {
+    let name = ciphertext.file_name().unwrap_or_default().to_string_lossy();
+    ciphertext.with_file_name(format!("{}.meta.json", name))
+}
+
+/// Metadata of `ciphertext`, None when it has none
+pub fn read(ciphertext: &Path) -> Option<Result<Metadata, String>> {
+    let contents = std::fs::read(path(ciphertext)).ok()?;
+    Some(serde_json::from_slice(&contents).map_err(|e| e.to_string()))
+}
+
+/// Who is making a change: git's user, or the login name
+pub fn user() -> Option<String> {
+    git::user().or_else(|| {
+        ["USER", "USERNAME"]
+            .iter()
+            .find_map(|var| std::env::var(var).ok())
+    })
+}
+
+/// Record that `ciphertext` was just written, keeping when it was first created. Rewriting the
+/// same secret to the same recipients changes nothing, so the sidecar doesn't churn in git.
+pub fn write(ciphertext: &Path, description: Option<&str>) -> std::io::Result<()> {
+    let key = pending_key(ciphertext);
+    let recipients = PENDING.lock().unwrap().remove(&key);
+    let rekeyed = REKEYS.lock().unwrap().remove(&key);
+    let now = Utc::now();
+    let previous = read(ciphertext).and_then(Result::ok);
+    let mut metadata = previous.clone().unwrap_or_else(|| Metadata {
+        version: VERSION,
+        arcanum: env!("CARGO_PKG_VERSION").to_string(),
+        created_at: now,
+        updated_at: now,
+        updated_by: user(),
+        description: None,
+        rotated_at: None,
+        recipients: None,
+    });
+    if !rekeyed || metadata.recipients != recipients {
+        metadata.version = VERSION;
+        metadata.arcanum = env!("CARGO_PKG_VERSION").to_string();
+        metadata.updated_at = now;
+        metadata.updated_by = user();
+        metadata.recipients = recipients;
+    }
+    if !rekeyed {
+        metadata.rotated_at = Some(now);
+    }
+    metadata.description = description.map(str::to_string);
+    if previous.as_ref() == Some(&metadata) {
+        return Ok(());
+    }
+    save(ciphertext, &metadata)
+}
+
+fn save(ciphertext: &Path, metadata: &Metadata) -> std::io::Result<()> {
+    let mut json = serde_json::to_vec_pretty(metadata).unwrap();
+    json.push(b'\n');
+    write_atomic(&path(ciphertext), &json, 0o644, None)
+}
@@ -0,0 +1,145 @@
+use crate::fsutil::normalize;
+use crate::identity::IdentityStore;
+use crate::{
+    ciphertext_from_plaintext_buffer, ciphertext_mode, exit_unwritable, is_stdio,
+    plaintext_from_ciphertext_source, verify, workspace, write_output, CacheFile, RecipientArgs,
+};
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+
+/// Managed files whose source matches `pattern`, relative to the project root. `*` also
+/// matches across directories, and `**` is accepted inside a component (`**.age`) too.
+pub fn matching_sources(cache: &CacheFile, pattern: &str) -> Vec<PathBuf> {
+    let relative = cache.project_relative(Path::new(pattern));
+    let normalized: Vec<String> = relative
+        .to_string_lossy()
+        .split('/')
+        .map(|part| match part {
+            "**" => part.to_string(),
+            _ => part.replace("**", "*"),
+        })
+        .collect();
+    let pattern = glob::Pattern::new(&normalized.join("/")).unwrap_or_else(|e| {
+        eprintln!("Invalid pattern {:?}: {}", pattern, e);
+        std::process::exit(1);
+    });
+    let sources: BTreeSet<PathBuf> = cache
+        .entries()
+        .into_iter()
+        .map(|(_, _, file)| file.source.clone())
+        .filter(|source| pattern.matches_path(&normalize(source)))
+        .collect();
+    sources.into_iter().collect()
+}
+
+/// Whether a path argument is a glob to expand against the managed files
+pub fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Path arguments with globs replaced by the managed files they match, in order and without
+/// duplicates. Exits when a glob matches nothing.
+pub fn expand_sources(cache: &CacheFile, paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut seen = BTreeSet::new();
+    let mut expanded = vec![];
+    for path in paths {
+        let matches = if is_glob(path) {
+            let matching = matching_sources(cache, &path.to_string_lossy());
+            if matching.is_empty() {
+                eprintln!("No managed files match {:?}", path);
+                std::process::exit(1);
+            }
+            matching
+        } else {
+            vec![path.clone()]
+        };
+        for path in matches {
+            if seen.insert(path.clone()) {
+                expanded.push(path);
+            }
+        }
+    }
+    expanded
+}
+
+/// Name of the temp file for `ciphertext`, mirroring its path with the `.age` suffix dropped
+/// so the editor shows recognisable names and picks the right syntax
+pub fn plaintext_name(ciphertext: &Path) -> PathBuf {
+    let relative: PathBuf = ciphertext
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    match relative.extension() {
+        Some(ext) if ext == "age" => relative.with_extension(""),
+        _ => relative,
+    }
+}
+
+/// Decrypt every file in `ciphertexts`, open them together in a single editor session and
+/// re-encrypt the ones that changed
+pub fn edit_many(
+    cache: &CacheFile,
+    ciphertexts: &[PathBuf],
+    identities: &IdentityStore,
+    args: &RecipientArgs,
+) {
+    if ciphertexts.iter().any(|c| is_stdio(c)) {
+        eprintln!("Editing from stdin is only possible for a single file.");
+        std::process::exit(1);
+    }
+
+    let mut files = Vec::new();
+    let mut seen = BTreeSet::new();
+    for ciphertext in ciphertexts {
+        let recipients = cache.recipients_for_target(ciphertext, args);
+        if recipients.is_empty() {
+            cache.report_no_recipients(ciphertext);
+            std::process::exit(1);
+        }
+        let name = plaintext_name(ciphertext);
+        if !seen.insert(name.clone()) {
+            eprintln!("{:?} was given more than once", ciphertext);
+            std::process::exit(1);
+        }
+        let source = cache.resolve_source(ciphertext);
+        let plaintext = plaintext_from_ciphertext_source(&source, identities);
+        files.push((ciphertext, name, plaintext, recipients));
+    }
+
+    let edited = workspace::edit(
+        &files
+            .iter()
+            .map(|(_, name, plaintext, _)| (name.clone(), plaintext.as_slice()))
+            .collect::<Vec<_>>(),
+    );
+    let edited = edited.unwrap_or_else(|e| {
+        eprintln!("Unable to edit plaintexts, not writing any files: {}", e);
+        std::process::exit(1);
+    });
+
+    let mut changed = 0;
+    for ((ciphertext, _, original, recipients), plaintext) in files.into_iter().zip(edited) {
+        if plaintext == original {
+            continue;
+        }
+        if plaintext.is_empty() {
+            eprintln!("edited plaintext is empty, not writing to {:?}", ciphertext);
+            continue;
+        }
+        let ciphertext_data = ciphertext_from_plaintext_buffer(&plaintext, recipients);
+        verify::round_trip(ciphertext, &ciphertext_data, &plaintext, identities);
+
+        write_output(
+            cache,
+            ciphertext,
+            &ciphertext_data,
+            ciphertext_mode(ciphertext),
+        )
+        .unwrap_or_else(|e| exit_unwritable(ciphertext, e));
+        eprintln!("Wrote ciphertext to {:?}", ciphertext);
+        changed += 1;
+    }
+    if changed == 0 {
+        eprintln!("No plaintexts were changed, not writing any files");
+    }
+}
@@ -0,0 +1,276 @@
+//! Bringing new machines and people into a project: `arcanum init-host` finds the host's key,
+//! prints the config to add it as a recipient of the host's secrets and rekeys them once that's
+//! done. `arcanum init-user` generates an identity for someone new to age and prints theirs.
+
+use crate::identity::{self, IdentitySource, IdentityStore};
+use crate::{
+    canonical_recipient, check, ciphertext_from_plaintext_buffer, fsutil, metadata, rekey,
+    try_decrypt, CacheFile, FileStatus, RecipientArgs, Scope,
+};
+use age::secrecy::ExposeSecret;
+use chrono::{Local, Utc};
+use clap::Args;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The key sshd generates on NixOS, read when no other way to get the host key is given
+const LOCAL_HOST_KEY: &str = "/etc/ssh/ssh_host_ed25519_key.pub";
+
+/// Where `init-host` gets the host's public key from [default: this machine's
+/// /etc/ssh/ssh_host_ed25519_key.pub]
+#[derive(Args)]
+pub struct HostKeyArgs {
+    /// Read the host's ssh public key, or an age recipient, from this file
+    #[clap(long, conflicts_with_all = ["keyscan", "generate"])]
+    key_file: Option<PathBuf>,
+
+    /// Ask the running host at this address for its ed25519 key with ssh-keyscan. Compare the
+    /// fingerprint with the one on its console, the network is trusted for this.
+    #[clap(long, conflicts_with = "generate")]
+    keyscan: Option<String>,
+
+    /// Generate a new ed25519 host key pair at this path, to copy to
+    /// /etc/ssh/ssh_host_ed25519_key on the machine before its first boot
+    #[clap(long)]
+    generate: Option<PathBuf>,
+}
+
+/// First line of `text` that isn't blank or a comment
+fn first_key_line(text: &str) -> Option<&str> {
+    text.lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn run(command: &mut Command) -> Result<String, String> {
+    let output = command.output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// The public key of host `name` as `args` says to get it
+fn host_key(name: &str, args: &HostKeyArgs) -> Result<String, String> {
+    let key = if let Some(address) = &args.keyscan {
+        let scanned = run(Command::new("ssh-keyscan").args(["-t", "ed25519", address]))
+            .map_err(|e| format!("ssh-keyscan failed: {}", e))?;
+        // Lines are `<host> <type> <key>`
+        let line = first_key_line(&scanned).ok_or(format!("{} sent no ed25519 key", address))?;
+        line.split_whitespace()
+            .skip(1)
+            .collect::<Vec<_>>()
+            .join(" ")
+    } else if let Some(path) = &args.generate {
+        if path.exists() {
+            return Err(format!("{:?} already exists", path));
+        }
+        let comment = format!("root@{}", name);
+        run(Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", &comment, "-f"])
+            .arg(path))
+        .map_err(|e| format!("ssh-keygen failed: {}", e))?;
+        eprintln!(
+            "Generated {:?}, install it as /etc/ssh/ssh_host_ed25519_key on {} (mode 0600) and \
+             delete it here",
+            path, name
+        );
+        read_key_file(&PathBuf::from(format!("{}.pub", path.display())))?
+    } else {
+        read_key_file(
+            args.key_file
+                .as_deref()
+                .unwrap_or(Path::new(LOCAL_HOST_KEY)),
+        )?
+    };
+    check::check_recipient(&key)?;
+    Ok(canonical_recipient(&key))
+}
+
+fn read_key_file(path: &Path) -> Result<String, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{:?}: {}", path, e))?;
+    first_key_line(&text)
+        .map(str::to_string)
+        .ok_or(format!("{:?} holds no key", path))
+}
+
+/// The NixOS config adding `key` as a recipient of every file of host `name`
+fn snippet(cache: &CacheFile, name: &str, key: &str) -> String {
+    let mut files: Vec<&String> = cache
+        .configs()
+        .into_iter()
+        .filter(|(scope, _)| *scope == Scope::Nixos(name.to_string()))
+        .flat_map(|(_, config)| config.files.keys())
+        .collect();
+    files.sort();
+    let mut snippet = format!(
+        "# In nixosConfigurations.{}\nlet\n  hostKey = \"{}\";\nin {{\n",
+        name, key
+    );
+    if files.is_empty() {
+        snippet.push_str("  # for each of the host's secrets:\n");
+        snippet.push_str("  arcanum.files.\"<name>\".recipients = [ hostKey ];\n");
+    }
+    for file in files {
+        snippet.push_str(&format!(
+            "  arcanum.files.{:?}.recipients = [ hostKey ];\n",
+            file
+        ));
+    }
+    snippet.push_str(&format!(
+        "  arcanum.recipientMetadata.${{hostKey}} = {{\n    owner = \"host {}\";\n    \
+         added = \"{}\";\n  }};\n}}\n",
+        name,
+        Local::now().date_naive()
+    ));
+    snippet
+}
+
+/// Rekey the files of host `name` that aren't encrypted to `key` yet, once the config has it
+/// as their recipient. Returns false when the config isn't updated yet.
+fn rekey_host(cache: &CacheFile, name: &str, key: &str, identities: &IdentityStore) -> bool {
+    let scope = Scope::Nixos(name.to_string());
+    let entries: Vec<_> = cache
+        .entries()
+        .into_iter()
+        .filter(|(s, _, _)| *s == scope)
+        .collect();
+    if entries.is_empty() {
+        eprintln!("No secrets are configured for host {} yet", name);
+        return false;
+    }
+    let missing: Vec<_> = entries
+        .iter()
+        .filter(|(_, config, file)| {
+            !file
+                .readers(config)
+                .iter()
+                .any(|r| canonical_recipient(r) == key)
+        })
+        .collect();
+    if !missing.is_empty() {
+        eprintln!("The host key isn't a recipient of these files yet:");
+        for (_, _, file) in missing {
+            eprintln!(" - {}", file.source.display());
+        }
+        eprintln!("Add the snippet to the config, run `arcanum cache` and try again.");
+        return false;
+    }
+    for (_, _, file) in entries {
+        if cache.status_of(file) == FileStatus::Stale {
+            rekey(cache, &file.source, identities, &RecipientArgs::default());
+        }
+    }
+    true
+}
+
+/// `arcanum init-host`: print the config adding host `name`'s key as a recipient of its
+/// secrets, and with `rekey` rekey them once it's in place
+pub fn init_host(
+    cache: &CacheFile,
+    name: &str,
+    args: &HostKeyArgs,
+    rekey: bool,
+    identities: &IdentityStore,
+) -> bool {
+    let key = match host_key(name, args) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("Unable to get the host key of {}: {}", name, e);
+            return false;
+        }
+    };
+    eprintln!("Host key of {}: {}", name, key);
+    if rekey {
+        return rekey_host(cache, name, &key, identities);
+    }
+    print!("{}", snippet(cache, name, &key));
+    eprintln!(
+        "Add this to the config, run `arcanum cache`, then `arcanum init-host {} --rekey` with \
+         the same key to re-encrypt its secrets.",
+        name
+    );
+    true
+}
+
+/// Encrypt a probe to `recipient` and decrypt it with the identity file at `path`, to be sure
+/// the setup works before anyone encrypts secrets to it
+fn probe(recipient: age::x25519::Recipient, path: &Path) -> Result<(), String> {
+    let plaintext = b"arcanum init-user probe";
+    let encrypted = ciphertext_from_plaintext_buffer(plaintext, vec![Box::new(recipient)]);
+    let identities = IdentityStore::local(vec![IdentitySource::file(path.display().to_string())]);
+    match try_decrypt(&encrypted, &identities) {
+        Ok(decrypted) if decrypted == plaintext => Ok(()),
+        Ok(_) => Err("the probe decrypted to something else".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// `arcanum init-user`: generate an age identity in `~/.config/arcanum/identity.txt`, unless
+/// there is one already, check it works and print the config making `owner` an admin
+pub fn init_user(owner: Option<&str>) -> bool {
+    let Some(path) = identity::user_identity_path() else {
+        eprintln!("Unable to find the config directory");
+        return false;
+    };
+    let recipient = match std::fs::read_to_string(&path) {
+        Ok(text) => {
+            let parsed = first_key_line(&text).and_then(|line| line.parse().ok());
+            let Some(existing) = parsed.map(|i: age::x25519::Identity| i.to_public()) else {
+                eprintln!(
+                    "{:?} exists but holds no age identity, not replacing it",
+                    path
+                );
+                return false;
+            };
+            eprintln!("Using the existing identity in {:?}", path);
+            existing
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let generated = age::x25519::Identity::generate();
+            let recipient = generated.to_public();
+            let contents = format!(
+                "# created: {}\n# public key: {}\n{}\n",
+                Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                recipient,
+                generated.to_string().expose_secret()
+            );
+            let written = fsutil::create_dir_all_with_mode(path.parent().unwrap(), 0o700)
+                .and_then(|_| fsutil::write_atomic(&path, contents.as_bytes(), 0o600, None));
+            if let Err(e) = written {
+                eprintln!("Unable to write {:?}: {}", path, e);
+                return false;
+            }
+            eprintln!(
+                "Generated a new identity in {:?}, back it up somewhere safe",
+                path
+            );
+            recipient
+        }
+        Err(e) => {
+            eprintln!("Unable to read {:?}: {}", path, e);
+            return false;
+        }
+    };
+    if let Err(e) = probe(recipient.clone(), &path) {
+        eprintln!("The identity in {:?} doesn't work: {}", path, e);
+        return false;
+    }
+    eprintln!("Checked that it decrypts what is encrypted to it");
+
+    let owner = owner
+        .map(str::to_string)
+        .or_else(metadata::user)
+        .unwrap_or_else(|| "<your name>".to_string());
+    eprintln!("Your public key:");
+    eprintln!("  {}", recipient);
+    eprintln!("Ask an admin to add you to the config, rekey and commit:");
+    println!("arcanum.adminRecipients = [ \"{}\" ];", recipient);
+    println!(
+        "arcanum.recipientMetadata.\"{}\" = {{\n  owner = {:?};\n  added = \"{}\";\n}};",
+        recipient,
+        owner,
+        Local::now().date_naive()
+    );
+    true
+}
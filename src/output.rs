@@ -0,0 +1,15 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+/// Switch to plain output when asked to, or when the terminal can't do anything richer
+pub fn init(plain: bool) {
+    let dumb = std::env::var("TERM").is_ok_and(|term| term == "dumb");
+    PLAIN.store(plain || dumb, Ordering::Relaxed);
+}
+
+/// Whether output should stick to simple lines of text: no colors, spinners, progress bars,
+/// box drawing or full screen interfaces
+pub fn plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+}
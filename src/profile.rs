@@ -0,0 +1,123 @@
+//! Named identity profiles, for people with separate keys for separate projects. They are
+//! defined in `~/.config/arcanum/config.toml` and picked with `--profile`, or by the project
+//! root:
+//!
+//! ```toml
+//! [profile.work]
+//! identity = ["~/.ssh/id_work", "~/.config/age/work-yubikey.txt"]
+//!
+//! [profile.personal]
+//! identity = ["~/.ssh/id_ed25519"]
+//!
+//! [project]
+//! "~/src/company" = "work"
+//! "~/src" = "personal"
+//! ```
+//!
+//! A profile replaces the default identities and the chain in `identities.toml`, so another
+//! profile's keys are never tried against a project.
+//!
+//! The same file holds the per-action confirmation defaults, in its `[confirm]` table, and the
+//! commands `exec:` recipients may run, in its `[providers]` table (see `providers`).
+
+use crate::confirm::{Action, Policy};
+use crate::identity::{expand_home, IdentitySource};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    identity: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct UserConfig {
+    #[serde(default)]
+    profile: BTreeMap<String, Profile>,
+    /// Project directories to the profile used below them
+    #[serde(default)]
+    project: BTreeMap<String, String>,
+    /// What to do when an action needs confirming, see `confirm`
+    #[serde(default)]
+    confirm: HashMap<Action, Policy>,
+    /// Project directories to the commands `exec:` recipients may run below them
+    #[serde(default)]
+    providers: BTreeMap<String, Vec<String>>,
+}
+
+/// Where profiles and other user settings are configured
+pub fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("arcanum").join("config.toml"))
+}
+
+fn load() -> UserConfig {
+    let Some(path) = config_path() else {
+        return UserConfig::default();
+    };
+    let Ok(text) = std::fs::read_to_string(&path) else {
+        return UserConfig::default();
+    };
+    toml::from_str(&text).unwrap_or_else(|e| {
+        eprintln!("Unable to parse {:?}: {}", path, e);
+        std::process::exit(1);
+    })
+}
+
+/// The configured policy for each action needing confirmation
+pub fn confirm_defaults() -> HashMap<Action, Policy> {
+    load().confirm
+}
+
+/// The commands `exec:` recipients of the project at `project_root` may run, those allowed
+/// for any directory containing it
+pub fn recipient_providers(project_root: &Path) -> Vec<String> {
+    load()
+        .providers
+        .into_iter()
+        .filter(|(dir, _)| project_root.starts_with(expand_home(dir)))
+        .flat_map(|(_, commands)| commands)
+        .collect()
+}
+
+/// The identities of the profile `name`, or else of the one mapped to the deepest directory
+/// containing `project_root`. None when no profile applies.
+pub fn identities(name: Option<&str>, project_root: Option<&Path>) -> Option<Vec<IdentitySource>> {
+    let config = load();
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => {
+            let project_root = project_root?;
+            config
+                .project
+                .iter()
+                .map(|(dir, profile)| (PathBuf::from(expand_home(dir)), profile))
+                .filter(|(dir, _)| project_root.starts_with(dir))
+                .max_by_key(|(dir, _)| dir.components().count())
+                .map(|(_, profile)| profile.clone())?
+        }
+    };
+    let Some(profile) = config.profile.get(&name) else {
+        let known: Vec<&String> = config.profile.keys().collect();
+        eprintln!("No identity profile {:?}, known: {:?}", name, known);
+        std::process::exit(1);
+    };
+    let mut sources = vec![];
+    for file in &profile.identity {
+        let file = expand_home(file);
+        if !Path::new(&file).exists() {
+            eprintln!(
+                "warning: identity file {} of profile {} does not exist",
+                file, name
+            );
+            continue;
+        }
+        sources.push(IdentitySource::labelled(
+            format!("{}: {}", name, file),
+            file,
+        ));
+    }
+    Some(sources)
+}
@@ -0,0 +1,66 @@
+//! Per-project settings of arcanum itself, kept in the repository at `.arcanum/config.toml`.
+//! Command line flags and environment variables take precedence over them.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Where the config is evaluated from
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct EvalSettings {
+    /// Flake reference, relative to the project root
+    pub flake: Option<String>,
+    /// Attribute path of the config in the flake
+    pub attribute: Option<String>,
+    /// JSON or TOML file holding the evaluated config instead, relative to the project root
+    pub file: Option<PathBuf>,
+    /// HTTPS URL serving the evaluated config instead, as JSON or, ending in `.toml`, TOML
+    pub url: Option<String>,
+}
+
+/// A client of `arcanum serve`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ServeClient {
+    /// Globs of the managed files it may read, relative to the project root
+    pub allow: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ServeSettings {
+    /// Clients by name
+    #[serde(default)]
+    pub clients: BTreeMap<String, ServeClient>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ProjectConfig {
+    #[serde(default)]
+    pub eval: EvalSettings,
+    #[serde(default)]
+    pub serve: ServeSettings,
+}
+
+pub fn path(project_root: &Path) -> PathBuf {
+    project_root.join(".arcanum").join("config.toml")
+}
+
+/// The settings of the project at `project_root`, the defaults when it has none
+pub fn load(project_root: &Path) -> ProjectConfig {
+    let path = path(project_root);
+    let data = match std::fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return ProjectConfig::default(),
+        Err(e) => {
+            eprintln!("Unable to read {:?}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    toml::from_str(&data).unwrap_or_else(|e| {
+        eprintln!("Unable to parse {:?}: {}", path, e);
+        std::process::exit(1);
+    })
+}
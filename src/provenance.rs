@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Command;
+use std::sync::OnceLock;
+
+/// Git revision the binary was built from, set by the build (e.g. the nix derivation)
+const GIT_REV: Option<&str> = option_env!("ARCANUM_GIT_REV");
+
+/// Version of the age crate linked in, set by the build from Cargo.lock
+const AGE_VERSION: Option<&str> = option_env!("ARCANUM_AGE_VERSION");
+
+/// Build of arcanum a project expects its users to run
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildPin {
+    pub git_rev: Option<String>,
+    pub store_hash: Option<String>,
+}
+
+/// The software producing a ciphertext or bundle, recorded so it can be reconstructed later.
+/// Never holds anything secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Environment {
+    pub arcanum: String,
+    pub git_rev: Option<String>,
+    pub store_hash: Option<String>,
+    pub age: Option<String>,
+    /// First line of `nix --version`, when nix is installed
+    pub nix: Option<String>,
+    /// Operating system and architecture
+    pub platform: String,
+}
+
+impl std::fmt::Display for Environment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "arcanum {} (git rev {}, age {}) with {} on {}",
+            self.arcanum,
+            self.git_rev.as_deref().unwrap_or("unknown"),
+            self.age.as_deref().unwrap_or("unknown"),
+            self.nix.as_deref().unwrap_or("no nix"),
+            self.platform
+        )
+    }
+}
+
+fn nix_version() -> Option<String> {
+    let output = Command::new("nix").arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Some(stdout.lines().next()?.trim().to_string())
+}
+
+/// The environment of this process, looked up once
+pub fn environment() -> &'static Environment {
+    static ENVIRONMENT: OnceLock<Environment> = OnceLock::new();
+    ENVIRONMENT.get_or_init(|| Environment {
+        arcanum: env!("CARGO_PKG_VERSION").to_string(),
+        git_rev: GIT_REV.map(str::to_string),
+        store_hash: store_hash(),
+        age: AGE_VERSION.map(str::to_string),
+        nix: nix_version(),
+        platform: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+    })
+}
+
+/// Hash part of the nix store path the running binary lives in, if it lives in one
+fn store_hash() -> Option<String> {
+    let exe = std::env::current_exe().ok()?.canonicalize().ok()?;
+    store_hash_of(&exe)
+}
+
+fn store_hash_of(path: &Path) -> Option<String> {
+    let relative = path.strip_prefix("/nix/store").ok()?;
+    let entry = relative.components().next()?.as_os_str().to_string_lossy();
+    let (hash, _) = entry.split_once('-')?;
+    Some(hash.to_string())
+}
+
+fn check(label: &str, expected: Option<&str>, actual: Option<&str>) -> bool {
+    let Some(expected) = expected else {
+        return true;
+    };
+    match actual {
+        Some(actual) if actual == expected => {
+            println!("ok        {}: {}", label, actual);
+            true
+        }
+        Some(actual) => {
+            println!(
+                "mismatch  {}: expected {}, running {}",
+                label, expected, actual
+            );
+            false
+        }
+        None => {
+            println!(
+                "unknown   {}: expected {}, but this build doesn't record it",
+                label, expected
+            );
+            false
+        }
+    }
+}
+
+/// Compare the running binary against the build pinned by the project. Returns true when it
+/// matches.
+pub fn verify(pin: Option<&BuildPin>) -> bool {
+    let store_hash = store_hash();
+    println!("arcanum {}", env!("CARGO_PKG_VERSION"));
+    println!("git rev:    {}", GIT_REV.unwrap_or("unknown"));
+    println!("store hash: {}", store_hash.as_deref().unwrap_or("unknown"));
+
+    let Some(pin) = pin else {
+        eprintln!("The project does not pin an expected arcanum build.");
+        return false;
+    };
+    if pin.git_rev.is_none() && pin.store_hash.is_none() {
+        eprintln!("The project's build pin is empty.");
+        return false;
+    }
+
+    let git_rev_ok = check("git rev", pin.git_rev.as_deref(), GIT_REV);
+    let store_hash_ok = check(
+        "store hash",
+        pin.store_hash.as_deref(),
+        store_hash.as_deref(),
+    );
+    git_rev_ok && store_hash_ok
+}
@@ -0,0 +1,204 @@
+//! Recipients that stand for keys kept elsewhere, resolved when the cache is generated and
+//! kept in it (and in `arcanum.lock`), so `arcanum cache` picks up changed keys and the lock
+//! pins them for review.
+//!
+//! - `github:<user>` and `gitlab:<user>` are the user's ssh keys published by GitHub or
+//!   gitlab.com, fetched with `curl`. Key types age can't encrypt to are skipped.
+//! - `exec:<command> <args>` runs the command from the project root and stands for every
+//!   line it prints, for organizations keeping keys in LDAP, an IdP or anywhere else arcanum
+//!   has no integration for:
+//!
+//! ```nix
+//! arcanum.files.db.recipients = [ "exec:./scripts/ldap-keys.sh team-sre" "github:alice" ];
+//! ```
+//!
+//! A cloned repository mustn't be able to run commands just by being evaluated, so which may
+//! run is up to each user, per project directory in `~/.config/arcanum/config.toml`:
+//!
+//! ```toml
+//! [providers]
+//! "~/src/company" = ["./scripts/ldap-keys.sh"]
+//! ```
+
+use crate::{canonical_recipient, check, profile, CacheFile};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::process::Command;
+
+const PREFIX: &str = "exec:";
+
+/// Forges publishing their users' ssh keys, by recipient prefix
+const FORGES: [(&str, &str); 2] = [
+    ("github:", "https://github.com"),
+    ("gitlab:", "https://gitlab.com"),
+];
+
+/// The ssh keys `account`, such as `github:alice`, has published that age can encrypt to.
+/// None when `account` isn't a forge account.
+pub fn fetch_keys(account: &str) -> Option<Result<Vec<String>, String>> {
+    let (user, base) = FORGES
+        .iter()
+        .find_map(|(prefix, base)| Some((account.strip_prefix(prefix)?, base)))?;
+    Some(fetch(user, base))
+}
+
+fn check_user(user: &str) -> Result<(), String> {
+    if user.is_empty()
+        || !user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(format!("{:?} isn't a user name", user));
+    }
+    Ok(())
+}
+
+fn fetch(user: &str, base: &str) -> Result<Vec<String>, String> {
+    check_user(user)?;
+    let url = format!("{}/{}.keys", base, user);
+    let output = Command::new("curl")
+        .args(["-fsSL", "--proto", "=https", "--max-time", "30", &url])
+        .output()
+        .map_err(|e| format!("unable to run curl: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "fetching {} failed: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let keys: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(canonical_recipient)
+        .filter(|key| !key.is_empty() && check::check_recipient(key).is_ok())
+        .collect();
+    if keys.is_empty() {
+        return Err(format!("{} lists no ed25519 or rsa keys", url));
+    }
+    Ok(keys)
+}
+
+/// The program and arguments of `command`
+fn words(command: &str) -> Result<Vec<String>, String> {
+    shlex::split(command)
+        .filter(|words| !words.is_empty())
+        .ok_or_else(|| "no command given".to_string())
+}
+
+/// The program and arguments of `command`, when `providers` allows running it
+fn allowed(command: &str, providers: &[String]) -> Result<Vec<String>, String> {
+    let words = words(command)?;
+    if !providers.contains(&words[0]) {
+        return Err(format!(
+            "{} isn't allowed to run for this project, add it to [providers] in \
+             ~/.config/arcanum/config.toml",
+            words[0]
+        ));
+    }
+    Ok(words)
+}
+
+/// Check `recipient`, when it stands for keys kept elsewhere, without resolving it: forge
+/// accounts must name a user and commands must parse. Whether a command may run is up to each
+/// user, so it isn't checked. None for other recipients.
+pub fn check(recipient: &str) -> Option<Result<(), String>> {
+    let checked = if let Some(command) = recipient.strip_prefix(PREFIX) {
+        words(command).map(|_| ())
+    } else {
+        let user = FORGES
+            .iter()
+            .find_map(|(prefix, _)| recipient.strip_prefix(prefix))?;
+        check_user(user)
+    };
+    Some(checked.map_err(|e| format!("{:?}: {}", recipient, e)))
+}
+
+/// The recipients the command `words` lists, run from `project_root`
+fn run(words: &[String], project_root: &Path) -> Result<Vec<String>, String> {
+    let (program, args) = words.split_first().unwrap();
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(project_root)
+        .output()
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!(
+            "it failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let recipients: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    if recipients.is_empty() {
+        return Err("it listed no recipients".to_string());
+    }
+    Ok(recipients)
+}
+
+/// `recipients` with every one standing for keys kept elsewhere replaced by those keys
+fn expand(
+    recipients: &mut Vec<String>,
+    providers: &[String],
+    project_root: &Path,
+    listed: &mut HashMap<String, Vec<String>>,
+) {
+    if !recipients
+        .iter()
+        .any(|r| r.starts_with(PREFIX) || FORGES.iter().any(|(prefix, _)| r.starts_with(prefix)))
+    {
+        return;
+    }
+    let mut expanded = vec![];
+    for recipient in recipients.drain(..) {
+        let found = if let Some(command) = recipient.strip_prefix(PREFIX) {
+            allowed(command, providers).and_then(|words| match listed.get(&recipient) {
+                Some(found) => Ok(found.clone()),
+                None => run(&words, project_root),
+            })
+        } else if let Some(found) = listed.get(&recipient) {
+            Ok(found.clone())
+        } else if let Some(fetched) = fetch_keys(&recipient) {
+            fetched
+        } else {
+            expanded.push(recipient);
+            continue;
+        };
+        let found = found.unwrap_or_else(|e| {
+            eprintln!("Unable to resolve recipient {:?}: {}", recipient, e);
+            std::process::exit(1);
+        });
+        listed.insert(recipient, found.clone());
+        expanded.extend(found);
+    }
+    let mut seen = HashSet::new();
+    expanded.retain(|recipient| seen.insert(recipient.clone()));
+    *recipients = expanded;
+}
+
+/// Replace the recipients of every section of `cache` that stand for keys kept elsewhere by
+/// those keys. Each is resolved once, however many files use it.
+pub fn resolve(cache: &mut CacheFile, project_root: &Path) {
+    let mut listed = HashMap::new();
+    let providers = profile::recipient_providers(project_root);
+    for config in cache.sections.values_mut() {
+        expand(
+            &mut config.admin_recipients,
+            &providers,
+            project_root,
+            &mut listed,
+        );
+        for file in config.files.values_mut() {
+            expand(&mut file.recipients, &providers, project_root, &mut listed);
+            expand(
+                &mut file.share_holders,
+                &providers,
+                project_root,
+                &mut listed,
+            );
+        }
+    }
+}
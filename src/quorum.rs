@@ -0,0 +1,171 @@
+//! Threshold encryption: the age file key is split with Shamir's secret sharing so that any
+//! `threshold` of the share holders are needed to decrypt, and no single one can alone. Each
+//! share is age encrypted to its holder and stored as an `arcanum-share <threshold> <index>
+//! <holder tag>` stanza, so threshold files are still age files, just ones only arcanum unwraps.
+
+use crate::header;
+use age::secrecy::ExposeSecret;
+use age::{DecryptError, EncryptError, Identity, Recipient};
+use age_core::format::{FileKey, Stanza};
+use arcanum::shamir::{combine, split, FILE_KEY_BYTES};
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+
+const TAG: &str = "arcanum-share";
+/// Short tag identifying a share holder in the header, so status can tell who holds shares
+fn holder_tag(recipient: &str) -> String {
+    let digest = Sha256::digest(recipient.as_bytes());
+    STANDARD_NO_PAD.encode(&digest[..4])
+}
+
+/// Check a threshold and set of share holders from the config, returning a message when they
+/// can't be used
+pub fn validate(threshold: u8, holders: &BTreeSet<String>) -> Result<(), String> {
+    if holders.len() > u8::MAX as usize {
+        return Err(format!("at most {} share holders are supported", u8::MAX));
+    }
+    if threshold < 1 || threshold as usize > holders.len() {
+        return Err(format!(
+            "threshold {} needs between 1 and {} share holders",
+            threshold,
+            holders.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Recipient wrapping the file key into one share per holder
+pub struct QuorumRecipient {
+    threshold: u8,
+    /// Holder tags and canonical recipients
+    holders: Vec<(String, String)>,
+}
+
+impl QuorumRecipient {
+    /// `holders` are canonical recipients, already checked with `validate`
+    pub fn new(threshold: u8, holders: &BTreeSet<String>) -> Self {
+        QuorumRecipient {
+            threshold,
+            holders: holders.iter().map(|h| (holder_tag(h), h.clone())).collect(),
+        }
+    }
+}
+
+impl Recipient for QuorumRecipient {
+    fn wrap_file_key(&self, file_key: &FileKey) -> Result<Vec<Stanza>, EncryptError> {
+        let shares = split(
+            file_key.expose_secret(),
+            self.threshold,
+            self.holders.len() as u8,
+        );
+        let mut stanzas = vec![];
+        for (index, ((tag, holder), share)) in (1u8..).zip(self.holders.iter().zip(shares)) {
+            let mut indexed = [0u8; 1 + FILE_KEY_BYTES];
+            indexed[0] = index;
+            indexed[1..].copy_from_slice(&share);
+
+            let encryptor = age::Encryptor::with_recipients(vec![crate::parse_recipient(holder)])
+                .ok_or(EncryptError::InvalidRecipients)?;
+            let mut body = vec![];
+            let mut writer = encryptor.wrap_output(&mut body)?;
+            writer.write_all(&indexed)?;
+            writer.finish()?;
+
+            stanzas.push(Stanza {
+                tag: TAG.to_string(),
+                args: vec![self.threshold.to_string(), index.to_string(), tag.clone()],
+                body,
+            });
+        }
+        Ok(stanzas)
+    }
+}
+
+/// Identity combining the shares any of the user's identities can decrypt, succeeding once
+/// enough of them are available. Give several `--identity` flags to combine holders' keys.
+pub struct QuorumIdentity<'a> {
+    identities: &'a [Box<dyn Identity>],
+}
+
+impl<'a> QuorumIdentity<'a> {
+    pub fn new(identities: &'a [Box<dyn Identity>]) -> Self {
+        QuorumIdentity { identities }
+    }
+
+    fn unwrap_share(&self, body: &[u8]) -> Option<(u8, [u8; FILE_KEY_BYTES])> {
+        let decryptor = match age::Decryptor::new(body).ok()? {
+            age::Decryptor::Recipients(d) => d,
+            _ => return None,
+        };
+        let mut reader = decryptor
+            .decrypt(self.identities.iter().map(|i| i.as_ref()))
+            .ok()?;
+        let mut indexed = vec![];
+        reader.read_to_end(&mut indexed).ok()?;
+        if indexed.len() != 1 + FILE_KEY_BYTES {
+            return None;
+        }
+        Some((indexed[0], indexed[1..].try_into().ok()?))
+    }
+}
+
+impl Identity for QuorumIdentity<'_> {
+    fn unwrap_stanza(&self, _stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        // A single share is never enough, see unwrap_stanzas
+        None
+    }
+
+    fn unwrap_stanzas(&self, stanzas: &[Stanza]) -> Option<Result<FileKey, DecryptError>> {
+        let shares: Vec<&Stanza> = stanzas.iter().filter(|s| s.tag == TAG).collect();
+        let threshold: u8 = shares
+            .first()?
+            .args
+            .first()?
+            .parse()
+            .ok()
+            .filter(|t| *t > 0)?;
+
+        let mut unwrapped = BTreeMap::new();
+        for stanza in shares {
+            if let Some((index, share)) = self.unwrap_share(&stanza.body) {
+                unwrapped.insert(index, share);
+            }
+            if unwrapped.len() == threshold as usize {
+                return Some(Ok(FileKey::from(combine(&unwrapped))));
+            }
+        }
+        eprintln!(
+            "Only {} of the {} shares needed to decrypt could be unlocked with the given identities",
+            unwrapped.len(),
+            threshold
+        );
+        None
+    }
+}
+
+/// Whether `stanzas` hold exactly one share for each of `holders` at `threshold`
+pub fn matches_holders(
+    stanzas: &[header::Stanza],
+    threshold: u8,
+    holders: &BTreeSet<String>,
+) -> bool {
+    let stanzas: Vec<&header::Stanza> = stanzas
+        .iter()
+        .filter(|s| !s.tag.ends_with("-grease"))
+        .collect();
+    if stanzas.len() != holders.len() {
+        return false;
+    }
+    let threshold = threshold.to_string();
+    let tags: BTreeSet<&str> = stanzas
+        .iter()
+        .filter(|s| s.tag == TAG && s.args.first() == Some(&threshold))
+        .filter_map(|s| s.args.get(2).map(|a| a.as_str()))
+        .collect();
+    holders
+        .iter()
+        .all(|h| tags.contains(holder_tag(h).as_str()))
+}
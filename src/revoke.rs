@@ -0,0 +1,142 @@
+use crate::fsutil::write_atomic;
+use crate::identity::IdentityStore;
+use crate::{canonical_recipient, confirm, rekey, CacheFile, RecipientArgs};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// Recipients revoked locally, excluded from every recipient set until the Nix config catches up
+pub fn revoked_file(project_root: &Path) -> PathBuf {
+    project_root.join(".arcanum").join("revoked-recipients")
+}
+
+pub fn load_revoked(project_root: &Path) -> BTreeSet<String> {
+    let Ok(data) = std::fs::read_to_string(revoked_file(project_root)) else {
+        return BTreeSet::new();
+    };
+    data.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(canonical_recipient)
+        .collect()
+}
+
+fn save_revoked(project_root: &Path, revoked: &BTreeSet<String>) -> std::io::Result<()> {
+    let path = revoked_file(project_root);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut data = String::from(
+        "# Recipients revoked with `arcanum revoke`, remove them from the Nix config too\n",
+    );
+    for recipient in revoked {
+        data.push_str(recipient);
+        data.push('\n');
+    }
+    write_atomic(&path, data.as_bytes(), 0o644, None)
+}
+
+/// Keys matching `recipient`, either the key itself or every key whose owner is `recipient`
+fn resolve_keys(cache: &CacheFile, recipient: &str) -> BTreeSet<String> {
+    let canonical = canonical_recipient(recipient);
+    let mut keys = BTreeSet::new();
+    for (_, config) in cache.configs() {
+        for (key, metadata) in &config.recipient_metadata {
+            if metadata.owner.as_deref() == Some(recipient) {
+                keys.insert(canonical_recipient(key));
+            }
+        }
+    }
+    if keys.is_empty() {
+        keys.insert(canonical);
+    }
+    keys
+}
+
+/// Report every file readable by `recipient` and, with `rekey`, revoke it locally and rekey
+/// those files without it
+pub fn revoke(
+    cache: &mut CacheFile,
+    project_root: &Path,
+    recipient: &str,
+    do_rekey: bool,
+    identities: &IdentityStore,
+) {
+    let keys = resolve_keys(cache, recipient);
+
+    // Files per key, and the config sections that still mention each key
+    let mut files: BTreeMap<&String, BTreeSet<PathBuf>> = BTreeMap::new();
+    let mut sections: BTreeMap<&String, BTreeSet<String>> = BTreeMap::new();
+    for (scope, config, file) in cache.entries() {
+        for key in &keys {
+            let direct = file
+                .recipients
+                .iter()
+                .chain(&file.share_holders)
+                .any(|r| canonical_recipient(r) == *key);
+            // Admins can't read threshold files on their own
+            let is_admin = file.threshold.is_none()
+                && config
+                    .admin_recipients
+                    .iter()
+                    .any(|r| canonical_recipient(r) == *key);
+            if direct || is_admin {
+                files.entry(key).or_default().insert(file.source.clone());
+                let role = if direct { "recipient" } else { "admin" };
+                sections
+                    .entry(key)
+                    .or_default()
+                    .insert(format!("{} ({})", scope, role));
+            }
+        }
+    }
+
+    if files.is_empty() {
+        eprintln!("No files are encrypted to {}", recipient);
+        return;
+    }
+
+    let affected: BTreeSet<PathBuf> = files.values().flatten().cloned().collect();
+    for (key, key_files) in &files {
+        println!("{}", key);
+        for file in key_files {
+            println!("  - {}", file.display());
+        }
+    }
+
+    if !do_rekey {
+        eprintln!("Run again with --rekey to revoke access and rekey these files.");
+        return;
+    }
+
+    let question = format!(
+        "Revoke {} and rekey {} files without it?",
+        recipient,
+        affected.len()
+    );
+    confirm::require(confirm::Action::RemoveRecipient, &question);
+
+    let mut revoked = load_revoked(project_root);
+    revoked.extend(keys.iter().cloned());
+    save_revoked(project_root, &revoked).unwrap();
+    cache.revoked = revoked;
+
+    for file in &affected {
+        rekey(cache, file, identities, &RecipientArgs::default());
+    }
+
+    println!();
+    println!(
+        "Rekeyed {} files. Still to do in the Nix config:",
+        affected.len()
+    );
+    for (key, key_sections) in &sections {
+        println!("  remove {} from:", key);
+        for section in key_sections {
+            println!("    - {}", section);
+        }
+    }
+    println!(
+        "Then remove it from {}",
+        revoked_file(project_root).display()
+    );
+}
@@ -0,0 +1,130 @@
+use crate::identity::IdentityStore;
+use crate::{
+    canonical_recipient, command_output, encrypt_data, shell, CacheFile, RecipientArgs,
+    RecipientMetadata,
+};
+use chrono::{Duration, Local};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+/// Report files that are readable by recipients whose keys have expired or will expire within
+/// `within_days`. Returns true when any were found.
+pub fn rotate_check(cache: &CacheFile, within_days: i64) -> bool {
+    let today = Local::now().date_naive();
+    let horizon = today + Duration::days(within_days);
+
+    // Both by canonical recipient, as metadata may write a key differently than recipients
+    let mut metadata: BTreeMap<String, &RecipientMetadata> = BTreeMap::new();
+    for (_, config) in cache.configs() {
+        for (recipient, meta) in &config.recipient_metadata {
+            metadata
+                .entry(canonical_recipient(recipient))
+                .or_insert(meta);
+        }
+    }
+
+    let mut files_by_recipient: BTreeMap<String, BTreeSet<&Path>> = BTreeMap::new();
+    for (_, config, file) in cache.entries() {
+        for recipient in file.readers(config) {
+            files_by_recipient
+                .entry(canonical_recipient(recipient))
+                .or_default()
+                .insert(&file.source);
+        }
+    }
+
+    let mut flagged = false;
+    for (recipient, meta) in metadata {
+        let Some(expires) = meta.expires else {
+            continue;
+        };
+        if expires > horizon {
+            continue;
+        }
+        let Some(files) = files_by_recipient.get(&recipient) else {
+            continue;
+        };
+        flagged = true;
+        let owner = meta.owner.as_deref().unwrap_or("unknown owner");
+        if expires <= today {
+            println!("expired   {} ({}, expired {})", recipient, owner, expires);
+        } else {
+            let days = (expires - today).num_days();
+            println!(
+                "expiring  {} ({}, expires {}, in {} days)",
+                recipient, owner, expires, days
+            );
+        }
+        for file in files {
+            println!("  - {}", file.display());
+        }
+    }
+
+    if !flagged {
+        eprintln!("No files are encrypted to expired or expiring recipients.");
+    }
+    flagged
+}
+
+/// Replace the secret in `ciphertext` with what its `rotationCommand` prints, then run its
+/// `postRotationCommand`. Both run from `project_root` with the file's source in
+/// `ARCANUM_SOURCE`. Returns false when the hook failed.
+pub fn rotate(
+    cache: &CacheFile,
+    project_root: &Path,
+    ciphertext: &Path,
+    args: &RecipientArgs,
+    identities: &IdentityStore,
+) -> bool {
+    let relative = cache.project_relative(ciphertext);
+    let Some((_, _, file)) = cache
+        .entries()
+        .into_iter()
+        .find(|(_, _, file)| file.matches(&relative))
+    else {
+        cache.report_no_recipients(ciphertext);
+        return false;
+    };
+    let Some(command) = &file.rotation_command else {
+        eprintln!(
+            "No rotationCommand configured for {:?}, set one to mint its new value",
+            ciphertext
+        );
+        return false;
+    };
+    // Checked first, as a value minted for nobody would be lost
+    if cache.recipient_strings_for_file(ciphertext).is_empty() && !args.replace_recipients {
+        cache.report_no_recipients(ciphertext);
+        return false;
+    }
+
+    eprintln!("Rotating {:?} with {:?}", ciphertext, command);
+    let value = command_output(
+        shell(command)
+            .current_dir(project_root)
+            .env("ARCANUM_SOURCE", &file.source),
+        command,
+    );
+    encrypt_data(cache, value, ciphertext, false, None, args, identities);
+
+    let Some(hook) = &file.post_rotation_command else {
+        return true;
+    };
+    eprintln!("Running post-rotation hook {:?}", hook);
+    let status = shell(hook)
+        .current_dir(project_root)
+        .env("ARCANUM_SOURCE", &file.source)
+        .status();
+    match status {
+        Ok(status) if status.success() => true,
+        result => {
+            eprintln!(
+                "The post-rotation hook of {:?} failed ({}). The new value is written, run the \
+                 hook again by hand.",
+                ciphertext,
+                result.map_or_else(|e| e.to_string(), |status| status.to_string())
+            );
+            false
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! `arcanum serve`: decrypt secrets on demand for local development tools that can't run a
+//! command, such as docker-compose or an IDE's run configurations, over HTTP on localhost.
+//!
+//! Clients are configured per project in `.arcanum/config.toml`, each with the managed files
+//! it may read:
+//!
+//! ```toml
+//! [serve.clients.compose]
+//! allow = ["secrets/db.env.age", "secrets/api/*.age"]
+//! ```
+//!
+//! A `*` in a pattern stays within one directory, `secrets/**/*.age` reaches into all of them.
+//!
+//! Every client gets its own token, generated the first time and kept outside the project.
+//! Clients send it as `Authorization: Bearer <token>` with `GET /secret/<path of the file>`
+//! and get the plaintext back, with its transforms applied.
+
+use crate::identity::IdentityStore;
+use crate::project_config::ServeClient;
+use crate::{fsutil, transform, try_decrypt, CacheFile};
+use rand::RngCore;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+/// Largest request head read, anything longer is refused
+const MAX_HEAD: u64 = 8 * 1024;
+
+struct Client<'a> {
+    name: &'a str,
+    token: String,
+    allow: Vec<glob::Pattern>,
+}
+
+/// The token of client `name` in `dir`, generating it when there is none yet
+fn token(dir: &Path, name: &str) -> String {
+    let path = dir.join(format!("{}.token", name));
+    if let Ok(token) = std::fs::read_to_string(&path) {
+        return token.trim().to_string();
+    }
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let written = fsutil::create_dir_all_with_mode(dir, 0o700)
+        .and_then(|_| fsutil::write_atomic(&path, format!("{}\n", token).as_bytes(), 0o600, None));
+    if let Err(e) = written {
+        eprintln!("Unable to write the token of {} to {:?}: {}", name, path, e);
+        std::process::exit(1);
+    }
+    token
+}
+
+/// Compare without stopping at the first difference, so response times don't give the token
+/// away
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |d, (x, y)| d | (x ^ y)) == 0
+}
+
+/// Decode `%XX` escapes in a request path
+fn percent_decode(path: &str) -> Option<String> {
+    let mut decoded = vec![];
+    let mut bytes = path.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    String::from_utf8(decoded).ok()
+}
+
+struct Request {
+    method: String,
+    path: String,
+    token: Option<String>,
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream.take(MAX_HEAD));
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+    let mut token = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("authorization") {
+            token = value
+                .trim()
+                .strip_prefix("Bearer ")
+                .map(|t| t.trim().to_string());
+        }
+    }
+    Some(Request {
+        method,
+        path,
+        token,
+    })
+}
+
+fn respond(mut stream: &TcpStream, status: &str, body: &[u8]) {
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n",
+        status,
+        body.len()
+    );
+    if status.starts_with("401") {
+        head.push_str("WWW-Authenticate: Bearer\r\n");
+    }
+    head.push_str(if status.starts_with("200") {
+        "Content-Type: application/octet-stream\r\n\r\n"
+    } else {
+        "Content-Type: text/plain\r\n\r\n"
+    });
+    let _ = stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(body));
+}
+
+/// Answer one request, returning its status and body
+fn answer(
+    cache: &CacheFile,
+    clients: &[Client],
+    request: &Request,
+    identities: &IdentityStore,
+) -> (&'static str, Vec<u8>) {
+    let Some(client) = request.token.as_deref().and_then(|token| {
+        clients
+            .iter()
+            .find(|client| same_token(&client.token, token))
+    }) else {
+        return ("401 Unauthorized", b"missing or unknown token\n".to_vec());
+    };
+    if request.method != "GET" {
+        return (
+            "405 Method Not Allowed",
+            b"only GET is supported\n".to_vec(),
+        );
+    }
+    let Some(name) = request
+        .path
+        .strip_prefix("/secret/")
+        .and_then(percent_decode)
+    else {
+        return ("404 Not Found", b"not found\n".to_vec());
+    };
+    let source = fsutil::normalize(Path::new(&name));
+    // `*` stays within a directory, so `secrets/*` doesn't grant `secrets/prod/db.age`
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    if !client
+        .allow
+        .iter()
+        .any(|p| p.matches_path_with(&source, options))
+    {
+        eprintln!("{}: refused {}, not in its allowlist", client.name, name);
+        return ("403 Forbidden", b"not allowed for this client\n".to_vec());
+    }
+    if !cache
+        .sources()
+        .iter()
+        .any(|s| fsutil::normalize(s) == source)
+    {
+        return ("404 Not Found", b"not a managed file\n".to_vec());
+    }
+    let plaintext = std::fs::read(cache.resolve_source(&source))
+        .map_err(|e| e.to_string())
+        .and_then(|encrypted| try_decrypt(&encrypted, identities).map_err(|e| e.to_string()))
+        .and_then(|plaintext| transform::apply_all(&cache.transforms_for_file(&source), plaintext));
+    match plaintext {
+        Ok(plaintext) => {
+            eprintln!("{}: read {}", client.name, name);
+            ("200 OK", plaintext)
+        }
+        Err(e) => {
+            eprintln!("{}: unable to decrypt {}: {}", client.name, name, e);
+            ("500 Internal Server Error", b"unable to decrypt\n".to_vec())
+        }
+    }
+}
+
+/// Serve the secrets `clients` may read on localhost `port` until killed, with the clients'
+/// tokens kept in `token_dir`
+pub fn serve(
+    cache: &CacheFile,
+    clients: &BTreeMap<String, ServeClient>,
+    port: u16,
+    token_dir: &Path,
+    identities: &IdentityStore,
+) -> ! {
+    if clients.is_empty() {
+        eprintln!(
+            "No clients to serve, configure them as [serve.clients.<name>] with the files they \
+             may read in .arcanum/config.toml"
+        );
+        std::process::exit(1);
+    }
+    // Names end up in file names
+    if let Some(name) = clients.keys().find(|name| {
+        !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }) {
+        eprintln!(
+            "Invalid client name {:?}, use letters, digits, - and _",
+            name
+        );
+        std::process::exit(1);
+    }
+    let clients: Vec<Client> = clients
+        .iter()
+        .map(|(name, client)| Client {
+            name,
+            token: token(token_dir, name),
+            allow: client
+                .allow
+                .iter()
+                .map(|pattern| {
+                    glob::Pattern::new(pattern).unwrap_or_else(|e| {
+                        eprintln!("Invalid pattern {:?} for client {}: {}", pattern, name, e);
+                        std::process::exit(1);
+                    })
+                })
+                .collect(),
+        })
+        .collect();
+    // Only ever reachable from this machine
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).unwrap_or_else(|e| {
+        eprintln!("Unable to listen on 127.0.0.1:{}: {}", port, e);
+        std::process::exit(1);
+    });
+    eprintln!("Serving secrets on http://127.0.0.1:{}/secret/<path>", port);
+    for client in &clients {
+        let path = token_dir.join(format!("{}.token", client.name));
+        eprintln!("  {}: token in {:?}", client.name, path);
+    }
+    // One request at a time, so identities are never asked to unlock from two at once
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+        let Some(request) = read_request(&stream) else {
+            respond(&stream, "400 Bad Request", b"malformed request\n");
+            continue;
+        };
+        let (status, body) = answer(cache, &clients, &request, identities);
+        respond(&stream, status, &body);
+    }
+    std::process::exit(1);
+}
@@ -0,0 +1,78 @@
+//! Shamir's secret sharing over GF(2^8), splitting file keys of threshold files into shares
+//! any `threshold` of which recover the key, while fewer reveal nothing about it.
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::BTreeMap;
+
+/// Size of an age file key, the secrets split here
+pub const FILE_KEY_BYTES: usize = 16;
+
+/// Multiply in GF(2^8) with the AES polynomial
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse in GF(2^8), a^254
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1;
+    for _ in 0..254 {
+        result = gf_mul(result, a);
+    }
+    result
+}
+
+/// Split `secret` into `holders` shares, indexed from 1, any `threshold` of which recover it.
+/// `threshold` has to be between 1 and `holders`, see `quorum::validate`.
+pub fn split(
+    secret: &[u8; FILE_KEY_BYTES],
+    threshold: u8,
+    holders: u8,
+) -> Vec<[u8; FILE_KEY_BYTES]> {
+    // One random polynomial per byte, with the secret byte as its constant term
+    let mut coefficients = vec![[0u8; FILE_KEY_BYTES]; threshold as usize - 1];
+    for c in &mut coefficients {
+        OsRng.fill_bytes(c);
+    }
+    (1..=holders)
+        .map(|x| {
+            let mut share = [0u8; FILE_KEY_BYTES];
+            for (i, byte) in share.iter_mut().enumerate() {
+                // Horner's method, highest coefficient first
+                let mut y = 0;
+                for c in coefficients.iter().rev() {
+                    y = gf_mul(y, x) ^ c[i];
+                }
+                *byte = gf_mul(y, x) ^ secret[i];
+            }
+            share
+        })
+        .collect()
+}
+
+/// Recover the secret from shares by Lagrange interpolation at zero
+pub fn combine(shares: &BTreeMap<u8, [u8; FILE_KEY_BYTES]>) -> [u8; FILE_KEY_BYTES] {
+    let mut secret = [0u8; FILE_KEY_BYTES];
+    for (&xi, yi) in shares {
+        let mut basis = 1;
+        for &xj in shares.keys().filter(|&&xj| xj != xi) {
+            basis = gf_mul(basis, gf_mul(xj, gf_inv(xj ^ xi)));
+        }
+        for (byte, y) in secret.iter_mut().zip(yi) {
+            *byte ^= gf_mul(basis, *y);
+        }
+    }
+    secret
+}
@@ -0,0 +1,246 @@
+use crate::dotenv::shell_quote;
+use crate::fsutil::{create_dir_all_with_mode, parse_mode, set_mode, write_atomic};
+use crate::identity::IdentityStore;
+use crate::{
+    cache, project_hash, transform, try_decrypt, ArcanumConfig, ArcanumFile, CacheFile, Scope,
+};
+use digest::Digest;
+use notify::{RecursiveMode, Watcher};
+use sha3::Sha3_256;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The nix system double of this machine, as devShells are keyed by it
+fn current_system() -> String {
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+    format!("{}-{}", std::env::consts::ARCH, os)
+}
+
+/// Private directory holding a project's decrypted devShell secrets. It lives in the runtime
+/// directory when there is one, so the secrets are gone after logging out.
+pub fn secrets_dir(project_root: &Path) -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("arcanum")
+        .join(project_hash(project_root))
+}
+
+/// Environment variable pointing at the decrypted copy of the file entry `name`
+fn variable_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("ARCANUM_{}", name)
+}
+
+/// The configuration of the devShell `shell` for this system
+fn devshell<'a>(cache: &'a CacheFile, shell: &str) -> Option<&'a ArcanumConfig> {
+    let scope = Scope::DevShell(current_system(), shell.to_string());
+    let config = cache.sections.get(&scope);
+    if config.is_none() {
+        eprintln!("No configuration for {} in the cache", scope);
+    }
+    config
+}
+
+fn create_secrets_dir(project_root: &Path) -> Option<PathBuf> {
+    let dir = secrets_dir(project_root);
+    match create_dir_all_with_mode(&dir, 0o700).and_then(|()| set_mode(&dir, 0o700)) {
+        Ok(()) => Some(dir),
+        Err(e) => {
+            eprintln!("Unable to create {:?}: {}", dir, e);
+            None
+        }
+    }
+}
+
+/// Decrypt the file entry `name` into `dir`, returning where it was written
+fn provide(
+    cache: &CacheFile,
+    dir: &Path,
+    name: &str,
+    file: &ArcanumFile,
+    identities: &IdentityStore,
+) -> Result<PathBuf, String> {
+    if name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err("not usable as a file name".to_string());
+    }
+    let mode = parse_mode(&file.permissions)?;
+    let source = cache.resolve_source(&file.source);
+    let encrypted =
+        std::fs::read(&source).map_err(|e| format!("unable to read {:?}: {}", source, e))?;
+    // An error rather than an exit, so `watch` keeps providing the other secrets
+    let plaintext = try_decrypt(&encrypted, identities).map_err(|e| match e {
+        age::DecryptError::NoMatchingKeys => "none of your identities can decrypt it".to_string(),
+        e => format!("unable to decrypt it: {}", e),
+    })?;
+    if plaintext.is_empty() {
+        return Err("plaintext is empty".to_string());
+    }
+    let plaintext = transform::apply_all(&file.transform, plaintext)?;
+    let path = dir.join(name);
+    write_atomic(&path, &plaintext, mode, None).map_err(|e| e.to_string())?;
+    Ok(path)
+}
+
+/// Remove secrets from `dir` that were removed from the config
+fn remove_stale(dir: &Path, config: &ArcanumConfig) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !config.files.contains_key(&name) {
+            if let Err(e) = std::fs::remove_file(entry.path()) {
+                eprintln!("Unable to remove stale {:?}: {}", entry.path(), e);
+            }
+        }
+    }
+}
+
+/// Decrypt every secret of the devShell `shell` for this system into the project's secrets
+/// directory, remove ones no longer configured and print `export` lines pointing at them.
+/// Returns false when any couldn't be written.
+pub fn shell_hook(
+    cache: &CacheFile,
+    project_root: &Path,
+    shell: &str,
+    identities: &IdentityStore,
+) -> bool {
+    let Some(config) = devshell(cache, shell) else {
+        return false;
+    };
+    let Some(dir) = create_secrets_dir(project_root) else {
+        return false;
+    };
+
+    let mut ok = true;
+    let mut names: Vec<&String> = config.files.keys().collect();
+    names.sort();
+    println!(
+        "export ARCANUM_SECRETS_DIR={}",
+        shell_quote(&dir.to_string_lossy())
+    );
+    for name in names {
+        match provide(cache, &dir, name, &config.files[name], identities) {
+            Ok(path) => println!(
+                "export {}={}",
+                variable_name(name),
+                shell_quote(&path.to_string_lossy())
+            ),
+            Err(e) => {
+                eprintln!("Unable to provide {}: {}", name, e);
+                ok = false;
+            }
+        }
+    }
+    remove_stale(&dir, config);
+    ok
+}
+
+/// Hash of a ciphertext on disk, to tell when it really changed
+fn fingerprint(path: &Path) -> Option<Vec<u8>> {
+    let data = std::fs::read(path).ok()?;
+    Some(Sha3_256::digest(&data).to_vec())
+}
+
+/// Keep the devShell's secrets in the secrets directory in sync with their ciphertexts and
+/// the cache until interrupted, re-decrypting whatever changes
+pub fn watch(
+    cache: &CacheFile,
+    cache_path: &Path,
+    project_root: &Path,
+    shell: &str,
+    identities: &IdentityStore,
+) -> bool {
+    let Some(dir) = create_secrets_dir(project_root) else {
+        return false;
+    };
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(tx) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("Unable to watch for changes: {}", e);
+            return false;
+        }
+    };
+    // Directories rather than files, so ciphertexts replaced by a rename are still noticed
+    let mut watched: BTreeSet<PathBuf> = BTreeSet::new();
+    let mut reloaded: Option<CacheFile> = None;
+    let mut provided: HashMap<String, Option<Vec<u8>>> = HashMap::new();
+
+    loop {
+        let current = reloaded.as_ref().unwrap_or(cache);
+        let Some(config) = devshell(current, shell) else {
+            return false;
+        };
+        let mut names: Vec<&String> = config.files.keys().collect();
+        names.sort();
+        for name in names {
+            let file = &config.files[name];
+            let source = current.resolve_source(&file.source);
+            let fingerprint = fingerprint(&source);
+            if provided.get(name) == Some(&fingerprint) {
+                continue;
+            }
+            match provide(current, &dir, name, file, identities) {
+                Ok(path) => eprintln!("Updated {} at {:?}", name, path),
+                Err(e) => eprintln!("Unable to provide {}: {}", name, e),
+            }
+            provided.insert(name.clone(), fingerprint);
+        }
+        provided.retain(|name, _| config.files.contains_key(name));
+        remove_stale(&dir, config);
+
+        let parents = config
+            .files
+            .values()
+            .map(|file| project_root.join(&file.source))
+            .chain(std::iter::once(cache_path.to_path_buf()))
+            .filter_map(|path| path.parent().map(Path::to_path_buf));
+        for parent in parents {
+            if !watched.contains(&parent)
+                && watcher.watch(&parent, RecursiveMode::NonRecursive).is_ok()
+            {
+                watched.insert(parent);
+            }
+        }
+
+        // Wait for a change, then let a burst of events (editors, git checkouts) settle
+        let Ok(first) = rx.recv() else {
+            return true;
+        };
+        let mut events = vec![first];
+        while let Ok(event) = rx.recv_timeout(Duration::from_millis(200)) {
+            events.push(event);
+        }
+        let cache_changed = events
+            .iter()
+            .flatten()
+            .any(|event| event.paths.iter().any(|p| p == cache_path));
+        if cache_changed {
+            match std::fs::read(cache_path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| cache::decode(&data, identities))
+            {
+                Ok(mut fresh) => {
+                    eprintln!("Reloaded the cache");
+                    fresh.revoked = current.revoked.clone();
+                    reloaded = Some(fresh);
+                }
+                Err(e) => eprintln!("Ignoring unreadable cache {:?}: {}", cache_path, e),
+            }
+        }
+    }
+}
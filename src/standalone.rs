@@ -0,0 +1,189 @@
+//! Config for projects that aren't Nix flakes, read from a file at the project root instead of
+//! evaluated. It describes the files and recipients of the project-wide (flake) section with
+//! the same keys as the Nix module, only with defaults for what is required there.
+
+use crate::transform::Transform;
+use crate::{dotenv, ArcanumConfig, ArcanumFile, CacheFile, RecipientMetadata, Scope};
+use chrono::NaiveDate;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Names looked for at the project root, in order
+pub const FILE_NAMES: [&str; 4] = [
+    "arcanum.toml",
+    ".arcanum.toml",
+    "arcanum.yaml",
+    ".arcanum.yaml",
+];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct StandaloneFile {
+    source: PathBuf,
+    /// Defaults to `source` without its `.age` extension
+    dest: Option<PathBuf>,
+    #[serde(default = "default_directory_permissions")]
+    directory_permissions: String,
+    #[serde(default = "default_true")]
+    make_directory: bool,
+    #[serde(default = "default_root")]
+    group: String,
+    #[serde(default = "default_root")]
+    owner: String,
+    #[serde(default = "default_permissions")]
+    permissions: String,
+    #[serde(default)]
+    recipients: Vec<String>,
+    #[serde(default)]
+    legacy_sources: Vec<PathBuf>,
+    #[serde(default)]
+    transform: Vec<Transform>,
+    #[serde(default)]
+    restart_units: Vec<String>,
+    #[serde(default)]
+    threshold: Option<u8>,
+    #[serde(default)]
+    share_holders: Vec<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    k8s_key: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    rotation_command: Option<String>,
+    #[serde(default)]
+    post_rotation_command: Option<String>,
+    #[serde(default)]
+    expires: Option<NaiveDate>,
+    #[serde(default)]
+    max_age: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct StandaloneConfig {
+    #[serde(default)]
+    files: HashMap<String, StandaloneFile>,
+    #[serde(default)]
+    admin_recipients: Vec<String>,
+    #[serde(default)]
+    recipient_metadata: HashMap<String, RecipientMetadata>,
+    #[serde(default)]
+    env_templates: HashMap<String, dotenv::EnvTemplate>,
+}
+
+fn default_directory_permissions() -> String {
+    "0755".to_string()
+}
+
+fn default_permissions() -> String {
+    "0400".to_string()
+}
+
+fn default_root() -> String {
+    "root".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl From<StandaloneFile> for ArcanumFile {
+    fn from(file: StandaloneFile) -> Self {
+        let dest = file.dest.unwrap_or_else(|| match file.source.extension() {
+            Some(extension) if extension == "age" => file.source.with_extension(""),
+            _ => file.source.clone(),
+        });
+        ArcanumFile {
+            dest,
+            source: file.source,
+            directory_permissions: file.directory_permissions,
+            make_directory: file.make_directory,
+            group: file.group,
+            owner: file.owner,
+            permissions: file.permissions,
+            recipients: file.recipients,
+            legacy_sources: file.legacy_sources,
+            transform: file.transform,
+            restart_units: file.restart_units,
+            threshold: file.threshold,
+            share_holders: file.share_holders,
+            description: file.description,
+            k8s_key: file.k8s_key,
+            tags: file.tags,
+            rotation_command: file.rotation_command,
+            post_rotation_command: file.post_rotation_command,
+            expires: file.expires,
+            max_age: file.max_age,
+        }
+    }
+}
+
+/// The standalone config file of the project at `project_root`, if it has one
+pub fn find(project_root: &Path) -> Option<PathBuf> {
+    FILE_NAMES
+        .iter()
+        .map(|name| project_root.join(name))
+        .find(|path| path.is_file())
+}
+
+/// The closest directory from `dir` upwards with a standalone config file
+pub fn find_root(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|dir| find(dir).is_some())
+        .map(Path::to_path_buf)
+}
+
+/// Whether the project at `project_root` is configured by its standalone config file rather
+/// than by nix: it isn't a flake, or nix isn't allowed or installed to evaluate it
+pub fn in_use(project_root: &Path) -> bool {
+    find(project_root).is_some()
+        && (!project_root.join("flake.nix").exists()
+            || crate::cache::eval_forbidden()
+            || crate::provenance::environment().nix.is_none())
+}
+
+fn parse(path: &Path, data: &str) -> Result<StandaloneConfig, String> {
+    let yaml = path
+        .extension()
+        .is_some_and(|extension| extension == "yaml");
+    if yaml {
+        serde_yaml::from_str(data).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(data).map_err(|e| e.to_string())
+    }
+}
+
+/// Read the standalone config file at `path` into a cache with a single flake section
+pub fn load(path: &Path) -> Result<CacheFile, String> {
+    let data = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let config = parse(path, &data)?;
+    let config = ArcanumConfig {
+        files: config
+            .files
+            .into_iter()
+            .map(|(name, file)| (name, file.into()))
+            .collect(),
+        admin_recipients: config.admin_recipients,
+        recipient_metadata: config.recipient_metadata,
+        env_templates: config.env_templates,
+    };
+    Ok(CacheFile {
+        sections: BTreeMap::from([(Scope::Flake, config)]),
+        build: None,
+        revoked: Default::default(),
+        failed: vec![],
+        project: None,
+    })
+}
+
+/// Whether the standalone config file of the project changed since `cache` was written
+pub fn newer_than(project_root: &Path, cache: &Path) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    match find(project_root).and_then(|path| modified(&path)) {
+        Some(config) => modified(cache).is_none_or(|cache| config > cache),
+        None => false,
+    }
+}
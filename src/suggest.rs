@@ -0,0 +1,41 @@
+//! "Did you mean" suggestions for paths that don't match anything in the config.
+
+use std::path::{Path, PathBuf};
+
+/// Number of single character insertions, deletions or substitutions turning `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Up to three of `candidates` close enough to `path` to be what was meant: the same file in
+/// another directory, or a few typos away. Closest first.
+pub fn closest<'a>(path: &Path, candidates: impl IntoIterator<Item = &'a Path>) -> Vec<PathBuf> {
+    let wanted = path.to_string_lossy();
+    let limit = (wanted.chars().count() / 4).max(2);
+    let mut scored: Vec<(usize, &Path)> = candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let distance = edit_distance(&wanted, &candidate.to_string_lossy());
+            let same_name =
+                candidate.file_name().is_some() && candidate.file_name() == path.file_name();
+            (distance <= limit || same_name).then_some((distance, candidate))
+        })
+        .collect();
+    scored.sort();
+    scored.dedup();
+    scored
+        .into_iter()
+        .take(3)
+        .map(|(_, candidate)| candidate.to_path_buf())
+        .collect()
+}
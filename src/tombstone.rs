@@ -0,0 +1,106 @@
+use crate::fsutil::create_dir_all_with_mode;
+use crate::{confirm, git, metadata, CacheFile};
+use chrono::Utc;
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+
+/// A ciphertext still in the repository after its secret was removed from the config
+pub struct Tombstone {
+    /// Relative to the project root
+    pub path: PathBuf,
+    /// Commit that last added or removed the path in the Nix config, most likely its removal
+    pub removed_in: Option<String>,
+}
+
+/// Where `gc` moves tombstoned ciphertexts. They stay encrypted, so the archive is as safe to
+/// keep as the files were.
+pub fn archive_dir(project_root: &Path) -> PathBuf {
+    project_root.join(".arcanum").join("archive")
+}
+
+fn normalized(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+/// Tracked `.age` files that no entry of the config uses, at its source or a legacy location
+pub fn tombstones(cache: &CacheFile, project_root: &Path) -> Vec<Tombstone> {
+    let Some(tracked) = git::tracked(project_root, "*.age") else {
+        return vec![];
+    };
+    let managed: BTreeSet<PathBuf> = cache
+        .entries()
+        .into_iter()
+        .flat_map(|(_, _, file)| std::iter::once(&file.source).chain(&file.legacy_sources))
+        .map(|path| normalized(path))
+        .collect();
+    let archive = archive_dir(Path::new(""));
+    tracked
+        .into_iter()
+        .filter(|path| !managed.contains(path) && !path.starts_with(&archive))
+        .map(|path| {
+            let needle = path.to_string_lossy().into_owned();
+            Tombstone {
+                removed_in: git::last_commit_changing(project_root, &needle, "*.nix"),
+                path,
+            }
+        })
+        .collect()
+}
+
+/// Move the metadata sidecar of `source`, if it has one, next to its archived ciphertext
+fn archive_metadata(source: &Path, archived: &Path) -> std::io::Result<()> {
+    let sidecar = metadata::path(source);
+    if !sidecar.exists() {
+        return Ok(());
+    }
+    std::fs::copy(&sidecar, metadata::path(archived))?;
+    std::fs::remove_file(&sidecar)
+}
+
+/// Offer to archive and delete every tombstoned ciphertext. Returns false when any of them
+/// couldn't be.
+pub fn gc(cache: &CacheFile, project_root: &Path) -> bool {
+    let tombstones = tombstones(cache, project_root);
+    if tombstones.is_empty() {
+        eprintln!("No tombstoned ciphertexts");
+        return true;
+    }
+    let archive = archive_dir(project_root).join(Utc::now().format("%Y%m%dT%H%M%SZ").to_string());
+    let mut ok = true;
+    for tombstone in tombstones {
+        let source = project_root.join(&tombstone.path);
+        let question = match &tombstone.removed_in {
+            Some(commit) => format!(
+                "{} was removed from the config in {}, archive and delete it?",
+                tombstone.path.display(),
+                commit
+            ),
+            None => format!(
+                "{} isn't in the config, archive and delete it?",
+                tombstone.path.display()
+            ),
+        };
+        if !confirm::confirm(confirm::Action::Delete, &question) {
+            continue;
+        }
+        let archived = archive.join(&tombstone.path);
+        let result = create_dir_all_with_mode(archived.parent().unwrap(), 0o700)
+            .and_then(|()| std::fs::copy(&source, &archived))
+            .and_then(|_| std::fs::remove_file(&source))
+            .and_then(|()| archive_metadata(&source, &archived));
+        match result {
+            Ok(()) => println!(
+                "archived  {} to {}",
+                tombstone.path.display(),
+                archived.display()
+            ),
+            Err(e) => {
+                println!("error     {}: {}", tombstone.path.display(), e);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
@@ -0,0 +1,111 @@
+//! `arcanum totp`: current one-time codes (RFC 6238) for `otpauth://totp/...` URIs kept as
+//! secrets, so the second factor of a shared account can live next to its password.
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The parts of an `otpauth://totp/` URI needed to compute codes
+pub struct Totp {
+    secret: Vec<u8>,
+    algorithm: String,
+    digits: u32,
+    period: u64,
+}
+
+/// Decode RFC 4648 base32, ignoring case, padding and the spaces seeds are often shown with
+fn base32(encoded: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut bits: u64 = 0;
+    let mut count = 0;
+    let mut decoded = vec![];
+    for c in encoded.bytes().filter(|c| !matches!(c, b'=' | b' ' | b'-')) {
+        let value = ALPHABET.iter().position(|a| *a == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u64;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+impl Totp {
+    /// Parse an `otpauth://totp/<label>?secret=...` URI, taking the defaults of Google
+    /// Authenticator's key URI format for anything it leaves out
+    pub fn parse(uri: &str) -> Result<Self, String> {
+        let rest = uri
+            .trim()
+            .strip_prefix("otpauth://")
+            .ok_or("not an otpauth:// URI")?;
+        let (kind, rest) = rest.split_once('/').ok_or("otpauth URI without a type")?;
+        if !kind.eq_ignore_ascii_case("totp") {
+            return Err(format!("{} codes aren't supported, only totp", kind));
+        }
+        let query = rest.split_once('?').map(|(_, q)| q).unwrap_or_default();
+        let mut totp = Totp {
+            secret: vec![],
+            algorithm: "SHA1".to_string(),
+            digits: 6,
+            period: 30,
+        };
+        for (name, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+            match name.to_ascii_lowercase().as_str() {
+                "secret" => totp.secret = base32(value).ok_or("the secret is not base32")?,
+                "algorithm" => totp.algorithm = value.to_ascii_uppercase(),
+                "digits" => totp.digits = value.parse().map_err(|_| "invalid digits")?,
+                "period" => totp.period = value.parse().map_err(|_| "invalid period")?,
+                _ => {}
+            }
+        }
+        if totp.secret.is_empty() {
+            return Err("otpauth URI without a secret".to_string());
+        }
+        if !(6..=10).contains(&totp.digits) || totp.period == 0 {
+            return Err("unsupported digits or period".to_string());
+        }
+        if !["SHA1", "SHA256", "SHA512"].contains(&totp.algorithm.as_str()) {
+            return Err(format!("unsupported algorithm {}", totp.algorithm));
+        }
+        Ok(totp)
+    }
+
+    fn hmac(&self, message: &[u8]) -> Vec<u8> {
+        fn sign<M: Mac + hmac::digest::KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+            let mut mac = <M as Mac>::new_from_slice(key).unwrap();
+            mac.update(message);
+            mac.finalize().into_bytes().to_vec()
+        }
+        match self.algorithm.as_str() {
+            "SHA256" => sign::<Hmac<Sha256>>(&self.secret, message),
+            "SHA512" => sign::<Hmac<Sha512>>(&self.secret, message),
+            _ => sign::<Hmac<Sha1>>(&self.secret, message),
+        }
+    }
+
+    /// The code for `unix_time` and the seconds it stays valid for
+    pub fn code_at(&self, unix_time: u64) -> (String, u64) {
+        let counter = unix_time / self.period;
+        let hash = self.hmac(&counter.to_be_bytes());
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+        let code = u64::from(binary) % 10u64.pow(self.digits);
+        let remaining = self.period - unix_time % self.period;
+        (
+            format!("{:0width$}", code, width = self.digits as usize),
+            remaining,
+        )
+    }
+
+    /// The current code and the seconds it stays valid for
+    pub fn now(&self) -> (String, u64) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.code_at(now)
+    }
+}
@@ -0,0 +1,81 @@
+//! `--trace-file` appends a JSON line for every ciphertext arcanum writes, with a hash of it and
+//! the environment that produced it, so a ciphertext can be traced back to the software that
+//! wrote it. Plaintext and keys never end up in the trace.
+
+use crate::provenance::{environment, Environment};
+use chrono::{DateTime, Utc};
+use digest::Digest;
+use serde::Serialize;
+use sha2::Sha256;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+static TRACE: Mutex<Option<File>> = Mutex::new(None);
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Record<'a> {
+    time: DateTime<Utc>,
+    path: &'a Path,
+    /// SHA-256 of the ciphertext as written
+    sha256: String,
+    environment: &'a Environment,
+}
+
+/// Start tracing to `path`, appending to it when it exists
+pub fn init(path: Option<&Path>) {
+    let Some(path) = path else {
+        return;
+    };
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .unwrap_or_else(|e| {
+            eprintln!("Unable to open trace file {:?}: {}", path, e);
+            std::process::exit(1);
+        });
+    *TRACE.lock().unwrap() = Some(file);
+}
+
+/// Record that `ciphertext` was written to `path`
+pub fn ciphertext_written(path: &Path, ciphertext: &[u8]) {
+    record(path, || Ok(Sha256::digest(ciphertext).to_vec()));
+}
+
+/// Record that a ciphertext was streamed to the file at `path`, reading it back to hash it
+pub fn ciphertext_file_written(path: &Path) {
+    record(path, || {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut File::open(path)?, &mut hasher)?;
+        Ok(hasher.finalize().to_vec())
+    });
+}
+
+/// Append a record for `path` when tracing, with the hash `sha256` computes
+fn record(path: &Path, sha256: impl FnOnce() -> std::io::Result<Vec<u8>>) {
+    let mut trace = TRACE.lock().unwrap();
+    let Some(file) = trace.as_mut() else {
+        return;
+    };
+    let sha256 = match sha256() {
+        Ok(sha256) => sha256,
+        Err(e) => {
+            eprintln!("Unable to hash {:?} for the trace file: {}", path, e);
+            return;
+        }
+    };
+    let record = Record {
+        time: Utc::now(),
+        path,
+        sha256: sha256.iter().map(|b| format!("{:02x}", b)).collect(),
+        environment: environment(),
+    };
+    let mut line = serde_json::to_vec(&record).unwrap();
+    line.push(b'\n');
+    if let Err(e) = file.write_all(&line) {
+        eprintln!("Unable to write to the trace file: {}", e);
+    }
+}
@@ -0,0 +1,93 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+/// A reversible conversion between the plaintext stored in a ciphertext and the bytes a
+/// consumer of the secret expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transform {
+    StripTrailingNewline,
+    JsonPretty,
+    Base64Decode,
+}
+
+impl Transform {
+    /// Convert stored plaintext into the form handed out on decrypt
+    fn apply(&self, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        match self {
+            Transform::StripTrailingNewline => {
+                let mut data = data;
+                while data.last() == Some(&b'\n') || data.last() == Some(&b'\r') {
+                    data.pop();
+                }
+                Ok(data)
+            }
+            Transform::JsonPretty => {
+                let value: serde_json::Value =
+                    serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+                let mut pretty = serde_json::to_vec_pretty(&value).unwrap();
+                pretty.push(b'\n');
+                Ok(pretty)
+            }
+            Transform::Base64Decode => {
+                let trimmed: Vec<u8> = data
+                    .into_iter()
+                    .filter(|b| !b.is_ascii_whitespace())
+                    .collect();
+                STANDARD.decode(trimmed).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Convert decrypted-form plaintext back into the form that is stored on encrypt
+    fn reverse(&self, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        match self {
+            Transform::StripTrailingNewline => {
+                let mut data = data;
+                if data.last() != Some(&b'\n') {
+                    data.push(b'\n');
+                }
+                Ok(data)
+            }
+            Transform::JsonPretty => {
+                let value: serde_json::Value =
+                    serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+                Ok(serde_json::to_vec(&value).unwrap())
+            }
+            Transform::Base64Decode => Ok(STANDARD.encode(data).into_bytes()),
+        }
+    }
+}
+
+impl std::fmt::Display for Transform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transform::StripTrailingNewline => write!(f, "strip-trailing-newline"),
+            Transform::JsonPretty => write!(f, "json-pretty"),
+            Transform::Base64Decode => write!(f, "base64-decode"),
+        }
+    }
+}
+
+/// Apply `transforms` in order to freshly decrypted plaintext
+pub fn apply_all(transforms: &[Transform], data: Vec<u8>) -> Result<Vec<u8>, String> {
+    let mut data = data;
+    for transform in transforms {
+        data = transform
+            .apply(data)
+            .map_err(|e| format!("{} failed: {}", transform, e))?;
+    }
+    Ok(data)
+}
+
+/// Undo `transforms` in reverse order before plaintext is encrypted
+pub fn reverse_all(transforms: &[Transform], data: Vec<u8>) -> Result<Vec<u8>, String> {
+    let mut data = data;
+    for transform in transforms.iter().rev() {
+        data = transform
+            .reverse(data)
+            .map_err(|e| format!("reversing {} failed: {}", transform, e))?;
+    }
+    Ok(data)
+}
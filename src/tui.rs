@@ -0,0 +1,247 @@
+use crate::{output, CacheFile, FileStatus, Scope};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::process::Command;
+
+struct Entry {
+    scope: Scope,
+    source: PathBuf,
+    recipients: BTreeSet<String>,
+    status: FileStatus,
+}
+
+struct App {
+    entries: Vec<Entry>,
+    state: ListState,
+    marked: BTreeSet<usize>,
+    /// How the last action failed, shown until the next one
+    error: Option<String>,
+}
+
+impl App {
+    fn new(cache: &CacheFile) -> Self {
+        let mut app = App {
+            entries: vec![],
+            state: ListState::default(),
+            marked: BTreeSet::new(),
+            error: None,
+        };
+        app.refresh(cache);
+        if !app.entries.is_empty() {
+            app.state.select(Some(0));
+        }
+        app
+    }
+
+    /// Recompute every entry's recipients and status, e.g. after a rekey
+    fn refresh(&mut self, cache: &CacheFile) {
+        self.entries = cache
+            .entries()
+            .into_iter()
+            .map(|(scope, _, file)| Entry {
+                scope,
+                source: file.source.clone(),
+                recipients: cache.recipient_strings_for_file(&file.source),
+                status: cache.status_of(file),
+            })
+            .collect();
+    }
+
+    fn current(&self) -> Option<&Entry> {
+        self.state.selected().and_then(|i| self.entries.get(i))
+    }
+
+    /// Marked entries, or the highlighted one when nothing is marked
+    fn targets(&self) -> Vec<PathBuf> {
+        let mut targets: BTreeSet<PathBuf> = self
+            .marked
+            .iter()
+            .filter_map(|i| self.entries.get(*i))
+            .map(|entry| entry.source.clone())
+            .collect();
+        if targets.is_empty() {
+            if let Some(entry) = self.current() {
+                targets.insert(entry.source.clone());
+            }
+        }
+        targets.into_iter().collect()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Percentage(65),
+                Constraint::Min(5),
+                Constraint::Length(1),
+            ])
+            .split(frame.area());
+
+        let mut items = vec![];
+        let mut previous_scope: Option<&Scope> = None;
+        for (i, entry) in self.entries.iter().enumerate() {
+            // Only label the first file of each section to visually group them
+            let scope = if previous_scope == Some(&entry.scope) {
+                String::new()
+            } else {
+                entry.scope.to_string()
+            };
+            previous_scope = Some(&entry.scope);
+            let mark = if self.marked.contains(&i) { "*" } else { " " };
+            items.push(ListItem::new(format!(
+                "{} {:<32} {:<10} {}",
+                mark,
+                scope,
+                entry.status.label(),
+                entry.source.display()
+            )));
+        }
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title("Secrets"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, chunks[0], &mut self.state);
+
+        let details: Vec<Line> = match self.current() {
+            Some(entry) => entry
+                .recipients
+                .iter()
+                .map(|r| Line::from(r.as_str()))
+                .collect(),
+            None => vec![],
+        };
+        let details = Paragraph::new(details)
+            .block(Block::default().borders(Borders::ALL).title("Recipients"));
+        frame.render_widget(details, chunks[1]);
+
+        let help = match &self.error {
+            Some(error) => Paragraph::new(error.as_str())
+                .style(Style::default().add_modifier(Modifier::BOLD | Modifier::REVERSED)),
+            None => Paragraph::new("↑/↓ move  space mark  enter edit  r rekey  q quit"),
+        };
+        frame.render_widget(help, chunks[2]);
+    }
+
+    fn next(&mut self) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let i = self
+            .state
+            .selected()
+            .map_or(0, |i| (i + 1) % self.entries.len());
+        self.state.select(Some(i));
+    }
+
+    fn previous(&mut self) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let i = self
+            .state
+            .selected()
+            .map_or(0, |i| (i + self.entries.len() - 1) % self.entries.len());
+        self.state.select(Some(i));
+    }
+
+    fn toggle_mark(&mut self) {
+        if let Some(i) = self.state.selected() {
+            if !self.marked.remove(&i) {
+                self.marked.insert(i);
+            }
+        }
+    }
+}
+
+/// Run `action` with the terminal restored to normal mode, so editors and prompts work
+fn suspended<T>(terminal: &mut DefaultTerminal, action: impl FnOnce() -> T) -> T {
+    ratatui::restore();
+    let result = action();
+    eprintln!("Press enter to return");
+    let mut line = String::new();
+    let _ = std::io::stdin().lock().read_line(&mut line);
+    *terminal = ratatui::init();
+    result
+}
+
+/// Run `arcanum <command> <sources>` with the global options this was started with. The
+/// commands exit on anything going wrong, which would leave the terminal in raw mode if they
+/// ran in this process, so failures come back as an error to show instead.
+fn run_command(command: &str, sources: &[PathBuf]) -> Result<(), String> {
+    let program = std::env::current_exe().map_err(|e| format!("Unable to run arcanum: {}", e))?;
+    let mut args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    let Some(tui) = args.iter().rposition(|arg| arg == "tui") else {
+        return Err("Unable to tell the global options apart".to_string());
+    };
+    args[tui] = command.into();
+    args.extend(sources.iter().map(OsString::from));
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| format!("Unable to run arcanum {}: {}", command, e))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(format!(
+            "arcanum {} failed ({}), see above",
+            command, status
+        )),
+    }
+}
+
+fn event_loop(
+    terminal: &mut DefaultTerminal,
+    app: &mut App,
+    cache: &CacheFile,
+) -> std::io::Result<()> {
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Down | KeyCode::Char('j') => app.next(),
+            KeyCode::Up | KeyCode::Char('k') => app.previous(),
+            KeyCode::Char(' ') => app.toggle_mark(),
+            KeyCode::Enter => {
+                if let Some(entry) = app.current() {
+                    let source = entry.source.clone();
+                    app.error = suspended(terminal, || run_command("edit", &[source])).err();
+                    app.refresh(cache);
+                }
+            }
+            KeyCode::Char('r') => {
+                let targets = app.targets();
+                app.error = suspended(terminal, || run_command("rekey", &targets)).err();
+                app.marked.clear();
+                app.refresh(cache);
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn run(cache: &CacheFile) -> std::io::Result<()> {
+    if output::plain() {
+        eprintln!("The interactive interface isn't available with plain output.");
+        eprintln!(
+            "Use `arcanum status` to list files, and `arcanum edit` or `arcanum rekey` on them."
+        );
+        std::process::exit(1);
+    }
+    let mut app = App::new(cache);
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &mut app, cache);
+    ratatui::restore();
+    result
+}
@@ -0,0 +1,80 @@
+//! Checking that a ciphertext about to be written decrypts back to the plaintext it was made
+//! from, before it replaces anything. On by default, `--verify false` turns it off.
+
+use crate::identity::IdentityStore;
+use crate::{confirm, try_decrypt};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static VERIFY: AtomicBool = AtomicBool::new(true);
+
+pub fn init(verify: bool) {
+    VERIFY.store(verify, Ordering::Relaxed);
+}
+
+/// Decrypt `ciphertext_data`, meant for `path`, and exit unless it gives back `plaintext`.
+/// Ciphertexts none of `identities` can decrypt, such as ones only for hosts, can't be checked
+/// and are only written once confirmed, as you couldn't read them back.
+pub fn round_trip(
+    path: &Path,
+    ciphertext_data: &[u8],
+    plaintext: &[u8],
+    identities: &IdentityStore,
+) {
+    if !VERIFY.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Err(e) = check(path, ciphertext_data, plaintext, identities) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// `round_trip` for a ciphertext for `path` already staged in the file `staged`, returning why
+/// it mustn't be written instead of exiting, so the staged file can be removed first
+pub fn round_trip_file(
+    path: &Path,
+    staged: &Path,
+    plaintext: &[u8],
+    identities: &IdentityStore,
+) -> Result<(), String> {
+    if !VERIFY.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let ciphertext_data = std::fs::read(staged).map_err(|e| {
+        format!(
+            "Unable to read back the new ciphertext for {:?}: {}",
+            path, e
+        )
+    })?;
+    check(path, &ciphertext_data, plaintext, identities)
+}
+
+fn check(
+    path: &Path,
+    ciphertext_data: &[u8],
+    plaintext: &[u8],
+    identities: &IdentityStore,
+) -> Result<(), String> {
+    match try_decrypt(ciphertext_data, identities) {
+        Ok(decrypted) if decrypted == plaintext => Ok(()),
+        Ok(_) => Err(format!(
+            "The new ciphertext for {:?} doesn't decrypt to the plaintext, not writing it",
+            path
+        )),
+        Err(age::DecryptError::NoMatchingKeys) => {
+            let question = format!(
+                "None of your identities can decrypt the new ciphertext for {:?}. Write it anyway?",
+                path
+            );
+            match confirm::confirm(confirm::Action::Unreadable, &question) {
+                true => Ok(()),
+                false => Err(format!("Not writing {:?}", path)),
+            }
+        }
+        Err(e) => Err(format!(
+            "The new ciphertext for {:?} doesn't decrypt ({}), not writing it",
+            path, e
+        )),
+    }
+}
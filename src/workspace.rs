@@ -0,0 +1,181 @@
+//! Private directory plaintexts are edited in. Editors leave swap, backup and undo files next
+//! to what they edit and sometimes in their own state directories, so everything the editor
+//! may have written is looked for afterwards, reported and wiped.
+
+use crate::editor;
+use crate::fsutil::{create_dir_all_with_mode, create_dir_with_mode, create_with_mode};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+struct Workspace(PathBuf);
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        for file in files_below(&self.0) {
+            wipe(&file);
+        }
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn files_below(dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return files;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => files.extend(files_below(&path)),
+            Ok(_) => files.push(path),
+            Err(_) => {}
+        }
+    }
+    files
+}
+
+/// Overwrite `path` with zeros before removing it. Best effort: copy-on-write file systems
+/// and SSDs may keep the old blocks around anyway.
+fn wipe(path: &Path) {
+    let overwrite = || -> std::io::Result<()> {
+        let metadata = std::fs::symlink_metadata(path)?;
+        if !metadata.is_file() {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.write_all(&vec![0; metadata.len() as usize])?;
+        file.sync_all()
+    };
+    let _ = overwrite();
+    let _ = std::fs::remove_file(path);
+}
+
+/// Editor files that keep history, registers or auto-saves, and so can end up holding parts
+/// of an edited plaintext
+fn editor_state_files() -> Vec<PathBuf> {
+    let Some(home) = dirs::home_dir() else {
+        return vec![];
+    };
+    [
+        ".viminfo",
+        ".local/state/nvim/shada/main.shada",
+        ".local/share/nvim/shada/main.shada",
+        ".emacs.d/auto-save-list",
+    ]
+    .iter()
+    .map(|path| home.join(path))
+    .collect()
+}
+
+/// Directories editors can be configured to keep swap, backup and undo files in, named after
+/// the full path of the edited file
+fn editor_leak_dirs() -> Vec<PathBuf> {
+    let Some(home) = dirs::home_dir() else {
+        return vec![];
+    };
+    [
+        ".vim/swap",
+        ".vim/backup",
+        ".vim/undo",
+        ".local/state/vim/swap",
+        ".local/state/vim/undo",
+        ".local/state/nvim/swap",
+        ".local/state/nvim/undo",
+        ".local/share/nvim/swap",
+        ".local/share/nvim/undo",
+        ".emacs.d/backups",
+    ]
+    .iter()
+    .map(|path| home.join(path))
+    .collect()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Report and wipe what the editor left behind, inside the workspace and in its own state
+/// directories, and warn about state files it updated while the plaintexts were open
+fn clean_up(workspace: &Path, expected: &[PathBuf], state_before: &[Option<SystemTime>]) {
+    for stray in files_below(workspace) {
+        if !expected.contains(&stray) {
+            let name = stray.strip_prefix(workspace).unwrap_or(&stray);
+            eprintln!(
+                "warning: the editor left {:?} next to the plaintexts, wiping it",
+                name
+            );
+        }
+    }
+    let marker = workspace
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    for dir in editor_leak_dirs() {
+        for file in files_below(&dir) {
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            if name.contains(&marker) {
+                eprintln!("warning: the editor kept {:?}, wiping it", file);
+                wipe(&file);
+            }
+        }
+    }
+    for (path, before) in editor_state_files().iter().zip(state_before) {
+        let after = modified(path);
+        if after.is_some() && after != *before {
+            eprintln!(
+                "warning: {:?} was updated while editing and may hold parts of the plaintext \
+                 (registers, history or auto-saves)",
+                path
+            );
+        }
+    }
+}
+
+/// Write `files`, each a relative name and its plaintext, into a fresh private directory and
+/// run `f` with their paths. Everything in the directory is wiped afterwards, whether `f`
+/// succeeded or not, so `f` must not exit.
+pub fn with_files<T>(
+    files: &[(PathBuf, &[u8])],
+    f: impl FnOnce(&Path, &[PathBuf]) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let root = std::env::temp_dir().join(format!("arcanum-edit-{}", std::process::id()));
+    // Never reuse an existing directory, someone else may control it
+    create_dir_with_mode(&root, 0o700)?;
+    let workspace = Workspace(root);
+    let result = (|| {
+        let paths = files
+            .iter()
+            .map(|(name, plaintext)| {
+                let path = workspace.0.join(name);
+                create_dir_all_with_mode(path.parent().unwrap(), 0o700)?;
+                create_with_mode(&path, 0o600)?.write_all(plaintext)?;
+                Ok(path)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        f(&workspace.0, &paths)
+    })();
+    drop(workspace);
+    result
+}
+
+/// Open `files`, each a relative name and its plaintext, together in the editor from a
+/// private directory and return what they were edited into. Nothing is left on disk
+/// afterwards, whether the edit succeeded or not.
+pub fn edit(files: &[(PathBuf, &[u8])]) -> std::io::Result<Vec<Vec<u8>>> {
+    let state_before: Vec<Option<SystemTime>> =
+        editor_state_files().iter().map(|p| modified(p)).collect();
+    with_files(files, |root, paths| {
+        let refs: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        let result = editor::open(&refs).and_then(|()| {
+            paths
+                .iter()
+                .map(std::fs::read)
+                .collect::<std::io::Result<Vec<_>>>()
+        });
+        clean_up(root, paths, &state_before);
+        result
+    })
+}
@@ -0,0 +1,468 @@
+//! The arcanum binary run against throwaway git repositories. The config is read with
+//! `--config-json` from a file standing for what the flake would evaluate to, so no nix is
+//! needed, and identities are generated per test and passed in `ARCANUM_IDENTITY`.
+
+use age::secrecy::ExposeSecret;
+use age::Recipient;
+use arcanum::{decrypt_bytes, encrypt_bytes};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+struct Project {
+    root: TempDir,
+    /// Cache directory, config directory and home, kept out of the project
+    home: TempDir,
+    identity: age::x25519::Identity,
+}
+
+impl Project {
+    /// An empty git repository, give it a config with `set_config`
+    fn new() -> Self {
+        let root = tempfile::tempdir().unwrap();
+        let status = std::process::Command::new("git")
+            .args(["init", "--quiet"])
+            .current_dir(root.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        std::fs::create_dir(root.path().join("secrets")).unwrap();
+        Project {
+            root,
+            home: tempfile::tempdir().unwrap(),
+            identity: age::x25519::Identity::generate(),
+        }
+    }
+
+    fn set_config(&self, config: Value) {
+        let data = serde_json::to_vec_pretty(&config).unwrap();
+        std::fs::write(self.root.path().join("config.json"), data).unwrap();
+    }
+
+    fn path(&self, relative: &str) -> PathBuf {
+        self.root.path().join(relative)
+    }
+
+    fn recipient(&self) -> String {
+        self.identity.to_public().to_string()
+    }
+
+    fn arcanum(&self) -> assert_cmd::Command {
+        assert_cmd::Command::from_std(self.command())
+    }
+
+    /// The binary set up like `arcanum`, for running it in the background
+    fn command(&self) -> std::process::Command {
+        let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_arcanum"));
+        command
+            .current_dir(self.root.path())
+            .env("HOME", self.home.path())
+            .env("XDG_CONFIG_HOME", self.home.path().join("config"))
+            .env("ARCANUM_CONFIG_JSON", self.path("config.json"))
+            .env("ARCANUM_CACHE_DIR", self.home.path().join("cache"))
+            .env(
+                "ARCANUM_IDENTITY",
+                self.identity.to_string().expose_secret(),
+            )
+            .env("ARCANUM_ALLOW_ROOT", "true")
+            .env("ARCANUM_NO_INPUT", "true")
+            .env_remove("ARCANUM_AGENT_SOCK")
+            .env_remove("ARCANUM_PROJECT")
+            .env_remove("ARCANUM_CACHE_FILE");
+        command
+    }
+
+    fn stdout(&self, args: &[&str]) -> String {
+        let assert = self.arcanum().args(args).assert().success();
+        String::from_utf8(assert.get_output().stdout.clone()).unwrap()
+    }
+
+    fn decrypt(&self, path: &Path, identity: &age::x25519::Identity) -> Vec<u8> {
+        decrypt_bytes(&[identity], &std::fs::read(path).unwrap()).unwrap()
+    }
+}
+
+/// A config with the file `secrets/db.age` for the host `web`, readable by `recipients`
+fn config(recipients: &[String]) -> Value {
+    json!({
+        "nixos": {
+            "web": {
+                "adminRecipients": [],
+                "files": {
+                    "db": {
+                        "source": "secrets/db.age",
+                        "dest": "/run/secrets/db",
+                        "directoryPermissions": "0755",
+                        "makeDirectory": true,
+                        "owner": "",
+                        "group": "",
+                        "permissions": "0400",
+                        "recipients": recipients,
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn to(identities: &[&age::x25519::Identity]) -> Vec<Box<dyn Recipient + Send>> {
+    identities
+        .iter()
+        .map(|identity| Box::new(identity.to_public()) as Box<dyn Recipient + Send>)
+        .collect()
+}
+
+#[test]
+fn cache_reads_the_config() {
+    let project = Project::new();
+    project.set_config(config(&[project.recipient()]));
+    project.arcanum().arg("cache").assert().success();
+    let shown = project.stdout(&["cache", "show"]);
+    assert!(shown.contains("nixos web"), "{}", shown);
+    assert!(
+        shown.contains("secrets/db.age -> /run/secrets/db"),
+        "{}",
+        shown
+    );
+}
+
+#[test]
+fn cache_picks_up_config_changes() {
+    let project = Project::new();
+    project.set_config(config(&[]));
+    project.arcanum().arg("cache").assert().success();
+    let mut changed = config(&[]);
+    changed["nixos"]["db"] = changed["nixos"]["web"].clone();
+    project.set_config(changed);
+    project.arcanum().arg("cache").assert().success();
+    let shown = project.stdout(&["cache", "show"]);
+    assert!(shown.contains("nixos db"), "{}", shown);
+}
+
+#[test]
+fn cache_refuses_unknown_schema_versions() {
+    let mut newer = config(&[]);
+    newer["schemaVersion"] = json!(999);
+    let project = Project::new();
+    project.set_config(newer);
+    project.arcanum().arg("cache").assert().failure();
+}
+
+/// Check `value` against the parts of JSON Schema the API schemas use, also failing on fields
+/// the schema doesn't know, as a version's documents must not change
+fn assert_matches(schema: &Value, value: &Value, at: &str) {
+    if let Some(expected) = schema.get("const") {
+        assert_eq!(value, expected, "{}", at);
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        assert!(
+            allowed.contains(value),
+            "{}: {} isn't one of {:?}",
+            at,
+            value,
+            allowed
+        );
+    }
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            other => other.as_str().into_iter().collect(),
+        };
+        let actual = match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        };
+        assert!(
+            types.contains(&actual),
+            "{}: {} isn't {:?}",
+            at,
+            actual,
+            types
+        );
+    }
+    if let (Some(minimum), Some(number)) = (schema.get("minimum"), value.as_f64()) {
+        assert!(
+            number >= minimum.as_f64().unwrap(),
+            "{}: below the minimum",
+            at
+        );
+    }
+    if let (Some(items), Some(values)) = (schema.get("items"), value.as_array()) {
+        for (i, item) in values.iter().enumerate() {
+            assert_matches(items, item, &format!("{}[{}]", at, i));
+        }
+    }
+    if let Some(object) = value.as_object() {
+        let properties = schema["properties"].as_object().unwrap();
+        for required in schema["required"].as_array().unwrap() {
+            let required = required.as_str().unwrap();
+            assert!(object.contains_key(required), "{}: no {}", at, required);
+        }
+        for (key, field) in object {
+            let property = properties.get(key);
+            let property = property.unwrap_or_else(|| panic!("{}: unknown field {}", at, key));
+            assert_matches(property, field, &format!("{}.{}", at, key));
+        }
+    }
+}
+
+/// `cache emit` of API `version` against `schema` of the same version
+fn assert_emit_matches_schema(version: &str) {
+    let project = Project::new();
+    project.set_config(config(&[project.recipient()]));
+    let schema = project.stdout(&["schema", "--version", version]);
+    let emitted = project.stdout(&["cache", "emit", "--version", version]);
+    let schema: Value = serde_json::from_str(&schema).unwrap();
+    let emitted: Value = serde_json::from_str(&emitted).unwrap();
+    assert!(!emitted["sections"][0]["files"]
+        .as_array()
+        .unwrap()
+        .is_empty());
+    assert_matches(&schema, &emitted, "$");
+}
+
+#[test]
+fn emit_version_1_matches_its_schema() {
+    assert_emit_matches_schema("1");
+}
+
+#[test]
+fn doctor_runs_outside_a_project() {
+    let project = Project::new();
+    project
+        .arcanum()
+        .current_dir(project.home.path())
+        .arg("doctor")
+        .assert()
+        .success();
+}
+
+#[test]
+fn edit_stdin_encrypts_to_the_recipients() {
+    let project = Project::new();
+    let other = age::x25519::Identity::generate();
+    project.set_config(config(&[
+        project.recipient(),
+        other.to_public().to_string(),
+    ]));
+    project
+        .arcanum()
+        .args(["edit", "secrets/db.age", "--stdin"])
+        .write_stdin("hunter2\n")
+        .assert()
+        .success();
+    let db = project.path("secrets/db.age");
+    assert_eq!(project.decrypt(&db, &project.identity), b"hunter2\n");
+    assert_eq!(project.decrypt(&db, &other), b"hunter2\n");
+
+    project
+        .arcanum()
+        .args(["edit", "secrets/db.age", "--stdin"])
+        .write_stdin("hunter3\n")
+        .assert()
+        .success();
+    assert_eq!(project.decrypt(&db, &other), b"hunter3\n");
+}
+
+#[test]
+fn edit_stdin_refuses_unmanaged_files() {
+    let project = Project::new();
+    project.set_config(config(&[project.recipient()]));
+    project
+        .arcanum()
+        .args(["edit", "secrets/unknown.age", "--stdin"])
+        .write_stdin("hunter2\n")
+        .assert()
+        .failure();
+    assert!(!project.path("secrets/unknown.age").exists());
+}
+
+#[test]
+fn rekey_encrypts_to_added_recipients() {
+    let project = Project::new();
+    project.set_config(config(&[project.recipient()]));
+    let db = project.path("secrets/db.age");
+    std::fs::write(&db, encrypt_bytes(to(&[&project.identity]), b"hunter2")).unwrap();
+
+    let added = age::x25519::Identity::generate();
+    project.set_config(config(&[
+        project.recipient(),
+        added.to_public().to_string(),
+    ]));
+    project.arcanum().arg("cache").assert().success();
+    project
+        .arcanum()
+        .args(["rekey", "secrets/db.age"])
+        .assert()
+        .success();
+    assert_eq!(project.decrypt(&db, &added), b"hunter2");
+    assert_eq!(project.decrypt(&db, &project.identity), b"hunter2");
+}
+
+#[test]
+fn rekey_drops_removed_recipients() {
+    let project = Project::new();
+    let removed = age::x25519::Identity::generate();
+    let db = project.path("secrets/db.age");
+    std::fs::write(
+        &db,
+        encrypt_bytes(to(&[&project.identity, &removed]), b"hunter2"),
+    )
+    .unwrap();
+    project.set_config(config(&[project.recipient()]));
+    project
+        .arcanum()
+        .args(["rekey", "secrets/db.age"])
+        .assert()
+        .success();
+    assert_eq!(project.decrypt(&db, &project.identity), b"hunter2");
+    assert!(decrypt_bytes(&[&removed], &std::fs::read(&db).unwrap()).is_err());
+}
+
+/// Write the three sides of a merge of `secrets/db.age`, returning their paths
+fn sides(project: &Project, base: &str, ours: &str, theirs: &str) -> [PathBuf; 3] {
+    let recipients = || to(&[&project.identity]);
+    let paths = ["base", "ours", "theirs"].map(|side| project.home.path().join(side));
+    for (path, plaintext) in paths.iter().zip([base, ours, theirs]) {
+        std::fs::write(path, encrypt_bytes(recipients(), plaintext.as_bytes())).unwrap();
+    }
+    paths
+}
+
+#[test]
+fn merge_combines_both_sides() {
+    let project = Project::new();
+    project.set_config(config(&[project.recipient()]));
+    let [base, ours, theirs] = sides(
+        &project,
+        "one\ntwo\nthree\nfour\nfive\n",
+        "ONE\ntwo\nthree\nfour\nfive\n",
+        "one\ntwo\nthree\nfour\nFIVE\n",
+    );
+    project
+        .arcanum()
+        .arg("merge")
+        .args([&base, &ours, &theirs])
+        .args(["--path", "secrets/db.age"])
+        .assert()
+        .success();
+    assert_eq!(
+        project.decrypt(&ours, &project.identity),
+        b"ONE\ntwo\nthree\nfour\nFIVE\n"
+    );
+}
+
+#[test]
+fn merge_combines_changed_keys() {
+    let project = Project::new();
+    project.set_config(config(&[project.recipient()]));
+    let [base, ours, theirs] = sides(&project, "a: 1\nb: 2\n", "a: 10\nb: 2\n", "a: 1\nb: 20\n");
+    project
+        .arcanum()
+        .arg("merge")
+        .args([&base, &ours, &theirs])
+        .args(["--path", "secrets/db.age"])
+        .assert()
+        .success();
+    assert_eq!(project.decrypt(&ours, &project.identity), b"a: 10\nb: 20\n");
+}
+
+#[test]
+fn merge_reports_conflicts() {
+    let project = Project::new();
+    project.set_config(config(&[project.recipient()]));
+    let [base, ours, theirs] = sides(&project, "one\n", "two\n", "three\n");
+    project
+        .arcanum()
+        .arg("merge")
+        .args([&base, &ours, &theirs])
+        .args(["--path", "secrets/db.age"])
+        .assert()
+        .failure();
+    let merged = project.decrypt(&ours, &project.identity);
+    let merged = String::from_utf8(merged).unwrap();
+    assert!(
+        merged.contains("<<<<<<<") && merged.contains(">>>>>>>"),
+        "{}",
+        merged
+    );
+}
+
+/// Status line of `arcanum serve`'s answer to `GET /secret/<path>` with `token`
+fn serve_status(port: u16, token: &str, path: &str) -> String {
+    use std::io::{BufRead, BufReader, Write};
+    let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(
+        stream,
+        "GET /secret/{} HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+        path, token
+    )
+    .unwrap();
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status).unwrap();
+    status.trim_end().to_string()
+}
+
+#[test]
+fn serve_allowlist_globs_stay_within_a_directory() {
+    let project = Project::new();
+    let mut config = config(&[project.recipient()]);
+    let files = &mut config["nixos"]["web"]["files"];
+    let mut nested = files["db"].clone();
+    nested["source"] = json!("secrets/prod/db.age");
+    files["prod-db"] = nested;
+    project.set_config(config);
+    std::fs::create_dir(project.path(".arcanum")).unwrap();
+    std::fs::write(
+        project.path(".arcanum/config.toml"),
+        "[serve.clients.compose]\nallow = [\"secrets/*\"]\n",
+    )
+    .unwrap();
+    let port = std::net::TcpListener::bind(("127.0.0.1", 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut server = project
+        .command()
+        .args(["serve", "--port", &port.to_string()])
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // The token is written before the server listens
+    let mut statuses = None;
+    for _ in 0..100 {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let token = glob::glob(&format!(
+            "{}/cache/*-serve/compose.token",
+            project.home.path().display()
+        ))
+        .unwrap()
+        .flatten()
+        .find_map(|path| std::fs::read_to_string(path).ok());
+        let Some(token) = token.filter(|token| !token.is_empty()) else {
+            continue;
+        };
+        if std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+            continue;
+        }
+        statuses = Some([
+            serve_status(port, token.trim(), "secrets/prod/db.age"),
+            serve_status(port, token.trim(), "secrets/other.age"),
+        ]);
+        break;
+    }
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    let [nested, unmanaged] = statuses.expect("serve didn't start");
+    assert_eq!(nested, "HTTP/1.1 403 Forbidden");
+    // Allowed, so it gets as far as not being managed
+    assert_eq!(unmanaged, "HTTP/1.1 404 Not Found");
+}
@@ -0,0 +1,261 @@
+//! Round trips through `encrypt_bytes` and `decrypt_bytes` with random payloads and mixes of
+//! recipients. Payloads come from a seeded generator, so a failing case reproduces on every
+//! run; its seed and size are in the assertion message.
+//!
+//! The ssh keys in `tests/fixtures` were generated for these tests and protect nothing.
+
+use age::{DecryptError, EncryptError, Identity, Recipient};
+use arcanum::{armor, decrypt_bytes, encrypt_binary, encrypt_bytes, try_encrypt_bytes};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use std::io::Write;
+use std::str::FromStr;
+
+const CASES: u64 = 64;
+
+fn ssh_identity(name: &str) -> (age::ssh::Identity, age::ssh::Recipient) {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let private = std::fs::read(dir.join(name)).unwrap();
+    let identity = age::ssh::Identity::from_buffer(&private[..], Some(name.to_string())).unwrap();
+    // Without the comment, as arcanum passes recipients to age
+    let public = std::fs::read_to_string(dir.join(format!("{}.pub", name))).unwrap();
+    let public: Vec<&str> = public.split_whitespace().take(2).collect();
+    let recipient = age::ssh::Recipient::from_str(&public.join(" ")).unwrap();
+    (identity, recipient)
+}
+
+fn random_payload(rng: &mut StdRng, len: usize) -> Vec<u8> {
+    let mut payload = vec![0; len];
+    rng.fill_bytes(&mut payload);
+    payload
+}
+
+fn round_trip(
+    recipients: Vec<Box<dyn Recipient + Send>>,
+    identity: &dyn Identity,
+    plaintext: &[u8],
+) -> Vec<u8> {
+    let ciphertext = encrypt_bytes(recipients, plaintext);
+    assert!(ciphertext.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----"));
+    decrypt_bytes(&[identity], &ciphertext).unwrap()
+}
+
+#[test]
+fn random_payloads() {
+    let identity = age::x25519::Identity::generate();
+    for seed in 0..CASES {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let len = rng.gen_range(0..64 * 1024);
+        let plaintext = random_payload(&mut rng, len);
+        let decrypted = round_trip(vec![Box::new(identity.to_public())], &identity, &plaintext);
+        assert!(decrypted == plaintext, "seed {} with {} bytes", seed, len);
+    }
+}
+
+#[test]
+fn sizes_around_parallel_armoring() {
+    let identity = age::x25519::Identity::generate();
+    let mut rng = StdRng::seed_from_u64(0);
+    let threshold = armor::PARALLEL_THRESHOLD;
+    // age encrypts in 64KiB chunks, so chunk boundaries are covered too
+    for len in [
+        1,
+        64 * 1024 - 1,
+        64 * 1024,
+        64 * 1024 + 1,
+        threshold - 1,
+        threshold,
+        threshold + 1,
+        3 * threshold + 17,
+    ] {
+        let plaintext = random_payload(&mut rng, len);
+        let decrypted = round_trip(vec![Box::new(identity.to_public())], &identity, &plaintext);
+        assert!(decrypted == plaintext, "{} bytes", len);
+    }
+}
+
+#[test]
+fn empty_plaintext() {
+    let identity = age::x25519::Identity::generate();
+    let decrypted = round_trip(vec![Box::new(identity.to_public())], &identity, b"");
+    assert!(decrypted.is_empty());
+}
+
+#[test]
+fn binary_data() {
+    let identity = age::x25519::Identity::generate();
+    let every_byte: Vec<u8> = (0..=255).cycle().take(256 * 64).collect();
+    let nuls = vec![0; 4096];
+    // Plaintext that looks like armor must not confuse dearmoring
+    let armor_lookalike =
+        b"-----BEGIN AGE ENCRYPTED FILE-----\nYWdl\n-----END AGE ENCRYPTED FILE-----\n";
+    let crlf = b"line\r\nline\r\n\r\n".repeat(100);
+    for plaintext in [&every_byte[..], &nuls, armor_lookalike, &crlf] {
+        let decrypted = round_trip(vec![Box::new(identity.to_public())], &identity, plaintext);
+        assert_eq!(decrypted, plaintext);
+    }
+}
+
+#[test]
+fn binary_ciphertext() {
+    let identity = age::x25519::Identity::generate();
+    let mut rng = StdRng::seed_from_u64(1);
+    for len in [0, 1000, armor::PARALLEL_THRESHOLD + 1] {
+        let plaintext = random_payload(&mut rng, len);
+        let ciphertext = encrypt_binary(vec![Box::new(identity.to_public())], &plaintext);
+        assert!(ciphertext.starts_with(b"age-encryption.org/v1\n"));
+        assert!(decrypt_bytes(&[&identity], &ciphertext).unwrap() == plaintext);
+        // And armored the way arcanum does it for large secrets
+        let armored = armor::armor(&ciphertext);
+        assert!(decrypt_bytes(&[&identity], &armored).unwrap() == plaintext);
+    }
+}
+
+#[test]
+fn streamed_armor_matches_armor() {
+    let mut rng = StdRng::seed_from_u64(2);
+    for len in [0, 1, 47, 48, 49, 2 * armor::PARALLEL_THRESHOLD + 17] {
+        let binary = random_payload(&mut rng, len);
+        let mut streamed = vec![];
+        let mut writer = armor::ArmorWriter::new(&mut streamed).unwrap();
+        // In pieces not lining up with armored lines, as an encryptor writes
+        for piece in binary.chunks(7919) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap();
+        assert!(streamed == armor::armor(&binary), "{} bytes", len);
+    }
+}
+
+#[test]
+fn huge_recipient_sets() {
+    let identities: Vec<age::x25519::Identity> = (0..500)
+        .map(|_| age::x25519::Identity::generate())
+        .collect();
+    let recipients = identities
+        .iter()
+        .map(|identity| Box::new(identity.to_public()) as Box<dyn Recipient + Send>)
+        .collect();
+    let ciphertext = encrypt_bytes(recipients, b"shared by everyone");
+    // Every identity tries every stanza, so only a few are checked
+    for identity in [0, 1, 250, 499].map(|i| &identities[i]) {
+        assert_eq!(
+            decrypt_bytes(&[identity], &ciphertext).unwrap(),
+            b"shared by everyone"
+        );
+    }
+}
+
+#[test]
+fn ssh_and_x25519_mixed() {
+    let x25519 = age::x25519::Identity::generate();
+    let (ed25519, ed25519_recipient) = ssh_identity("ssh_ed25519");
+    let (rsa, rsa_recipient) = ssh_identity("ssh_rsa");
+    let mut rng = StdRng::seed_from_u64(2);
+    for len in [0, 1, 4096, 100_000] {
+        let plaintext = random_payload(&mut rng, len);
+        let ciphertext = encrypt_bytes(
+            vec![
+                Box::new(ed25519_recipient.clone()),
+                Box::new(x25519.to_public()),
+                Box::new(rsa_recipient.clone()),
+            ],
+            &plaintext,
+        );
+        for identity in [&x25519 as &dyn Identity, &ed25519, &rsa] {
+            assert!(decrypt_bytes(&[identity], &ciphertext).unwrap() == plaintext);
+        }
+    }
+}
+
+#[test]
+fn ssh_only() {
+    for name in ["ssh_ed25519", "ssh_rsa"] {
+        let (identity, recipient) = ssh_identity(name);
+        let decrypted = round_trip(vec![Box::new(recipient)], &identity, b"for one host");
+        assert_eq!(decrypted, b"for one host", "{}", name);
+    }
+}
+
+#[test]
+fn first_matching_identity_decrypts() {
+    let recipient = age::x25519::Identity::generate();
+    let others: Vec<age::x25519::Identity> =
+        (0..5).map(|_| age::x25519::Identity::generate()).collect();
+    let ciphertext = encrypt_bytes(vec![Box::new(recipient.to_public())], b"x");
+    let mut chain: Vec<&dyn Identity> = others.iter().map(|i| i as &dyn Identity).collect();
+    chain.push(&recipient);
+    assert_eq!(decrypt_bytes(&chain, &ciphertext).unwrap(), b"x");
+}
+
+#[test]
+fn other_identities_cannot_decrypt() {
+    let recipient = age::x25519::Identity::generate();
+    let other = age::x25519::Identity::generate();
+    let (ssh, _) = ssh_identity("ssh_ed25519");
+    let ciphertext = encrypt_bytes(vec![Box::new(recipient.to_public())], b"not for you");
+    assert!(matches!(
+        decrypt_bytes(&[&other, &ssh], &ciphertext),
+        Err(DecryptError::NoMatchingKeys)
+    ));
+    assert!(matches!(
+        decrypt_bytes(&[], &ciphertext),
+        Err(DecryptError::NoMatchingKeys)
+    ));
+}
+
+#[test]
+fn tampering_is_detected() {
+    let identity = age::x25519::Identity::generate();
+    let mut rng = StdRng::seed_from_u64(3);
+    let plaintext = random_payload(&mut rng, 10_000);
+    let ciphertext = encrypt_binary(vec![Box::new(identity.to_public())], &plaintext);
+    for _ in 0..CASES {
+        let mut tampered = ciphertext.clone();
+        let i = rng.gen_range(0..tampered.len());
+        tampered[i] ^= 1 << rng.gen_range(0..8);
+        assert!(
+            decrypt_bytes(&[&identity], &tampered).is_err(),
+            "flipping a bit of byte {} went unnoticed",
+            i
+        );
+    }
+}
+
+#[test]
+#[should_panic]
+fn no_recipients() {
+    encrypt_bytes(vec![], b"for nobody");
+}
+
+/// Recipient standing for a key management service that can't be reached
+struct Unreachable;
+
+impl Recipient for Unreachable {
+    fn wrap_file_key(
+        &self,
+        _: &age_core::format::FileKey,
+    ) -> Result<Vec<age_core::format::Stanza>, EncryptError> {
+        Err(EncryptError::Io(std::io::Error::other(
+            "kms:alias/x: aws failed",
+        )))
+    }
+}
+
+#[test]
+fn failing_recipients_are_errors() {
+    let identity = age::x25519::Identity::generate();
+    for len in [10, armor::PARALLEL_THRESHOLD + 1] {
+        let result = try_encrypt_bytes(
+            vec![Box::new(identity.to_public()), Box::new(Unreachable)],
+            &vec![0; len],
+        );
+        match result {
+            Err(EncryptError::Io(e)) => assert_eq!(e.to_string(), "kms:alias/x: aws failed"),
+            _ => panic!(
+                "encrypting to an unreachable recipient succeeded with {} bytes",
+                len
+            ),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Splitting and combining file keys for threshold files, over every threshold and number of
+//! share holders up to five.
+
+use arcanum::shamir::{combine, split, FILE_KEY_BYTES};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::collections::BTreeMap;
+
+const MAX_HOLDERS: u8 = 5;
+
+/// Every subset of the shares, as bit masks over their positions
+fn subsets(shares: &[[u8; FILE_KEY_BYTES]]) -> Vec<BTreeMap<u8, [u8; FILE_KEY_BYTES]>> {
+    (1u32..1 << shares.len())
+        .map(|mask| {
+            (1u8..)
+                .zip(shares)
+                .filter(|(index, _)| mask & 1 << (index - 1) != 0)
+                .map(|(index, share)| (index, *share))
+                .collect()
+        })
+        .collect()
+}
+
+fn random_key(rng: &mut StdRng) -> [u8; FILE_KEY_BYTES] {
+    let mut key = [0; FILE_KEY_BYTES];
+    rng.fill_bytes(&mut key);
+    key
+}
+
+#[test]
+fn threshold_shares_recover_the_key() {
+    let mut rng = StdRng::seed_from_u64(0);
+    for holders in 1..=MAX_HOLDERS {
+        for threshold in 1..=holders {
+            let key = random_key(&mut rng);
+            let shares = split(&key, threshold, holders);
+            assert_eq!(shares.len(), holders as usize);
+            for subset in subsets(&shares) {
+                if subset.len() >= threshold as usize {
+                    assert_eq!(
+                        combine(&subset),
+                        key,
+                        "{}-of-{} with shares {:?}",
+                        threshold,
+                        holders,
+                        subset.keys()
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn fewer_shares_do_not_recover_the_key() {
+    let mut rng = StdRng::seed_from_u64(1);
+    for holders in 2..=MAX_HOLDERS {
+        for threshold in 2..=holders {
+            let key = random_key(&mut rng);
+            let shares = split(&key, threshold, holders);
+            for subset in subsets(&shares) {
+                if subset.len() < threshold as usize {
+                    assert_ne!(
+                        combine(&subset),
+                        key,
+                        "{}-of-{} recovered from shares {:?}",
+                        threshold,
+                        holders,
+                        subset.keys()
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! One-time codes against the test vectors of RFC 6238 appendix B.
+
+use arcanum::totp::Totp;
+
+/// The seeds of the appendix, the ASCII digits repeated to the length of each hash
+const SEEDS: [(&str, usize); 3] = [("SHA1", 20), ("SHA256", 32), ("SHA512", 64)];
+
+/// Times and the eight digit codes for each of `SEEDS`
+const VECTORS: [(u64, [&str; 3]); 6] = [
+    (59, ["94287082", "46119246", "90693936"]),
+    (1111111109, ["07081804", "68084774", "25091201"]),
+    (1111111111, ["14050471", "67062674", "99943326"]),
+    (1234567890, ["89005924", "91819424", "93441116"]),
+    (2000000000, ["69279037", "90698825", "38618901"]),
+    (20000000000, ["65353130", "77737706", "47863826"]),
+];
+
+/// RFC 4648 base32, as secrets are given in otpauth URIs
+fn base32(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut encoded = String::new();
+    let mut bits: u64 = 0;
+    let mut count = 0;
+    for byte in data {
+        bits = (bits << 8) | u64::from(*byte);
+        count += 8;
+        while count >= 5 {
+            count -= 5;
+            encoded.push(ALPHABET[(bits >> count) as usize & 31] as char);
+        }
+    }
+    if count > 0 {
+        encoded.push(ALPHABET[(bits << (5 - count)) as usize & 31] as char);
+    }
+    encoded
+}
+
+fn totp(algorithm: &str, length: usize) -> Totp {
+    let seed: Vec<u8> = b"1234567890".iter().copied().cycle().take(length).collect();
+    let uri = format!(
+        "otpauth://totp/rfc6238?secret={}&algorithm={}&digits=8&period=30",
+        base32(&seed),
+        algorithm
+    );
+    Totp::parse(&uri).unwrap()
+}
+
+#[test]
+fn codes_match_rfc_6238() {
+    for (index, (algorithm, length)) in SEEDS.into_iter().enumerate() {
+        let totp = totp(algorithm, length);
+        for (time, codes) in VECTORS {
+            let (code, _) = totp.code_at(time);
+            assert_eq!(code, codes[index], "{} at {}", algorithm, time);
+        }
+    }
+}
+
+#[test]
+fn codes_stay_valid_until_the_end_of_their_period() {
+    let totp = totp("SHA1", 20);
+    assert_eq!(totp.code_at(59).1, 1);
+    assert_eq!(totp.code_at(60).1, 30);
+}